@@ -0,0 +1,205 @@
+//! 联系人核验：安全码、已核验状态与身份公钥变更提醒
+//!
+//! 联系人的身份公钥取其设备证书中的用户公钥，没有证书时取设备公钥本身，因此同一
+//! 账户的各台设备安全码相同。首次与联系人私聊时记下身份公钥，之后公钥变化即推送
+//! [`MessageEvent::IdentityKeyChanged`] 并清除已核验状态。
+use libp2p::PeerId;
+use rootcell::SafetyNumber;
+use tokio::sync::mpsc;
+
+use std::collections::HashMap;
+
+use crate::{
+    ChatCore, MessageEvent, identity,
+    storage::{ContactIdentity, Storage},
+};
+
+/// 与联系人的核验信息
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub peer: PeerId,
+    pub safety_number: SafetyNumber,
+    ///用户已确认当前安全码
+    pub verified: bool,
+}
+
+/// 已记录的联系人身份
+#[derive(Debug)]
+pub(crate) struct Contacts {
+    known: HashMap<PeerId, ContactIdentity>,
+    ///按顺序写入存储，后写的核验状态不会被先前的记录覆盖
+    writes: mpsc::UnboundedSender<ContactIdentity>,
+}
+
+impl Contacts {
+    pub(crate) fn new(storage: Storage) -> Self {
+        let (writes, mut rx) = mpsc::unbounded_channel::<ContactIdentity>();
+        tokio::spawn(async move {
+            while let Some(contact) = rx.recv().await {
+                if let Err(e) = storage.save_contact_identity(&contact).await {
+                    tracing::warn!("failed to store contact identity: {e:?}");
+                }
+            }
+        });
+        Self {
+            known: HashMap::new(),
+            writes,
+        }
+    }
+
+    fn save(&mut self, peer: PeerId, contact: ContactIdentity) {
+        let _ = self.writes.send(contact.clone());
+        let _ = self.known.insert(peer, contact);
+    }
+}
+
+impl ChatCore {
+    /// 载入已记录的联系人身份
+    pub(crate) async fn restore_contacts(&mut self) -> anyhow::Result<()> {
+        for contact in self.storage.contact_identities().await? {
+            match contact.peer_id.parse() {
+                Ok(peer) => {
+                    let _ = self.contacts.known.insert(peer, contact);
+                }
+                Err(e) => tracing::warn!("skipped contact {}: {e}", contact.peer_id),
+            }
+        }
+        Ok(())
+    }
+
+    /// 节点当前的身份公钥：所属用户的公钥，没有证书时为设备公钥
+    fn identity_key_of(&self, peer: &PeerId) -> anyhow::Result<[u8; 32]> {
+        let device_key = identity::peer_public_key(peer)?;
+        Ok(self.user_of(&device_key).unwrap_or(device_key))
+    }
+
+    /// 是否已记录该联系人
+    pub(crate) fn is_contact(&self, peer: &PeerId) -> bool {
+        self.contacts.known.contains_key(peer)
+    }
+
+    /// 记录联系人的身份公钥；与之前记录的不同时推送提醒并清除核验状态
+    pub(crate) fn observe_contact(&mut self, peer: PeerId) {
+        let Ok(identity_key) = self.identity_key_of(&peer) else {
+            return;
+        };
+        if let Some(known) = self.contacts.known.get(&peer) {
+            if known.identity_key == identity_key {
+                return;
+            }
+            // 先于证书看到设备公钥、随后得知所属用户属于正常情况，除非已按设备公钥核验过
+            let learned_user = identity::peer_public_key(&peer)
+                .is_ok_and(|device_key| device_key == known.identity_key);
+            if known.verified || !learned_user {
+                tracing::warn!("identity key of {peer} changed");
+                self.send_event(MessageEvent::IdentityKeyChanged {
+                    peer,
+                    was_verified: known.verified,
+                });
+            }
+        }
+        let contact = ContactIdentity {
+            peer_id: peer.to_string(),
+            identity_key,
+            verified: false,
+        };
+        self.contacts.save(peer, contact);
+    }
+
+    /// 与联系人的安全码及核验状态
+    pub fn verification(&mut self, peer: PeerId) -> anyhow::Result<Verification> {
+        self.observe_contact(peer);
+        let own_key = self.identity_key_of(self.swarm.local_peer_id())?;
+        let contact = self
+            .contacts
+            .known
+            .get(&peer)
+            .ok_or_else(|| anyhow::anyhow!("{peer} has no identity key"))?;
+        Ok(Verification {
+            peer,
+            safety_number: SafetyNumber::new(&own_key, &contact.identity_key),
+            verified: contact.verified,
+        })
+    }
+
+    /// 用户带外比对安全码后标记（或取消）联系人为已核验
+    pub fn set_verified(&mut self, peer: PeerId, verified: bool) -> anyhow::Result<Verification> {
+        let mut verification = self.verification(peer)?;
+        if let Some(contact) = self.contacts.known.get(&peer).cloned() {
+            self.contacts.save(
+                peer,
+                ContactIdentity {
+                    verified,
+                    ..contact
+                },
+            );
+        }
+        verification.verified = verified;
+        Ok(verification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CoreConfig, device::unix_now};
+    use rootcell::{DeviceCertificate, Identity};
+
+    #[tokio::test]
+    async fn identity_key_change_resets_verification() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let mut events = core.rx_message.take().unwrap();
+        let device = Identity::generate().unwrap();
+        let peer = identity::peer_id(&device.public_key()).unwrap();
+        let certify = |user: &Identity| {
+            let now = unix_now();
+            DeviceCertificate::issue(user, device.public_key(), "phone", now, now + 60_000)
+        };
+
+        // 先看到设备公钥、随后收到其账户证书，不算变更
+        core.observe_contact(peer);
+        let bob = Identity::generate().unwrap();
+        core.accept_certificate(certify(&bob)).unwrap();
+        let verification = core.set_verified(peer, true).unwrap();
+        assert!(verification.verified);
+        let own_user = core.keys.user.as_ref().unwrap().public_key();
+        assert_eq!(
+            verification.safety_number,
+            SafetyNumber::new(&bob.public_key(), &own_user)
+        );
+        // 写入按顺序进行，最后一次写入的是已核验状态
+        for _ in 0..50 {
+            let stored = core.storage.contact_identities().await.unwrap();
+            if stored.first().is_some_and(|c| c.verified) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(core.storage.contact_identities().await.unwrap()[0].verified);
+
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let mallory = Identity::generate().unwrap();
+        core.accept_certificate(certify(&mallory)).unwrap();
+        let changed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    MessageEvent::IdentityKeyChanged { peer, was_verified } => {
+                        break (peer, was_verified);
+                    }
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(changed, (peer, true));
+        let verification = core.verification(peer).unwrap();
+        assert!(!verification.verified);
+        assert_ne!(
+            verification.safety_number,
+            SafetyNumber::new(&bob.public_key(), &own_user)
+        );
+    }
+}
@@ -0,0 +1,598 @@
+//! 多设备账户：设备证书广播、配对协议 `/mychat/pair/1` 与私聊扇出
+//!
+//! 每个安装默认是只有一台设备的账户，启动时用本地用户身份给自己签发证书。另一台
+//! 设备输入配对口令加入账户后丢弃自己的用户身份，改持签发方给的证书；签发设备
+//! 定期为账户内的全部设备续签。证书经 [`DEVICE_TOPIC`] 广播，其他节点据此把发给
+//! 某台设备的私聊同时发给同一用户的全部有效设备。
+use libp2p::{
+    PeerId, StreamProtocol,
+    gossipsub::{self, IdentTopic, MessageId},
+    request_response::{self, OutboundRequestId, ProtocolSupport, cbor},
+};
+use rootcell::{DeviceCertificate, device};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use crate::{ChatCore, MessageEvent, identity, storage};
+
+/// 广播设备证书的主题，不作为聊天房间
+pub const DEVICE_TOPIC: &str = "mychat-devices";
+/// 配对协议名
+pub const PAIR_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/pair/1");
+/// 设备证书有效期：90 天
+const CERTIFICATE_LIFETIME_MS: u64 = 90 * 24 * 60 * 60 * 1000;
+/// 配对口令有效期：5 分钟
+const PAIRING_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+/// 运行期间检查证书是否需要续签的间隔：1 小时
+pub(crate) const RENEWAL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// 配对口令允许的错误尝试次数，用尽后作废
+const PAIRING_ATTEMPTS: u32 = 3;
+
+/// 配对请求：新设备证明自己知道口令
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairRequest {
+    ///[`rootcell::device::pairing_proof`] 的输出
+    #[serde(with = "serde_bytes")]
+    pub proof: Vec<u8>,
+    pub device_name: String,
+}
+
+/// 配对回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PairResponse {
+    /// 为新设备签发的证书，附签发设备自己的证书
+    Certified {
+        certificate: Box<DeviceCertificate>,
+        issuer: Box<DeviceCertificate>,
+    },
+    /// 口令错误、已过期或签发方没有用户身份
+    Rejected { reason: String },
+}
+
+/// 证书主题上的消息
+///
+/// gossipsub 按内容去重，附上发布时间后，新节点订阅时重发的同一批证书不会被当作
+/// 重复消息丢弃
+#[derive(Debug, Serialize, Deserialize)]
+struct CertificateBundle {
+    published_at: u64,
+    certificates: Vec<DeviceCertificate>,
+}
+
+pub type PairBehaviour = cbor::Behaviour<PairRequest, PairResponse>;
+pub type PairEvent = request_response::Event<PairRequest, PairResponse>;
+
+pub(crate) fn behaviour() -> PairBehaviour {
+    cbor::Behaviour::new(
+        [(PAIR_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// 本设备发出的配对口令
+struct PairingOffer {
+    secret: [u8; device::PAIRING_SECRET_LEN],
+    expires_at: u64,
+    attempts_left: u32,
+}
+
+/// 等待签发方回复的配对
+#[derive(Debug)]
+pub(crate) struct PendingPair {
+    issuer: PeerId,
+    reply: oneshot::Sender<anyhow::Result<DeviceCertificate>>,
+}
+
+/// 已知的设备证书与配对状态
+#[derive(Default)]
+pub(crate) struct Devices {
+    ///本设备的显示名称
+    name: String,
+    ///签名与有效期均已校验的证书，按设备公钥索引
+    certificates: HashMap<[u8; 32], DeviceCertificate>,
+    offer: Option<PairingOffer>,
+    pending: HashMap<OutboundRequestId, PendingPair>,
+}
+
+impl fmt::Debug for Devices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Devices")
+            .field("name", &self.name)
+            .field("certificates", &self.certificates.len())
+            .field("pairing", &self.offer.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// 当前 unix 时间（毫秒）
+pub(crate) fn unix_now() -> u64 {
+    u64::try_from(storage::now_millis()).unwrap_or(0)
+}
+
+/// 没有配置设备名时使用主机名
+fn default_device_name() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .unwrap_or_else(|| "unnamed device".to_string())
+}
+
+impl ChatCore {
+    /// 订阅证书主题，载入已保存的证书，并确保本设备持有有效证书
+    pub(crate) async fn restore_devices(&mut self, name: Option<String>) -> anyhow::Result<()> {
+        self.devices.name = name.unwrap_or_else(default_device_name);
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&IdentTopic::new(DEVICE_TOPIC))?;
+        let now = unix_now();
+        let local_user = self.keys.user.as_ref().map(|u| u.public_key());
+        let mut lapsed = Vec::new();
+        for certificate in self.storage.device_certificates().await? {
+            match certificate.verify(now) {
+                Ok(()) => {
+                    let _ = self
+                        .devices
+                        .certificates
+                        .insert(certificate.device_key, certificate);
+                }
+                // 本账户签发过的证书过期了也要续签，否则离线太久的签发设备会丢掉已配对的设备
+                Err(_)
+                    if Some(certificate.user_key) == local_user
+                        && certificate.verify(certificate.issued_at).is_ok() =>
+                {
+                    lapsed.push(certificate);
+                }
+                Err(e) => tracing::debug!("skipped stored device certificate: {e}"),
+            }
+        }
+
+        let own_key = self.keys.identity.public_key();
+        let own_user = self.devices.certificates.get(&own_key).map(|c| c.user_key);
+        match (own_user, local_user) {
+            // 通过配对加入了其他账户
+            (Some(owner), local) if local != Some(owner) => self.keys.forget_user()?,
+            // 单设备账户或签发设备，按需续签
+            (_, Some(_)) => self.renew_certificates(lapsed),
+            // 没有证书也没有用户身份：首次启动，或配对得到的证书已过期
+            (_, None) => {
+                let _ = self.keys.create_user()?;
+                self.renew_certificates(lapsed);
+            }
+        }
+        Ok(())
+    }
+
+    /// 持有用户身份时，为本设备及本账户签发过的其他设备重新签发缺失或临近过期的证书
+    ///
+    /// 启动时与运行期间定期调用；`lapsed` 是存储中已过期、不再载入的本账户证书
+    pub(crate) fn renew_certificates(&mut self, lapsed: Vec<DeviceCertificate>) {
+        let Some(user) = &self.keys.user else {
+            return;
+        };
+        let user_key = user.public_key();
+        let own_key = self.keys.identity.public_key();
+        let now = unix_now();
+        let mut due: HashMap<[u8; 32], String> = self
+            .devices
+            .certificates
+            .values()
+            .chain(&lapsed)
+            .filter(|c| c.user_key == user_key && c.needs_renewal(now))
+            .map(|c| (c.device_key, c.device_name.clone()))
+            .collect();
+        let current = self.devices.certificates.get(&own_key);
+        if current.is_none_or(|c| c.user_key != user_key) {
+            let _ = due.insert(own_key, self.devices.name.clone());
+        }
+        let issued: Vec<_> = due
+            .into_iter()
+            .map(|(device_key, name)| {
+                DeviceCertificate::issue(
+                    user,
+                    device_key,
+                    name,
+                    now,
+                    now.saturating_add(CERTIFICATE_LIFETIME_MS),
+                )
+            })
+            .collect();
+        let mut renewed = Vec::new();
+        for certificate in issued {
+            match self.accept_certificate(certificate.clone()) {
+                Ok(true) => renewed.push(certificate),
+                Ok(false) => {}
+                // 已吊销的设备不再续签
+                Err(e) => tracing::debug!("device certificate not renewed: {e}"),
+            }
+        }
+        if !renewed.is_empty()
+            && let Err(e) = self.publish_certificates(renewed)
+        {
+            tracing::warn!("failed to publish renewed device certificates: {e}");
+        }
+    }
+
+    /// 已知证书的设备公钥
+    pub(crate) fn certified_keys(&self) -> impl Iterator<Item = [u8; 32]> + '_ {
+        self.devices.certificates.keys().copied()
+    }
+
+    /// 本设备的证书
+    fn own_certificate(&self) -> Option<&DeviceCertificate> {
+        self.devices
+            .certificates
+            .get(&self.keys.identity.public_key())
+    }
+
+    /// 设备所属用户的身份公钥，没有有效证书时为 None
+    pub(crate) fn user_of(&self, device_key: &[u8; 32]) -> Option<[u8; 32]> {
+        self.devices
+            .certificates
+            .get(device_key)
+            .filter(|c| c.expires_at > unix_now())
+            .map(|c| c.user_key)
+    }
+
+    /// 与节点同属一个用户的其他有效设备，不含节点本身与本设备
+    pub(crate) fn other_devices(&self, peer: &PeerId) -> Vec<PeerId> {
+        let Some(user) = identity::peer_public_key(peer)
+            .ok()
+            .and_then(|key| self.user_of(&key))
+        else {
+            return Vec::new();
+        };
+        let now = unix_now();
+        let own_key = self.keys.identity.public_key();
+        self.devices
+            .certificates
+            .values()
+            .filter(|c| c.user_key == user && c.device_key != own_key && c.expires_at > now)
+            .filter_map(|c| identity::peer_id(&c.device_key).ok())
+            .filter(|device| device != peer && self.check_peer(device).is_ok())
+            .collect()
+    }
+
+    /// 校验并保存证书，返回是否是新的或更新的证书；持有对应用户身份时顺带续签并发布
+    pub(crate) fn accept_certificate(
+        &mut self,
+        certificate: DeviceCertificate,
+    ) -> anyhow::Result<bool> {
+        let now = unix_now();
+        certificate.verify(now)?;
+        self.revocations
+            .check_device(&certificate.user_key, &certificate.device_key)?;
+        let previous = self.devices.certificates.get(&certificate.device_key);
+        if previous.is_some_and(|p| p.issued_at >= certificate.issued_at) {
+            return Ok(false);
+        }
+        let added = previous.is_none_or(|p| p.user_key != certificate.user_key);
+
+        let storage = self.storage.clone();
+        let stored = certificate.clone();
+        tokio::spawn(async move {
+            if let Err(e) = storage.upsert_device_certificate(&stored).await {
+                tracing::warn!("failed to store device certificate: {e:?}");
+            }
+        });
+        if added {
+            self.send_event(MessageEvent::DeviceAdded {
+                user: identity::peer_id(&certificate.user_key)?,
+                device: identity::peer_id(&certificate.device_key)?,
+                name: certificate.device_name.clone(),
+            });
+        }
+        let renew = self
+            .keys
+            .user
+            .as_ref()
+            .filter(|user| user.public_key() == certificate.user_key)
+            .filter(|_| certificate.needs_renewal(now))
+            .map(|user| {
+                DeviceCertificate::issue(
+                    user,
+                    certificate.device_key,
+                    certificate.device_name.clone(),
+                    now,
+                    now.saturating_add(CERTIFICATE_LIFETIME_MS),
+                )
+            });
+        let device = identity::peer_id(&certificate.device_key)?;
+        let _ = self
+            .devices
+            .certificates
+            .insert(certificate.device_key, certificate);
+        if self.is_contact(&device) {
+            self.observe_contact(device);
+        }
+        if let Some(renewed) = renew
+            && self.accept_certificate(renewed.clone())?
+        {
+            self.publish_certificates(vec![renewed])?;
+        }
+        Ok(true)
+    }
+
+    /// 发布证书；没有订阅者时只记日志
+    fn publish_certificates(&mut self, certificates: Vec<DeviceCertificate>) -> anyhow::Result<()> {
+        let bundle = CertificateBundle {
+            published_at: unix_now(),
+            certificates,
+        };
+        let mut data = Vec::new();
+        ciborium::into_writer(&bundle, &mut data)?;
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(IdentTopic::new(DEVICE_TOPIC), data)
+        {
+            tracing::debug!("device certificate not published: {e}");
+        }
+        Ok(())
+    }
+
+    /// 生成一次性配对口令 `<PeerId>:<随机串>`，在新设备上输入即可加入本账户
+    pub fn start_pairing(&mut self) -> anyhow::Result<String> {
+        if self.keys.user.is_none() {
+            anyhow::bail!("this device joined another account, pair from the issuing device");
+        }
+        let secret = device::pairing_secret()?;
+        self.devices.offer = Some(PairingOffer {
+            secret,
+            expires_at: unix_now().saturating_add(PAIRING_TIMEOUT_MS),
+            attempts_left: PAIRING_ATTEMPTS,
+        });
+        Ok(format!(
+            "{}:{}",
+            self.swarm.local_peer_id(),
+            hex::encode(secret)
+        ))
+    }
+
+    /// 用另一台设备显示的口令请求加入其账户，结果经 `reply` 返回
+    pub(crate) fn pair_with(
+        &mut self,
+        code: &str,
+        reply: oneshot::Sender<anyhow::Result<DeviceCertificate>>,
+    ) -> anyhow::Result<()> {
+        let (issuer, secret) = code
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("malformed pairing code"))?;
+        let issuer: PeerId = issuer.parse()?;
+        let secret = hex::decode(secret)?;
+        let proof = device::pairing_proof(
+            &secret,
+            &self.keys.identity.public_key(),
+            &identity::peer_public_key(&issuer)?,
+        );
+        let request_id = self.swarm.behaviour_mut().pair.send_request(
+            &issuer,
+            PairRequest {
+                proof: proof.to_vec(),
+                device_name: self.devices.name.clone(),
+            },
+        );
+        let _ = self
+            .devices
+            .pending
+            .insert(request_id, PendingPair { issuer, reply });
+        Ok(())
+    }
+
+    /// 校验配对请求并为对方签发证书
+    fn certify_peer(&mut self, peer: PeerId, request: PairRequest) -> anyhow::Result<PairResponse> {
+        let offer = self
+            .devices
+            .offer
+            .as_mut()
+            .filter(|offer| offer.expires_at > unix_now())
+            .ok_or_else(|| anyhow::anyhow!("no pairing in progress"))?;
+        let device_key = identity::peer_public_key(&peer)?;
+        if let Err(e) = device::verify_pairing_proof(
+            &offer.secret,
+            &device_key,
+            &self.keys.identity.public_key(),
+            &request.proof,
+        ) {
+            offer.attempts_left = offer.attempts_left.saturating_sub(1);
+            if offer.attempts_left == 0 {
+                self.devices.offer = None;
+            }
+            return Err(e.into());
+        }
+        self.devices.offer = None;
+
+        let user = self
+            .keys
+            .user
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("this device has no user identity"))?;
+        let now = unix_now();
+        let certificate = DeviceCertificate::issue(
+            user,
+            device_key,
+            request.device_name,
+            now,
+            now.saturating_add(CERTIFICATE_LIFETIME_MS),
+        );
+        let _ = self.accept_certificate(certificate.clone())?;
+        self.publish_certificates(vec![certificate.clone()])?;
+        let issuer = self
+            .own_certificate()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("this device has no certificate"))?;
+        Ok(PairResponse::Certified {
+            certificate: Box::new(certificate),
+            issuer: Box::new(issuer),
+        })
+    }
+
+    /// 校验签发方的回复并加入其账户
+    fn join_account(
+        &mut self,
+        issuer_peer: PeerId,
+        certificate: DeviceCertificate,
+        issuer: DeviceCertificate,
+    ) -> anyhow::Result<DeviceCertificate> {
+        let now = unix_now();
+        certificate.verify(now)?;
+        issuer.verify(now)?;
+        if certificate.device_key != self.keys.identity.public_key()
+            || issuer.device_key != identity::peer_public_key(&issuer_peer)?
+            || certificate.user_key != issuer.user_key
+        {
+            anyhow::bail!("pairing response does not match this pairing");
+        }
+        let _ = self.accept_certificate(issuer)?;
+        // 替换本设备自签的证书，即使两者签发时间相同
+        let _ = self.devices.certificates.remove(&certificate.device_key);
+        let _ = self.accept_certificate(certificate.clone())?;
+        self.keys.forget_user()?;
+        Ok(certificate)
+    }
+}
+
+/// 处理证书主题上的消息，证书本身带签名，转发者是谁无关紧要
+pub(crate) fn handle_message(core: &mut ChatCore, id: MessageId, message: gossipsub::Message) {
+    let bundle = match ciborium::from_reader::<CertificateBundle, _>(message.data.as_slice()) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            tracing::debug!("ignored malformed device certificates {id}: {e}");
+            return;
+        }
+    };
+    for certificate in bundle.certificates {
+        if let Err(e) = core.accept_certificate(certificate) {
+            tracing::debug!("ignored device certificate in {id}: {e}");
+        }
+    }
+}
+
+/// 新节点订阅证书主题时重新发布已知的有效证书
+pub(crate) fn peer_subscribed(core: &mut ChatCore) {
+    let now = unix_now();
+    let known: Vec<_> = core
+        .devices
+        .certificates
+        .values()
+        .filter(|c| c.expires_at > now)
+        .cloned()
+        .collect();
+    if !known.is_empty()
+        && let Err(e) = core.publish_certificates(known)
+    {
+        tracing::warn!("failed to republish device certificates: {e}");
+    }
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: PairEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => {
+            let response = core.certify_peer(peer, request).unwrap_or_else(|e| {
+                tracing::warn!("rejected pairing request from {peer}: {e}");
+                PairResponse::Rejected {
+                    reason: e.to_string(),
+                }
+            });
+            if core
+                .swarm
+                .behaviour_mut()
+                .pair
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("pairing response to {peer} dropped, connection closed");
+            }
+        }
+        request_response::Event::Message {
+            message:
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => {
+            let Some(pending) = core.devices.pending.remove(&request_id) else {
+                return;
+            };
+            let result = match response {
+                PairResponse::Certified {
+                    certificate,
+                    issuer,
+                } => core.join_account(pending.issuer, *certificate, *issuer),
+                PairResponse::Rejected { reason } => {
+                    Err(anyhow::anyhow!("pairing rejected: {reason}"))
+                }
+            };
+            let _ = pending.reply.send(result);
+        }
+        request_response::Event::OutboundFailure {
+            request_id, error, ..
+        } => {
+            if let Some(pending) = core.devices.pending.remove(&request_id) {
+                let _ = pending.reply.send(Err(error.into()));
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound pairing request from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use rootcell::Identity;
+
+    #[tokio::test]
+    async fn issuer_renews_paired_devices() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let user = core.keys.user.as_ref().unwrap();
+        let now = unix_now();
+        let day = 24 * 60 * 60 * 1000;
+        // 临近过期的已配对设备，直接放入内存以免 accept_certificate 立即续签
+        let expiring = DeviceCertificate::issue(
+            user,
+            Identity::generate().unwrap().public_key(),
+            "laptop",
+            now - 80 * day,
+            now + 10 * day,
+        );
+        // 签发设备离线期间已过期的证书
+        let lapsed = DeviceCertificate::issue(
+            user,
+            Identity::generate().unwrap().public_key(),
+            "phone",
+            now - 100 * day,
+            now - 10 * day,
+        );
+        let _ = core
+            .devices
+            .certificates
+            .insert(expiring.device_key, expiring.clone());
+
+        core.renew_certificates(vec![lapsed.clone()]);
+        for old in [expiring, lapsed] {
+            let renewed = &core.devices.certificates[&old.device_key];
+            assert!(renewed.issued_at >= now);
+            assert_eq!(renewed.device_name, old.device_name);
+            assert!(!renewed.needs_renewal(unix_now()));
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! 一对一私聊：基于 request-response 的 `/mychat/dm/1` 协议
+//!
+//! 请求携带经双棘轮会话端到端加密的 [`WireMessage`]，对方解密后回复确认并按到达
+//! 顺序写入记录，发送方据此标记消息已送达。会话由首条消息的 X3DH 协商建立，见
+//! [`crate::session`]。对方不在线时改交信箱代存，见 [`crate::mailbox`]。
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, OutboundRequestId, ProtocolSupport, cbor},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    Author, ChatCore, MessageEvent,
+    session::{NoSession, Queued},
+    storage::{ConversationKind, Storage},
+    wire::{MessageKind, WireMessage},
+};
+
+/// 私聊协议名
+pub const DM_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/dm/1");
+
+/// 私聊请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectRequest {
+    ///加密后的消息信封
+    #[serde(with = "serde_bytes")]
+    pub sealed: Vec<u8>,
+}
+
+/// 私聊回执
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectResponse {
+    /// 已收到并保存
+    Ack { id: String },
+    /// 无法处理，如解密失败或版本不兼容
+    Rejected { reason: String },
+    /// 没有可用的会话，发送方应重新协商
+    NoSession,
+}
+
+pub type DirectBehaviour = cbor::Behaviour<DirectRequest, DirectResponse>;
+pub type DirectEvent = request_response::Event<DirectRequest, DirectResponse>;
+
+/// 已解密、待写入记录的私聊
+#[derive(Debug)]
+pub(crate) struct Received {
+    from: PeerId,
+    author: Author,
+    payload: WireMessage,
+}
+
+/// 按到达顺序写入收到的私聊，只有写入了新消息才通知前端；
+/// 经信箱和直连先后收到的同一条消息只通知一次
+pub(crate) fn inbox(
+    storage: Storage,
+    events: mpsc::UnboundedSender<MessageEvent>,
+) -> mpsc::UnboundedSender<Received> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Received>();
+    tokio::spawn(async move {
+        while let Some(Received {
+            from,
+            author,
+            payload,
+        }) = rx.recv().await
+        {
+            let name = from.to_string();
+            let stored = crate::save_message(
+                &storage,
+                ConversationKind::Direct,
+                &name,
+                payload.clone(),
+                false,
+            )
+            .await;
+            match stored {
+                Ok(Some(_)) => {
+                    let _ = events.send(MessageEvent::DirectMessageReceived {
+                        from,
+                        author,
+                        payload,
+                    });
+                }
+                Ok(None) => tracing::debug!("duplicate direct message {} from {from}", payload.id),
+                Err(e) => tracing::warn!("failed to store direct message: {e:?}"),
+            }
+        }
+    });
+    tx
+}
+
+pub(crate) fn behaviour() -> DirectBehaviour {
+    cbor::Behaviour::new(
+        [(DM_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// 等待回执的私聊
+#[derive(Debug)]
+pub(crate) struct PendingDirect {
+    peer: PeerId,
+    queued: Queued,
+    ///加密后的请求，对方不在线时交给信箱
+    sealed: Vec<u8>,
+}
+
+impl ChatCore {
+    /// 向指定节点发送私聊消息，并扇出到同一用户的其他已认证设备；
+    /// 各设备的送达结果分别通过事件通知
+    pub fn send_direct(&mut self, peer: PeerId, text: String) -> anyhow::Result<WireMessage> {
+        self.check_peer(&peer)?;
+        self.observe_contact(peer);
+        let message = WireMessage::text(self.swarm.local_peer_id().to_string(), text)
+            .sign(&self.keys.identity)?;
+        self.send_tracked(peer, &message)?;
+        for device in self.other_devices(&peer) {
+            if let Err(e) = self.send_tracked(device, &message) {
+                tracing::warn!("failed to send to {peer}'s device {device}: {e}");
+            }
+        }
+        self.store_message(
+            ConversationKind::Direct,
+            peer.to_string(),
+            message.clone(),
+            true,
+        );
+        Ok(message)
+    }
+
+    /// 加密并发出一条不通知前端的私聊，如发送者密钥
+    pub(crate) fn send_sealed(
+        &mut self,
+        peer: PeerId,
+        message: &WireMessage,
+    ) -> anyhow::Result<()> {
+        self.send_queued(peer, Queued::new(message.clone(), false))
+    }
+
+    /// 加密并发出一条等待回执的私聊
+    fn send_tracked(&mut self, peer: PeerId, message: &WireMessage) -> anyhow::Result<()> {
+        self.send_queued(peer, Queued::new(message.clone(), true))
+    }
+
+    /// 有会话时加密并发出，否则排队等待建立会话
+    pub(crate) fn send_queued(&mut self, peer: PeerId, queued: Queued) -> anyhow::Result<()> {
+        if !self.has_session(&peer) {
+            return self.queue_direct(peer, queued);
+        }
+        let sealed = self.seal_direct(peer, &queued.message.encode()?)?;
+        let request_id = self.swarm.behaviour_mut().direct.send_request(
+            &peer,
+            DirectRequest {
+                sealed: sealed.clone(),
+            },
+        );
+        self.pending_direct.insert(
+            request_id,
+            PendingDirect {
+                peer,
+                queued,
+                sealed,
+            },
+        );
+        Ok(())
+    }
+
+    /// 私聊发送失败，需要回执的通知前端
+    pub(crate) fn direct_failed(&mut self, peer: PeerId, queued: &Queued, error: String) {
+        if queued.tracked {
+            self.send_event(MessageEvent::DirectFailed {
+                peer,
+                id: queued.message.id.clone(),
+                error,
+            });
+        } else {
+            tracing::warn!("failed to send {} to {peer}: {error}", queued.message.id);
+        }
+    }
+
+    /// 解密并解码节点发来的私聊
+    fn open_direct(&mut self, peer: PeerId, sealed: &[u8]) -> anyhow::Result<WireMessage> {
+        let plaintext = self.open_direct_sealed(peer, sealed)?;
+        Ok(WireMessage::decode(&plaintext)?)
+    }
+
+    fn take_pending(&mut self, request_id: &OutboundRequestId) -> Option<PendingDirect> {
+        self.pending_direct.remove(request_id)
+    }
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: DirectEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => {
+            let response = match core.check_peer(&peer) {
+                Err(e) => {
+                    crate::revocation::drop_message(core, peer);
+                    DirectResponse::Rejected {
+                        reason: e.to_string(),
+                    }
+                }
+                Ok(()) => handle_request(core, peer, &request.sealed),
+            };
+            if core
+                .swarm
+                .behaviour_mut()
+                .direct
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("direct message ack to {peer} dropped, connection closed");
+            }
+        }
+        request_response::Event::Message {
+            message:
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => {
+            let Some(PendingDirect { peer, queued, .. }) = core.take_pending(&request_id) else {
+                return;
+            };
+            match response {
+                DirectResponse::Ack { .. } if queued.tracked => {
+                    let storage = core.storage.clone();
+                    let id = queued.message.id.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = storage.mark_delivered(&id).await {
+                            tracing::warn!("failed to mark {id} delivered: {e:?}");
+                        }
+                    });
+                    core.send_event(MessageEvent::DirectDelivered {
+                        peer,
+                        id: queued.message.id,
+                    });
+                }
+                DirectResponse::Ack { .. } => {}
+                DirectResponse::Rejected { reason } => core.direct_failed(peer, &queued, reason),
+                // 对方丢失了会话：重新协商后重发一次
+                DirectResponse::NoSession if !queued.retried => {
+                    core.reset_session(&peer);
+                    let retry = Queued {
+                        retried: true,
+                        ..queued.clone()
+                    };
+                    if let Err(e) = core.send_queued(peer, retry) {
+                        core.direct_failed(peer, &queued, e.to_string());
+                    }
+                }
+                DirectResponse::NoSession => {
+                    core.direct_failed(peer, &queued, NoSession(peer).to_string());
+                }
+            }
+        }
+        request_response::Event::OutboundFailure {
+            request_id, error, ..
+        } => {
+            if let Some(pending) = core.take_pending(&request_id) {
+                if pending.queued.tracked {
+                    core.deposit_mail(
+                        pending.peer,
+                        pending.queued.message.id,
+                        pending.sealed,
+                        error.to_string(),
+                    );
+                } else {
+                    tracing::debug!("direct message to {} failed: {error}", pending.peer);
+                }
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound direct message from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+/// 解密、验证并处理一条私聊请求，返回回执
+pub(crate) fn handle_request(core: &mut ChatCore, peer: PeerId, sealed: &[u8]) -> DirectResponse {
+    let opened = core
+        .open_direct(peer, sealed)
+        .and_then(|payload| Ok((core.authenticate(&payload)?, payload)));
+    match opened {
+        // 发送者密钥只接受持有者本人分发的
+        Ok((author, payload)) if payload.kind == MessageKind::SenderKey => {
+            let accepted = if author.device == peer {
+                core.accept_sender_key(peer, &payload)
+            } else {
+                Err(anyhow::anyhow!(
+                    "sender key relayed on behalf of {}",
+                    author.device
+                ))
+            };
+            match accepted {
+                Ok(()) => DirectResponse::Ack { id: payload.id },
+                Err(e) => {
+                    tracing::warn!("rejected sender key from {peer}: {e}");
+                    DirectResponse::Rejected {
+                        reason: e.to_string(),
+                    }
+                }
+            }
+        }
+        Ok((author, payload)) => {
+            core.observe_contact(peer);
+            let id = payload.id.clone();
+            let _ = core.inbox.send(Received {
+                from: peer,
+                author,
+                payload,
+            });
+            DirectResponse::Ack { id }
+        }
+        Err(e) if e.is::<NoSession>() => {
+            tracing::debug!("no session for direct message from {peer}");
+            DirectResponse::NoSession
+        }
+        Err(e) => {
+            tracing::warn!("rejected direct message from {peer}: {e}");
+            DirectResponse::Rejected {
+                reason: e.to_string(),
+            }
+        }
+    }
+}
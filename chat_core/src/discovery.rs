@@ -0,0 +1,179 @@
+//! 广域网节点发现：Kademlia DHT `/mychat/kad/1`
+//!
+//! mDNS 只能发现同一局域网的节点。启动时把 [`crate::CoreConfig::with_bootstrap_node`]
+//! 配置的引导节点写入路由表并 bootstrap，此后按 [`BOOTSTRAP_INTERVAL`] 定期刷新。
+//! identify 交换的监听地址写入路由表；路由表新加入的节点会被主动连接，房间消息与
+//! 发送者密钥才能直接送达。gossipsub 剪枝时交换的节点只有 PeerId，拨号地址同样由
+//! 路由表提供。按 PeerId 查找节点见 [`crate::ChatHandle::find_peer`]。
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, identify,
+    identity::Keypair,
+    kad::{self, store::MemoryStore},
+    multiaddr::Protocol,
+    swarm::dial_opts::{DialOpts, PeerCondition},
+};
+use tokio::sync::oneshot;
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{ChatCore, MessageEvent};
+
+/// DHT 协议名，与公共 IPFS DHT 隔离
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/kad/1");
+
+/// identify 中声明的协议版本
+pub const IDENTIFY_VERSION: &str = "/mychat/1.0.0";
+
+/// 定期 bootstrap 的间隔
+pub const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub type KadBehaviour = kad::Behaviour<MemoryStore>;
+
+pub(crate) fn kademlia(local: PeerId) -> KadBehaviour {
+    let mut config = kad::Config::new(KAD_PROTOCOL);
+    config.set_periodic_bootstrap_interval(Some(BOOTSTRAP_INTERVAL));
+    let mut kad = kad::Behaviour::with_config(local, MemoryStore::new(local), config);
+    // 聊天节点都应回应查询，不等待外部地址确认
+    kad.set_mode(Some(kad::Mode::Server));
+    kad
+}
+
+pub(crate) fn identify(key: &Keypair) -> identify::Behaviour {
+    identify::Behaviour::new(
+        identify::Config::new(IDENTIFY_VERSION.to_string(), key.public())
+            .with_push_listen_addr_updates(true),
+    )
+}
+
+/// 从引导节点地址末尾的 `/p2p/<PeerId>` 取出节点，返回节点与不含该段的地址
+pub(crate) fn split_peer(addr: &Multiaddr) -> anyhow::Result<(PeerId, Multiaddr)> {
+    let mut transport = addr.clone();
+    match transport.pop() {
+        Some(Protocol::P2p(peer)) => Ok((peer, transport)),
+        _ => anyhow::bail!("bootstrap address {addr} must end with /p2p/<peer id>"),
+    }
+}
+
+/// 进行中的按 PeerId 查找
+#[derive(Debug, Default)]
+pub(crate) struct Discovery {
+    lookups: HashMap<kad::QueryId, PendingLookup>,
+}
+
+#[derive(Debug)]
+struct PendingLookup {
+    peer: PeerId,
+    reply: oneshot::Sender<anyhow::Result<Vec<Multiaddr>>>,
+}
+
+impl ChatCore {
+    /// 把引导节点写入路由表并开始 bootstrap
+    pub(crate) fn add_bootstrap_nodes(&mut self, nodes: &[Multiaddr]) -> anyhow::Result<()> {
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        for node in nodes {
+            let (peer, addr) = split_peer(node)?;
+            let _ = self.swarm.behaviour_mut().kad.add_address(&peer, addr);
+        }
+        self.swarm.behaviour_mut().kad.bootstrap()?;
+        Ok(())
+    }
+
+    /// 在 DHT 中查找节点的地址，找到后随即连接；结果经 `reply` 返回
+    pub(crate) fn find_peer(
+        &mut self,
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<Vec<Multiaddr>>>,
+    ) {
+        if peer == *self.swarm.local_peer_id() {
+            let _ = reply.send(Err(anyhow::anyhow!("cannot look up the local peer")));
+            return;
+        }
+        let query = self.swarm.behaviour_mut().kad.get_closest_peers(peer);
+        let _ = self
+            .discovery
+            .lookups
+            .insert(query, PendingLookup { peer, reply });
+    }
+
+    /// 查找结束：在最近节点中找到目标时连接并返回其地址
+    fn finish_lookup(&mut self, query: kad::QueryId, closest: Vec<kad::PeerInfo>) {
+        let Some(PendingLookup { peer, reply }) = self.discovery.lookups.remove(&query) else {
+            return;
+        };
+        let addrs = closest
+            .into_iter()
+            .find(|info| info.peer_id == peer)
+            .map(|info| info.addrs)
+            .unwrap_or_default();
+        if addrs.is_empty() {
+            let _ = reply.send(Err(anyhow::anyhow!("peer {peer} not found in the DHT")));
+            return;
+        }
+        self.connect_to(peer, addrs.clone());
+        let _ = reply.send(Ok(addrs));
+    }
+
+    /// 尚未连接时拨号，地址为空时由路由表提供
+    fn connect_to(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .addresses(addrs)
+            .build();
+        if let Err(e) = self.swarm.dial(opts) {
+            tracing::debug!("failed to dial {peer}: {e}");
+        }
+    }
+}
+
+pub(crate) fn handle_kad_event(core: &mut ChatCore, event: kad::Event) {
+    match event {
+        kad::Event::RoutingUpdated {
+            peer,
+            is_new_peer: true,
+            addresses,
+            ..
+        } => {
+            if !core.swarm.is_connected(&peer) {
+                core.connect_to(peer, Vec::new());
+            }
+            if let Some(addr) = addresses.iter().next() {
+                core.send_event(MessageEvent::PeerDiscovered {
+                    peer,
+                    addr: addr.clone(),
+                });
+            }
+        }
+        kad::Event::OutboundQueryProgressed {
+            id,
+            result: kad::QueryResult::GetClosestPeers(result),
+            ..
+        } => {
+            let closest = match result {
+                Ok(kad::GetClosestPeersOk { peers, .. }) => peers,
+                Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+            };
+            core.finish_lookup(id, closest);
+        }
+        kad::Event::OutboundQueryProgressed {
+            result: kad::QueryResult::Bootstrap(Err(e)),
+            ..
+        } => {
+            tracing::debug!("DHT bootstrap failed: {e}");
+        }
+        _ => {}
+    }
+}
+
+/// 对方支持 DHT 时把它声明的监听地址写入路由表
+pub(crate) fn handle_identify_event(core: &mut ChatCore, event: identify::Event) {
+    if let identify::Event::Received { peer_id, info, .. } = event {
+        if !info.protocols.contains(&KAD_PROTOCOL) {
+            return;
+        }
+        for addr in info.listen_addrs {
+            let _ = core.swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+        }
+    }
+}
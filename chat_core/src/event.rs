@@ -0,0 +1,174 @@
+//! 核心推送给前端的事件
+use libp2p::{Multiaddr, PeerId};
+
+use std::fmt;
+
+use crate::wire::WireMessage;
+
+/// 经信封签名认证的消息作者，与转发消息的节点无关
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Author {
+    ///签名所用的设备
+    pub device: PeerId,
+    ///设备所属用户，已收到其设备证书时才有
+    pub user: Option<PeerId>,
+}
+
+impl fmt::Display for Author {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.user {
+            Some(user) => write!(f, "{user} ({})", self.device),
+            None => write!(f, "{}", self.device),
+        }
+    }
+}
+
+/// 网络与聊天事件，前端按变体渲染，不需要解析文本
+#[derive(Debug, Clone)]
+pub enum MessageEvent {
+    /// mDNS 或 DHT 发现了新节点
+    PeerDiscovered { peer: PeerId, addr: Multiaddr },
+    /// mDNS 记录过期（DHT 节点不会过期）
+    PeerExpired { peer: PeerId, addr: Multiaddr },
+    /// 收到聊天消息
+    MessageReceived {
+        ///转发该消息的节点
+        from: PeerId,
+        ///签名认证的作者
+        author: Author,
+        topic: String,
+        ///gossipsub 消息 id：作者、序号与密文的 SHA-256，用于去重与排查转发；
+        ///回复与表情回应引用的是 `payload.id`
+        gossip_id: String,
+        payload: WireMessage,
+    },
+    /// 收到私聊消息
+    DirectMessageReceived {
+        ///递送该消息的节点
+        from: PeerId,
+        ///签名认证的作者
+        author: Author,
+        payload: WireMessage,
+    },
+    /// 对方确认收到私聊消息
+    DirectDelivered { peer: PeerId, id: String },
+    /// 对方不在线，私聊已交给信箱代存，对方上线后转交
+    DirectStored {
+        peer: PeerId,
+        id: String,
+        mailbox: PeerId,
+    },
+    /// 私聊消息未能送达，如对方离线且没有信箱代存，或对方拒收
+    DirectFailed {
+        peer: PeerId,
+        id: String,
+        error: String,
+    },
+    /// 与节点的一轮房间历史同步完成
+    HistorySynced {
+        room: String,
+        peer: PeerId,
+        ///新写入记录的消息数
+        count: usize,
+    },
+    /// 加入了房间
+    RoomJoined(String),
+    /// 离开了房间
+    RoomLeft(String),
+    /// 非成员发来了房间的发送者密钥，接纳对方为成员后才交换密钥
+    RoomInvited { room: String, from: PeerId },
+    /// 本地开始监听新地址
+    ListeningOn(Multiaddr),
+    /// 与节点建立连接
+    ConnectionEstablished(PeerId),
+    /// 与节点的连接关闭
+    ConnectionClosed { peer: PeerId, cause: Option<String> },
+    /// 得知某个密钥被撤销（包括本节点自己签发的撤销）
+    KeyRevoked {
+        peer: PeerId,
+        kind: rootcell::RevocationKind,
+        reason: String,
+    },
+    /// 丢弃了已撤销身份发来的消息
+    RevokedMessageDropped { peer: PeerId, reason: String },
+    /// 得知某个用户的新设备（包括配对后本账户的设备）
+    DeviceAdded {
+        ///用户身份对应的 PeerId，仅用于显示与比较
+        user: PeerId,
+        device: PeerId,
+        name: String,
+    },
+    /// 联系人的身份公钥与之前记录的不同，可能遭遇中间人，应重新核验安全码
+    IdentityKeyChanged {
+        peer: PeerId,
+        ///变更前是否已核验
+        was_verified: bool,
+    },
+    /// 非致命错误，如发布失败、无法解码的消息
+    Error(String),
+}
+
+impl fmt::Display for MessageEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageEvent::PeerDiscovered { peer, addr } => {
+                write!(f, "discovered peer {peer} at {addr}")
+            }
+            MessageEvent::PeerExpired { peer, addr } => {
+                write!(f, "peer {peer} at {addr} expired")
+            }
+            MessageEvent::MessageReceived {
+                topic,
+                author,
+                payload,
+                ..
+            } => {
+                write!(f, "[{topic}] {author}: {}", payload.text_body())
+            }
+            MessageEvent::DirectMessageReceived {
+                author, payload, ..
+            } => {
+                write!(f, "[dm {author}] {}", payload.text_body())
+            }
+            MessageEvent::DirectDelivered { peer, id } => {
+                write!(f, "message {id} delivered to {peer}")
+            }
+            MessageEvent::DirectStored { peer, id, mailbox } => {
+                write!(f, "message {id} to {peer} stored at mailbox {mailbox}")
+            }
+            MessageEvent::DirectFailed { peer, id, error } => {
+                write!(f, "message {id} to {peer} failed: {error}")
+            }
+            MessageEvent::HistorySynced { room, peer, count } => {
+                write!(f, "synced {count} messages of {room} from {peer}")
+            }
+            MessageEvent::RoomJoined(room) => write!(f, "joined room {room}"),
+            MessageEvent::RoomLeft(room) => write!(f, "left room {room}"),
+            MessageEvent::RoomInvited { room, from } => {
+                write!(f, "{from} invited us to room {room}")
+            }
+            MessageEvent::ListeningOn(addr) => write!(f, "listening on {addr}"),
+            MessageEvent::ConnectionEstablished(peer) => write!(f, "connected to {peer}"),
+            MessageEvent::ConnectionClosed { peer, cause: None } => {
+                write!(f, "disconnected from {peer}")
+            }
+            MessageEvent::ConnectionClosed {
+                peer,
+                cause: Some(cause),
+            } => write!(f, "disconnected from {peer}: {cause}"),
+            MessageEvent::KeyRevoked { peer, kind, reason } => {
+                write!(f, "{kind:?} key {peer} revoked: {reason}")
+            }
+            MessageEvent::RevokedMessageDropped { peer, reason } => {
+                write!(f, "dropped message from {peer}: {reason}")
+            }
+            MessageEvent::DeviceAdded { user, device, name } => {
+                write!(f, "device {name} ({device}) added to {user}")
+            }
+            MessageEvent::IdentityKeyChanged { peer, .. } => {
+                write!(f, "identity key of {peer} changed")
+            }
+            MessageEvent::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! 房间端到端加密：每个成员使用自己的发送者密钥
+//!
+//! 加入房间时生成 [`rootcell::GroupSender`]，链状态经私聊加密通道只发给房间成员。
+//! 成员由本地邀请或接纳（[`ChatCore::add_member`]）加入并持久化，订阅主题不等于
+//! 成员；收到非成员的密钥只提示邀请。成员重新订阅时单独补发，移出成员或成员退订
+//! 房间时换用新密钥并只分发给留下的成员。gossipsub 上只传输密文，没有拿到密钥的订阅者只能看到密文。
+use libp2p::{
+    PeerId,
+    gossipsub::{self, MessageId, TopicHash},
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    ChatCore, MessageEvent,
+    storage::ConversationKind,
+    wire::{MessageKind, WireMessage},
+};
+
+/// 每个发送者暂存的待解密消息上限，超出时丢弃最早的
+const PENDING_LIMIT: usize = 32;
+
+/// 私聊中分发发送者密钥的消息体
+#[derive(Serialize, Deserialize)]
+struct SenderKeyBody {
+    room: String,
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+}
+
+/// 尚未收到对应密钥的群消息
+#[derive(Debug)]
+struct PendingMessage {
+    id: MessageId,
+    propagation_source: PeerId,
+    data: Vec<u8>,
+}
+
+/// 各房间的发送者密钥
+#[derive(Debug, Default)]
+pub(crate) struct GroupKeys {
+    ///本地在各房间的发送链
+    senders: HashMap<String, rootcell::GroupSender>,
+    ///其他成员的接收链，按（房间，发送者）索引
+    receivers: HashMap<(String, PeerId), rootcell::GroupReceiver>,
+    ///密钥尚未到达的消息
+    pending: HashMap<(String, PeerId), Vec<PendingMessage>>,
+    ///各房间邀请或接纳的成员
+    members: HashMap<String, BTreeSet<PeerId>>,
+    ///退订了房间的成员，重新订阅或再次加入前不再收到新的发送者密钥
+    away: HashSet<(String, PeerId)>,
+}
+
+impl ChatCore {
+    /// 为新加入的房间生成发送者密钥并分发给成员
+    pub(crate) fn start_group(&mut self, room: &str) -> anyhow::Result<()> {
+        let _ = self
+            .groups
+            .senders
+            .insert(room.to_string(), rootcell::GroupSender::generate()?);
+        for peer in self.room_members(room) {
+            self.distribute_sender_key(room, peer);
+            if self.swarm.is_connected(&peer) {
+                self.request_history(peer, room.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// 离开房间后丢弃该房间的全部密钥
+    pub(crate) fn end_group(&mut self, room: &str) {
+        let _ = self.groups.senders.remove(room);
+        self.groups.receivers.retain(|(r, _), _| r != room);
+        self.groups.pending.retain(|(r, _), _| r != room);
+        self.groups.away.retain(|(r, _)| r != room);
+    }
+
+    /// 丢弃某个成员在所有房间的接收链，如其身份已被撤销
+    pub(crate) fn forget_member(&mut self, peer: PeerId) {
+        self.groups.receivers.retain(|(_, p), _| *p != peer);
+        self.groups.pending.retain(|(_, p), _| *p != peer);
+    }
+
+    /// 载入持久化的房间成员，须在恢复房间之前
+    pub(crate) async fn restore_members(&mut self) -> anyhow::Result<()> {
+        for (room, peer_id) in self.storage.members().await? {
+            match peer_id.parse::<PeerId>() {
+                Ok(peer) => {
+                    let _ = self.groups.members.entry(room).or_default().insert(peer);
+                }
+                Err(e) => tracing::warn!("skipped member {peer_id} of {room}: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// 邀请或接纳房间成员并把本地发送者密钥发给对方，对方接纳后回送它的密钥；
+    /// 已是成员时重发密钥并返回 false
+    pub async fn add_member(&mut self, room: &str, peer: PeerId) -> anyhow::Result<bool> {
+        if !self.is_joined(room) {
+            anyhow::bail!("not a member of room {room}");
+        }
+        if peer == *self.swarm.local_peer_id() {
+            anyhow::bail!("cannot invite the local peer");
+        }
+        self.check_peer(&peer)?;
+        let added = self.storage.add_member(room, &peer.to_string()).await?;
+        let _ = self
+            .groups
+            .members
+            .entry(room.to_string())
+            .or_default()
+            .insert(peer);
+        let _ = self.groups.away.remove(&(room.to_string(), peer));
+        self.distribute_sender_key(room, peer);
+        Ok(added)
+    }
+
+    /// 移出房间成员，丢弃它的密钥并换新的发送链；不是成员时返回 false
+    pub async fn remove_member(&mut self, room: &str, peer: PeerId) -> anyhow::Result<bool> {
+        let removed = self.storage.remove_member(room, &peer.to_string()).await?;
+        if let Some(members) = self.groups.members.get_mut(room) {
+            let _ = members.remove(&peer);
+        }
+        let key = (room.to_string(), peer);
+        let _ = self.groups.receivers.remove(&key);
+        let _ = self.groups.pending.remove(&key);
+        let _ = self.groups.away.remove(&key);
+        if removed {
+            self.rotate_sender_key(room)?;
+        }
+        Ok(removed)
+    }
+
+    /// 节点是否已被加入房间，不论是否在线
+    pub(crate) fn is_member(&self, room: &str, peer: &PeerId) -> bool {
+        self.groups
+            .members
+            .get(room)
+            .is_some_and(|members| members.contains(peer))
+    }
+
+    /// 房间的全部成员，不论是否在线
+    pub(crate) fn room_members(&self, room: &str) -> Vec<PeerId> {
+        self.groups
+            .members
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// 经加密私聊把本地发送链发给一个成员，失败只记日志
+    fn distribute_sender_key(&mut self, room: &str, peer: PeerId) {
+        let Some(sender) = self.groups.senders.get(room) else {
+            return;
+        };
+        if self.check_peer(&peer).is_err() {
+            return;
+        }
+        let body = SenderKeyBody {
+            room: room.to_string(),
+            key: sender.distribution().to_vec(),
+        };
+        if let Err(e) = self.send_sender_key(peer, &body) {
+            tracing::warn!("failed to send sender key for {room} to {peer}: {e}");
+        }
+    }
+
+    /// 把发送者密钥装入签名信封，经加密私聊发出
+    fn send_sender_key(&mut self, peer: PeerId, body: &SenderKeyBody) -> anyhow::Result<()> {
+        let mut message = WireMessage::text(self.swarm.local_peer_id().to_string(), "");
+        message.kind = MessageKind::SenderKey;
+        ciborium::into_writer(body, &mut message.body)?;
+        let message = message.sign(&self.keys.identity)?;
+        self.send_sealed(peer, &message)
+    }
+
+    /// 成员移出或退订后换用新的发送链，只分发给留下的成员
+    fn rotate_sender_key(&mut self, room: &str) -> anyhow::Result<()> {
+        let Some(sender) = self.groups.senders.get(room) else {
+            return Ok(());
+        };
+        let rotated = sender.rotate()?;
+        tracing::debug!("rotated sender key for {room} to {}", rotated.key_id());
+        let _ = self.groups.senders.insert(room.to_string(), rotated);
+        for peer in self.room_members(room) {
+            if !self.groups.away.contains(&(room.to_string(), peer)) {
+                self.distribute_sender_key(room, peer);
+            }
+        }
+        Ok(())
+    }
+
+    /// 用本地发送链加密房间消息，房间名作为关联数据
+    pub(crate) fn seal_room_message(
+        &mut self,
+        room: &str,
+        message: &WireMessage,
+    ) -> anyhow::Result<Vec<u8>> {
+        let sender = self
+            .groups
+            .senders
+            .get_mut(room)
+            .ok_or_else(|| anyhow::anyhow!("no sender key for room {room}"))?;
+        Ok(sender.encrypt(&message.encode()?, room.as_bytes())?)
+    }
+
+    /// 保存成员经私聊发来的发送者密钥，并重试此前无法解密的消息；
+    /// 非成员的密钥视为邀请，只通知前端
+    pub(crate) fn accept_sender_key(
+        &mut self,
+        peer: PeerId,
+        message: &WireMessage,
+    ) -> anyhow::Result<()> {
+        let body: SenderKeyBody = ciborium::from_reader(message.body.as_slice())?;
+        if !self.is_joined(&body.room) || !self.is_member(&body.room, &peer) {
+            tracing::debug!("sender key from non-member {peer} for {}", body.room);
+            self.send_event(MessageEvent::RoomInvited {
+                room: body.room,
+                from: peer,
+            });
+            return Ok(());
+        }
+        let receiver = rootcell::GroupReceiver::from_distribution(&body.key)?;
+        let key = (body.room, peer);
+        // 首次收到成员的密钥说明对方刚接纳本节点或刚重新加入，回送本地密钥并同步历史
+        if self
+            .groups
+            .receivers
+            .insert(key.clone(), receiver)
+            .is_none()
+        {
+            self.distribute_sender_key(&key.0, peer);
+            self.request_history(peer, key.0.clone());
+        }
+        for pending in self.groups.pending.remove(&key).unwrap_or_default() {
+            match self.open_room_message(&key.0, peer, &pending.data) {
+                Ok(Some(payload)) => {
+                    self.deliver_room_message(
+                        &key.0,
+                        pending.propagation_source,
+                        pending.id,
+                        payload,
+                    );
+                }
+                Ok(None) => {}
+                Err(e) => tracing::debug!("dropped stale message {} from {peer}: {e}", pending.id),
+            }
+        }
+        Ok(())
+    }
+
+    /// 解密房间消息；发送者的密钥尚未到达时返回 None
+    fn open_room_message(
+        &mut self,
+        room: &str,
+        author: PeerId,
+        data: &[u8],
+    ) -> anyhow::Result<Option<WireMessage>> {
+        let key_id = rootcell::group::message_key_id(data)
+            .ok_or_else(|| anyhow::anyhow!("not an encrypted room message"))?;
+        match self.groups.receivers.get_mut(&(room.to_string(), author)) {
+            Some(receiver) if receiver.key_id() == key_id => {
+                let plaintext = receiver.decrypt(data, room.as_bytes())?;
+                Ok(Some(WireMessage::decode(&plaintext)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 验证作者签名后写入记录并推送；未通过验证的消息丢弃
+    fn deliver_room_message(
+        &self,
+        room: &str,
+        propagation_source: PeerId,
+        id: MessageId,
+        payload: WireMessage,
+    ) {
+        if payload.room.as_deref().is_some_and(|r| r != room) {
+            tracing::warn!("dropped message {id} signed for another room than {room}");
+            return;
+        }
+        let author = match self.authenticate(&payload) {
+            Ok(author) => author,
+            Err(e) => {
+                tracing::warn!("dropped message {id} relayed by {propagation_source}: {e}");
+                self.send_event(MessageEvent::Error(format!(
+                    "dropped message relayed by {propagation_source}: {e}"
+                )));
+                return;
+            }
+        };
+        self.store_message(
+            ConversationKind::Room,
+            room.to_string(),
+            payload.clone(),
+            false,
+        );
+        self.send_event(MessageEvent::MessageReceived {
+            from: propagation_source,
+            author,
+            topic: room.to_string(),
+            gossip_id: id.to_string(),
+            payload,
+        });
+    }
+}
+
+/// 处理已加入房间的 gossipsub 消息
+pub(crate) fn handle_message(
+    core: &mut ChatCore,
+    propagation_source: PeerId,
+    id: MessageId,
+    message: gossipsub::Message,
+) {
+    let room = message.topic.to_string();
+    // Strict 校验下 source 必然存在且已验证签名
+    let Some(author) = message.source else {
+        tracing::warn!("dropped unsigned message {id} in {room}");
+        return;
+    };
+    match core.open_room_message(&room, author, &message.data) {
+        Ok(Some(payload)) => core.deliver_room_message(&room, propagation_source, id, payload),
+        Ok(None) => {
+            tracing::debug!("waiting for sender key of {author} in {room}");
+            let pending = core.groups.pending.entry((room, author)).or_default();
+            if pending.len() >= PENDING_LIMIT {
+                let _ = pending.remove(0);
+            }
+            pending.push(PendingMessage {
+                id,
+                propagation_source,
+                data: message.data,
+            });
+        }
+        Err(e) => {
+            tracing::warn!("dropped message {id} from {author}: {e}");
+            core.send_event(MessageEvent::Error(format!(
+                "dropped message from {author}: {e}"
+            )));
+        }
+    }
+}
+
+/// 成员订阅了已加入的房间，补发本地发送者密钥并同步双方的历史
+pub(crate) fn peer_subscribed(core: &mut ChatCore, peer: PeerId, topic: &TopicHash) {
+    if core.is_joined(topic.as_str()) && core.is_member(topic.as_str(), &peer) {
+        let _ = core.groups.away.remove(&(topic.to_string(), peer));
+        core.distribute_sender_key(topic.as_str(), peer);
+        core.request_history(peer, topic.to_string());
+    }
+}
+
+/// 节点退订了已加入的房间，丢弃它的密钥；退订的是成员时与移出成员一样换用新的
+/// 发送链，对方之后的消息无法解密，重新订阅时双方再交换密钥
+pub(crate) fn peer_unsubscribed(core: &mut ChatCore, peer: PeerId, topic: &TopicHash) {
+    let key = (topic.to_string(), peer);
+    let _ = core.groups.receivers.remove(&key);
+    let _ = core.groups.pending.remove(&key);
+    if !core.is_joined(topic.as_str()) || !core.is_member(topic.as_str(), &peer) {
+        return;
+    }
+    let _ = core.groups.away.insert(key);
+    if let Err(e) = core.rotate_sender_key(topic.as_str()) {
+        tracing::warn!("failed to rotate sender key for {topic}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use libp2p::gossipsub::IdentTopic;
+
+    #[tokio::test]
+    async fn room_payload_is_ciphertext() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let message = WireMessage::text("alice", "top secret");
+        let sealed = core
+            .seal_room_message(crate::DEFAULT_TOPIC, &message)
+            .unwrap();
+        assert!(WireMessage::decode(&sealed).is_err());
+        assert!(!sealed.windows(10).any(|w| w == b"top secret"));
+
+        // 没有发送者密钥的节点只能暂存密文
+        let stranger = PeerId::random();
+        assert!(
+            core.open_room_message(crate::DEFAULT_TOPIC, stranger, &sealed)
+                .unwrap()
+                .is_none()
+        );
+
+        core.end_group(crate::DEFAULT_TOPIC);
+        assert!(
+            core.seal_room_message(crate::DEFAULT_TOPIC, &message)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn leaving_member_cannot_read_later_messages() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let room = crate::DEFAULT_TOPIC;
+        let topic = IdentTopic::new(room).hash();
+        let leaver = PeerId::random();
+        let _ = core
+            .groups
+            .members
+            .entry(room.to_string())
+            .or_default()
+            .insert(leaver);
+        let mut old =
+            rootcell::GroupReceiver::from_distribution(&core.groups.senders[room].distribution())
+                .unwrap();
+
+        // 非成员退订不换密钥
+        let key_id = core.groups.senders[room].key_id();
+        peer_unsubscribed(&mut core, PeerId::random(), &topic);
+        assert_eq!(core.groups.senders[room].key_id(), key_id);
+
+        peer_unsubscribed(&mut core, leaver, &topic);
+        assert_ne!(core.groups.senders[room].key_id(), key_id);
+        let message = WireMessage::text("alice", "after leaving");
+        let sealed = core.seal_room_message(room, &message).unwrap();
+        assert!(old.decrypt(&sealed, room.as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn relayed_message_reports_signed_author() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let mut alice = ChatCore::try_init(&cfg).await.unwrap();
+        let mut bob = ChatCore::try_init(&cfg).await.unwrap();
+        let mut events = bob.rx_message.take().unwrap();
+        let room = crate::DEFAULT_TOPIC;
+        let alice_peer = *alice.swarm.local_peer_id();
+
+        let body = SenderKeyBody {
+            room: room.to_string(),
+            key: alice.groups.senders[room].distribution().to_vec(),
+        };
+        let mut distribution = WireMessage::text(alice_peer.to_string(), "");
+        ciborium::into_writer(&body, &mut distribution.body).unwrap();
+        // 非成员的密钥只是邀请
+        bob.accept_sender_key(alice_peer, &distribution).unwrap();
+        loop {
+            match events.recv().await.unwrap() {
+                MessageEvent::RoomInvited { from, .. } => break assert_eq!(from, alice_peer),
+                _ => continue,
+            }
+        }
+        assert!(
+            !bob.groups
+                .receivers
+                .contains_key(&(room.to_string(), alice_peer))
+        );
+        let _ = bob
+            .groups
+            .members
+            .entry(room.to_string())
+            .or_default()
+            .insert(alice_peer);
+        bob.accept_sender_key(alice_peer, &distribution).unwrap();
+
+        let relay = PeerId::random();
+        let signed = WireMessage::text(alice_peer.to_string(), "hi")
+            .sign(&alice.keys.identity)
+            .unwrap();
+        let unsigned = WireMessage::text(alice_peer.to_string(), "forged");
+        for (n, message) in [signed, unsigned].iter().enumerate() {
+            let data = alice.seal_room_message(room, message).unwrap();
+            let gossip = gossipsub::Message {
+                source: Some(alice_peer),
+                data,
+                sequence_number: None,
+                topic: IdentTopic::new(room).hash(),
+            };
+            handle_message(&mut bob, relay, MessageId::from(n.to_string()), gossip);
+        }
+
+        let mut next = async || loop {
+            match events.recv().await.unwrap() {
+                event @ (MessageEvent::MessageReceived { .. } | MessageEvent::Error(_)) => {
+                    break event;
+                }
+                _ => continue,
+            }
+        };
+        match next().await {
+            MessageEvent::MessageReceived {
+                from,
+                author,
+                payload,
+                ..
+            } => {
+                assert_eq!(from, relay);
+                assert_eq!(author.device, alice_peer);
+                assert_eq!(payload.text_body(), "hi");
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+        assert!(matches!(next().await, MessageEvent::Error(_)));
+    }
+}
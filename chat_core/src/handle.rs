@@ -0,0 +1,1049 @@
+//! 后台运行的聊天核心与其控制句柄
+//!
+//! [`ChatCore::spawn`] 把 swarm 移入独立任务，前端只持有可 clone 的 [`ChatHandle`]
+//! 和事件接收端，不再需要各自实现事件循环。
+use std::ops::ControlFlow;
+
+use libp2p::{Multiaddr, PeerId, futures::StreamExt};
+use rootcell::{DeviceCertificate, PreKeyBundle, Revocation};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    ChatCore, CoreConfig, MessageEvent, Verification, storage::Storage, wire::WireMessage,
+};
+
+/// 核心事件接收端
+pub type EventReceiver = mpsc::Receiver<MessageEvent>;
+
+/// 发给后台任务的命令，每个命令通过 oneshot 回复结果
+#[derive(Debug)]
+enum Command {
+    Send {
+        room: String,
+        text: String,
+        reply: oneshot::Sender<anyhow::Result<WireMessage>>,
+    },
+    SendDirect {
+        peer: PeerId,
+        text: String,
+        reply: oneshot::Sender<anyhow::Result<WireMessage>>,
+    },
+    FetchPreKeys {
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<Option<PreKeyBundle>>>,
+    },
+    Listen {
+        addr: Multiaddr,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Dial {
+        addr: Multiaddr,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Disconnect {
+        peer: PeerId,
+        reply: oneshot::Sender<bool>,
+    },
+    JoinRoom {
+        room: String,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    LeaveRoom {
+        room: String,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    AddMember {
+        room: String,
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    RemoveMember {
+        room: String,
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    Rooms {
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    RotateDatabaseKey {
+        reply: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    RevokeIdentity {
+        reason: String,
+        reply: oneshot::Sender<anyhow::Result<Revocation>>,
+    },
+    RevokeDevice {
+        device_key: [u8; 32],
+        reason: String,
+        reply: oneshot::Sender<anyhow::Result<Revocation>>,
+    },
+    StartPairing {
+        reply: oneshot::Sender<anyhow::Result<String>>,
+    },
+    Verification {
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<Verification>>,
+    },
+    SetVerified {
+        peer: PeerId,
+        verified: bool,
+        reply: oneshot::Sender<anyhow::Result<Verification>>,
+    },
+    PairWith {
+        code: String,
+        reply: oneshot::Sender<anyhow::Result<DeviceCertificate>>,
+    },
+    Peers {
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    FindPeer {
+        peer: PeerId,
+        reply: oneshot::Sender<anyhow::Result<Vec<Multiaddr>>>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// 后台聊天核心的句柄，可在多个任务间 clone 共享
+#[derive(Debug, Clone)]
+pub struct ChatHandle {
+    commands: mpsc::Sender<Command>,
+    storage: Storage,
+    local_peer_id: PeerId,
+}
+
+impl ChatCore {
+    /// 初始化核心并在后台任务中运行事件循环
+    pub async fn spawn(cfg: &CoreConfig) -> anyhow::Result<(ChatHandle, EventReceiver)> {
+        let mut core = ChatCore::try_init(cfg).await?;
+        let events = core
+            .rx_message
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("event receiver already taken"))?;
+        let history = core
+            .history
+            .take_tasks()
+            .ok_or_else(|| anyhow::anyhow!("history tasks already taken"))?;
+        let (tx, rx) = mpsc::channel(32);
+        let handle = ChatHandle {
+            commands: tx,
+            storage: core.storage.clone(),
+            local_peer_id: *core.swarm.local_peer_id(),
+        };
+        tokio::spawn(core.run(rx, history));
+        Ok((handle, events))
+    }
+
+    /// 驱动 swarm 并处理命令，直到收到 shutdown 或所有句柄被丢弃
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<Command>,
+        mut history: mpsc::UnboundedReceiver<crate::history::HistoryTask>,
+    ) {
+        let mut renewal = tokio::time::interval(crate::device::RENEWAL_INTERVAL);
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => crate::swarm_event(event, &mut self),
+                Some(task) = history.recv() => crate::history::run_task(&mut self, task),
+                _ = renewal.tick() => self.renew_certificates(Vec::new()),
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        if self.handle_command(command).await.is_break() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+        tracing::info!("chat core stopped");
+    }
+
+    /// 处理一条命令，收到 shutdown 时返回 `Break`
+    async fn handle_command(&mut self, command: Command) -> ControlFlow<()> {
+        // 调用方可能已放弃等待，回复失败可以忽略
+        match command {
+            Command::Send { room, text, reply } => {
+                let _ = reply.send(self.sendmessage(&room, text));
+            }
+            Command::SendDirect { peer, text, reply } => {
+                let _ = reply.send(self.send_direct(peer, text));
+            }
+            Command::FetchPreKeys { peer, reply } => {
+                // 结果在网络回复后经 oneshot 返回，不阻塞事件循环
+                let (tx, rx) = oneshot::channel();
+                match self.fetch_prekey_bundle(peer, tx) {
+                    Ok(()) => {
+                        tokio::spawn(async move {
+                            let _ = reply.send(Ok(rx.await.ok().flatten()));
+                        });
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Command::Listen { addr, reply } => {
+                let result = self.swarm.listen_on(addr).map(|_| ());
+                let _ = reply.send(result.map_err(Into::into));
+            }
+            Command::Dial { addr, reply } => {
+                let _ = reply.send(self.swarm.dial(addr).map_err(Into::into));
+            }
+            Command::Disconnect { peer, reply } => {
+                let _ = reply.send(self.swarm.disconnect_peer_id(peer).is_ok());
+            }
+            Command::JoinRoom { room, reply } => {
+                let _ = reply.send(self.join_room(&room).await);
+            }
+            Command::LeaveRoom { room, reply } => {
+                let _ = reply.send(self.leave_room(&room).await);
+            }
+            Command::AddMember { room, peer, reply } => {
+                let _ = reply.send(self.add_member(&room, peer).await);
+            }
+            Command::RemoveMember { room, peer, reply } => {
+                let _ = reply.send(self.remove_member(&room, peer).await);
+            }
+            Command::Rooms { reply } => {
+                let _ = reply.send(self.rooms());
+            }
+            Command::RotateDatabaseKey { reply } => {
+                let _ = reply.send(self.rotate_database_key().await);
+            }
+            Command::RevokeIdentity { reason, reply } => {
+                let _ = reply.send(self.revoke_identity(reason));
+            }
+            Command::RevokeDevice {
+                device_key,
+                reason,
+                reply,
+            } => {
+                let _ = reply.send(self.revoke_device(device_key, reason));
+            }
+            Command::StartPairing { reply } => {
+                let _ = reply.send(self.start_pairing());
+            }
+            Command::Verification { peer, reply } => {
+                let _ = reply.send(self.verification(peer));
+            }
+            Command::SetVerified {
+                peer,
+                verified,
+                reply,
+            } => {
+                let _ = reply.send(self.set_verified(peer, verified));
+            }
+            Command::PairWith { code, reply } => {
+                // 与查询预密钥相同，结果在对方回复后返回
+                let (tx, rx) = oneshot::channel();
+                match self.pair_with(&code, tx) {
+                    Ok(()) => {
+                        tokio::spawn(async move {
+                            let result = rx.await.unwrap_or_else(|_| {
+                                Err(anyhow::anyhow!("chat core has shut down"))
+                            });
+                            let _ = reply.send(result);
+                        });
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Command::Peers { reply } => {
+                let _ = reply.send(self.swarm.connected_peers().copied().collect());
+            }
+            Command::FindPeer { peer, reply } => {
+                // 查询经过多个节点，结果在查找结束后返回
+                let (tx, rx) = oneshot::channel();
+                self.find_peer(peer, tx);
+                tokio::spawn(async move {
+                    let result = rx
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("chat core has shut down")));
+                    let _ = reply.send(result);
+                });
+            }
+            Command::Shutdown { reply } => {
+                let _ = reply.send(());
+                return ControlFlow::Break(());
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl ChatHandle {
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> anyhow::Result<T> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| anyhow::anyhow!("chat core has shut down"))?;
+        rx.await
+            .map_err(|_| anyhow::anyhow!("chat core has shut down"))
+    }
+
+    /// 向已加入的房间发送文本消息，返回已发布的信封
+    pub async fn send(
+        &self,
+        room: impl Into<String>,
+        text: impl Into<String>,
+    ) -> anyhow::Result<WireMessage> {
+        let (room, text) = (room.into(), text.into());
+        self.request(|reply| Command::Send { room, text, reply })
+            .await?
+    }
+
+    /// 向节点发送私聊消息；返回时消息已排队，送达与否见
+    /// [`MessageEvent::DirectDelivered`] / [`MessageEvent::DirectFailed`]
+    pub async fn send_direct(
+        &self,
+        peer: PeerId,
+        text: impl Into<String>,
+    ) -> anyhow::Result<WireMessage> {
+        let text = text.into();
+        self.request(|reply| Command::SendDirect { peer, text, reply })
+            .await?
+    }
+
+    /// 查询节点的预密钥包，用于与离线节点建立会话；已连接节点都没有时返回 None
+    pub async fn prekey_bundle(&self, peer: PeerId) -> anyhow::Result<Option<PreKeyBundle>> {
+        self.request(|reply| Command::FetchPreKeys { peer, reply })
+            .await?
+    }
+
+    /// 在指定地址上监听
+    pub async fn listen(&self, addr: Multiaddr) -> anyhow::Result<()> {
+        self.request(|reply| Command::Listen { addr, reply })
+            .await?
+    }
+
+    /// 主动连接节点
+    pub async fn dial(&self, addr: Multiaddr) -> anyhow::Result<()> {
+        self.request(|reply| Command::Dial { addr, reply }).await?
+    }
+
+    /// 断开与节点的全部连接，未连接时返回 false
+    pub async fn disconnect(&self, peer: PeerId) -> anyhow::Result<bool> {
+        self.request(|reply| Command::Disconnect { peer, reply })
+            .await
+    }
+
+    /// 加入房间，已加入时返回 false
+    pub async fn join_room(&self, room: impl Into<String>) -> anyhow::Result<bool> {
+        let room = room.into();
+        self.request(|reply| Command::JoinRoom { room, reply })
+            .await?
+    }
+
+    /// 离开房间，未加入时返回 false
+    pub async fn leave_room(&self, room: impl Into<String>) -> anyhow::Result<bool> {
+        let room = room.into();
+        self.request(|reply| Command::LeaveRoom { room, reply })
+            .await?
+    }
+
+    /// 邀请或接纳房间成员，只有成员能拿到本节点的发送者密钥；已是成员时返回 false
+    pub async fn add_member(&self, room: impl Into<String>, peer: PeerId) -> anyhow::Result<bool> {
+        let room = room.into();
+        self.request(|reply| Command::AddMember { room, peer, reply })
+            .await?
+    }
+
+    /// 移出房间成员并换用新的发送者密钥，不是成员时返回 false
+    pub async fn remove_member(
+        &self,
+        room: impl Into<String>,
+        peer: PeerId,
+    ) -> anyhow::Result<bool> {
+        let room = room.into();
+        self.request(|reply| Command::RemoveMember { room, peer, reply })
+            .await?
+    }
+
+    /// 已加入的房间
+    pub async fn rooms(&self) -> anyhow::Result<Vec<String>> {
+        self.request(|reply| Command::Rooms { reply }).await
+    }
+
+    /// 轮换聊天记录加密密钥并重新加密全部消息，返回新的密钥代数
+    pub async fn rotate_database_key(&self) -> anyhow::Result<u64> {
+        self.request(|reply| Command::RotateDatabaseKey { reply })
+            .await?
+    }
+
+    /// 撤销本节点身份并广播撤销声明；此后其他节点会丢弃本身份的消息，
+    /// 应随即用 [`crate::rotate_identity`] 换用新身份
+    pub async fn revoke_identity(&self, reason: impl Into<String>) -> anyhow::Result<Revocation> {
+        let reason = reason.into();
+        self.request(|reply| Command::RevokeIdentity { reason, reply })
+            .await?
+    }
+
+    /// 撤销本身份名下的设备密钥并广播撤销声明
+    pub async fn revoke_device(
+        &self,
+        device_key: [u8; 32],
+        reason: impl Into<String>,
+    ) -> anyhow::Result<Revocation> {
+        let reason = reason.into();
+        self.request(|reply| Command::RevokeDevice {
+            device_key,
+            reason,
+            reply,
+        })
+        .await?
+    }
+
+    /// 与联系人的安全码及核验状态，用于带外比对
+    pub async fn verification(&self, peer: PeerId) -> anyhow::Result<Verification> {
+        self.request(|reply| Command::Verification { peer, reply })
+            .await?
+    }
+
+    /// 比对安全码后标记（或取消）联系人为已核验；身份公钥变化后自动失效
+    pub async fn set_verified(&self, peer: PeerId, verified: bool) -> anyhow::Result<Verification> {
+        self.request(|reply| Command::SetVerified {
+            peer,
+            verified,
+            reply,
+        })
+        .await?
+    }
+
+    /// 生成一次性配对口令，在新设备上用 [`ChatHandle::pair_with`] 输入后即加入本账户；
+    /// 口令 5 分钟内有效，输错 3 次作废
+    pub async fn start_pairing(&self) -> anyhow::Result<String> {
+        self.request(|reply| Command::StartPairing { reply })
+            .await?
+    }
+
+    /// 输入另一台设备显示的配对口令，加入其账户，返回签发给本设备的证书
+    ///
+    /// 需已与签发设备建立连接（或可经 mDNS 找到）
+    pub async fn pair_with(&self, code: impl Into<String>) -> anyhow::Result<DeviceCertificate> {
+        let code = code.into();
+        self.request(|reply| Command::PairWith { code, reply })
+            .await?
+    }
+
+    /// 当前已连接的节点
+    pub async fn peers(&self) -> anyhow::Result<Vec<PeerId>> {
+        self.request(|reply| Command::Peers { reply }).await
+    }
+
+    /// 在 DHT 中按 PeerId 查找节点，找到后连接并返回其地址；
+    /// 需已配置引导节点或与 DHT 中的节点相连
+    pub async fn find_peer(&self, peer: PeerId) -> anyhow::Result<Vec<Multiaddr>> {
+        self.request(|reply| Command::FindPeer { peer, reply })
+            .await?
+    }
+
+    /// 停止后台任务；核心已停止时直接返回
+    pub async fn shutdown(&self) {
+        let _ = self.request(|reply| Command::Shutdown { reply }).await;
+    }
+
+    /// 聊天记录存储
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handle_round_trip_and_shutdown() {
+        let (handle, _events) = ChatCore::spawn(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        handle
+            .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(handle.rooms().await.unwrap(), [crate::DEFAULT_TOPIC]);
+        assert!(handle.join_room("other-room").await.unwrap());
+        assert!(!handle.join_room("other-room").await.unwrap());
+        assert!(handle.leave_room(crate::DEFAULT_TOPIC).await.unwrap());
+        assert!(!handle.leave_room(crate::DEFAULT_TOPIC).await.unwrap());
+        assert_eq!(handle.rooms().await.unwrap(), ["other-room"]);
+        // 内部主题不能当作房间加入或离开
+        assert!(
+            handle
+                .join_room(crate::revocation::REVOCATION_TOPIC)
+                .await
+                .is_err()
+        );
+        assert!(
+            handle
+                .leave_room(crate::device::DEVICE_TOPIC)
+                .await
+                .is_err()
+        );
+        assert!(handle.send("nowhere", "hi").await.is_err());
+        assert!(handle.peers().await.unwrap().is_empty());
+        handle.shutdown().await;
+        assert!(handle.peers().await.is_err());
+    }
+
+    async fn next_matching<T>(
+        events: &mut EventReceiver,
+        mut f: impl FnMut(MessageEvent) -> Option<T>,
+    ) -> T {
+        let wait = async {
+            loop {
+                let event = events.recv().await.expect("core stopped");
+                if let Some(found) = f(event) {
+                    return found;
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(20), wait)
+            .await
+            .expect("timed out waiting for event")
+    }
+
+    /// 在本机回环地址上监听，返回实际地址
+    async fn listen(handle: &ChatHandle, events: &mut EventReceiver) -> Multiaddr {
+        handle
+            .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        next_matching(events, |e| match e {
+            MessageEvent::ListeningOn(addr) => Some(addr),
+            _ => None,
+        })
+        .await
+    }
+
+    /// `b` 开始监听，`a` 拨号并等到连接建立，返回 `b` 的地址
+    async fn connect(
+        a: &ChatHandle,
+        a_events: &mut EventReceiver,
+        b: &ChatHandle,
+        b_events: &mut EventReceiver,
+    ) -> Multiaddr {
+        let addr = listen(b, b_events).await;
+        let peer = b.local_peer_id();
+        // 可能已经经 mDNS 或 DHT 相连，建立事件已被先前的等待跳过
+        let connected = a.peers().await.unwrap().contains(&peer);
+        a.dial(addr.clone()).await.unwrap();
+        if !connected {
+            next_matching(a_events, |e| match e {
+                MessageEvent::ConnectionEstablished(p) if p == peer => Some(()),
+                _ => None,
+            })
+            .await;
+        }
+        addr
+    }
+
+    /// 双方互相接纳为房间成员
+    async fn admit(a: &ChatHandle, b: &ChatHandle, room: &str) {
+        a.add_member(room, b.local_peer_id()).await.unwrap();
+        b.add_member(room, a.local_peer_id()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_message_is_acknowledged() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+        connect(&alice, &mut alice_events, &bob, &mut bob_events).await;
+
+        let sent = alice
+            .send_direct(bob.local_peer_id(), "hi bob")
+            .await
+            .unwrap();
+        let received = next_matching(&mut bob_events, |e| match e {
+            MessageEvent::DirectMessageReceived {
+                from,
+                author,
+                payload,
+            } => Some((from, author, payload)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received.0, alice.local_peer_id());
+        assert_eq!(received.1.device, alice.local_peer_id());
+        assert_eq!(received.2.text_body(), "hi bob");
+        let delivered = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::DirectDelivered { id, .. } => Some(id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(delivered, sent.id);
+
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn offline_direct_messages_wait_in_mailbox() {
+        let relay_cfg = CoreConfig::new("sqlite::memory:")
+            .with_mailbox(crate::mailbox::MailboxConfig::default());
+        let (relay, mut relay_events) = ChatCore::spawn(&relay_cfg).await.unwrap();
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let bob_cfg = CoreConfig::new(format!("sqlite://{}", dir.path().join("chat.db").display()))
+            .with_identity_path(dir.path().join("identity"))
+            .with_vault_passphrase("secret");
+
+        // bob 上线时把预密钥包交给 relay 缓存，随后离线
+        let (bob, mut bob_events) = ChatCore::spawn(&bob_cfg).await.unwrap();
+        let bob_id = bob.local_peer_id();
+        connect(&bob, &mut bob_events, &relay, &mut relay_events).await;
+        for _ in 0..50 {
+            if relay.prekey_bundle(bob_id).await.unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        bob.shutdown().await;
+        drop(bob_events);
+
+        // alice 用缓存的预密钥包建立会话，私聊交给 relay 代存
+        connect(&alice, &mut alice_events, &relay, &mut relay_events).await;
+        let sent = alice.send_direct(bob_id, "are you there").await.unwrap();
+        let (id, mailbox) = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::DirectStored { id, mailbox, .. } => Some((id, mailbox)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((id, mailbox), (sent.id, relay.local_peer_id()));
+
+        // bob 重启后用同一身份取回信件，消耗对应的一次性预密钥建立会话
+        let (bob, mut bob_events) = ChatCore::spawn(&bob_cfg).await.unwrap();
+        assert_eq!(bob.local_peer_id(), bob_id);
+        connect(&bob, &mut bob_events, &relay, &mut relay_events).await;
+        let (from, author, text) = next_matching(&mut bob_events, |e| match e {
+            MessageEvent::DirectMessageReceived {
+                from,
+                author,
+                payload,
+            } => Some((from, author, payload.text_body())),
+            _ => None,
+        })
+        .await;
+        assert_eq!(from, alice.local_peer_id());
+        assert_eq!(author.device, alice.local_peer_id());
+        assert_eq!(text, "are you there");
+
+        // 收件人确认后信箱删除信封
+        for _ in 0..50 {
+            if relay.storage().mail().await.unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(relay.storage().mail().await.unwrap().is_empty());
+
+        relay.shutdown().await;
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn room_messages_use_sender_keys() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+        admit(&alice, &bob, crate::DEFAULT_TOPIC).await;
+        connect(&alice, &mut alice_events, &bob, &mut bob_events).await;
+
+        // 看到 bob 订阅房间后才发布，否则消息只写入本地记录
+        next_matching(&mut alice_events, |e| match e {
+            MessageEvent::HistorySynced { peer, .. } => Some(peer),
+            _ => None,
+        })
+        .await;
+        let sent = alice.send(crate::DEFAULT_TOPIC, "hi room").await.unwrap();
+        let received = next_matching(&mut bob_events, |e| match e {
+            MessageEvent::MessageReceived { payload, .. } => Some(payload),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received, sent);
+
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn room_keys_follow_membership() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (carol, mut carol_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let room = crate::DEFAULT_TOPIC;
+        connect(&alice, &mut alice_events, &carol, &mut carol_events).await;
+
+        // 只订阅主题拿不到密钥，alice 邀请后 carol 接纳才交换密钥
+        assert!(alice.add_member(room, carol.local_peer_id()).await.unwrap());
+        let from = next_matching(&mut carol_events, |e| match e {
+            MessageEvent::RoomInvited { from, .. } => Some(from),
+            _ => None,
+        })
+        .await;
+        assert_eq!(from, alice.local_peer_id());
+        carol.add_member(room, from).await.unwrap();
+        next_matching(&mut alice_events, |e| match e {
+            MessageEvent::HistorySynced { peer, .. } => Some(peer),
+            _ => None,
+        })
+        .await;
+        alice.send(room, "welcome").await.unwrap();
+        let text = next_matching(&mut carol_events, |e| match e {
+            MessageEvent::MessageReceived { payload, .. } => Some(payload.text_body()),
+            _ => None,
+        })
+        .await;
+        assert_eq!(text, "welcome");
+
+        // 移出后 alice 换用新密钥，carol 无法解密之后的消息
+        assert!(
+            alice
+                .remove_member(room, carol.local_peer_id())
+                .await
+                .unwrap()
+        );
+        alice.send(room, "after removal").await.unwrap();
+        let received = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            next_matching(&mut carol_events, |e| match e {
+                MessageEvent::MessageReceived { payload, .. } => Some(payload.text_body()),
+                _ => None,
+            }),
+        )
+        .await;
+        assert!(received.is_err());
+
+        alice.shutdown().await;
+        carol.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn partitioned_rooms_converge_after_reconnect() {
+        // 关闭 mDNS，断开后不会被自动重新连上
+        let cfg = CoreConfig::new("sqlite::memory:").with_mdns(false);
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let room = crate::DEFAULT_TOPIC;
+        admit(&alice, &bob, room).await;
+        let bob_addr = connect(&alice, &mut alice_events, &bob, &mut bob_events).await;
+        for events in [&mut alice_events, &mut bob_events] {
+            next_matching(events, |e| match e {
+                MessageEvent::HistorySynced { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+        }
+
+        // 连接期间的消息直接送达
+        async fn received(events: &mut EventReceiver) -> String {
+            next_matching(events, |e| match e {
+                MessageEvent::MessageReceived { payload, .. } => Some(payload.id),
+                _ => None,
+            })
+            .await
+        }
+        let a0 = alice.send(room, "a0").await.unwrap();
+        assert_eq!(received(&mut bob_events).await, a0.id);
+        let b0 = bob.send(room, "b0").await.unwrap();
+        assert_eq!(received(&mut alice_events).await, b0.id);
+
+        assert!(alice.disconnect(bob.local_peer_id()).await.unwrap());
+        for (events, peer) in [
+            (&mut alice_events, bob.local_peer_id()),
+            (&mut bob_events, alice.local_peer_id()),
+        ] {
+            next_matching(events, |e| match e {
+                MessageEvent::ConnectionClosed { peer: p, .. } if p == peer => Some(()),
+                _ => None,
+            })
+            .await;
+        }
+        assert!(!alice.disconnect(bob.local_peer_id()).await.unwrap());
+
+        // 分区期间双方各自发言，只写入本地记录
+        let tick = || tokio::time::sleep(std::time::Duration::from_millis(2));
+        let a1 = alice.send(room, "a1").await.unwrap();
+        tick().await;
+        let b1 = bob.send(room, "b1").await.unwrap();
+        tick().await;
+        let a2 = alice.send(room, "a2").await.unwrap();
+        tick().await;
+        let b2 = bob.send(room, "b2").await.unwrap();
+        // b2 先经其他途径到达 alice，更早的 b1 仍要经同步补上
+        let conversation = alice.storage().topic_conversation(room).await.unwrap();
+        alice
+            .storage()
+            .insert_message(&crate::storage::NewMessage {
+                conversation_id: conversation,
+                message_id: b2.id.clone(),
+                author: b2.author.clone(),
+                kind: b2.kind,
+                body: b2.body.clone(),
+                reply_to: None,
+                sent_at: b2.sent_at,
+                outgoing: false,
+                signature: b2.signature.clone(),
+            })
+            .await
+            .unwrap();
+
+        alice.dial(bob_addr).await.unwrap();
+        for events in [&mut alice_events, &mut bob_events] {
+            next_matching(events, |e| match e {
+                MessageEvent::HistorySynced { count, .. } if count > 0 => Some(count),
+                _ => None,
+            })
+            .await;
+        }
+
+        let history = |handle: &ChatHandle| {
+            let storage = handle.storage().clone();
+            async move {
+                let conversation = storage.topic_conversation(room).await.unwrap();
+                storage
+                    .messages_in_conversation(conversation, 0..10)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|m| m.message_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        // 各自的消息在前，同步来的消息按发送时间顺序写入
+        assert_eq!(
+            history(&alice).await,
+            [
+                a0.id.clone(),
+                b0.id.clone(),
+                a1.id.clone(),
+                a2.id.clone(),
+                b2.id.clone(),
+                b1.id.clone()
+            ]
+        );
+        assert_eq!(
+            history(&bob).await,
+            [a0.id, b0.id, b1.id, b2.id, a1.id, a2.id]
+        );
+
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn revoked_peer_messages_are_rejected() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+        admit(&alice, &bob, crate::DEFAULT_TOPIC).await;
+        connect(&alice, &mut alice_events, &bob, &mut bob_events).await;
+        // 等双方交换完订阅信息，撤销声明才有人接收
+        next_matching(&mut bob_events, |e| match e {
+            MessageEvent::HistorySynced { peer, .. } => Some(peer),
+            _ => None,
+        })
+        .await;
+
+        bob.revoke_identity("key leaked").await.unwrap();
+        assert!(bob.revoke_identity("again").await.is_err());
+        let revoked = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::KeyRevoked { peer, reason, .. } => Some((peer, reason)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(revoked, (bob.local_peer_id(), "key leaked".to_string()));
+        assert!(alice.send_direct(bob.local_peer_id(), "hi").await.is_err());
+
+        bob.send(crate::DEFAULT_TOPIC, "after").await.unwrap();
+        let dropped = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::RevokedMessageDropped { peer, .. } => Some(peer),
+            MessageEvent::MessageReceived { payload, .. } if payload.text_body() == "after" => {
+                panic!("message from revoked peer was delivered")
+            }
+            _ => None,
+        })
+        .await;
+        assert_eq!(dropped, bob.local_peer_id());
+        assert_eq!(alice.storage().revocations().await.unwrap().len(), 1);
+
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn revocations_reach_peers_that_connect_later() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+
+        // 撤销时还没有订阅者，alice 连接后由 bob 直接补发
+        bob.revoke_identity("lost device").await.unwrap();
+        connect(&alice, &mut alice_events, &bob, &mut bob_events).await;
+        let revoked = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::KeyRevoked { peer, reason, .. } => Some((peer, reason)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(revoked, (bob.local_peer_id(), "lost device".to_string()));
+
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn paired_devices_receive_direct_messages() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (primary, mut primary_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (laptop, mut laptop_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (carol, mut carol_events) = ChatCore::spawn(&cfg).await.unwrap();
+        connect(&laptop, &mut laptop_events, &primary, &mut primary_events).await;
+        connect(&carol, &mut carol_events, &primary, &mut primary_events).await;
+        connect(&carol, &mut carol_events, &laptop, &mut laptop_events).await;
+        for _ in 0..50 {
+            let ready = primary.send(crate::DEFAULT_TOPIC, "hello").await.is_ok()
+                && laptop.send(crate::DEFAULT_TOPIC, "hello").await.is_ok();
+            if ready {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        let code = primary.start_pairing().await.unwrap();
+        assert!(laptop.pair_with("not a code").await.is_err());
+        let certificate = laptop.pair_with(code.as_str()).await.unwrap();
+        assert_eq!(
+            crate::identity::peer_id(&certificate.device_key).unwrap(),
+            laptop.local_peer_id()
+        );
+        // 口令只能使用一次
+        assert!(laptop.pair_with(code).await.is_err());
+        // 配对前 carol 可能已收到笔记本自己账户的证书，要等到归属主设备账户的那张
+        let user = crate::identity::peer_id(&certificate.user_key).unwrap();
+        next_matching(&mut carol_events, |e| match e {
+            MessageEvent::DeviceAdded {
+                user: owner,
+                device,
+                ..
+            } if owner == user && device == laptop.local_peer_id() => Some(()),
+            _ => None,
+        })
+        .await;
+
+        carol
+            .send_direct(primary.local_peer_id(), "to every device")
+            .await
+            .unwrap();
+        for events in [&mut primary_events, &mut laptop_events] {
+            let (from, text) = next_matching(events, |e| match e {
+                MessageEvent::DirectMessageReceived { from, payload, .. } => {
+                    Some((from, payload.text_body()))
+                }
+                _ => None,
+            })
+            .await;
+            assert_eq!(from, carol.local_peer_id());
+            assert_eq!(text, "to every device");
+        }
+
+        primary.shutdown().await;
+        laptop.shutdown().await;
+        carol.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn prekey_bundle_is_served_by_other_peers() {
+        let cfg = CoreConfig::new("sqlite::memory:");
+        let (relay, mut relay_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (owner, mut owner_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (asker, mut asker_events) = ChatCore::spawn(&cfg).await.unwrap();
+        connect(&owner, &mut owner_events, &relay, &mut relay_events).await;
+        connect(&asker, &mut asker_events, &relay, &mut relay_events).await;
+        // 对方下线后仍可从中继节点取得预密钥包
+        let owner_id = owner.local_peer_id();
+        owner.shutdown().await;
+
+        let mut bundle = None;
+        for _ in 0..50 {
+            bundle = asker.prekey_bundle(owner_id).await.unwrap();
+            if bundle.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let bundle = bundle.expect("relay never served the bundle");
+        bundle.verify().unwrap();
+        assert_eq!(
+            bundle.identity_key,
+            crate::identity::peer_public_key(&owner_id).unwrap()
+        );
+        assert!(bundle.one_time_prekey.is_some());
+
+        relay.shutdown().await;
+        asker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn dht_finds_peers_without_mdns() {
+        let cfg = CoreConfig::new("sqlite::memory:").with_mdns(false);
+        let (boot, mut boot_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let addr = listen(&boot, &mut boot_events)
+            .await
+            .with(libp2p::multiaddr::Protocol::P2p(boot.local_peer_id()));
+
+        // alice 与 bob 只知道引导节点，互相不知道地址
+        let cfg = CoreConfig::new("sqlite::memory:")
+            .with_mdns(false)
+            .with_bootstrap_node(addr);
+        let (alice, mut alice_events) = ChatCore::spawn(&cfg).await.unwrap();
+        let (bob, mut bob_events) = ChatCore::spawn(&cfg).await.unwrap();
+        listen(&alice, &mut alice_events).await;
+        listen(&bob, &mut bob_events).await;
+
+        let mut found = Vec::new();
+        for _ in 0..50 {
+            if let Ok(addrs) = bob.find_peer(alice.local_peer_id()).await {
+                found = addrs;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(!found.is_empty(), "alice was never found in the DHT");
+        next_matching(&mut bob_events, |e| match e {
+            MessageEvent::ConnectionEstablished(peer) if peer == alice.local_peer_id() => Some(()),
+            _ => None,
+        })
+        .await;
+
+        bob.send_direct(alice.local_peer_id(), "found you")
+            .await
+            .unwrap();
+        let text = next_matching(&mut alice_events, |e| match e {
+            MessageEvent::DirectMessageReceived { payload, .. } => Some(payload.text_body()),
+            _ => None,
+        })
+        .await;
+        assert_eq!(text, "found you");
+        assert!(bob.find_peer(bob.local_peer_id()).await.is_err());
+
+        boot.shutdown().await;
+        alice.shutdown().await;
+        bob.shutdown().await;
+    }
+}
@@ -0,0 +1,444 @@
+//! 重新连接后的房间历史同步：`/mychat/history/2`
+//!
+//! 看到其他成员订阅了已加入的房间（包括自己重新加入房间）时，向对方从最新的消息起
+//! 逐页请求已签名的历史，用双方身份协商的 [`rootcell::Session`] 加密传输，已撤销作者
+//! 的消息不再转发。每页以上一页最早一条消息的 `(发送时间, 消息 id)` 为游标继续，
+//! 不依赖本地已有哪些消息，因此先前漏掉的消息即使比已有的更早也能补上；对方表示
+//! 还有更多时一直翻页，一次同步最多取 [`MAX_SYNC_MESSAGES`] 条。收到的消息逐条验证
+//! 作者签名与所属房间，已有的消息写入时自动去重。存储读写在后台任务中完成，需要
+//! 操作 swarm 的步骤以 [`HistoryTask`] 交回事件循环。
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel, cbor},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, hash_map::Entry};
+
+use crate::{
+    ChatCore, MessageEvent, identity,
+    storage::{ConversationKind, StoredMessage},
+    wire::{WIRE_VERSION, WireMessage},
+};
+
+/// 历史同步协议名
+pub const HISTORY_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/history/2");
+
+/// 每次回复的消息数上限
+const BATCH: usize = 200;
+/// 一次同步最多取回的消息数，更早的历史不再翻页
+pub const MAX_SYNC_MESSAGES: usize = 2_000;
+
+/// 分页游标，指向上一页中最早的一条消息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryCursor {
+    ///发送时间（unix 毫秒）
+    pub sent_at: i64,
+    ///消息 id，发送时间相同时按它排序
+    pub id: String,
+}
+
+/// 历史请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub room: String,
+    ///只要游标之前的消息，None 时从最新的消息开始
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<HistoryCursor>,
+}
+
+/// 历史回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HistoryResponse {
+    /// 加密的消息列表，`more` 表示还有未发送的消息
+    History {
+        #[serde(with = "serde_bytes")]
+        sealed: Vec<u8>,
+        more: bool,
+    },
+    /// 拒绝同步，如对方不是房间成员
+    Rejected { reason: String },
+}
+
+pub type HistoryBehaviour = cbor::Behaviour<HistoryRequest, HistoryResponse>;
+pub type HistoryEvent = request_response::Event<HistoryRequest, HistoryResponse>;
+
+pub(crate) fn behaviour() -> HistoryBehaviour {
+    cbor::Behaviour::new(
+        [(HISTORY_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// 后台读写存储后交回事件循环的步骤
+#[derive(Debug)]
+pub(crate) enum HistoryTask {
+    /// 回复成员的历史请求
+    Respond {
+        peer: PeerId,
+        channel: ResponseChannel<HistoryResponse>,
+        history: Vec<WireMessage>,
+        more: bool,
+    },
+}
+
+/// 进行中的历史同步
+#[derive(Debug)]
+pub(crate) struct HistorySync {
+    tasks: mpsc::UnboundedSender<HistoryTask>,
+    receiver: Option<mpsc::UnboundedReceiver<HistoryTask>>,
+    ///等待回复的请求对应的房间与本次同步已取回的消息数
+    pending: HashMap<OutboundRequestId, (String, usize)>,
+    ///与各节点加密历史的会话
+    sessions: HashMap<PeerId, rootcell::Session>,
+}
+
+impl Default for HistorySync {
+    fn default() -> Self {
+        let (tasks, receiver) = mpsc::unbounded_channel();
+        Self {
+            tasks,
+            receiver: Some(receiver),
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl HistorySync {
+    /// 取出交回事件循环的步骤，由运行事件循环的一方轮询
+    pub(crate) fn take_tasks(&mut self) -> Option<mpsc::UnboundedReceiver<HistoryTask>> {
+        self.receiver.take()
+    }
+}
+
+/// 由存储的消息还原签名时的信封
+fn envelope(message: StoredMessage, room: &str) -> WireMessage {
+    WireMessage {
+        version: WIRE_VERSION,
+        id: message.message_id,
+        sent_at: message.sent_at,
+        author: message.author,
+        kind: message.kind,
+        body: message.body,
+        reply_to: message.reply_to,
+        room: Some(room.to_string()),
+        signature: message.signature,
+        extra: BTreeMap::new(),
+    }
+}
+
+impl ChatCore {
+    /// 取得与节点加密历史的会话，首次使用时由双方身份协商
+    fn history_session(&mut self, peer: PeerId) -> anyhow::Result<&rootcell::Session> {
+        match self.history.sessions.entry(peer) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let public_key = identity::peer_public_key(&peer)?;
+                Ok(entry.insert(rootcell::Session::establish(
+                    &self.keys.identity,
+                    &public_key,
+                )?))
+            }
+        }
+    }
+
+    /// 丢弃与节点加密历史的会话
+    pub(crate) fn forget_history_session(&mut self, peer: &PeerId) {
+        let _ = self.history.sessions.remove(peer);
+    }
+
+    /// 未通过 [`ChatCore::check_peer`] 的已知设备：被撤销的密钥，以及所属身份被撤销的设备
+    fn revoked_authors(&self) -> Vec<PeerId> {
+        let candidates: BTreeSet<[u8; 32]> = self
+            .revocations
+            .iter()
+            .map(|r| r.revoked_key)
+            .chain(self.certified_keys())
+            .collect();
+        candidates
+            .iter()
+            .filter_map(|key| identity::peer_id(key).ok())
+            .filter(|peer| self.check_peer(peer).is_err())
+            .collect()
+    }
+
+    /// 从最新的消息起向房间成员请求历史
+    pub(crate) fn request_history(&mut self, peer: PeerId, room: String) {
+        self.send_history_request(peer, HistoryRequest { room, before: None }, 0);
+    }
+
+    /// 发出一页历史请求，`received` 为本次同步已取回的消息数
+    fn send_history_request(&mut self, peer: PeerId, request: HistoryRequest, received: usize) {
+        let room = request.room.clone();
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .history
+            .send_request(&peer, request);
+        let _ = self.history.pending.insert(request_id, (room, received));
+    }
+
+    /// 在后台读出对方缺少的消息，只回复给经 [`ChatCore::add_member`] 加入的成员，
+    /// 仅订阅了房间主题的节点不算；已撤销作者的消息不再转发
+    fn answer_history(
+        &mut self,
+        peer: PeerId,
+        request: HistoryRequest,
+        channel: ResponseChannel<HistoryResponse>,
+    ) {
+        let allowed = if !self.is_joined(&request.room) {
+            Err(anyhow::anyhow!("not a member of room {}", request.room))
+        } else if !self.is_member(&request.room, &peer) {
+            Err(anyhow::anyhow!(
+                "{peer} is not a member of {}",
+                request.room
+            ))
+        } else {
+            self.check_peer(&peer).map_err(Into::into)
+        };
+        if let Err(e) = allowed {
+            tracing::debug!("rejected history request from {peer}: {e}");
+            let response = HistoryResponse::Rejected {
+                reason: e.to_string(),
+            };
+            if self
+                .swarm
+                .behaviour_mut()
+                .history
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("history response to {peer} dropped, connection closed");
+            }
+            return;
+        }
+        let revoked: Vec<String> = self
+            .revoked_authors()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let storage = self.storage.clone();
+        let tasks = self.history.tasks.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let conversation_id = storage.topic_conversation(&request.room).await?;
+                let before = request
+                    .before
+                    .as_ref()
+                    .map(|cursor| (cursor.sent_at, cursor.id.as_str()));
+                storage
+                    .messages_before(conversation_id, before, &revoked, BATCH + 1)
+                    .await
+            }
+            .await;
+            let (history, more) = match result {
+                Ok(mut messages) => {
+                    let more = messages.len() > BATCH;
+                    messages.truncate(BATCH);
+                    let history = messages
+                        .into_iter()
+                        .map(|message| envelope(message, &request.room))
+                        .collect();
+                    (history, more)
+                }
+                Err(e) => {
+                    tracing::warn!("failed to read history of {}: {e:?}", request.room);
+                    (Vec::new(), false)
+                }
+            };
+            let _ = tasks.send(HistoryTask::Respond {
+                peer,
+                channel,
+                history,
+                more,
+            });
+        });
+    }
+
+    /// 验证收到的历史，按发送时间顺序在后台写入；还有更多且未达上限时请求下一页
+    fn receive_history(
+        &mut self,
+        peer: PeerId,
+        room: String,
+        received: usize,
+        sealed: &[u8],
+        more: bool,
+    ) {
+        let history: Vec<WireMessage> = match self
+            .history_session(peer)
+            .and_then(|session| Ok(session.open(sealed)?))
+            .and_then(|plaintext| Ok(ciborium::from_reader(plaintext.as_slice())?))
+        {
+            Ok(history) => history,
+            Err(e) => {
+                tracing::warn!("dropped history of {room} from {peer}: {e}");
+                return;
+            }
+        };
+        // 游标取这一页最早的消息，被拒绝的消息同样计入，下一页不会重复发送
+        let next = history
+            .iter()
+            .min_by(|a, b| (a.sent_at, &a.id).cmp(&(b.sent_at, &b.id)))
+            .map(|message| HistoryCursor {
+                sent_at: message.sent_at,
+                id: message.id.clone(),
+            });
+        let received = received.saturating_add(history.len());
+        if more && received < MAX_SYNC_MESSAGES {
+            if let Some(before) = next {
+                let request = HistoryRequest {
+                    room: room.clone(),
+                    before: Some(before),
+                };
+                self.send_history_request(peer, request, received);
+            }
+        } else if more {
+            tracing::debug!("stopped syncing {room} from {peer} after {received} messages");
+        }
+        let mut verified: Vec<WireMessage> = history
+            .into_iter()
+            .filter(|message| {
+                if message.room.as_deref() != Some(room.as_str()) {
+                    tracing::warn!("dropped synced message {} from another room", message.id);
+                    return false;
+                }
+                match self.authenticate(message) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        tracing::warn!("dropped synced message {} from {peer}: {e}", message.id);
+                        false
+                    }
+                }
+            })
+            .collect();
+        verified.sort_by(|a, b| (a.sent_at, &a.id).cmp(&(b.sent_at, &b.id)));
+
+        let local = self.swarm.local_peer_id().to_string();
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let mut count = 0;
+            for message in verified {
+                let outgoing = message.author == local;
+                match crate::save_message(
+                    &storage,
+                    ConversationKind::Room,
+                    &room,
+                    message,
+                    outgoing,
+                )
+                .await
+                {
+                    Ok(Some(_)) => count += 1,
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("failed to store synced message: {e:?}"),
+                }
+            }
+            let _ = events.send(MessageEvent::HistorySynced { room, peer, count });
+        });
+    }
+}
+
+/// 在事件循环中执行后台交回的步骤
+pub(crate) fn run_task(core: &mut ChatCore, task: HistoryTask) {
+    match task {
+        HistoryTask::Respond {
+            peer,
+            channel,
+            history,
+            more,
+        } => {
+            let mut plaintext = Vec::new();
+            let response = match ciborium::into_writer(&history, &mut plaintext)
+                .map_err(anyhow::Error::from)
+                .and_then(|()| core.history_session(peer))
+                .and_then(|s| Ok(s.seal(&plaintext)?))
+            {
+                Ok(sealed) => HistoryResponse::History { sealed, more },
+                Err(e) => HistoryResponse::Rejected {
+                    reason: e.to_string(),
+                },
+            };
+            if core
+                .swarm
+                .behaviour_mut()
+                .history
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("history response to {peer} dropped, connection closed");
+            }
+        }
+    }
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: HistoryEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => core.answer_history(peer, request, channel),
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => {
+            let Some((room, received)) = core.history.pending.remove(&request_id) else {
+                return;
+            };
+            match response {
+                HistoryResponse::History { sealed, more } => {
+                    core.receive_history(peer, room, received, &sealed, more);
+                }
+                HistoryResponse::Rejected { reason } => {
+                    tracing::debug!("{peer} rejected history request for {room}: {reason}");
+                }
+            }
+        }
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+            ..
+        } => {
+            tracing::debug!("history request to {peer} failed: {error}");
+            let _ = core.history.pending.remove(&request_id);
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound history request from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use rootcell::{Identity, Revocation};
+
+    #[tokio::test]
+    async fn revoked_authors_are_not_served() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let mallory = Identity::generate().unwrap();
+        let mallory_peer = identity::peer_id(&mallory.public_key()).unwrap();
+        assert!(core.revoked_authors().is_empty());
+        let _ = core
+            .revocations
+            .insert(Revocation::revoke_identity(&mallory, 1, "stolen"))
+            .unwrap();
+        assert_eq!(core.revoked_authors(), vec![mallory_peer]);
+    }
+}
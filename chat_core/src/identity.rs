@@ -0,0 +1,256 @@
+//! 节点身份：由 rootcell 加密保存的 ed25519 密钥及预密钥
+use libp2p::{
+    PeerId,
+    identity::{Keypair, PublicKey},
+};
+use rootcell::{
+    Identity, PreKeyStore, PublishedBundle, Ratchet, SecretBox, SecurityCore, TrustError,
+    prekey::X3dhHeader,
+};
+
+use std::path::{Path, PathBuf};
+
+use crate::{CoreConfig, storage::Storage};
+
+/// 每次向一个节点发布的一次性预密钥数
+pub(crate) const ONE_TIME_PER_PEER: usize = 5;
+/// 未发布的一次性预密钥不足时一次补充的数量
+const PREKEY_BATCH: u32 = 50;
+
+/// 节点密钥：设备身份、预密钥与（仅签发证书的设备持有的）用户身份
+#[derive(Debug)]
+pub(crate) struct NodeKeys {
+    pub identity: Identity,
+    pub prekeys: PreKeyStore,
+    ///用户身份，为本账户的设备签发证书；通过配对加入其他账户的设备没有
+    pub user: Option<Identity>,
+    ///加密保存预密钥所需的信任根与文件路径，临时身份时为 None
+    vault: Option<(SecurityCore, PathBuf)>,
+    ///用户身份文件，临时身份时为 None
+    user_path: Option<PathBuf>,
+}
+
+impl NodeKeys {
+    fn ephemeral() -> anyhow::Result<Self> {
+        Ok(Self {
+            identity: Identity::generate()?,
+            prekeys: PreKeyStore::generate(PREKEY_BATCH)?,
+            user: None,
+            vault: None,
+            user_path: None,
+        })
+    }
+
+    /// 创建（或载入已保存的）用户身份，本设备成为单设备账户
+    pub fn create_user(&mut self) -> anyhow::Result<&Identity> {
+        let user = match (&self.vault, &self.user_path) {
+            (Some((core, _)), Some(path)) => core.load_or_create_identity(path)?,
+            _ => Identity::generate()?,
+        };
+        Ok(self.user.insert(user))
+    }
+
+    /// 加入其他账户后丢弃本地用户身份
+    pub fn forget_user(&mut self) -> anyhow::Result<()> {
+        self.user = None;
+        if let Some(path) = &self.user_path
+            && let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// 生成发布用的预密钥包，附带 `count` 个从未发布的一次性预密钥，并写回存储；
+    /// 不附带一次性预密钥时不改变存储
+    pub fn publish_bundle(&mut self, count: usize) -> anyhow::Result<PublishedBundle> {
+        if self.prekeys.unpublished() < count {
+            self.prekeys.replenish(PREKEY_BATCH)?;
+        }
+        let bundle = self.prekeys.publish(&self.identity, count);
+        if count > 0
+            && let Some((core, path)) = &self.vault
+        {
+            core.save_prekeys(path, &self.prekeys)?;
+        }
+        Ok(bundle)
+    }
+
+    /// 作为接收方接受 X3DH 协商，消耗的一次性预密钥随即写回存储
+    pub fn accept_session(&mut self, header: &X3dhHeader) -> anyhow::Result<Ratchet> {
+        let ratchet = self.prekeys.accept(&self.identity, header)?;
+        if header.one_time_prekey_id.is_some()
+            && let Some((core, path)) = &self.vault
+        {
+            core.save_prekeys(path, &self.prekeys)?;
+        }
+        Ok(ratchet)
+    }
+
+    /// 加密持久化私聊会话的密钥；临时身份的会话不持久化，返回 None
+    pub fn session_state_key(&self) -> anyhow::Result<Option<SecretBox>> {
+        match &self.vault {
+            Some((core, _)) => Ok(Some(core.session_state_key()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 用信任根派生的数据库密钥解锁聊天记录；临时身份时不加密
+    pub async fn unlock_storage(&self, storage: &Storage) -> anyhow::Result<()> {
+        let Some((core, _)) = &self.vault else {
+            return Ok(());
+        };
+        let generation = storage.key_generation().await?.unwrap_or(0);
+        storage.unlock(core.database_key(generation)?).await
+    }
+
+    /// 换用下一代数据库密钥并重新加密全部正文，返回新代数
+    pub async fn rotate_database_key(&self, storage: &Storage) -> anyhow::Result<u64> {
+        let (core, _) = self.vault.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("chat history is not encrypted with an ephemeral identity")
+        })?;
+        let generation = storage
+            .key_generation()
+            .await?
+            .and_then(|g| g.checked_add(1))
+            .ok_or_else(|| anyhow::anyhow!("database is not encrypted yet"))?;
+        storage.rotate_key(core.database_key(generation)?).await?;
+        Ok(generation)
+    }
+}
+
+/// 预密钥文件与身份文件放在一起
+fn prekey_path(identity_path: &Path) -> PathBuf {
+    let mut name = identity_path.as_os_str().to_owned();
+    name.push(".prekeys");
+    PathBuf::from(name)
+}
+
+/// 用户身份文件与设备身份文件放在一起
+fn user_key_path(identity_path: &Path) -> PathBuf {
+    let mut name = identity_path.as_os_str().to_owned();
+    name.push(".user");
+    PathBuf::from(name)
+}
+
+/// 保险库文件与身份文件放在一起
+pub fn vault_path(identity_path: &Path) -> PathBuf {
+    let mut name = identity_path.as_os_str().to_owned();
+    name.push(".vault");
+    PathBuf::from(name)
+}
+
+/// 配置了口令时用保险库文件作信任根，否则使用系统密钥环
+fn trust_root(cfg: &CoreConfig, identity_path: &Path) -> Result<SecurityCore, TrustError> {
+    match &cfg.vault_passphrase {
+        Some(passphrase) => {
+            SecurityCore::from_vault(&vault_path(identity_path), passphrase.as_bytes())
+        }
+        None => SecurityCore::try_init(),
+    }
+}
+
+pub(crate) fn to_keypair(identity: &Identity) -> anyhow::Result<Keypair> {
+    let mut seed = identity.secret_bytes();
+    // ed25519_from_bytes 会清零传入的缓冲区
+    Ok(Keypair::ed25519_from_bytes(&mut *seed)?)
+}
+
+/// 载入节点身份与预密钥
+///
+/// 未配置身份文件时使用临时身份；密钥环不可用（如无图形界面的服务器）时同样退化为
+/// 临时身份并记录警告，此时每次启动 PeerId 都会变化。保险库口令错误直接报错
+pub(crate) fn node_keys(cfg: &CoreConfig) -> anyhow::Result<NodeKeys> {
+    let Some(path) = &cfg.identity_path else {
+        return NodeKeys::ephemeral();
+    };
+    match trust_root(cfg, path) {
+        Ok(core) => {
+            let identity = core.load_or_create_identity(path)?;
+            let prekey_path = prekey_path(path);
+            let prekeys = core.load_or_create_prekeys(&prekey_path, PREKEY_BATCH)?;
+            let user_path = user_key_path(path);
+            Ok(NodeKeys {
+                identity,
+                prekeys,
+                user: core.load_identity(&user_path)?,
+                vault: Some((core, prekey_path)),
+                user_path: Some(user_path),
+            })
+        }
+        // 用户明确选择了保险库，打不开时不能悄悄换成临时身份
+        Err(e) if cfg.vault_passphrase.is_some() => {
+            let context = match e {
+                TrustError::AccessDenied => "wrong vault passphrase",
+                _ => "failed to open key vault",
+            };
+            Err(anyhow::Error::new(e).context(context))
+        }
+        Err(e) => {
+            tracing::warn!("root of trust unavailable ({e}), using an ephemeral identity");
+            NodeKeys::ephemeral()
+        }
+    }
+}
+
+/// 从 PeerId 中取出对端的 ed25519 公钥
+///
+/// ed25519 的 PeerId 直接内嵌公钥（identity multihash），无需额外查询
+pub(crate) fn peer_public_key(peer: &PeerId) -> anyhow::Result<[u8; 32]> {
+    let multihash = peer.as_ref();
+    if multihash.code() != 0 {
+        anyhow::bail!("peer id {peer} does not embed its public key");
+    }
+    let key = PublicKey::try_decode_protobuf(multihash.digest())?.try_into_ed25519()?;
+    Ok(key.to_bytes())
+}
+
+/// 由 ed25519 身份公钥得到对应的 PeerId
+pub(crate) fn peer_id(public_key: &[u8; 32]) -> anyhow::Result<PeerId> {
+    let key = libp2p::identity::ed25519::PublicKey::try_from_bytes(public_key)?;
+    Ok(PublicKey::from(key).to_peer_id())
+}
+
+/// 轮换节点身份，返回新的 PeerId；需重启核心后生效
+pub fn rotate_identity(cfg: &CoreConfig) -> anyhow::Result<PeerId> {
+    let path = cfg
+        .identity_path
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("no identity file configured"))?;
+    let identity = trust_root(cfg, path)?.rotate_identity(path)?;
+    // 旧预密钥由旧身份签名发布过，随身份一起作废
+    if let Err(e) = std::fs::remove_file(prekey_path(path))
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+    Ok(to_keypair(&identity)?.public().to_peer_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_key_from_peer_id() {
+        let identity = Identity::generate().unwrap();
+        let peer = to_keypair(&identity).unwrap().public().to_peer_id();
+        assert_eq!(peer_public_key(&peer).unwrap(), identity.public_key());
+        assert_eq!(peer_id(&identity.public_key()).unwrap(), peer);
+    }
+
+    #[test]
+    fn vault_keeps_identity_and_rejects_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = |passphrase: &str| {
+            CoreConfig::new("sqlite::memory:")
+                .with_identity_path(dir.path().join("identity"))
+                .with_vault_passphrase(passphrase)
+        };
+        let first = node_keys(&cfg("secret")).unwrap();
+        let again = node_keys(&cfg("secret")).unwrap();
+        assert_eq!(first.identity.public_key(), again.identity.public_key());
+        assert!(node_keys(&cfg("wrong")).is_err());
+    }
+}
@@ -1,16 +1,16 @@
 use std::{
-    collections::hash_map::DefaultHasher,
-    error::Error,
-    hash::{Hash, Hasher},
-    str::FromStr,
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
     time::Duration,
 };
 
 use libp2p::{
     Swarm,
     futures::io,
-    gossipsub, mdns, noise,
-    swarm::{self, NetworkBehaviour, SwarmEvent},
+    gossipsub, identify,
+    identity::Keypair,
+    mdns, noise,
+    swarm::{NetworkBehaviour, SwarmEvent, behaviour::toggle::Toggle},
     tcp, yamux,
 };
 use tokio::sync::mpsc;
@@ -18,27 +18,98 @@ use tracing_subscriber::EnvFilter;
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    kad: discovery::KadBehaviour,
+    identify: identify::Behaviour,
+    direct: direct::DirectBehaviour,
+    prekey: prekey::PreKeyBehaviour,
+    pair: device::PairBehaviour,
+    mailbox: mailbox::MailboxBehaviour,
+    history: history::HistoryBehaviour,
+    revocation: revocation::RevocationBehaviour,
 }
 
+mod contact;
+pub mod device;
+pub mod direct;
+pub mod discovery;
+mod event;
+mod group;
+mod handle;
+pub mod history;
+mod identity;
+pub mod mailbox;
+pub mod prekey;
+pub mod revocation;
+mod room;
+mod session;
 pub mod storage;
+pub mod wire;
+pub use contact::Verification;
+pub use event::{Author, MessageEvent};
+pub use handle::{ChatHandle, EventReceiver};
+pub use identity::{rotate_identity, vault_path};
+pub use libp2p::{Multiaddr, PeerId};
+/// 启动时默认加入的主题
+pub const DEFAULT_TOPIC: &str = "test-net";
 pub struct CoreConfig {
     ///example :"sqlite:///path/to/database.db"
     database_path: String,
+    ///加密保存的节点身份，None 时每次启动使用临时身份
+    identity_path: Option<PathBuf>,
+    ///保险库口令；设置后主密钥保存在口令保护的文件中，不使用系统密钥环
+    vault_passphrase: Option<zeroize::Zeroizing<String>>,
+    ///本设备在账户中的显示名称，None 时使用主机名
+    device_name: Option<String>,
+    ///自愿为其他节点代存离线私聊，None 时不充当信箱
+    mailbox: Option<mailbox::MailboxConfig>,
+    ///DHT 引导节点，地址以 `/p2p/<PeerId>` 结尾
+    bootstrap_nodes: Vec<Multiaddr>,
+    ///是否用 mDNS 发现局域网内的节点
+    mdns: bool,
 }
 impl CoreConfig {
     pub fn new(database_path: impl Into<std::string::String>) -> Self {
         Self {
             database_path: database_path.into(),
+            identity_path: None,
+            vault_passphrase: None,
+            device_name: None,
+            mailbox: None,
+            bootstrap_nodes: Vec::new(),
+            mdns: true,
         }
     }
-}
-pub enum MessageEvent {
-    newmassage,
-}
-pub struct ChatMeassage {
-    pub event: MessageEvent,
-    pub data: String,
+    pub fn with_identity_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.identity_path = Some(path.into());
+        self
+    }
+    /// 用口令保护的保险库文件（见 [`vault_path`]）保存主密钥，用于没有密钥环的环境
+    pub fn with_vault_passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self.vault_passphrase = Some(zeroize::Zeroizing::new(passphrase.into()));
+        self
+    }
+    /// 本设备在账户设备列表中的显示名称
+    pub fn with_device_name(mut self, name: impl Into<String>) -> Self {
+        self.device_name = Some(name.into());
+        self
+    }
+    /// 充当信箱，按给定的保存时长与配额为离线节点代存私聊
+    pub fn with_mailbox(mut self, config: mailbox::MailboxConfig) -> Self {
+        self.mailbox = Some(config);
+        self
+    }
+    /// 添加 DHT 引导节点，地址须以 `/p2p/<PeerId>` 结尾，如
+    /// `/ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...`
+    pub fn with_bootstrap_node(mut self, addr: Multiaddr) -> Self {
+        self.bootstrap_nodes.push(addr);
+        self
+    }
+    /// 是否用 mDNS 发现局域网内的节点，默认开启
+    pub fn with_mdns(mut self, enabled: bool) -> Self {
+        self.mdns = enabled;
+        self
+    }
 }
 fn init_logger() {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
@@ -55,53 +126,196 @@ fn init_logger() {
 
 pub struct ChatCore {
     pub swarm: Swarm<MyBehaviour>,
-    pub topic: gossipsub::IdentTopic,
-    pub tx_message: tokio::sync::mpsc::Sender<ChatMeassage>,
-    pub rx_message: Option<tokio::sync::mpsc::Receiver<ChatMeassage>>,
+    pub storage: storage::Storage,
+    ///已加入的房间（主题名）
+    rooms: BTreeSet<String>,
+    ///节点身份与预密钥，私聊加密与 swarm 使用同一身份
+    keys: identity::NodeKeys,
+    ///其他节点发布的预密钥包，按身份公钥索引，代为分发
+    prekey_cache: prekey::PreKeyCache,
+    ///进行中的预密钥包查询
+    pending_fetches: HashMap<libp2p::request_response::OutboundRequestId, prekey::PendingFetch>,
+    ///与各节点的私聊棘轮会话
+    sessions: session::Sessions,
+    ///收到的私聊，由写入任务按顺序保存后通知前端
+    inbox: mpsc::UnboundedSender<direct::Received>,
+    ///等待对方回执的私聊
+    pending_direct: HashMap<libp2p::request_response::OutboundRequestId, direct::PendingDirect>,
+    ///各房间的发送者密钥，房间消息只以密文发布
+    groups: group::GroupKeys,
+    ///已知的密钥撤销，撤销身份的消息一律丢弃
+    revocations: rootcell::RevocationList,
+    ///已知的设备证书与进行中的配对
+    devices: device::Devices,
+    ///联系人的身份公钥与核验状态
+    contacts: contact::Contacts,
+    ///代存的离线私聊与进行中的信箱请求
+    mailbox: mailbox::Mailbox,
+    ///进行中的房间历史同步
+    history: history::HistorySync,
+    ///进行中的 DHT 节点查找
+    discovery: discovery::Discovery,
+    ///待推送的事件，由转发任务按产生顺序交给 `tx_message`
+    events: mpsc::UnboundedSender<MessageEvent>,
+    pub tx_message: tokio::sync::mpsc::Sender<MessageEvent>,
+    pub rx_message: Option<tokio::sync::mpsc::Receiver<MessageEvent>>,
 }
 impl ChatCore {
-    pub fn try_init(cfg: &CoreConfig) -> anyhow::Result<Self> {
+    pub async fn try_init(cfg: &CoreConfig) -> anyhow::Result<Self> {
         init_logger();
-        storage::init(cfg)?;
-        let mut swarm = swarm_init()?;
-        // Create a Gossipsub topic
-        let topic = gossipsub::IdentTopic::new("test-net");
-        // subscribes to our topic
-        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        let storage = storage::init(cfg).await?;
+        let keys = identity::node_keys(cfg)?;
+        keys.unlock_storage(&storage).await?;
+        let swarm = swarm_init(identity::to_keypair(&keys.identity)?, cfg.mdns)?;
         let (tx, rx) = mpsc::channel(32);
+        let (events, queue) = mpsc::unbounded_channel();
+        tokio::spawn(forward_events(queue, tx.clone()));
+        let contacts = contact::Contacts::new(storage.clone());
+        let mailbox = mailbox::Mailbox::new(storage.clone(), cfg.mailbox);
+        let sessions = session::Sessions::new(storage.clone(), keys.session_state_key()?);
+        let inbox = direct::inbox(storage.clone(), events.clone());
 
-        Ok(ChatCore {
+        let mut core = ChatCore {
             swarm,
+            storage,
+            rooms: BTreeSet::new(),
+            keys,
+            prekey_cache: prekey::PreKeyCache::default(),
+            pending_fetches: HashMap::new(),
+            sessions,
+            inbox,
+            pending_direct: HashMap::new(),
+            groups: group::GroupKeys::default(),
+            revocations: rootcell::RevocationList::new(),
+            devices: device::Devices::default(),
+            contacts,
+            mailbox,
+            history: history::HistorySync::default(),
+            discovery: discovery::Discovery::default(),
+            events,
             tx_message: tx,
             rx_message: Some(rx),
-            topic,
-        })
+        };
+        core.restore_revocations().await?;
+        core.restore_devices(cfg.device_name.clone()).await?;
+        core.restore_contacts().await?;
+        core.restore_mail().await?;
+        core.restore_sessions().await?;
+        core.restore_members().await?;
+        core.restore_rooms().await?;
+        core.add_bootstrap_nodes(&cfg.bootstrap_nodes)?;
+        Ok(core)
     }
-    pub fn sendmessage(&mut self, data: String) {
-        if let Err(e) = self
+    /// 用发送者密钥加密后向已加入的房间发布文本消息并写入聊天记录；
+    /// 房间暂时没有在线成员时只写入记录，由历史同步补发
+    pub fn sendmessage(&mut self, room: &str, data: String) -> anyhow::Result<wire::WireMessage> {
+        if !self.is_joined(room) {
+            anyhow::bail!("not a member of room {room}");
+        }
+        let message = wire::WireMessage::text(self.swarm.local_peer_id().to_string(), data)
+            .in_room(room)
+            .sign(&self.keys.identity)?;
+        let sealed = self.seal_room_message(room, &message)?;
+        match self
             .swarm
             .behaviour_mut()
             .gossipsub
-            .publish(self.topic.clone(), data.as_bytes())
+            .publish(gossipsub::IdentTopic::new(room), sealed)
         {
-            println!("Publish error: {e:?}");
+            Ok(_) => {}
+            // 暂时没有在线成员时只写入本地记录，其他成员连接后经历史同步取得
+            Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {
+                tracing::debug!("no peers in {room}, message {} kept for sync", message.id);
+            }
+            Err(e) => return Err(e.into()),
         }
+        self.store_message(
+            storage::ConversationKind::Room,
+            room.to_string(),
+            message.clone(),
+            true,
+        );
+        Ok(message)
     }
-    fn sendmessage_mpsc(&mut self, data: String) {
-        let message = ChatMeassage {
-            event: MessageEvent::newmassage,
-            data,
-        };
-        let tx = self.tx_message.clone();
+    /// 轮换聊天记录加密密钥，返回新的密钥代数
+    pub async fn rotate_database_key(&mut self) -> anyhow::Result<u64> {
+        self.keys.rotate_database_key(&self.storage).await
+    }
+    /// 验证收到的信封签名，返回作者；作者身份已撤销时按撤销丢弃
+    pub(crate) fn authenticate(&self, payload: &wire::WireMessage) -> anyhow::Result<Author> {
+        let device = payload.verify()?;
+        if let Err(e) = self.check_peer(&device) {
+            revocation::drop_message(self, device);
+            return Err(e.into());
+        }
+        let user = identity::peer_public_key(&device)
+            .ok()
+            .and_then(|key| self.user_of(&key))
+            .and_then(|key| identity::peer_id(&key).ok());
+        Ok(Author { device, user })
+    }
+    /// 后台写入聊天记录，失败只记日志
+    fn store_message(
+        &self,
+        kind: storage::ConversationKind,
+        name: String,
+        message: wire::WireMessage,
+        outgoing: bool,
+    ) {
+        let storage = self.storage.clone();
         tokio::spawn(async move {
-            tx.send(message)
-                .await
-                .expect("falied send message:tx_message ");
+            if let Err(e) = save_message(&storage, kind, &name, message, outgoing).await {
+                tracing::warn!("failed to store message: {e:?}");
+            }
         });
     }
+    /// 按产生顺序推送事件；前端已丢弃接收端时静默丢弃
+    fn send_event(&self, message: MessageEvent) {
+        let _ = self.events.send(message);
+    }
+}
+/// 把排队的事件依次转发给前端，接收端关闭后停止
+async fn forward_events(
+    mut queue: mpsc::UnboundedReceiver<MessageEvent>,
+    tx: mpsc::Sender<MessageEvent>,
+) {
+    while let Some(event) = queue.recv().await {
+        if tx.send(event).await.is_err() {
+            break;
+        }
+    }
 }
-fn swarm_init() -> anyhow::Result<Swarm<MyBehaviour>> {
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+/// 把信封写入会话记录，已存在时返回 None
+async fn save_message(
+    storage: &storage::Storage,
+    kind: storage::ConversationKind,
+    name: &str,
+    message: wire::WireMessage,
+    outgoing: bool,
+) -> anyhow::Result<Option<i64>> {
+    let conversation_id = match kind {
+        storage::ConversationKind::Room => storage.topic_conversation(name).await?,
+        storage::ConversationKind::Direct => storage.direct_conversation(name).await?,
+    };
+    if !outgoing {
+        storage.touch_peer(&message.author).await?;
+    }
+    storage
+        .insert_message(&storage::NewMessage {
+            conversation_id,
+            message_id: message.id,
+            author: message.author,
+            kind: message.kind,
+            body: message.body,
+            reply_to: message.reply_to,
+            sent_at: message.sent_at,
+            outgoing,
+            signature: message.signature,
+        })
+        .await
+}
+fn swarm_init(keypair: Keypair, mdns: bool) -> anyhow::Result<Swarm<MyBehaviour>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -110,19 +324,13 @@ fn swarm_init() -> anyhow::Result<Swarm<MyBehaviour>> {
         )?
         .with_quic()
         .with_behaviour(|key| {
-            // To content-address message, we can take the hash of message and use it as an ID.
-            let message_id_fn = |message: &gossipsub::Message| {
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
-            };
-
             // Set a custom gossipsub configuration
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
                 .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message
                 // signing)
-                .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
+                .message_id_fn(content_id) // content-address messages by author, sequence number and data
+                .do_px() // pruned peers learn other mesh members, dialed via the DHT routing table
                 .build()
                 .map_err(io::Error::other)?; // Temporary hack because `build` does not return a proper `std::error::Error`.
 
@@ -132,9 +340,24 @@ fn swarm_init() -> anyhow::Result<Swarm<MyBehaviour>> {
                 gossipsub_config,
             )?;
 
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
+            let local = key.public().to_peer_id();
+            let mdns = if mdns {
+                Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local)?)
+            } else {
+                None
+            };
+            Ok(MyBehaviour {
+                gossipsub,
+                mdns: mdns.into(),
+                kad: discovery::kademlia(local),
+                identify: discovery::identify(key),
+                direct: direct::behaviour(),
+                prekey: prekey::behaviour(),
+                pair: device::behaviour(),
+                mailbox: mailbox::behaviour(),
+                history: history::behaviour(),
+                revocation: revocation::behaviour(),
+            })
         })?
         .build();
 
@@ -143,40 +366,141 @@ fn swarm_init() -> anyhow::Result<Swarm<MyBehaviour>> {
 pub fn swarm_event(event: SwarmEvent<MyBehaviourEvent>, core: &mut ChatCore) {
     match event {
         SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-            for (peer_id, _multiaddr) in list {
-                core.sendmessage_mpsc(format!("mDNS discovered a new peer: {peer_id}"));
-
+            for (peer, addr) in list {
                 core.swarm
                     .behaviour_mut()
                     .gossipsub
-                    .add_explicit_peer(&peer_id);
+                    .add_explicit_peer(&peer);
+                core.send_event(MessageEvent::PeerDiscovered { peer, addr });
             }
         }
         SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-            for (peer_id, _multiaddr) in list {
-                core.sendmessage_mpsc(format!("mDNS discover peer has expired: {peer_id}"));
+            for (peer, addr) in list {
                 core.swarm
                     .behaviour_mut()
                     .gossipsub
-                    .remove_explicit_peer(&peer_id);
+                    .remove_explicit_peer(&peer);
+                core.send_event(MessageEvent::PeerExpired { peer, addr });
             }
         }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Kad(event)) => {
+            discovery::handle_kad_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => {
+            discovery::handle_identify_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message_id: id,
+            message,
+            ..
+        })) if message.topic.as_str() == revocation::REVOCATION_TOPIC => {
+            revocation::handle_message(core, id, message);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message_id: id,
+            message,
+            ..
+        })) if message.topic.as_str() == device::DEVICE_TOPIC => {
+            device::handle_message(core, id, message);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message_id: id,
+            message,
+            ..
+        })) if !core.is_joined(message.topic.as_str()) => {
+            tracing::debug!("ignored message {id} for unjoined room {}", message.topic);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message:
+                gossipsub::Message {
+                    source: Some(author),
+                    ..
+                },
+            ..
+        })) if core.check_peer(&author).is_err() => revocation::drop_message(core, author),
         SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
             propagation_source: peer_id,
             message_id: id,
             message,
-        })) => {
-            core.sendmessage_mpsc(format!(
-                "Got message: '{}' with id: {id} from peer: {peer_id}",
-                String::from_utf8_lossy(&message.data)
-            ));
+        })) => group::handle_message(core, peer_id, id, message),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            peer_id,
+            topic,
+        })) if topic.as_str() == revocation::REVOCATION_TOPIC => {
+            if let Err(e) = revocation::peer_subscribed(core, peer_id) {
+                tracing::warn!("failed to send known revocations to {peer_id}: {e}");
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            topic,
+            ..
+        })) if topic.as_str() == device::DEVICE_TOPIC => device::peer_subscribed(core),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            peer_id,
+            topic,
+        })) => group::peer_subscribed(core, peer_id, &topic),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed {
+            peer_id,
+            topic,
+        })) => group::peer_unsubscribed(core, peer_id, &topic),
+        SwarmEvent::Behaviour(MyBehaviourEvent::Direct(event)) => {
+            direct::handle_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Prekey(event)) => {
+            prekey::handle_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Pair(event)) => {
+            device::handle_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Mailbox(event)) => {
+            mailbox::handle_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::History(event)) => {
+            history::handle_event(core, event);
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Revocation(event)) => {
+            revocation::handle_event(core, event);
         }
         SwarmEvent::NewListenAddr { address, .. } => {
-            core.sendmessage_mpsc(format!("Local node is listening on {address}"));
+            core.send_event(MessageEvent::ListeningOn(address));
+        }
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            num_established,
+            ..
+        } if num_established.get() == 1 => {
+            core.publish_prekeys(peer_id);
+            core.deliver_mail(peer_id);
+            core.send_event(MessageEvent::ConnectionEstablished(peer_id));
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            cause,
+            ..
+        } => {
+            core.send_event(MessageEvent::ConnectionClosed {
+                peer: peer_id,
+                cause: cause.map(|e| e.to_string()),
+            });
         }
         _ => {}
     }
 }
+/// gossipsub 消息 id：作者、序号与内容的 SHA-256，所有构建结果一致；
+/// 不同作者或同一作者先后发送的相同内容不会被当作重复消息
+fn content_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let author = message
+        .source
+        .map(|peer| peer.to_bytes())
+        .unwrap_or_default();
+    let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+    digest.update(&(author.len() as u64).to_be_bytes());
+    digest.update(&author);
+    digest.update(&message.sequence_number.unwrap_or_default().to_be_bytes());
+    digest.update(&message.data);
+    gossipsub::MessageId::new(digest.finish().as_ref())
+}
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
@@ -190,4 +514,49 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[tokio::test]
+    async fn events_keep_order_and_tolerate_closed_receiver() {
+        let mut core = ChatCore::try_init(&CoreConfig::new("sqlite::memory:"))
+            .await
+            .unwrap();
+        let mut events = core.rx_message.take().unwrap();
+        for n in 0..100 {
+            core.send_event(MessageEvent::Error(n.to_string()));
+        }
+        let mut received = Vec::new();
+        while received.len() < 100 {
+            if let Some(MessageEvent::Error(n)) = events.recv().await {
+                received.push(n);
+            }
+        }
+        assert_eq!(
+            received,
+            (0..100).map(|n| n.to_string()).collect::<Vec<_>>()
+        );
+        drop(events);
+        core.send_event(MessageEvent::Error("nobody listens".to_string()));
+        tokio::task::yield_now().await;
+    }
+
+    #[test]
+    fn content_id_is_stable_and_author_scoped() {
+        let alice = PeerId::from_bytes(&[0, 2, 8, 1]).unwrap();
+        let message = |source, sequence_number| gossipsub::Message {
+            source: Some(source),
+            data: b"hi".to_vec(),
+            sequence_number: Some(sequence_number),
+            topic: gossipsub::IdentTopic::new(DEFAULT_TOPIC).hash(),
+        };
+        let id = content_id(&message(alice, 1));
+        assert_eq!(id.to_string().len(), 64);
+        assert_eq!(id, content_id(&message(alice, 1)));
+        assert_ne!(id, content_id(&message(alice, 2)));
+        assert_ne!(id, content_id(&message(PeerId::random(), 1)));
+        // 固定输入的 id 不随编译器或平台变化
+        assert_eq!(
+            id.to_string(),
+            "09421c17d77604c4c5004f734b5516585a2b2114ea8887424a0738424cc51100"
+        );
+    }
 }
@@ -0,0 +1,474 @@
+//! 离线私聊的代存转发：`/mychat/mailbox/1`
+//!
+//! 任何节点都可以通过 [`crate::CoreConfig::with_mailbox`] 自愿充当信箱。私聊因对方
+//! 不在线而发送失败时，发送方依次请已连接的节点代存加密后的请求，直到有信箱接受；
+//! 信箱在收件人连接时把信封转交给它，收到确认后删除。信箱只能看到密文以及收发双方
+//! 的 PeerId，信封超过保存时长即丢弃，每个收件人与信箱总量各有配额。
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, OutboundRequestId, ProtocolSupport, cbor},
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    ChatCore, MessageEvent,
+    storage::{MailboxEntry, Storage, now_millis},
+};
+
+/// 信箱协议名
+pub const MAILBOX_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/mailbox/1");
+
+/// 单个信封的大小上限
+const MAX_SEALED_LEN: usize = 64 * 1024;
+/// 每次转交的信封总字节数上限，低于请求大小上限（1 MiB）；其余的在收件人确认后
+/// 分批转交
+const DELIVERY_BYTES: usize = 512 * 1024;
+/// 估算编码长度时每个信封额外计入的 CBOR 开销
+const MAIL_OVERHEAD: usize = 32;
+
+/// 信箱的保存时长与配额
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxConfig {
+    ///信封的保存时长
+    pub ttl: Duration,
+    ///每个收件人最多代存的信封数
+    pub per_recipient: usize,
+    ///信箱最多代存的信封总数
+    pub total: usize,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            per_recipient: 100,
+            total: 10_000,
+        }
+    }
+}
+
+/// 信箱请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxRequest {
+    /// 请信箱为离线的收件人代存私聊
+    Deposit {
+        recipient: String,
+        ///发件人与收件人之间加密会话的密文
+        #[serde(with = "serde_bytes")]
+        sealed: Vec<u8>,
+    },
+    /// 信箱把代存的私聊转交给收件人
+    Deliver { mail: Vec<Mail> },
+}
+
+/// 转交给收件人的一个信封
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub id: String,
+    pub sender: String,
+    #[serde(with = "serde_bytes")]
+    pub sealed: Vec<u8>,
+}
+
+/// 信箱回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxResponse {
+    /// 已代存，到期时间为 unix 毫秒
+    Stored { expires_at: i64 },
+    /// 收件人已收到转交的信封
+    Received,
+    /// 拒绝代存，如未开启信箱或超出配额
+    Rejected { reason: String },
+}
+
+pub type MailboxBehaviour = cbor::Behaviour<MailboxRequest, MailboxResponse>;
+pub type MailboxEvent = request_response::Event<MailboxRequest, MailboxResponse>;
+
+pub(crate) fn behaviour() -> MailboxBehaviour {
+    cbor::Behaviour::new(
+        [(MAILBOX_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// 存储写入，按顺序执行
+#[derive(Debug)]
+enum MailWrite {
+    Insert(MailboxEntry),
+    Delete(Vec<String>),
+}
+
+/// 进行中的代存：依次询问候选节点，直到有信箱接受
+#[derive(Debug)]
+pub(crate) struct PendingDeposit {
+    recipient: PeerId,
+    message_id: String,
+    sealed: Vec<u8>,
+    ///直接发送失败的原因，没有信箱接受时上报
+    error: String,
+    candidates: Vec<PeerId>,
+}
+
+/// 代存的信封与进行中的信箱请求
+#[derive(Debug)]
+pub(crate) struct Mailbox {
+    ///本节点作为信箱的配置，None 时拒绝新的代存
+    config: Option<MailboxConfig>,
+    ///按收件人索引的信封
+    held: HashMap<PeerId, Vec<MailboxEntry>>,
+    ///等待信箱回复的代存
+    deposits: HashMap<OutboundRequestId, PendingDeposit>,
+    ///等待收件人确认的转交
+    deliveries: HashMap<OutboundRequestId, (PeerId, Vec<String>)>,
+    writes: mpsc::UnboundedSender<MailWrite>,
+}
+
+impl Mailbox {
+    pub(crate) fn new(storage: Storage, config: Option<MailboxConfig>) -> Self {
+        let (writes, mut rx) = mpsc::unbounded_channel::<MailWrite>();
+        tokio::spawn(async move {
+            while let Some(write) = rx.recv().await {
+                let result = match write {
+                    MailWrite::Insert(mail) => storage.insert_mail(&mail).await.map(|_| ()),
+                    MailWrite::Delete(ids) => storage.delete_mail(&ids).await.map(|_| ()),
+                };
+                if let Err(e) = result {
+                    tracing::warn!("failed to update mailbox: {e:?}");
+                }
+            }
+        });
+        Self {
+            config,
+            held: HashMap::new(),
+            deposits: HashMap::new(),
+            deliveries: HashMap::new(),
+            writes,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.held.values().map(Vec::len).sum()
+    }
+
+    /// 丢弃已过期的信封
+    fn expire(&mut self, now: i64) {
+        let mut expired = Vec::new();
+        self.held.retain(|_, mail| {
+            mail.retain(|entry| {
+                if entry.expires_at > now {
+                    return true;
+                }
+                expired.push(entry.id.clone());
+                false
+            });
+            !mail.is_empty()
+        });
+        if !expired.is_empty() {
+            let _ = self.writes.send(MailWrite::Delete(expired));
+        }
+    }
+}
+
+/// 从最早的信封起取一批不超过 [`DELIVERY_BYTES`] 的信封，至少一个
+fn delivery_batch(held: &[MailboxEntry]) -> Vec<Mail> {
+    let mut size = 0;
+    held.iter()
+        .take_while(|entry| {
+            let first = size == 0;
+            size += entry.id.len() + entry.sender.len() + entry.sealed.len() + MAIL_OVERHEAD;
+            first || size <= DELIVERY_BYTES
+        })
+        .map(|entry| Mail {
+            id: entry.id.clone(),
+            sender: entry.sender.clone(),
+            sealed: entry.sealed.clone(),
+        })
+        .collect()
+}
+
+impl ChatCore {
+    /// 载入未过期的代存信封
+    pub(crate) async fn restore_mail(&mut self) -> anyhow::Result<()> {
+        let _ = self.storage.purge_expired_mail(now_millis()).await?;
+        for entry in self.storage.mail().await? {
+            match entry.recipient.parse() {
+                Ok(recipient) => self.mailbox.held.entry(recipient).or_default().push(entry),
+                Err(e) => tracing::warn!("skipped mail {} for {}: {e}", entry.id, entry.recipient),
+            }
+        }
+        Ok(())
+    }
+
+    /// 直接发送失败后请已连接的节点代存；没有信箱接受时上报发送失败
+    pub(crate) fn deposit_mail(
+        &mut self,
+        recipient: PeerId,
+        message_id: String,
+        sealed: Vec<u8>,
+        error: String,
+    ) {
+        let candidates = self
+            .swarm
+            .connected_peers()
+            .filter(|p| **p != recipient)
+            .copied()
+            .collect();
+        self.next_deposit(PendingDeposit {
+            recipient,
+            message_id,
+            sealed,
+            error,
+            candidates,
+        });
+    }
+
+    fn next_deposit(&mut self, mut pending: PendingDeposit) {
+        let Some(candidate) = pending.candidates.pop() else {
+            self.send_event(MessageEvent::DirectFailed {
+                peer: pending.recipient,
+                id: pending.message_id,
+                error: pending.error,
+            });
+            return;
+        };
+        let request_id = self.swarm.behaviour_mut().mailbox.send_request(
+            &candidate,
+            MailboxRequest::Deposit {
+                recipient: pending.recipient.to_string(),
+                sealed: pending.sealed.clone(),
+            },
+        );
+        let _ = self.mailbox.deposits.insert(request_id, pending);
+    }
+
+    /// 把代存的信封转交给刚连接的收件人
+    pub(crate) fn deliver_mail(&mut self, recipient: PeerId) {
+        self.mailbox.expire(now_millis());
+        let Some(held) = self.mailbox.held.get(&recipient) else {
+            return;
+        };
+        // 同一收件人同时只有一次转交，避免重复投递
+        if self
+            .mailbox
+            .deliveries
+            .values()
+            .any(|(p, _)| *p == recipient)
+        {
+            return;
+        }
+        let mail = delivery_batch(held);
+        let ids = mail.iter().map(|m| m.id.clone()).collect();
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .mailbox
+            .send_request(&recipient, MailboxRequest::Deliver { mail });
+        let _ = self.mailbox.deliveries.insert(request_id, (recipient, ids));
+    }
+
+    /// 收件人确认后删除已转交的信封
+    fn delivered_mail(&mut self, recipient: PeerId, ids: Vec<String>) {
+        if let Some(held) = self.mailbox.held.get_mut(&recipient) {
+            held.retain(|entry| !ids.contains(&entry.id));
+            if held.is_empty() {
+                let _ = self.mailbox.held.remove(&recipient);
+            }
+        }
+        let _ = self.mailbox.writes.send(MailWrite::Delete(ids));
+        // 转交期间新存入的信封
+        if self.swarm.is_connected(&recipient) {
+            self.deliver_mail(recipient);
+        }
+    }
+
+    /// 作为信箱接受代存
+    fn accept_deposit(
+        &mut self,
+        sender: PeerId,
+        recipient: &str,
+        sealed: Vec<u8>,
+    ) -> anyhow::Result<i64> {
+        let Some(config) = self.mailbox.config else {
+            anyhow::bail!("not a mailbox");
+        };
+        self.check_peer(&sender)?;
+        let recipient: PeerId = recipient.parse()?;
+        if sealed.len() > MAX_SEALED_LEN {
+            anyhow::bail!("envelope exceeds {MAX_SEALED_LEN} bytes");
+        }
+        let now = now_millis();
+        self.mailbox.expire(now);
+        if self.mailbox.total() >= config.total {
+            anyhow::bail!("mailbox is full");
+        }
+        let held = self.mailbox.held.entry(recipient).or_default();
+        if held.len() >= config.per_recipient {
+            anyhow::bail!("mailbox for {recipient} is full");
+        }
+        let ttl = i64::try_from(config.ttl.as_millis()).unwrap_or(i64::MAX);
+        let entry = MailboxEntry {
+            id: hex::encode(digest::digest(&digest::SHA256, &sealed)),
+            recipient: recipient.to_string(),
+            sender: sender.to_string(),
+            sealed,
+            stored_at: now,
+            expires_at: now.saturating_add(ttl),
+        };
+        let expires_at = entry.expires_at;
+        if !held.iter().any(|held| held.id == entry.id) {
+            held.push(entry.clone());
+            let _ = self.mailbox.writes.send(MailWrite::Insert(entry));
+        }
+        if self.swarm.is_connected(&recipient) {
+            self.deliver_mail(recipient);
+        }
+        Ok(expires_at)
+    }
+
+    /// 作为收件人处理信箱转交的信封，逐个按私聊请求解密与验证
+    fn receive_mail(&mut self, mailbox: PeerId, mail: Vec<Mail>) {
+        for item in mail {
+            let sender: PeerId = match item.sender.parse() {
+                Ok(sender) => sender,
+                Err(e) => {
+                    tracing::warn!("dropped mail {} from mailbox {mailbox}: {e}", item.id);
+                    continue;
+                }
+            };
+            if self.check_peer(&sender).is_err() {
+                crate::revocation::drop_message(self, sender);
+                continue;
+            }
+            let reason = match crate::direct::handle_request(self, sender, &item.sealed) {
+                crate::direct::DirectResponse::Ack { .. } => continue,
+                crate::direct::DirectResponse::Rejected { reason } => reason,
+                crate::direct::DirectResponse::NoSession => "no session".to_string(),
+            };
+            tracing::warn!(
+                "dropped mail {} from {sender} via {mailbox}: {reason}",
+                item.id
+            );
+        }
+    }
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: MailboxEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => {
+            let response = match request {
+                MailboxRequest::Deposit { recipient, sealed } => {
+                    match core.accept_deposit(peer, &recipient, sealed) {
+                        Ok(expires_at) => MailboxResponse::Stored { expires_at },
+                        Err(e) => {
+                            tracing::debug!("rejected mail from {peer} for {recipient}: {e}");
+                            MailboxResponse::Rejected {
+                                reason: e.to_string(),
+                            }
+                        }
+                    }
+                }
+                MailboxRequest::Deliver { mail } => {
+                    core.receive_mail(peer, mail);
+                    MailboxResponse::Received
+                }
+            };
+            if core
+                .swarm
+                .behaviour_mut()
+                .mailbox
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("mailbox response to {peer} dropped, connection closed");
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => {
+            if let Some((recipient, ids)) = core.mailbox.deliveries.remove(&request_id) {
+                if matches!(response, MailboxResponse::Received) {
+                    core.delivered_mail(recipient, ids);
+                }
+                return;
+            }
+            let Some(pending) = core.mailbox.deposits.remove(&request_id) else {
+                return;
+            };
+            match response {
+                MailboxResponse::Stored { .. } => {
+                    core.send_event(MessageEvent::DirectStored {
+                        peer: pending.recipient,
+                        id: pending.message_id,
+                        mailbox: peer,
+                    });
+                }
+                _ => core.next_deposit(pending),
+            }
+        }
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+            ..
+        } => {
+            tracing::debug!("mailbox request to {peer} failed: {error}");
+            let _ = core.mailbox.deliveries.remove(&request_id);
+            if let Some(pending) = core.mailbox.deposits.remove(&request_id) {
+                core.next_deposit(pending);
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound mailbox request from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliveries_stay_below_request_limit() {
+        let held: Vec<MailboxEntry> = (0..40)
+            .map(|i| MailboxEntry {
+                id: format!("{i:064x}"),
+                recipient: PeerId::random().to_string(),
+                sender: PeerId::random().to_string(),
+                sealed: vec![0xab; MAX_SEALED_LEN],
+                stored_at: 0,
+                expires_at: i64::MAX,
+            })
+            .collect();
+        let mut rest = held.as_slice();
+        let mut batches = 0;
+        while !rest.is_empty() {
+            let mail = delivery_batch(rest);
+            assert!(!mail.is_empty());
+            let mut data = Vec::new();
+            ciborium::into_writer(&MailboxRequest::Deliver { mail: mail.clone() }, &mut data)
+                .unwrap();
+            assert!(data.len() < 1024 * 1024);
+            rest = &rest[mail.len()..];
+            batches += 1;
+        }
+        assert!(batches > 1);
+    }
+}
@@ -0,0 +1,392 @@
+//! 预密钥包的发布与查询：`/mychat/prekey/1`
+//!
+//! 节点与对端建立连接时把自己的预密钥包（附少量一次性预密钥）交给对端缓存；
+//! 想与离线节点建立会话时，向在线的节点查询其缓存。每个缓存的一次性预密钥只
+//! 分发一次，不同节点拿到的一次性预密钥互不重叠。缓存按最近使用淘汰；直接向本节点
+//! 查询时限制每个请求方的频率与可领取的一次性预密钥数，超出后只给不含一次性预密钥的包。
+use libp2p::{
+    PeerId, StreamProtocol,
+    request_response::{self, ProtocolSupport, cbor},
+};
+use rootcell::{PreKeyBundle, PublishedBundle};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::{ChatCore, identity};
+
+/// 预密钥协议名
+pub const PREKEY_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/prekey/1");
+
+/// 预密钥请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PreKeyRequest {
+    /// 发布自己的预密钥包，请对端缓存
+    Publish(PublishedBundle),
+    /// 查询某个身份的预密钥包
+    Fetch { identity_key: [u8; 32] },
+}
+
+/// 预密钥回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PreKeyResponse {
+    /// 已缓存
+    Stored,
+    /// 查询结果，没有缓存时为 None
+    Bundle(Option<PreKeyBundle>),
+    /// 拒绝缓存，如签名无效或不是发送方自己的包
+    Rejected { reason: String },
+}
+
+/// 最多缓存的其他节点预密钥包数
+pub(crate) const MAX_CACHED_BUNDLES: usize = 1024;
+
+/// 同一请求方两次查询本节点预密钥包的最短间隔
+pub(crate) const FETCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 最多记录的请求方数
+const MAX_TRACKED_FETCHERS: usize = 1024;
+
+pub type PreKeyBehaviour = cbor::Behaviour<PreKeyRequest, PreKeyResponse>;
+pub type PreKeyEvent = request_response::Event<PreKeyRequest, PreKeyResponse>;
+
+pub(crate) fn behaviour() -> PreKeyBehaviour {
+    cbor::Behaviour::new(
+        [(PREKEY_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// 进行中的查询：依次询问候选节点，直到拿到有效的包
+#[derive(Debug)]
+pub(crate) struct PendingFetch {
+    identity_key: [u8; 32],
+    candidates: Vec<PeerId>,
+    reply: FetchReply,
+}
+
+/// 查询结果的去向
+#[derive(Debug)]
+pub(crate) enum FetchReply {
+    /// 经 [`crate::ChatHandle`] 发起的查询
+    Caller(oneshot::Sender<Option<PreKeyBundle>>),
+    /// 为与该节点建立私聊会话而查询，见 [`crate::session`]
+    Session(PeerId),
+}
+
+/// 按最近使用淘汰的有界映射
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    entries: HashMap<K, V>,
+    ///从最久未用到最近使用
+    order: VecDeque<K>,
+    capacity: usize,
+}
+
+impl<K: Eq + Hash + Copy, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if self.entries.contains_key(key) {
+            self.touch(*key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// 插入或替换，超出容量时淘汰最久未用的
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.touch(key);
+        let _ = self.entries.insert(key, value);
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    let _ = self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn touch(&mut self, key: K) {
+        self.order.retain(|k| *k != key);
+        self.order.push_back(key);
+    }
+}
+
+/// 向本节点查询预密钥包的请求方
+#[derive(Debug)]
+pub(crate) struct Fetcher {
+    last_fetch: Instant,
+    ///已领取的一次性预密钥数
+    one_time_taken: usize,
+}
+
+/// 其他节点的预密钥包缓存与本节点的分发限额
+#[derive(Debug)]
+pub(crate) struct PreKeyCache {
+    ///按身份公钥索引，代为分发
+    bundles: Lru<[u8; 32], PublishedBundle>,
+    fetchers: Lru<PeerId, Fetcher>,
+}
+
+impl Default for PreKeyCache {
+    fn default() -> Self {
+        Self {
+            bundles: Lru::new(MAX_CACHED_BUNDLES),
+            fetchers: Lru::new(MAX_TRACKED_FETCHERS),
+        }
+    }
+}
+
+impl PreKeyCache {
+    /// 记录一次对本节点的查询；过于频繁时返回 None，否则返回可附带的一次性预密钥数
+    fn admit_fetch(&mut self, peer: PeerId, now: Instant) -> Option<usize> {
+        match self.fetchers.get_mut(&peer) {
+            Some(fetcher) if now.duration_since(fetcher.last_fetch) < FETCH_INTERVAL => None,
+            Some(fetcher) => {
+                fetcher.last_fetch = now;
+                if fetcher.one_time_taken < identity::ONE_TIME_PER_PEER {
+                    fetcher.one_time_taken += 1;
+                    Some(1)
+                } else {
+                    Some(0)
+                }
+            }
+            None => {
+                self.fetchers.insert(
+                    peer,
+                    Fetcher {
+                        last_fetch: now,
+                        one_time_taken: 1,
+                    },
+                );
+                Some(1)
+            }
+        }
+    }
+}
+
+impl ChatCore {
+    /// 向刚连接的节点发布自己的预密钥包
+    pub(crate) fn publish_prekeys(&mut self, peer: PeerId) {
+        match self.keys.publish_bundle(identity::ONE_TIME_PER_PEER) {
+            Ok(bundle) => {
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .prekey
+                    .send_request(&peer, PreKeyRequest::Publish(bundle));
+            }
+            Err(e) => tracing::warn!("failed to publish pre-keys to {peer}: {e:?}"),
+        }
+    }
+
+    /// 查询节点的预密钥包，结果经 `reply` 返回
+    pub(crate) fn fetch_prekey_bundle(
+        &mut self,
+        peer: PeerId,
+        reply: oneshot::Sender<Option<PreKeyBundle>>,
+    ) -> anyhow::Result<()> {
+        let identity_key = identity::peer_public_key(&peer)?;
+        self.start_fetch(peer, identity_key, FetchReply::Caller(reply));
+        Ok(())
+    }
+
+    /// 查询节点的预密钥包：先查本地缓存，再依次询问对方本身和其他已连接节点
+    pub(crate) fn start_fetch(&mut self, peer: PeerId, identity_key: [u8; 32], reply: FetchReply) {
+        if let Some(cached) = self.prekey_cache.bundles.get_mut(&identity_key) {
+            let bundle = cached.take_bundle();
+            self.finish_fetch(reply, Some(bundle));
+            return;
+        }
+        // 候选从末尾取出，对方本身排在最后即最先询问
+        let mut candidates: Vec<PeerId> = self
+            .swarm
+            .connected_peers()
+            .filter(|p| **p != peer)
+            .copied()
+            .collect();
+        if self.swarm.is_connected(&peer) {
+            candidates.push(peer);
+        }
+        self.next_fetch(PendingFetch {
+            identity_key,
+            candidates,
+            reply,
+        });
+    }
+
+    fn finish_fetch(&mut self, reply: FetchReply, bundle: Option<PreKeyBundle>) {
+        match reply {
+            FetchReply::Caller(reply) => {
+                let _ = reply.send(bundle);
+            }
+            FetchReply::Session(peer) => self.bundle_fetched(peer, bundle),
+        }
+    }
+
+    fn next_fetch(&mut self, mut pending: PendingFetch) {
+        let Some(candidate) = pending.candidates.pop() else {
+            self.finish_fetch(pending.reply, None);
+            return;
+        };
+        let request_id = self.swarm.behaviour_mut().prekey.send_request(
+            &candidate,
+            PreKeyRequest::Fetch {
+                identity_key: pending.identity_key,
+            },
+        );
+        self.pending_fetches.insert(request_id, pending);
+    }
+
+    fn answer_prekey_request(&mut self, peer: PeerId, request: PreKeyRequest) -> PreKeyResponse {
+        match request {
+            PreKeyRequest::Publish(bundle) => {
+                let owner = identity::peer_public_key(&peer).ok();
+                if owner != Some(bundle.identity_key) {
+                    return PreKeyResponse::Rejected {
+                        reason: "bundle does not belong to sender".to_string(),
+                    };
+                }
+                if let Err(e) = bundle.verify() {
+                    return PreKeyResponse::Rejected {
+                        reason: e.to_string(),
+                    };
+                }
+                self.prekey_cache
+                    .bundles
+                    .insert(bundle.identity_key, bundle);
+                PreKeyResponse::Stored
+            }
+            PreKeyRequest::Fetch { identity_key }
+                if identity_key == self.keys.identity.public_key() =>
+            {
+                let Some(count) = self.prekey_cache.admit_fetch(peer, Instant::now()) else {
+                    return PreKeyResponse::Rejected {
+                        reason: "too many pre-key requests".to_string(),
+                    };
+                };
+                match self.keys.publish_bundle(count) {
+                    Ok(mut bundle) => PreKeyResponse::Bundle(Some(bundle.take_bundle())),
+                    Err(e) => {
+                        tracing::warn!("failed to hand out own pre-key bundle: {e:?}");
+                        PreKeyResponse::Bundle(None)
+                    }
+                }
+            }
+            PreKeyRequest::Fetch { identity_key } => PreKeyResponse::Bundle(
+                self.prekey_cache
+                    .bundles
+                    .get_mut(&identity_key)
+                    .map(PublishedBundle::take_bundle),
+            ),
+        }
+    }
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: PreKeyEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => {
+            let response = core.answer_prekey_request(peer, request);
+            if core
+                .swarm
+                .behaviour_mut()
+                .prekey
+                .send_response(channel, response)
+                .is_err()
+            {
+                tracing::debug!("pre-key response to {peer} dropped, connection closed");
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => {
+            let Some(pending) = core.pending_fetches.remove(&request_id) else {
+                if let PreKeyResponse::Rejected { reason } = response {
+                    tracing::warn!("{peer} rejected our pre-key bundle: {reason}");
+                }
+                return;
+            };
+            match response {
+                PreKeyResponse::Bundle(Some(bundle))
+                    if bundle.identity_key == pending.identity_key && bundle.verify().is_ok() =>
+                {
+                    core.finish_fetch(pending.reply, Some(bundle));
+                }
+                _ => core.next_fetch(pending),
+            }
+        }
+        request_response::Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+            ..
+        } => {
+            tracing::debug!("pre-key request to {peer} failed: {error}");
+            if let Some(pending) = core.pending_fetches.remove(&request_id) {
+                core.next_fetch(pending);
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound pre-key request from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert!(lru.get_mut(&1).is_some());
+        lru.insert(3, "c");
+        assert!(lru.get_mut(&2).is_none());
+        assert!(lru.get_mut(&1).is_some());
+        assert!(lru.get_mut(&3).is_some());
+    }
+
+    #[test]
+    fn fetches_are_rate_limited_and_capped() {
+        let mut cache = PreKeyCache::default();
+        let peer = PeerId::random();
+        let mut now = Instant::now();
+        assert_eq!(cache.admit_fetch(peer, now), Some(1));
+        assert_eq!(cache.admit_fetch(peer, now), None);
+        for _ in 1..identity::ONE_TIME_PER_PEER {
+            now += FETCH_INTERVAL;
+            assert_eq!(cache.admit_fetch(peer, now), Some(1));
+        }
+        // 领完配额后只给不含一次性预密钥的包
+        now += FETCH_INTERVAL;
+        assert_eq!(cache.admit_fetch(peer, now), Some(0));
+    }
+}
@@ -0,0 +1,248 @@
+//! 密钥撤销：签名声明经 [`REVOCATION_TOPIC`] 主题广播
+//!
+//! 所有节点启动时订阅该主题。收到的声明校验签名后写入存储并立即生效：已撤销身份
+//! 发来的房间消息和私聊都被丢弃，并推送 [`MessageEvent::RevokedMessageDropped`]。
+//! 主题只广播新签发的声明；有节点订阅该主题时经 [`REVOCATION_PROTOCOL`] 把本地已知的
+//! 声明直接发给它，让后上线的节点也能收到，而不是让所有节点在主题上重新广播一遍。
+use libp2p::{
+    PeerId, StreamProtocol,
+    gossipsub::{self, IdentTopic, MessageId},
+    request_response::{self, ProtocolSupport, cbor},
+};
+use rootcell::{Revocation, TrustError};
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatCore, MessageEvent, device::unix_now, identity};
+
+/// 广播撤销声明的主题，不作为聊天房间
+pub const REVOCATION_TOPIC: &str = "mychat-revocations";
+
+/// 向新订阅者补发已知声明的协议名
+pub const REVOCATION_PROTOCOL: StreamProtocol = StreamProtocol::new("/mychat/revocation/1");
+
+/// 每次补发的编码后字节数上限，低于请求大小上限（1 MiB）
+const SYNC_BYTES: usize = 512 * 1024;
+
+/// 补发的已知声明
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationSync {
+    pub revocations: Vec<Revocation>,
+}
+
+/// 补发回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RevocationSyncResponse {
+    /// 已处理，`count` 为其中新撤销的密钥数
+    Applied { count: usize },
+}
+
+pub type RevocationBehaviour = cbor::Behaviour<RevocationSync, RevocationSyncResponse>;
+pub type RevocationEvent = request_response::Event<RevocationSync, RevocationSyncResponse>;
+
+pub(crate) fn behaviour() -> RevocationBehaviour {
+    cbor::Behaviour::new(
+        [(REVOCATION_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+impl ChatCore {
+    /// 订阅撤销主题并载入已保存的声明
+    pub(crate) async fn restore_revocations(&mut self) -> anyhow::Result<()> {
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&IdentTopic::new(REVOCATION_TOPIC))?;
+        for revocation in self.storage.revocations().await? {
+            let _ = self.revocations.insert(revocation)?;
+        }
+        Ok(())
+    }
+
+    /// 撤销本节点的身份并广播；其他节点此后丢弃本身份的消息，应随即轮换身份
+    pub fn revoke_identity(&mut self, reason: String) -> anyhow::Result<Revocation> {
+        let revocation = Revocation::revoke_identity(&self.keys.identity, unix_now(), reason);
+        self.issue_revocation(revocation)
+    }
+
+    /// 以用户身份撤销本账户的一台设备并广播；只有持有用户身份的设备可以撤销
+    pub fn revoke_device(
+        &mut self,
+        device_key: [u8; 32],
+        reason: String,
+    ) -> anyhow::Result<Revocation> {
+        let user = self.keys.user.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("this device joined another account, revoke from the issuing device")
+        })?;
+        let revocation = Revocation::revoke_device(user, device_key, unix_now(), reason);
+        self.issue_revocation(revocation)
+    }
+
+    fn issue_revocation(&mut self, revocation: Revocation) -> anyhow::Result<Revocation> {
+        if !self.apply_revocation(revocation.clone())? {
+            anyhow::bail!("key is already revoked");
+        }
+        self.publish_revocation(&revocation)?;
+        Ok(revocation)
+    }
+
+    /// 节点身份、其设备证书或所属用户已撤销时返回 `KeyRevoked`
+    pub(crate) fn check_peer(&self, peer: &PeerId) -> Result<(), TrustError> {
+        // 不内嵌公钥的 PeerId 对应不到身份，也无法建立加密会话
+        let Ok(key) = identity::peer_public_key(peer) else {
+            return Ok(());
+        };
+        self.revocations.check_identity(&key)?;
+        match self.user_of(&key) {
+            Some(user) => self.revocations.check_device(&user, &key),
+            None => Ok(()),
+        }
+    }
+
+    /// 校验并应用声明，返回是否是新撤销的密钥
+    fn apply_revocation(&mut self, revocation: Revocation) -> anyhow::Result<bool> {
+        if !self.revocations.insert(revocation.clone())? {
+            return Ok(false);
+        }
+        let peer = identity::peer_id(&revocation.revoked_key)?;
+        if self.check_peer(&peer).is_err() {
+            self.reset_session(&peer);
+            self.forget_history_session(&peer);
+            self.forget_member(peer);
+        }
+        tracing::warn!(
+            "{peer} revoked ({:?}): {}",
+            revocation.kind,
+            revocation.reason
+        );
+        let storage = self.storage.clone();
+        let stored = revocation.clone();
+        tokio::spawn(async move {
+            if let Err(e) = storage.insert_revocation(&stored).await {
+                tracing::warn!("failed to store revocation: {e:?}");
+            }
+        });
+        self.send_event(MessageEvent::KeyRevoked {
+            peer,
+            kind: revocation.kind,
+            reason: revocation.reason,
+        });
+        Ok(true)
+    }
+
+    /// 在主题上广播新签发的声明；没有订阅者时只记日志，之后订阅的节点经补发收到
+    fn publish_revocation(&mut self, revocation: &Revocation) -> anyhow::Result<()> {
+        let mut data = Vec::new();
+        ciborium::into_writer(revocation, &mut data)?;
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(IdentTopic::new(REVOCATION_TOPIC), data)
+        {
+            tracing::debug!("revocation not published: {e}");
+        }
+        Ok(())
+    }
+}
+
+/// 处理撤销主题上的消息，声明本身带签名，转发者是谁无关紧要
+pub(crate) fn handle_message(core: &mut ChatCore, id: MessageId, message: gossipsub::Message) {
+    let result = ciborium::from_reader::<Revocation, _>(message.data.as_slice())
+        .map_err(anyhow::Error::from)
+        .and_then(|revocation| core.apply_revocation(revocation));
+    if let Err(e) = result {
+        tracing::warn!("rejected revocation {id}: {e}");
+    }
+}
+
+/// 丢弃已撤销身份的消息并推送警告
+pub(crate) fn drop_message(core: &ChatCore, peer: PeerId) {
+    let Err(e) = core.check_peer(&peer) else {
+        return;
+    };
+    tracing::warn!("dropped message from revoked peer {peer}: {e}");
+    core.send_event(MessageEvent::RevokedMessageDropped {
+        peer,
+        reason: e.to_string(),
+    });
+}
+
+/// 节点订阅撤销主题时把已知的声明分批直接发给它
+pub(crate) fn peer_subscribed(core: &mut ChatCore, peer: PeerId) -> anyhow::Result<()> {
+    let mut batch = Vec::new();
+    let mut size = 0;
+    let known: Vec<_> = core.revocations.iter().cloned().collect();
+    for revocation in known {
+        let mut data = Vec::new();
+        ciborium::into_writer(&revocation, &mut data)?;
+        if !batch.is_empty() && size + data.len() > SYNC_BYTES {
+            send_sync(core, peer, std::mem::take(&mut batch));
+            size = 0;
+        }
+        size += data.len();
+        batch.push(revocation);
+    }
+    if !batch.is_empty() {
+        send_sync(core, peer, batch);
+    }
+    Ok(())
+}
+
+fn send_sync(core: &mut ChatCore, peer: PeerId, revocations: Vec<Revocation>) {
+    let _ = core
+        .swarm
+        .behaviour_mut()
+        .revocation
+        .send_request(&peer, RevocationSync { revocations });
+}
+
+pub(crate) fn handle_event(core: &mut ChatCore, event: RevocationEvent) {
+    match event {
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request, channel, ..
+                },
+            ..
+        } => {
+            let mut count = 0;
+            for revocation in request.revocations {
+                match core.apply_revocation(revocation) {
+                    Ok(true) => count += 1,
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!("rejected revocation from {peer}: {e}"),
+                }
+            }
+            if core
+                .swarm
+                .behaviour_mut()
+                .revocation
+                .send_response(channel, RevocationSyncResponse::Applied { count })
+                .is_err()
+            {
+                tracing::debug!("revocation sync response to {peer} dropped, connection closed");
+            }
+        }
+        request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Response {
+                    response: RevocationSyncResponse::Applied { count },
+                    ..
+                },
+            ..
+        } => {
+            tracing::debug!("{peer} learned {count} revocations from us");
+        }
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            tracing::debug!("revocation sync to {peer} failed: {error}");
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("inbound revocation sync from {peer} failed: {error}");
+        }
+        request_response::Event::ResponseSent { .. } => {}
+    }
+}
@@ -0,0 +1,75 @@
+//! 聊天房间：每个房间对应一个 gossipsub 主题，加入状态保存在存储中
+use libp2p::gossipsub::IdentTopic;
+
+use crate::{ChatCore, MessageEvent, device::DEVICE_TOPIC, revocation::REVOCATION_TOPIC};
+
+/// 核心内部使用的主题，不能作为房间加入或离开
+const RESERVED_TOPICS: [&str; 2] = [REVOCATION_TOPIC, DEVICE_TOPIC];
+
+fn check_room_name(name: &str) -> anyhow::Result<()> {
+    if RESERVED_TOPICS.contains(&name) {
+        anyhow::bail!("{name} is reserved and cannot be used as a room");
+    }
+    Ok(())
+}
+
+impl ChatCore {
+    /// 加入房间并订阅对应主题，已加入时返回 false
+    pub async fn join_room(&mut self, name: &str) -> anyhow::Result<bool> {
+        check_room_name(name)?;
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&IdentTopic::new(name))?;
+        self.storage.join_topic(name).await?;
+        let joined = self.rooms.insert(name.to_string());
+        if joined {
+            self.start_group(name)?;
+            self.send_event(MessageEvent::RoomJoined(name.to_string()));
+        }
+        Ok(joined)
+    }
+
+    /// 离开房间并取消订阅，未加入时返回 false；历史消息保留
+    pub async fn leave_room(&mut self, name: &str) -> anyhow::Result<bool> {
+        check_room_name(name)?;
+        if !self.rooms.remove(name) {
+            return Ok(false);
+        }
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .unsubscribe(&IdentTopic::new(name));
+        self.storage.leave_topic(name).await?;
+        self.end_group(name);
+        self.send_event(MessageEvent::RoomLeft(name.to_string()));
+        Ok(true)
+    }
+
+    /// 已加入的房间，按名称排序
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms.iter().cloned().collect()
+    }
+
+    pub fn is_joined(&self, room: &str) -> bool {
+        self.rooms.contains(room)
+    }
+
+    /// 启动时恢复上次加入的房间，只在首次启动时加入默认房间
+    ///
+    /// 之后即使离开了所有房间，重启也不会再自动加入
+    pub(crate) async fn restore_rooms(&mut self) -> anyhow::Result<()> {
+        let saved = self.storage.joined_topics().await?;
+        if self.storage.begin_room_setup().await? && saved.is_empty() {
+            self.join_room(crate::DEFAULT_TOPIC).await?;
+        }
+        for room in saved {
+            if let Err(e) = check_room_name(&room.name) {
+                tracing::warn!("skipped saved room: {e}");
+                continue;
+            }
+            self.join_room(&room.name).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! 私聊的棘轮会话
+//!
+//! 首次向节点发送私聊时先取得其预密钥包（见 [`crate::prekey`]），用 X3DH 建立
+//! [`Ratchet`]；收到对方回复前，每条消息都附带协商信息，接收方据此消耗一次性预密钥
+//! 并建立对应的会话，因此对方不在线时也能建立会话。双方同时发起时各自保留多个会话，
+//! 解密成功的会话提到最前面，之后都用它发送。会话状态加密后写入数据库，重启后继续使用。
+use libp2p::PeerId;
+use rootcell::{
+    PreKeyBundle, Ratchet, SecretBox,
+    prekey::{self, X3dhHeader},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+
+use std::{collections::HashMap, fmt};
+
+use crate::{ChatCore, identity, prekey::FetchReply, storage::Storage, wire::WireMessage};
+
+/// 每个节点最多保留的会话数
+const MAX_SESSIONS: usize = 3;
+
+/// 私聊请求中的密文，首条消息附带 X3DH 协商信息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SealedDirect {
+    x3dh: Option<X3dhHeader>,
+    #[serde(with = "serde_bytes")]
+    ciphertext: Vec<u8>,
+}
+
+/// 与对方没有可用的会话，如对方丢失了会话状态
+#[derive(Debug)]
+pub(crate) struct NoSession(pub PeerId);
+
+impl fmt::Display for NoSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no ratchet session with {}", self.0)
+    }
+}
+
+impl std::error::Error for NoSession {}
+
+/// 一个棘轮会话
+struct RatchetSession {
+    ratchet: Ratchet,
+    ///关联数据：发起方与接收方的身份公钥
+    ad: Vec<u8>,
+    ///协商时发起方的临时公钥，标识这个会话
+    ephemeral: [u8; 32],
+    ///本方发起且尚未收到回复时随消息附带的协商信息
+    x3dh: Option<X3dhHeader>,
+}
+
+/// 持久化的会话，棘轮状态单独加密
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    #[serde(with = "serde_bytes")]
+    state: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    ad: Vec<u8>,
+    ephemeral: [u8; 32],
+    x3dh: Option<X3dhHeader>,
+}
+
+/// 等待发出的私聊
+#[derive(Debug, Clone)]
+pub(crate) struct Queued {
+    pub message: WireMessage,
+    ///是否等待回执并通知前端，发送者密钥等不需要
+    pub tracked: bool,
+    ///对方丢失会话后是否已重新协商并重发过
+    pub retried: bool,
+}
+
+impl Queued {
+    pub(crate) fn new(message: WireMessage, tracked: bool) -> Self {
+        Self {
+            message,
+            tracked,
+            retried: false,
+        }
+    }
+}
+
+enum SessionWrite {
+    Save(PeerId, Vec<u8>),
+    Delete(PeerId),
+}
+
+/// 各节点的棘轮会话与等待建立会话的私聊
+pub(crate) struct Sessions {
+    ///按最近成功解密排序，第一个用于发送
+    peers: HashMap<PeerId, Vec<RatchetSession>>,
+    ///正在查询预密钥包的节点及排队的私聊
+    waiting: HashMap<PeerId, Vec<Queued>>,
+    ///加密持久化状态的密钥，临时身份时为 None，会话只保存在内存中
+    key: Option<SecretBox>,
+    writes: mpsc::UnboundedSender<SessionWrite>,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("peers", &self.peers.len())
+            .field("waiting", &self.waiting.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new(storage: Storage, key: Option<SecretBox>) -> Self {
+        let (writes, mut rx) = mpsc::unbounded_channel::<SessionWrite>();
+        tokio::spawn(async move {
+            while let Some(write) = rx.recv().await {
+                let result = match write {
+                    SessionWrite::Save(peer, sessions) => {
+                        storage.save_sessions(&peer.to_string(), &sessions).await
+                    }
+                    SessionWrite::Delete(peer) => storage.delete_sessions(&peer.to_string()).await,
+                };
+                if let Err(e) = result {
+                    tracing::warn!("failed to store ratchet sessions: {e:?}");
+                }
+            }
+        });
+        Self {
+            peers: HashMap::new(),
+            waiting: HashMap::new(),
+            key,
+            writes,
+        }
+    }
+
+    /// 把节点的会话列表加密后交给后台写入
+    fn persist(&self, peer: PeerId) {
+        let Some(key) = &self.key else {
+            return;
+        };
+        let Some(sessions) = self.peers.get(&peer) else {
+            let _ = self.writes.send(SessionWrite::Delete(peer));
+            return;
+        };
+        let stored = sessions
+            .iter()
+            .map(|session| {
+                Ok(StoredSession {
+                    state: session.ratchet.seal_state(key.expose())?,
+                    ad: session.ad.clone(),
+                    ephemeral: session.ephemeral,
+                    x3dh: session.x3dh,
+                })
+            })
+            .collect::<Result<Vec<_>, rootcell::TrustError>>()
+            .map_err(anyhow::Error::from)
+            .and_then(|stored| {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&stored, &mut bytes)?;
+                Ok(bytes)
+            });
+        match stored {
+            Ok(bytes) => {
+                let _ = self.writes.send(SessionWrite::Save(peer, bytes));
+            }
+            Err(e) => tracing::warn!("failed to persist ratchet sessions with {peer}: {e}"),
+        }
+    }
+
+    /// 在列表最前面加入新会话，超出上限时丢弃最旧的
+    fn insert(&mut self, peer: PeerId, session: RatchetSession) {
+        let sessions = self.peers.entry(peer).or_default();
+        sessions.insert(0, session);
+        sessions.truncate(MAX_SESSIONS);
+        self.persist(peer);
+    }
+
+    /// 丢弃与节点的全部会话
+    pub(crate) fn remove(&mut self, peer: &PeerId) {
+        if self.peers.remove(peer).is_some() {
+            self.persist(*peer);
+        }
+    }
+}
+
+impl ChatCore {
+    /// 载入持久化的会话
+    pub(crate) async fn restore_sessions(&mut self) -> anyhow::Result<()> {
+        let Some(key) = &self.sessions.key else {
+            return Ok(());
+        };
+        for (peer_id, bytes) in self.storage.sessions().await? {
+            let restored = peer_id
+                .parse::<PeerId>()
+                .map_err(anyhow::Error::from)
+                .and_then(|peer| {
+                    let stored: Vec<StoredSession> = ciborium::from_reader(bytes.as_slice())?;
+                    let sessions = stored
+                        .into_iter()
+                        .map(|s| {
+                            Ok(RatchetSession {
+                                ratchet: Ratchet::open_state(&s.state, key.expose())?,
+                                ad: s.ad,
+                                ephemeral: s.ephemeral,
+                                x3dh: s.x3dh,
+                            })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    Ok((peer, sessions))
+                });
+            match restored {
+                Ok((peer, sessions)) => {
+                    let _ = self.sessions.peers.insert(peer, sessions);
+                }
+                Err(e) => tracing::warn!("skipped ratchet sessions with {peer_id}: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_session(&self, peer: &PeerId) -> bool {
+        self.sessions.peers.contains_key(peer)
+    }
+
+    /// 排队等待建立会话，首条排队的私聊触发预密钥包查询
+    pub(crate) fn queue_direct(&mut self, peer: PeerId, queued: Queued) -> anyhow::Result<()> {
+        let identity_key = identity::peer_public_key(&peer)?;
+        match self.sessions.waiting.get_mut(&peer) {
+            Some(waiting) => waiting.push(queued),
+            None => {
+                let _ = self.sessions.waiting.insert(peer, vec![queued]);
+                self.start_fetch(peer, identity_key, FetchReply::Session(peer));
+            }
+        }
+        Ok(())
+    }
+
+    /// 用与节点的当前会话加密
+    pub(crate) fn seal_direct(
+        &mut self,
+        peer: PeerId,
+        plaintext: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let session = self
+            .sessions
+            .peers
+            .get_mut(&peer)
+            .and_then(|sessions| sessions.first_mut())
+            .ok_or(NoSession(peer))?;
+        let sealed = SealedDirect {
+            x3dh: session.x3dh,
+            ciphertext: session.ratchet.encrypt(plaintext, &session.ad)?,
+        };
+        self.sessions.persist(peer);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&sealed, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// 解密节点发来的私聊：带协商信息时按需建立会话，否则依次尝试已有会话
+    pub(crate) fn open_direct_sealed(
+        &mut self,
+        peer: PeerId,
+        sealed: &[u8],
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let sealed: SealedDirect = ciborium::from_reader(sealed)?;
+        let existing = self.sessions.peers.get(&peer).and_then(|sessions| {
+            let x3dh = sealed.x3dh.as_ref()?;
+            sessions
+                .iter()
+                .position(|s| s.ephemeral == x3dh.ephemeral_key)
+        });
+        if let (Some(x3dh), None) = (&sealed.x3dh, existing) {
+            return self.accept_direct(peer, x3dh, &sealed.ciphertext);
+        }
+        let sessions = self.sessions.peers.get_mut(&peer).ok_or(NoSession(peer))?;
+        let candidates = existing.map_or_else(|| (0..sessions.len()).collect(), |i| vec![i]);
+        for i in candidates {
+            let Some(session) = sessions.get_mut(i) else {
+                continue;
+            };
+            if let Ok(plaintext) = session.ratchet.decrypt(&sealed.ciphertext, &session.ad) {
+                // 对方已建立会话，之后不再附带协商信息
+                session.x3dh = None;
+                let session = sessions.remove(i);
+                sessions.insert(0, session);
+                self.sessions.persist(peer);
+                return Ok(plaintext);
+            }
+        }
+        Err(NoSession(peer).into())
+    }
+
+    /// 接受对方的 X3DH 协商，解密成功后才保留新会话
+    fn accept_direct(
+        &mut self,
+        peer: PeerId,
+        x3dh: &X3dhHeader,
+        ciphertext: &[u8],
+    ) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        if x3dh.identity_key != identity::peer_public_key(&peer)? {
+            anyhow::bail!("key agreement from {peer} names another identity");
+        }
+        let mut ratchet = self.keys.accept_session(x3dh)?;
+        let ad = prekey::associated_data(&x3dh.identity_key, &self.keys.identity.public_key());
+        let plaintext = ratchet.decrypt(ciphertext, &ad)?;
+        self.sessions.insert(
+            peer,
+            RatchetSession {
+                ratchet,
+                ad,
+                ephemeral: x3dh.ephemeral_key,
+                x3dh: None,
+            },
+        );
+        Ok(plaintext)
+    }
+
+    /// 预密钥包查询结束：建立会话并发出排队的私聊，查不到时全部失败
+    pub(crate) fn bundle_fetched(&mut self, peer: PeerId, bundle: Option<PreKeyBundle>) {
+        let queued = self.sessions.waiting.remove(&peer).unwrap_or_default();
+        match self.initiate_session(peer, bundle) {
+            Ok(()) => {
+                for queued in queued {
+                    if let Err(e) = self.send_queued(peer, queued.clone()) {
+                        self.direct_failed(peer, &queued, e.to_string());
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!("failed to establish session with {peer}: {e}");
+                for queued in queued {
+                    self.direct_failed(peer, &queued, e.to_string());
+                }
+            }
+        }
+    }
+
+    /// 对预密钥包执行 X3DH，作为当前会话
+    fn initiate_session(
+        &mut self,
+        peer: PeerId,
+        bundle: Option<PreKeyBundle>,
+    ) -> anyhow::Result<()> {
+        let bundle =
+            bundle.ok_or_else(|| anyhow::anyhow!("no pre-key bundle available for {peer}"))?;
+        if bundle.identity_key != identity::peer_public_key(&peer)? {
+            anyhow::bail!("pre-key bundle does not belong to {peer}");
+        }
+        let (x3dh, ratchet) = prekey::initiate(&self.keys.identity, &bundle)?;
+        self.sessions.insert(
+            peer,
+            RatchetSession {
+                ratchet,
+                ad: prekey::associated_data(&x3dh.identity_key, &bundle.identity_key),
+                ephemeral: x3dh.ephemeral_key,
+                x3dh: Some(x3dh),
+            },
+        );
+        Ok(())
+    }
+
+    /// 丢弃与节点的会话，下一条私聊重新协商
+    pub(crate) fn reset_session(&mut self, peer: &PeerId) {
+        self.sessions.remove(peer);
+    }
+}
@@ -0,0 +1,55 @@
+//! 联系人身份公钥与核验状态的持久化
+use sqlx::Row;
+
+use super::{Storage, now_millis};
+
+/// 记录的联系人身份
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactIdentity {
+    pub peer_id: String,
+    ///联系人的用户身份公钥，没有设备证书时为设备公钥
+    pub identity_key: [u8; 32],
+    ///用户已带外比对过当前公钥的安全码
+    pub verified: bool,
+}
+
+impl Storage {
+    /// 保存联系人身份，覆盖已有记录
+    pub async fn save_contact_identity(&self, contact: &ContactIdentity) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO contact_identities (peer_id, identity_key, verified, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer_id) DO UPDATE
+             SET identity_key = excluded.identity_key, verified = excluded.verified,
+                 updated_at = excluded.updated_at",
+        )
+        .bind(&contact.peer_id)
+        .bind(contact.identity_key.as_slice())
+        .bind(contact.verified)
+        .bind(now_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// 全部联系人身份
+    pub async fn contact_identities(&self) -> anyhow::Result<Vec<ContactIdentity>> {
+        let rows = sqlx::query(
+            "SELECT peer_id, identity_key, verified FROM contact_identities ORDER BY peer_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                let key: Vec<u8> = row.try_get("identity_key")?;
+                Ok(ContactIdentity {
+                    peer_id: row.try_get("peer_id")?,
+                    identity_key: key.try_into().map_err(|_| {
+                        anyhow::anyhow!("malformed identity_key in contact_identities")
+                    })?,
+                    verified: row.try_get("verified")?,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,64 @@
+//! 设备证书的持久化
+//!
+//! 签名与有效期由调用方在载入后校验，这里只负责按设备保留最新签发的证书。
+use rootcell::DeviceCertificate;
+use sqlx::Row;
+
+use super::{Storage, now_millis};
+
+fn certificate_from_row(row: &sqlx::sqlite::SqliteRow) -> anyhow::Result<DeviceCertificate> {
+    let key = |column: &str| -> anyhow::Result<[u8; 32]> {
+        let bytes: Vec<u8> = row.try_get(column)?;
+        bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("malformed {column} in device_certificates"))
+    };
+    Ok(DeviceCertificate {
+        user_key: key("user_key")?,
+        device_key: key("device_key")?,
+        device_name: row.try_get("device_name")?,
+        issued_at: u64::try_from(row.try_get::<i64, _>("issued_at")?)?,
+        expires_at: u64::try_from(row.try_get::<i64, _>("expires_at")?)?,
+        signature: row.try_get("signature")?,
+    })
+}
+
+impl Storage {
+    /// 保存设备证书；同一设备已有更新或相同时间签发的证书时忽略并返回 false
+    pub async fn upsert_device_certificate(
+        &self,
+        certificate: &DeviceCertificate,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO device_certificates
+                (device_key, user_key, device_name, issued_at, expires_at, signature, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (device_key) DO UPDATE
+             SET user_key = excluded.user_key, device_name = excluded.device_name,
+                 issued_at = excluded.issued_at, expires_at = excluded.expires_at,
+                 signature = excluded.signature, received_at = excluded.received_at
+             WHERE excluded.issued_at > device_certificates.issued_at",
+        )
+        .bind(certificate.device_key.as_slice())
+        .bind(certificate.user_key.as_slice())
+        .bind(&certificate.device_name)
+        .bind(i64::try_from(certificate.issued_at)?)
+        .bind(i64::try_from(certificate.expires_at)?)
+        .bind(certificate.signature.as_slice())
+        .bind(now_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 全部已保存的设备证书，未校验签名
+    pub async fn device_certificates(&self) -> anyhow::Result<Vec<DeviceCertificate>> {
+        let rows = sqlx::query(
+            "SELECT device_key, user_key, device_name, issued_at, expires_at, signature
+             FROM device_certificates ORDER BY user_key, issued_at",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(certificate_from_row).collect()
+    }
+}
@@ -0,0 +1,146 @@
+//! 消息正文静态加密
+//!
+//! 正文用 rootcell 派生的 [`DatabaseKey`] 加密，消息 id 作为关联数据；会话、作者、
+//! 时间等列保持明文以便查询。数据库记录当前密钥代数与校验值，解锁时先校验，
+//! 主密钥不对时直接返回 [`rootcell::TrustError::WrongKey`]。
+use rootcell::{DatabaseKey, TrustError};
+use sqlx::Row;
+
+use super::Storage;
+
+/// 密钥代数在 sqlite 中以 INTEGER 保存
+pub(super) fn generation_column(key: &DatabaseKey) -> anyhow::Result<i64> {
+    Ok(i64::try_from(key.generation())?)
+}
+
+/// 解密一行消息正文，`generation` 为 None 时是明文
+pub(super) fn open_body(
+    key: Option<&DatabaseKey>,
+    message_id: &str,
+    body: Vec<u8>,
+    generation: Option<i64>,
+) -> anyhow::Result<Vec<u8>> {
+    if generation.is_none() {
+        return Ok(body);
+    }
+    let key =
+        key.ok_or_else(|| anyhow::anyhow!("message bodies are encrypted, database is locked"))?;
+    Ok(key.open(message_id.as_bytes(), &body)?.to_vec())
+}
+
+impl Storage {
+    /// 数据库当前使用的密钥代数，从未启用加密时为 None
+    pub async fn key_generation(&self) -> anyhow::Result<Option<u64>> {
+        let generation: Option<i64> =
+            sqlx::query_scalar("SELECT generation FROM database_key WHERE id = 1")
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(generation.map(u64::try_from).transpose()?)
+    }
+
+    /// 用数据库密钥解锁：校验密钥，并加密启用前写入的明文正文
+    ///
+    /// 密钥代数或主密钥不对时返回 `TrustError::WrongKey`，数据不受影响
+    pub async fn unlock(&self, key: DatabaseKey) -> anyhow::Result<()> {
+        let mut cipher = self.cipher.write().await;
+        let mut tx = self.pool.begin().await?;
+        let saved = sqlx::query("SELECT generation, check_value FROM database_key WHERE id = 1")
+            .fetch_optional(&mut *tx)
+            .await?;
+        match saved {
+            Some(row) => {
+                let generation: i64 = row.try_get("generation")?;
+                if generation != generation_column(&key)? {
+                    return Err(TrustError::WrongKey(format!(
+                        "database uses key generation {generation}, got {}",
+                        key.generation()
+                    ))
+                    .into());
+                }
+                key.verify(row.try_get("check_value")?)?;
+            }
+            None => {
+                sqlx::query(
+                    "INSERT INTO database_key (id, generation, check_value) VALUES (1, ?1, ?2)",
+                )
+                .bind(generation_column(&key)?)
+                .bind(key.check_value()?.as_slice())
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        let plaintext_rows =
+            sqlx::query("SELECT id, message_id, body FROM messages WHERE key_generation IS NULL")
+                .fetch_all(&mut *tx)
+                .await?;
+        for row in &plaintext_rows {
+            let message_id: String = row.try_get("message_id")?;
+            let body: Vec<u8> = row.try_get("body")?;
+            sqlx::query("UPDATE messages SET body = ?1, key_generation = ?2 WHERE id = ?3")
+                .bind(key.seal(message_id.as_bytes(), &body)?)
+                .bind(generation_column(&key)?)
+                .bind(row.try_get::<i64, _>("id")?)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        if !plaintext_rows.is_empty() {
+            tracing::info!("encrypted {} existing messages", plaintext_rows.len());
+        }
+        *cipher = Some(key);
+        Ok(())
+    }
+
+    /// 轮换数据库密钥：用新密钥重新加密全部正文，整体在一个事务中完成
+    pub async fn rotate_key(&self, new: DatabaseKey) -> anyhow::Result<()> {
+        let mut cipher = self.cipher.write().await;
+        let old = cipher
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("database is locked, unlock it before rotating"))?;
+        if new.generation() <= old.generation() {
+            anyhow::bail!(
+                "new key generation {} must be newer than {}",
+                new.generation(),
+                old.generation()
+            );
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query("SELECT id, message_id, body, key_generation FROM messages")
+            .fetch_all(&mut *tx)
+            .await?;
+        for row in &rows {
+            let message_id: String = row.try_get("message_id")?;
+            let body = open_body(
+                Some(old),
+                &message_id,
+                row.try_get("body")?,
+                row.try_get("key_generation")?,
+            )?;
+            sqlx::query("UPDATE messages SET body = ?1, key_generation = ?2 WHERE id = ?3")
+                .bind(new.seal(message_id.as_bytes(), &body)?)
+                .bind(generation_column(&new)?)
+                .bind(row.try_get::<i64, _>("id")?)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "INSERT INTO database_key (id, generation, check_value) VALUES (1, ?1, ?2)
+             ON CONFLICT (id) DO UPDATE
+             SET generation = excluded.generation, check_value = excluded.check_value",
+        )
+        .bind(generation_column(&new)?)
+        .bind(new.check_value()?.as_slice())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        tracing::info!(
+            "re-encrypted {} messages with database key generation {}",
+            rows.len(),
+            new.generation()
+        );
+        *cipher = Some(new);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! 信箱代存信封的持久化
+//!
+//! 信封已端到端加密，按原样保存；过期的信封在载入前清除。
+use sqlx::Row;
+
+use super::Storage;
+
+/// 为离线收件人代存的一个信封
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxEntry {
+    ///信封内容的 SHA-256，重复代存时去重
+    pub id: String,
+    pub recipient: String,
+    pub sender: String,
+    ///发件人与收件人之间加密会话的密文
+    pub sealed: Vec<u8>,
+    ///存入时间（unix 毫秒）
+    pub stored_at: i64,
+    ///过期时间（unix 毫秒）
+    pub expires_at: i64,
+}
+
+impl Storage {
+    /// 保存代存的信封，相同 id 已存在时忽略并返回 false
+    pub async fn insert_mail(&self, mail: &MailboxEntry) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO mailbox (id, recipient, sender, sealed, stored_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .bind(&mail.id)
+        .bind(&mail.recipient)
+        .bind(&mail.sender)
+        .bind(mail.sealed.as_slice())
+        .bind(mail.stored_at)
+        .bind(mail.expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 删除已交付或已过期的信封，返回删除数量
+    pub async fn delete_mail(&self, ids: &[String]) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut deleted = 0;
+        for id in ids {
+            deleted += sqlx::query("DELETE FROM mailbox WHERE id = ?1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// 清除在 `now`（unix 毫秒）之前过期的信封，返回清除数量
+    pub async fn purge_expired_mail(&self, now: i64) -> anyhow::Result<u64> {
+        let result = sqlx::query("DELETE FROM mailbox WHERE expires_at <= ?1")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// 全部代存的信封，按存入顺序
+    pub async fn mail(&self) -> anyhow::Result<Vec<MailboxEntry>> {
+        let rows = sqlx::query(
+            "SELECT id, recipient, sender, sealed, stored_at, expires_at
+             FROM mailbox ORDER BY stored_at, id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(MailboxEntry {
+                    id: row.try_get("id")?,
+                    recipient: row.try_get("recipient")?,
+                    sender: row.try_get("sender")?,
+                    sealed: row.try_get("sealed")?,
+                    stored_at: row.try_get("stored_at")?,
+                    expires_at: row.try_get("expires_at")?,
+                })
+            })
+            .collect()
+    }
+}
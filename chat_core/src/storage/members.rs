@@ -0,0 +1,40 @@
+//! 房间成员的持久化
+use sqlx::Row;
+
+use super::{Storage, now_millis};
+
+impl Storage {
+    /// 把节点加入房间成员，已是成员时返回 false
+    pub async fn add_member(&self, room: &str, peer_id: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO room_members (room, peer_id, added_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (room, peer_id) DO NOTHING",
+        )
+        .bind(room)
+        .bind(peer_id)
+        .bind(now_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 把节点移出房间成员，不是成员时返回 false
+    pub async fn remove_member(&self, room: &str, peer_id: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM room_members WHERE room = ?1 AND peer_id = ?2")
+            .bind(room)
+            .bind(peer_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 全部房间成员，返回（房间，PeerId），按房间与加入时间排列
+    pub async fn members(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let rows = sqlx::query("SELECT room, peer_id FROM room_members ORDER BY room, added_at")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("room")?, row.try_get("peer_id")?)))
+            .collect()
+    }
+}
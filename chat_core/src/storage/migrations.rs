@@ -0,0 +1,267 @@
+//! 内嵌的版本化数据库迁移
+//!
+//! 每个迁移只执行一次，已执行的版本记录在 `schema_version` 表中。
+//! 新增迁移时只能在 [`MIGRATIONS`] 末尾追加，已发布的迁移不得修改。
+use sqlx::SqlitePool;
+
+use std::path::{Path, PathBuf};
+
+use super::now_millis;
+
+/// 单个迁移步骤
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+    ///会删除或改写已有数据，执行前先备份数据库文件
+    pub destructive: bool,
+}
+
+/// 按版本号升序排列的全部迁移
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: include_str!("migrations/0001_initial.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 2,
+        description: "message kind and reply target",
+        sql: include_str!("migrations/0002_message_envelope.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 3,
+        description: "direct message delivery receipts",
+        sql: include_str!("migrations/0003_direct_delivery.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 4,
+        description: "encrypted message bodies",
+        sql: include_str!("migrations/0004_encrypted_bodies.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 5,
+        description: "key revocations",
+        sql: include_str!("migrations/0005_revocations.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 6,
+        description: "device certificates",
+        sql: include_str!("migrations/0006_device_certificates.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 7,
+        description: "contact identities",
+        sql: include_str!("migrations/0007_contact_identities.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 8,
+        description: "store-and-forward mailbox",
+        sql: include_str!("migrations/0008_mailbox.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 9,
+        description: "message signatures",
+        sql: include_str!("migrations/0009_message_signatures.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 10,
+        description: "direct ratchet sessions",
+        sql: include_str!("migrations/0010_direct_sessions.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 11,
+        description: "room members",
+        sql: include_str!("migrations/0011_room_members.sql"),
+        destructive: false,
+    },
+    Migration {
+        version: 12,
+        description: "first-run room setup",
+        sql: include_str!("migrations/0012_room_setup.sql"),
+        destructive: false,
+    },
+];
+
+/// 当前程序支持的最新 schema 版本
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// 读取数据库当前 schema 版本，未做过迁移的库为 0
+pub(crate) async fn current_version(pool: &SqlitePool) -> anyhow::Result<i64> {
+    sqlx::raw_sql(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version     INTEGER PRIMARY KEY,
+            description TEXT    NOT NULL,
+            applied_at  INTEGER NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// 执行所有未应用的迁移，返回迁移后的版本
+///
+/// `db_file` 为数据库文件路径（内存库为 None），用于破坏性迁移前的备份
+pub(crate) async fn run(
+    pool: &SqlitePool,
+    migrations: &[Migration],
+    db_file: Option<&Path>,
+) -> anyhow::Result<i64> {
+    let current = current_version(pool).await?;
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if current > latest {
+        anyhow::bail!(
+            "database schema version {current} is newer than supported version {latest}, please upgrade the app"
+        );
+    }
+
+    let pending: Vec<&Migration> = migrations.iter().filter(|m| m.version > current).collect();
+    if current > 0
+        && pending.iter().any(|m| m.destructive)
+        && let Some(file) = db_file
+    {
+        let backup = backup(pool, file, current).await?;
+        tracing::info!("database backed up to {}", backup.display());
+    }
+
+    for migration in pending {
+        let mut tx = pool.begin().await?;
+        sqlx::raw_sql(migration.sql).execute(&mut *tx).await?;
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(now_millis())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        tracing::info!(
+            "applied migration {} ({})",
+            migration.version,
+            migration.description
+        );
+    }
+    Ok(latest.max(current))
+}
+
+/// 用 `VACUUM INTO` 生成一致的数据库副本，WAL 模式下也安全
+async fn backup(pool: &SqlitePool, file: &Path, version: i64) -> anyhow::Result<PathBuf> {
+    let mut name = file.as_os_str().to_owned();
+    name.push(format!(".v{version}-{}.bak", now_millis()));
+    let backup = PathBuf::from(name);
+    sqlx::query("VACUUM INTO ?1")
+        .bind(backup.to_string_lossy().into_owned())
+        .execute(pool)
+        .await?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+    use std::str::FromStr;
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upgrades_from_every_historic_version() {
+        for from in 0..=latest_version() {
+            let pool = memory_pool().await;
+            let historic: Vec<Migration> = MIGRATIONS
+                .iter()
+                .filter(|m| m.version <= from)
+                .copied()
+                .collect();
+            assert_eq!(run(&pool, &historic, None).await.unwrap(), from);
+            assert_eq!(
+                run(&pool, MIGRATIONS, None).await.unwrap(),
+                latest_version()
+            );
+            assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrades_unversioned_database() {
+        // 引入迁移之前的库直接建表，没有 schema_version
+        let pool = memory_pool().await;
+        sqlx::raw_sql(MIGRATIONS[0].sql)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            run(&pool, MIGRATIONS, None).await.unwrap(),
+            latest_version()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_newer_schema() {
+        let pool = memory_pool().await;
+        let future = [Migration {
+            version: latest_version() + 1,
+            description: "from the future",
+            sql: "SELECT 1",
+            destructive: false,
+        }];
+        run(&pool, &future, None).await.unwrap();
+        assert!(run(&pool, MIGRATIONS, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn backs_up_before_destructive_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("chat.db");
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(&file)
+                    .create_if_missing(true),
+            )
+            .await
+            .unwrap();
+        run(&pool, MIGRATIONS, Some(&file)).await.unwrap();
+
+        let mut with_destructive = MIGRATIONS.to_vec();
+        with_destructive.push(Migration {
+            version: latest_version() + 1,
+            description: "drop peers",
+            sql: "DROP TABLE peers",
+            destructive: true,
+        });
+        run(&pool, &with_destructive, Some(&file)).await.unwrap();
+
+        let backups = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(backups, 1);
+    }
+}
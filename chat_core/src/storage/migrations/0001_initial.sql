@@ -0,0 +1,37 @@
+-- 会话：群聊房间或私聊
+CREATE TABLE IF NOT EXISTS conversations (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind        TEXT    NOT NULL CHECK (kind IN ('room', 'direct')),
+    name        TEXT    NOT NULL,
+    created_at  INTEGER NOT NULL,
+    UNIQUE (kind, name)
+);
+
+-- gossipsub 主题与会话的映射
+CREATE TABLE IF NOT EXISTS topics (
+    name            TEXT    PRIMARY KEY,
+    conversation_id INTEGER NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    joined          INTEGER NOT NULL DEFAULT 1
+);
+
+-- 见过的节点
+CREATE TABLE IF NOT EXISTS peers (
+    peer_id     TEXT    PRIMARY KEY,
+    first_seen  INTEGER NOT NULL,
+    last_seen   INTEGER NOT NULL
+);
+
+-- 消息
+CREATE TABLE IF NOT EXISTS messages (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    conversation_id INTEGER NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    message_id      TEXT    NOT NULL UNIQUE,
+    author          TEXT    NOT NULL,
+    body            BLOB    NOT NULL,
+    sent_at         INTEGER NOT NULL,
+    received_at     INTEGER NOT NULL,
+    outgoing        INTEGER NOT NULL DEFAULT 0,
+    read            INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages (conversation_id, id);
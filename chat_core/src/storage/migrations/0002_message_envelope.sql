@@ -0,0 +1,3 @@
+-- 结构化信封带来的消息类型与回复目标
+ALTER TABLE messages ADD COLUMN kind TEXT NOT NULL DEFAULT 'text';
+ALTER TABLE messages ADD COLUMN reply_to TEXT;
@@ -0,0 +1,2 @@
+-- 私聊送达回执
+ALTER TABLE messages ADD COLUMN delivered INTEGER NOT NULL DEFAULT 0;
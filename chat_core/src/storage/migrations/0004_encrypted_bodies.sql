@@ -0,0 +1,9 @@
+-- 消息正文静态加密：记录加密所用的密钥代数，NULL 表示明文
+ALTER TABLE messages ADD COLUMN key_generation INTEGER;
+
+-- 当前数据库密钥的代数与校验值，最多一行
+CREATE TABLE IF NOT EXISTS database_key (
+    id          INTEGER PRIMARY KEY CHECK (id = 1),
+    generation  INTEGER NOT NULL,
+    check_value BLOB    NOT NULL
+);
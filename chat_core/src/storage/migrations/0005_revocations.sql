@@ -0,0 +1,12 @@
+-- 已校验的密钥撤销声明，启动时载入内存并转发给新节点
+CREATE TABLE IF NOT EXISTS revocations (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind        TEXT    NOT NULL CHECK (kind IN ('identity', 'device')),
+    issuer      BLOB    NOT NULL,
+    revoked_key BLOB    NOT NULL,
+    issued_at   INTEGER NOT NULL,
+    reason      TEXT    NOT NULL,
+    signature   BLOB    NOT NULL,
+    received_at INTEGER NOT NULL,
+    UNIQUE (kind, issuer, revoked_key)
+);
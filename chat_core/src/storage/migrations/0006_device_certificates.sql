@@ -0,0 +1,12 @@
+-- 设备证书：用户身份密钥为各设备签发，每台设备只保留最新的一张
+CREATE TABLE IF NOT EXISTS device_certificates (
+    device_key  BLOB    PRIMARY KEY,
+    user_key    BLOB    NOT NULL,
+    device_name TEXT    NOT NULL,
+    issued_at   INTEGER NOT NULL,
+    expires_at  INTEGER NOT NULL,
+    signature   BLOB    NOT NULL,
+    received_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_device_certificates_user ON device_certificates (user_key);
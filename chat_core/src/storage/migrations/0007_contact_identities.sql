@@ -0,0 +1,7 @@
+-- 联系人的身份公钥与核验状态，公钥变化时清除核验
+CREATE TABLE IF NOT EXISTS contact_identities (
+    peer_id      TEXT    PRIMARY KEY,
+    identity_key BLOB    NOT NULL,
+    verified     INTEGER NOT NULL DEFAULT 0,
+    updated_at   INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- 为离线节点代存的私聊信封，交付或过期后删除
+CREATE TABLE IF NOT EXISTS mailbox (
+    id         TEXT    PRIMARY KEY,
+    recipient  TEXT    NOT NULL,
+    sender     TEXT    NOT NULL,
+    sealed     BLOB    NOT NULL,
+    stored_at  INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_mailbox_recipient ON mailbox (recipient, stored_at);
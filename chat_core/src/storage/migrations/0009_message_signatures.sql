@@ -0,0 +1,2 @@
+-- 消息信封的作者签名，历史同步时转发给其他节点重新验证；NULL 表示未签名的旧消息
+ALTER TABLE messages ADD COLUMN signature BLOB;
@@ -0,0 +1,6 @@
+-- 私聊棘轮会话状态，每个节点一行，内容已用会话状态密钥加密
+CREATE TABLE IF NOT EXISTS direct_sessions (
+    peer_id    TEXT    PRIMARY KEY,
+    sessions   BLOB    NOT NULL,
+    updated_at INTEGER NOT NULL
+);
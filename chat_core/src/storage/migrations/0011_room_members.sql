@@ -0,0 +1,7 @@
+-- 房间成员：邀请或接纳的节点，只有成员能拿到本节点的发送者密钥
+CREATE TABLE IF NOT EXISTS room_members (
+    room     TEXT    NOT NULL,
+    peer_id  TEXT    NOT NULL,
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (room, peer_id)
+);
@@ -0,0 +1,10 @@
+-- 首次启动时加入默认房间后写入，之后即使没有加入任何房间也不再自动加入
+CREATE TABLE IF NOT EXISTS room_setup (
+    id          INTEGER PRIMARY KEY CHECK (id = 1),
+    created_at  INTEGER NOT NULL
+);
+
+-- 已有房间记录的旧库视为已完成首次启动
+INSERT OR IGNORE INTO room_setup (id, created_at)
+SELECT 1, CAST(strftime('%s', 'now') AS INTEGER) * 1000
+WHERE EXISTS (SELECT 1 FROM topics);
@@ -1,9 +1,102 @@
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{
+    Row, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+};
 
-use std::{error::Error, ops::ControlFlow, str::FromStr, time::Duration};
+use std::{
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::CoreConfig;
-pub fn init(cfg: &CoreConfig) -> anyhow::Result<()> {
+use crate::{CoreConfig, wire::MessageKind};
+
+mod contacts;
+mod devices;
+mod encryption;
+mod mailbox;
+mod members;
+mod migrations;
+mod revocation;
+mod sessions;
+pub use contacts::ContactIdentity;
+pub use mailbox::MailboxEntry;
+pub use migrations::latest_version;
+
+/// 会话类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationKind {
+    /// 群聊房间（gossipsub 主题）
+    Room,
+    /// 一对一私聊
+    Direct,
+}
+impl ConversationKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConversationKind::Room => "room",
+            ConversationKind::Direct => "direct",
+        }
+    }
+}
+
+/// 待写入的消息
+#[derive(Debug, Clone)]
+pub struct NewMessage {
+    pub conversation_id: i64,
+    ///网络层消息 id，用于去重
+    pub message_id: String,
+    pub author: String,
+    pub kind: MessageKind,
+    pub body: Vec<u8>,
+    pub reply_to: Option<String>,
+    ///发送时间（unix 毫秒）
+    pub sent_at: i64,
+    pub outgoing: bool,
+    ///作者对信封的签名
+    pub signature: Option<Vec<u8>>,
+}
+
+/// 已存储的消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub conversation_id: i64,
+    pub message_id: String,
+    pub author: String,
+    pub kind: MessageKind,
+    pub body: Vec<u8>,
+    pub reply_to: Option<String>,
+    pub sent_at: i64,
+    pub received_at: i64,
+    pub outgoing: bool,
+    pub read: bool,
+    ///私聊消息已收到对方回执
+    pub delivered: bool,
+    pub signature: Option<Vec<u8>>,
+}
+
+/// 已加入的房间
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Room {
+    pub name: String,
+    pub conversation_id: i64,
+}
+
+/// 聊天记录存储句柄，内部为连接池，可廉价 clone
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pool: SqlitePool,
+    ///消息正文加密密钥，所有 clone 共享；未解锁时正文按明文写入
+    cipher: Arc<tokio::sync::RwLock<Option<rootcell::DatabaseKey>>>,
+}
+
+pub async fn init(cfg: &CoreConfig) -> anyhow::Result<Storage> {
+    if cfg.database_path.contains(":memory:") {
+        return Storage::in_memory().await;
+    }
     let pool_options = SqlitePoolOptions::new()
         .max_connections(10) // 连接池最大连接数 (默认取决于特性)
         .min_connections(0) // 连接池最小（保持）连接数 (默认 0)
@@ -21,6 +114,577 @@ pub fn init(cfg: &CoreConfig) -> anyhow::Result<()> {
         .pragma("temp_store", "memory") // 设置 PRAGMA 参数
         .pragma("cache_size", "-10000"); // 设置缓存大小（约 10MB）
 
+    let db_file = connect_options.get_filename().to_path_buf();
     let pool = pool_options.connect_lazy_with(connect_options);
-    Ok(())
+    Storage::with_pool(pool, Some(&db_file)).await
+}
+
+impl Storage {
+    /// 内存数据库，进程退出即丢失（测试用）
+    pub async fn in_memory() -> anyhow::Result<Self> {
+        // 内存库每个连接各自独立，只能保持唯一且永不回收的连接
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:")?.foreign_keys(true))
+            .await?;
+        Self::with_pool(pool, None).await
+    }
+
+    async fn with_pool(pool: SqlitePool, db_file: Option<&Path>) -> anyhow::Result<Self> {
+        let db_file = db_file.filter(|f| f.is_file());
+        migrations::run(&pool, migrations::MIGRATIONS, db_file).await?;
+        Ok(Self {
+            pool,
+            cipher: Arc::default(),
+        })
+    }
+
+    /// 数据库当前 schema 版本
+    pub async fn schema_version(&self) -> anyhow::Result<i64> {
+        migrations::current_version(&self.pool).await
+    }
+
+    /// 取得（不存在则创建）会话，返回会话 id
+    pub async fn ensure_conversation(
+        &self,
+        kind: ConversationKind,
+        name: &str,
+    ) -> anyhow::Result<i64> {
+        let id = sqlx::query_scalar(
+            "INSERT INTO conversations (kind, name, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (kind, name) DO UPDATE SET name = excluded.name
+             RETURNING id",
+        )
+        .bind(kind.as_str())
+        .bind(name)
+        .bind(now_millis())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// 取得主题对应的房间会话 id，不改变加入状态
+    pub async fn topic_conversation(&self, topic: &str) -> anyhow::Result<i64> {
+        let conversation_id = self
+            .ensure_conversation(ConversationKind::Room, topic)
+            .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO topics (name, conversation_id, joined) VALUES (?1, ?2, 0)",
+        )
+        .bind(topic)
+        .bind(conversation_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(conversation_id)
+    }
+
+    /// 记录加入房间，返回会话 id
+    pub async fn join_topic(&self, topic: &str) -> anyhow::Result<i64> {
+        let conversation_id = self.topic_conversation(topic).await?;
+        sqlx::query("UPDATE topics SET joined = 1 WHERE name = ?1")
+            .bind(topic)
+            .execute(&self.pool)
+            .await?;
+        Ok(conversation_id)
+    }
+
+    /// 记录离开房间，历史消息保留
+    pub async fn leave_topic(&self, topic: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE topics SET joined = 0 WHERE name = ?1")
+            .bind(topic)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// 已加入的房间，按名称排序
+    pub async fn joined_topics(&self) -> anyhow::Result<Vec<Room>> {
+        let rows =
+            sqlx::query("SELECT name, conversation_id FROM topics WHERE joined = 1 ORDER BY name")
+                .fetch_all(&self.pool)
+                .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Room {
+                    name: row.try_get("name")?,
+                    conversation_id: row.try_get("conversation_id")?,
+                })
+            })
+            .collect()
+    }
+
+    /// 记录已完成首次启动的房间设置，只有第一次调用返回 true
+    pub async fn begin_room_setup(&self) -> anyhow::Result<bool> {
+        let result =
+            sqlx::query("INSERT OR IGNORE INTO room_setup (id, created_at) VALUES (1, ?1)")
+                .bind(now_millis())
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// 取得与节点的私聊会话 id
+    pub async fn direct_conversation(&self, peer_id: &str) -> anyhow::Result<i64> {
+        self.ensure_conversation(ConversationKind::Direct, peer_id)
+            .await
+    }
+
+    /// 有过私聊的节点，按最近活动排序
+    pub async fn direct_peers(&self) -> anyhow::Result<Vec<String>> {
+        let peers = sqlx::query_scalar(
+            "SELECT c.name FROM conversations c
+             LEFT JOIN messages m ON m.conversation_id = c.id
+             WHERE c.kind = 'direct'
+             GROUP BY c.id ORDER BY MAX(COALESCE(m.sent_at, c.created_at)) DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(peers)
+    }
+
+    /// 记录对方已确认收到消息，返回是否有记录被更新
+    pub async fn mark_delivered(&self, message_id: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE messages SET delivered = 1 WHERE message_id = ?1")
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 记录见到的节点
+    pub async fn touch_peer(&self, peer_id: &str) -> anyhow::Result<()> {
+        let now = now_millis();
+        sqlx::query(
+            "INSERT INTO peers (peer_id, first_seen, last_seen) VALUES (?1, ?2, ?2)
+             ON CONFLICT (peer_id) DO UPDATE SET last_seen = excluded.last_seen",
+        )
+        .bind(peer_id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// 写入消息，已解锁时正文加密保存；同一 message_id 重复写入时返回 None
+    pub async fn insert_message(&self, msg: &NewMessage) -> anyhow::Result<Option<i64>> {
+        // 持有读锁直到写入完成，避免与密钥轮换交错
+        let cipher = self.cipher.read().await;
+        let (body, generation) = match cipher.as_ref() {
+            Some(key) => (
+                key.seal(msg.message_id.as_bytes(), &msg.body)?,
+                Some(encryption::generation_column(key)?),
+            ),
+            None => (msg.body.clone(), None),
+        };
+        let id = sqlx::query_scalar(
+            "INSERT INTO messages
+                (conversation_id, message_id, author, kind, body, reply_to,
+                 sent_at, received_at, outgoing, read, key_generation, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, ?10, ?11)
+             ON CONFLICT (message_id) DO NOTHING
+             RETURNING id",
+        )
+        .bind(msg.conversation_id)
+        .bind(&msg.message_id)
+        .bind(&msg.author)
+        .bind(msg.kind.as_str())
+        .bind(body)
+        .bind(&msg.reply_to)
+        .bind(msg.sent_at)
+        .bind(now_millis())
+        .bind(msg.outgoing)
+        .bind(generation)
+        .bind(&msg.signature)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// 按时间顺序分页读取会话消息，`range` 为会话内的序号区间
+    pub async fn messages_in_conversation(
+        &self,
+        conversation_id: i64,
+        range: Range<u32>,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        let limit = range.end.saturating_sub(range.start);
+        let cipher = self.cipher.read().await;
+        let rows = sqlx::query(
+            "SELECT id, conversation_id, message_id, author, kind, body, reply_to,
+                    sent_at, received_at, outgoing, read, delivered, key_generation, signature
+             FROM messages WHERE conversation_id = ?1
+             ORDER BY id LIMIT ?2 OFFSET ?3",
+        )
+        .bind(conversation_id)
+        .bind(limit)
+        .bind(range.start)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| stored_message_from_row(row, cipher.as_ref()))
+            .collect()
+    }
+
+    /// 游标 `(发送时间, 消息 id)` 之前的已签名消息，从新到旧排列，最多 `limit` 条；
+    /// `excluded` 中作者的消息不返回
+    ///
+    /// 排除的作者以一个 JSON 参数经 `json_each` 传入，过滤与截断都在 SQL 中完成
+    pub async fn messages_before(
+        &self,
+        conversation_id: i64,
+        before: Option<(i64, &str)>,
+        excluded: &[String],
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        let cipher = self.cipher.read().await;
+        let (sent_at, message_id) = before.unzip();
+        let rows = sqlx::query(
+            "SELECT id, conversation_id, message_id, author, kind, body, reply_to,
+                    sent_at, received_at, outgoing, read, delivered, key_generation, signature
+             FROM messages
+             WHERE conversation_id = ?1 AND signature IS NOT NULL
+                   AND (?2 IS NULL OR sent_at < ?2 OR (sent_at = ?2 AND message_id < ?3))
+                   AND author NOT IN (SELECT value FROM json_each(?4))
+             ORDER BY sent_at DESC, message_id DESC LIMIT ?5",
+        )
+        .bind(conversation_id)
+        .bind(sent_at)
+        .bind(message_id)
+        .bind(serde_json::to_string(excluded)?)
+        .bind(i64::try_from(limit)?)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| stored_message_from_row(row, cipher.as_ref()))
+            .collect()
+    }
+
+    /// 会话内消息总数，配合分页使用
+    pub async fn message_count(&self, conversation_id: i64) -> anyhow::Result<u32> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation_id = ?1")
+            .bind(conversation_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    /// 将会话中 id 不超过 `up_to` 的消息标记为已读，返回受影响行数
+    pub async fn mark_read(&self, conversation_id: i64, up_to: i64) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            "UPDATE messages SET read = 1 WHERE conversation_id = ?1 AND id <= ?2 AND read = 0",
+        )
+        .bind(conversation_id)
+        .bind(up_to)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// 会话内未读消息数
+    pub async fn unread_count(&self, conversation_id: i64) -> anyhow::Result<u32> {
+        let count = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM messages WHERE conversation_id = ?1 AND read = 0",
+        )
+        .bind(conversation_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+}
+
+fn stored_message_from_row(
+    row: &sqlx::sqlite::SqliteRow,
+    cipher: Option<&rootcell::DatabaseKey>,
+) -> anyhow::Result<StoredMessage> {
+    let message_id: String = row.try_get("message_id")?;
+    Ok(StoredMessage {
+        id: row.try_get("id")?,
+        conversation_id: row.try_get("conversation_id")?,
+        body: encryption::open_body(
+            cipher,
+            &message_id,
+            row.try_get("body")?,
+            row.try_get("key_generation")?,
+        )?,
+        message_id,
+        author: row.try_get("author")?,
+        kind: MessageKind::from_name(row.try_get("kind")?),
+        reply_to: row.try_get("reply_to")?,
+        sent_at: row.try_get("sent_at")?,
+        received_at: row.try_get("received_at")?,
+        outgoing: row.try_get("outgoing")?,
+        read: row.try_get("read")?,
+        delivered: row.try_get("delivered")?,
+        signature: row.try_get("signature")?,
+    })
+}
+
+/// 当前 unix 时间（毫秒）
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(conversation_id: i64, message_id: &str, body: &str) -> NewMessage {
+        NewMessage {
+            conversation_id,
+            message_id: message_id.to_string(),
+            author: "peer".to_string(),
+            kind: MessageKind::Text,
+            body: body.as_bytes().to_vec(),
+            reply_to: None,
+            sent_at: now_millis(),
+            outgoing: false,
+            signature: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn insert_and_page_messages() {
+        let storage = Storage::in_memory().await.unwrap();
+        let room = storage.topic_conversation("test-net").await.unwrap();
+        for i in 0..5 {
+            let id = format!("m{i}");
+            let inserted = storage
+                .insert_message(&text(room, &id, "hi"))
+                .await
+                .unwrap();
+            assert!(inserted.is_some());
+        }
+        // 重复 id 被忽略
+        let dup = storage
+            .insert_message(&text(room, "m0", "hi"))
+            .await
+            .unwrap();
+        assert_eq!(dup, None);
+
+        assert_eq!(storage.message_count(room).await.unwrap(), 5);
+        let page = storage.messages_in_conversation(room, 1..3).await.unwrap();
+        let ids: Vec<_> = page.iter().map(|m| m.message_id.as_str()).collect();
+        assert_eq!(ids, ["m1", "m2"]);
+    }
+
+    #[tokio::test]
+    async fn room_membership() {
+        let storage = Storage::in_memory().await.unwrap();
+        storage.join_topic("b").await.unwrap();
+        storage.join_topic("a").await.unwrap();
+        // 仅收到消息不算加入
+        storage.topic_conversation("c").await.unwrap();
+        storage.leave_topic("b").await.unwrap();
+        let rooms = storage.joined_topics().await.unwrap();
+        let names: Vec<_> = rooms.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["a"]);
+
+        assert!(storage.add_member("a", "peer").await.unwrap());
+        assert!(!storage.add_member("a", "peer").await.unwrap());
+        assert_eq!(
+            storage.members().await.unwrap(),
+            [("a".to_string(), "peer".to_string())]
+        );
+        assert!(storage.remove_member("a", "peer").await.unwrap());
+        assert!(storage.members().await.unwrap().is_empty());
+
+        assert!(storage.begin_room_setup().await.unwrap());
+        assert!(!storage.begin_room_setup().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn mark_read_up_to() {
+        let storage = Storage::in_memory().await.unwrap();
+        let room = storage.topic_conversation("test-net").await.unwrap();
+        let mut last = 0;
+        for i in 0..3 {
+            last = storage
+                .insert_message(&text(room, &format!("m{i}"), "hi"))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(storage.unread_count(room).await.unwrap(), 3);
+        assert_eq!(storage.mark_read(room, last - 1).await.unwrap(), 2);
+        assert_eq!(storage.unread_count(room).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn direct_delivery_receipts() {
+        let storage = Storage::in_memory().await.unwrap();
+        let dm = storage.direct_conversation("peer-b").await.unwrap();
+        assert_ne!(dm, storage.topic_conversation("peer-b").await.unwrap());
+        storage.insert_message(&text(dm, "d1", "hi")).await.unwrap();
+        assert!(storage.mark_delivered("d1").await.unwrap());
+        assert!(!storage.mark_delivered("missing").await.unwrap());
+
+        let stored = storage.messages_in_conversation(dm, 0..1).await.unwrap();
+        assert!(stored[0].delivered);
+        assert_eq!(storage.direct_peers().await.unwrap(), ["peer-b"]);
+    }
+
+    #[tokio::test]
+    async fn encrypted_bodies_and_key_rotation() {
+        let storage = Storage::in_memory().await.unwrap();
+        let room = storage.topic_conversation("test-net").await.unwrap();
+        storage
+            .insert_message(&text(room, "old", "before unlock"))
+            .await
+            .unwrap();
+        let vault =
+            rootcell::SecurityCore::with_master_key(rootcell::SecretKey::generate().unwrap());
+        assert_eq!(storage.key_generation().await.unwrap(), None);
+        storage
+            .unlock(vault.database_key(0).unwrap())
+            .await
+            .unwrap();
+        storage
+            .insert_message(&text(room, "new", "after unlock"))
+            .await
+            .unwrap();
+
+        let raw: Vec<Vec<u8>> = sqlx::query_scalar("SELECT body FROM messages ORDER BY id")
+            .fetch_all(&storage.pool)
+            .await
+            .unwrap();
+        assert!(raw.iter().all(|b| !b.ends_with(b"unlock")));
+
+        storage
+            .rotate_key(vault.database_key(1).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(storage.key_generation().await.unwrap(), Some(1));
+        let bodies: Vec<_> = storage
+            .messages_in_conversation(room, 0..2)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.body)
+            .collect();
+        assert_eq!(
+            bodies,
+            [b"before unlock".to_vec(), b"after unlock".to_vec()]
+        );
+
+        // 旧代数或其他主密钥都无法解锁
+        let stale = storage.unlock(vault.database_key(0).unwrap()).await;
+        let other =
+            rootcell::SecurityCore::with_master_key(rootcell::SecretKey::generate().unwrap());
+        let wrong = storage.unlock(other.database_key(1).unwrap()).await;
+        for result in [stale, wrong] {
+            assert!(matches!(
+                result.unwrap_err().downcast_ref(),
+                Some(rootcell::TrustError::WrongKey(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn revocations_round_trip() {
+        let storage = Storage::in_memory().await.unwrap();
+        let identity = rootcell::Identity::generate().unwrap();
+        let revocation = rootcell::Revocation::revoke_identity(&identity, 1, "lost phone");
+        assert!(storage.insert_revocation(&revocation).await.unwrap());
+        assert!(!storage.insert_revocation(&revocation).await.unwrap());
+
+        // 被改动的行不会再被载入
+        let device = rootcell::Identity::generate().unwrap();
+        let mut tampered =
+            rootcell::Revocation::revoke_device(&identity, device.public_key(), 2, "sold");
+        tampered.issued_at = 3;
+        storage.insert_revocation(&tampered).await.unwrap();
+        assert_eq!(storage.revocations().await.unwrap(), [revocation]);
+    }
+
+    #[tokio::test]
+    async fn device_certificates_keep_newest() {
+        let storage = Storage::in_memory().await.unwrap();
+        let user = rootcell::Identity::generate().unwrap();
+        let device = rootcell::Identity::generate().unwrap();
+        let issue = |issued_at| {
+            rootcell::DeviceCertificate::issue(
+                &user,
+                device.public_key(),
+                "laptop",
+                issued_at,
+                1000,
+            )
+        };
+        assert!(storage.upsert_device_certificate(&issue(10)).await.unwrap());
+        assert!(storage.upsert_device_certificate(&issue(20)).await.unwrap());
+        // 旧证书不会覆盖新证书
+        assert!(!storage.upsert_device_certificate(&issue(15)).await.unwrap());
+        assert_eq!(storage.device_certificates().await.unwrap(), [issue(20)]);
+    }
+
+    #[tokio::test]
+    async fn history_pages_backwards() {
+        let storage = Storage::in_memory().await.unwrap();
+        let room = storage.topic_conversation("test-net").await.unwrap();
+        for (id, author, sent_at) in [
+            ("a1", "alice", 1),
+            ("b1", "bob", 2),
+            ("a2", "alice", 2),
+            ("a3", "alice", 3),
+        ] {
+            let mut msg = text(room, id, id);
+            msg.author = author.to_string();
+            msg.sent_at = sent_at;
+            msg.signature = Some(vec![0; 64]);
+            storage.insert_message(&msg).await.unwrap();
+        }
+        // 未签名的旧消息不参与同步
+        storage
+            .insert_message(&text(room, "old", "old"))
+            .await
+            .unwrap();
+
+        let ids = |messages: Vec<StoredMessage>| -> Vec<String> {
+            messages.into_iter().map(|m| m.message_id).collect()
+        };
+        let all = storage.messages_before(room, None, &[], 10).await.unwrap();
+        assert_eq!(ids(all), ["a3", "b1", "a2", "a1"]);
+        // 发送时间相同的消息按 id 翻页，不会漏掉
+        let first = storage.messages_before(room, None, &[], 2).await.unwrap();
+        assert_eq!(ids(first), ["a3", "b1"]);
+        let rest = storage
+            .messages_before(room, Some((2, "b1")), &[], 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(rest), ["a2", "a1"]);
+        // 排除的作者数不受 SQLite 复合查询项数的限制
+        let mut excluded: Vec<String> = (0..2000).map(|i| format!("peer{i}")).collect();
+        excluded.push("bob".to_string());
+        let without_bob = storage
+            .messages_before(room, None, &excluded, 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(without_bob), ["a3", "a2", "a1"]);
+    }
+
+    #[tokio::test]
+    async fn mailbox_expires_and_deletes() {
+        let storage = Storage::in_memory().await.unwrap();
+        let mail = |id: &str, expires_at| MailboxEntry {
+            id: id.to_string(),
+            recipient: "bob".to_string(),
+            sender: "alice".to_string(),
+            sealed: vec![1, 2, 3],
+            stored_at: 1,
+            expires_at,
+        };
+        assert!(storage.insert_mail(&mail("a", 10)).await.unwrap());
+        assert!(!storage.insert_mail(&mail("a", 10)).await.unwrap());
+        assert!(storage.insert_mail(&mail("b", 30)).await.unwrap());
+        assert!(storage.insert_mail(&mail("c", 30)).await.unwrap());
+
+        assert_eq!(storage.purge_expired_mail(20).await.unwrap(), 1);
+        assert_eq!(storage.delete_mail(&["b".to_string()]).await.unwrap(), 1);
+        assert_eq!(storage.mail().await.unwrap(), [mail("c", 30)]);
+    }
 }
@@ -0,0 +1,76 @@
+//! 密钥撤销声明的持久化
+//!
+//! 只保存已校验签名的声明；读取时重新校验，被改动的行直接跳过。
+use rootcell::{Revocation, RevocationKind};
+use sqlx::Row;
+
+use super::{Storage, now_millis};
+
+fn kind_name(kind: RevocationKind) -> &'static str {
+    match kind {
+        RevocationKind::Identity => "identity",
+        RevocationKind::Device => "device",
+    }
+}
+
+fn revocation_from_row(row: &sqlx::sqlite::SqliteRow) -> anyhow::Result<Revocation> {
+    let kind = match row.try_get::<&str, _>("kind")? {
+        "identity" => RevocationKind::Identity,
+        "device" => RevocationKind::Device,
+        other => anyhow::bail!("unknown revocation kind {other}"),
+    };
+    let key = |column: &str| -> anyhow::Result<[u8; 32]> {
+        let bytes: Vec<u8> = row.try_get(column)?;
+        bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("malformed {column} in revocations"))
+    };
+    Ok(Revocation {
+        kind,
+        issuer: key("issuer")?,
+        revoked_key: key("revoked_key")?,
+        issued_at: u64::try_from(row.try_get::<i64, _>("issued_at")?)?,
+        reason: row.try_get("reason")?,
+        signature: row.try_get("signature")?,
+    })
+}
+
+impl Storage {
+    /// 保存撤销声明，同一密钥已有声明时忽略并返回 false
+    pub async fn insert_revocation(&self, revocation: &Revocation) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO revocations
+                (kind, issuer, revoked_key, issued_at, reason, signature, received_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(kind_name(revocation.kind))
+        .bind(revocation.issuer.as_slice())
+        .bind(revocation.revoked_key.as_slice())
+        .bind(i64::try_from(revocation.issued_at)?)
+        .bind(&revocation.reason)
+        .bind(revocation.signature.as_slice())
+        .bind(now_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 全部签名有效的撤销声明，按收到顺序
+    pub async fn revocations(&self) -> anyhow::Result<Vec<Revocation>> {
+        let rows = sqlx::query(
+            "SELECT kind, issuer, revoked_key, issued_at, reason, signature
+             FROM revocations ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut revocations = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let revocation = revocation_from_row(row)?;
+            match revocation.verify() {
+                Ok(()) => revocations.push(revocation),
+                Err(e) => tracing::warn!("skipped stored revocation with bad signature: {e}"),
+            }
+        }
+        Ok(revocations)
+    }
+}
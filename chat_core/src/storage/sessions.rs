@@ -0,0 +1,42 @@
+//! 私聊棘轮会话的持久化
+//!
+//! 每个节点的会话列表由调用方序列化并加密，这里只按 PeerId 整体读写。
+use sqlx::Row;
+
+use super::{Storage, now_millis};
+
+impl Storage {
+    /// 保存与节点的会话列表，覆盖已有记录
+    pub async fn save_sessions(&self, peer_id: &str, sessions: &[u8]) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO direct_sessions (peer_id, sessions, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer_id) DO UPDATE
+             SET sessions = excluded.sessions, updated_at = excluded.updated_at",
+        )
+        .bind(peer_id)
+        .bind(sessions)
+        .bind(now_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// 删除与节点的会话，如对方身份被撤销
+    pub async fn delete_sessions(&self, peer_id: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM direct_sessions WHERE peer_id = ?1")
+            .bind(peer_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// 全部已保存的会话列表，按 PeerId 排列
+    pub async fn sessions(&self) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let rows = sqlx::query("SELECT peer_id, sessions FROM direct_sessions ORDER BY peer_id")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| Ok((row.try_get("peer_id")?, row.try_get("sessions")?)))
+            .collect()
+    }
+}
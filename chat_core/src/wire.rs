@@ -0,0 +1,298 @@
+//! 网络传输的消息信封
+//!
+//! 所有发布到 gossipsub 的负载都是 CBOR 编码的 [`WireMessage`]。
+//! 主版本号不同的消息直接拒绝；同一主版本内新增的字段由旧版本原样保留在
+//! `extra` 中，转发或重新编码时不会丢失。
+//!
+//! 信封由作者的 rootcell 身份密钥签名，签名覆盖除 `signature` 外的全部字段（包括
+//! `extra`），因此经他人转发后仍能验证真正的作者。
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+/// 当前协议主版本号，不兼容的改动才递增
+pub const WIRE_VERSION: u16 = 1;
+/// 签名的上下文前缀，避免与其他用途的签名混用
+const SIGNATURE_CONTEXT: &[u8] = b"mychat/envelope/v1";
+
+/// 消息内容类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// UTF-8 纯文本
+    Text,
+    /// 私聊中分发的房间发送者密钥，不进入聊天记录
+    SenderKey,
+    /// 本版本不认识的类型
+    #[serde(other)]
+    Unknown,
+}
+impl MessageKind {
+    /// 存储用的名称，与序列化名称一致
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::SenderKey => "sender_key",
+            MessageKind::Unknown => "unknown",
+        }
+    }
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "text" => MessageKind::Text,
+            "sender_key" => MessageKind::SenderKey,
+            _ => MessageKind::Unknown,
+        }
+    }
+}
+
+/// 版本化的消息信封
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireMessage {
+    pub version: u16,
+    ///全局唯一的消息 id
+    pub id: String,
+    ///发送时间（unix 毫秒）
+    pub sent_at: i64,
+    ///发送者
+    pub author: String,
+    pub kind: MessageKind,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    ///所回复消息的 id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    ///所属房间，私聊为空；受签名保护，转发或同步时不能被挪到其他房间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    ///作者身份密钥对其余字段的 ed25519 签名
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub signature: Option<Vec<u8>>,
+    ///更新版本添加、本版本不认识的字段
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, ciborium::Value>,
+}
+
+/// 信封解码错误
+#[derive(Debug)]
+pub enum WireError {
+    /// 不是合法的 CBOR 信封
+    Malformed(String),
+    /// 主版本号不受支持
+    UnsupportedVersion(u16),
+    /// 缺少签名或签名与作者不符
+    BadSignature(String),
+    /// 无法编码为 CBOR
+    Unencodable(String),
+}
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::Malformed(e) => write!(f, "malformed wire message: {e}"),
+            WireError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported wire version {v} (supported: {WIRE_VERSION})"
+                )
+            }
+            WireError::BadSignature(e) => write!(f, "unauthenticated wire message: {e}"),
+            WireError::Unencodable(e) => write!(f, "failed to encode wire message: {e}"),
+        }
+    }
+}
+impl std::error::Error for WireError {}
+
+/// 仅用于先行读取版本号，避免按错误的结构解析新版本消息
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u16,
+}
+
+impl WireMessage {
+    /// 构造一条当前版本的文本消息
+    pub fn text(author: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            version: WIRE_VERSION,
+            id: new_message_id(),
+            sent_at: crate::storage::now_millis(),
+            author: author.into(),
+            kind: MessageKind::Text,
+            body: text.into().into_bytes(),
+            reply_to: None,
+            room: None,
+            signature: None,
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// 设置回复目标
+    pub fn reply_to(mut self, id: impl Into<String>) -> Self {
+        self.reply_to = Some(id.into());
+        self
+    }
+
+    /// 标记所属房间
+    pub fn in_room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// 用作者的身份密钥签名，之后不应再修改任何字段
+    pub fn sign(mut self, identity: &rootcell::Identity) -> Result<Self, WireError> {
+        self.signature = Some(identity.sign(&self.signed_bytes()?).to_vec());
+        Ok(self)
+    }
+
+    /// 验证签名，返回经认证的作者
+    pub fn verify(&self) -> Result<PeerId, WireError> {
+        let author: PeerId = self
+            .author
+            .parse()
+            .map_err(|e| WireError::BadSignature(format!("invalid author {}: {e}", self.author)))?;
+        let public_key = crate::identity::peer_public_key(&author)
+            .map_err(|e| WireError::BadSignature(e.to_string()))?;
+        let signature: [u8; 64] = self
+            .signature
+            .as_deref()
+            .ok_or_else(|| WireError::BadSignature("missing signature".to_string()))?
+            .try_into()
+            .map_err(|_| WireError::BadSignature("invalid signature length".to_string()))?;
+        if !rootcell::identity::verify(&public_key, &self.signed_bytes()?, &signature) {
+            return Err(WireError::BadSignature(format!(
+                "signature does not match author {author}"
+            )));
+        }
+        Ok(author)
+    }
+
+    /// 签名覆盖的内容：去掉签名后按键排序的 CBOR 映射，与字段顺序和版本无关
+    fn signed_bytes(&self) -> Result<Vec<u8>, WireError> {
+        let mut fields = match ciborium::Value::serialized(self) {
+            Ok(ciborium::Value::Map(fields)) => fields,
+            Ok(_) => return Err(WireError::Unencodable("envelope is not a map".to_string())),
+            Err(e) => return Err(WireError::Unencodable(e.to_string())),
+        };
+        fields.retain(|(key, _)| key.as_text() != Some("signature"));
+        fields.sort_by(|(a, _), (b, _)| a.as_text().cmp(&b.as_text()));
+        let mut buf = SIGNATURE_CONTEXT.to_vec();
+        ciborium::into_writer(&ciborium::Value::Map(fields), &mut buf)
+            .map_err(|e| WireError::Unencodable(e.to_string()))?;
+        Ok(buf)
+    }
+
+    /// 编码为 CBOR
+    pub fn encode(&self) -> Result<Vec<u8>, WireError> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).map_err(|e| WireError::Unencodable(e.to_string()))?;
+        Ok(buf)
+    }
+
+    /// 从 CBOR 解码，拒绝不支持的主版本
+    pub fn decode(bytes: &[u8]) -> Result<Self, WireError> {
+        let probe: VersionProbe =
+            ciborium::from_reader(bytes).map_err(|e| WireError::Malformed(e.to_string()))?;
+        if probe.version != WIRE_VERSION {
+            return Err(WireError::UnsupportedVersion(probe.version));
+        }
+        ciborium::from_reader(bytes).map_err(|e| WireError::Malformed(e.to_string()))
+    }
+
+    /// 文本内容（非 UTF-8 时有损转换）
+    pub fn text_body(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// 128 位随机消息 id
+fn new_message_id() -> String {
+    let mut bytes = [0u8; 16];
+    // 取不到随机数时退化为时间戳，仍能在本地保持唯一性
+    if getrandom::fill(&mut bytes).is_err() {
+        bytes[..8].copy_from_slice(&crate::storage::now_millis().to_be_bytes());
+    }
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let msg = WireMessage::text("alice", "hi").reply_to("abc");
+        assert_eq!(WireMessage::decode(&msg.encode().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn rejects_unknown_major_version() {
+        let mut msg = WireMessage::text("alice", "hi");
+        msg.version = WIRE_VERSION + 1;
+        assert!(matches!(
+            WireMessage::decode(&msg.encode().unwrap()),
+            Err(WireError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            WireMessage::decode(b"hello"),
+            Err(WireError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_unknown_fields() {
+        let mut msg = WireMessage::text("alice", "hi");
+        msg.extra.insert(
+            "reactions".to_string(),
+            ciborium::Value::Text("👍".to_string()),
+        );
+        let decoded = WireMessage::decode(&msg.encode().unwrap()).unwrap();
+        assert_eq!(decoded.extra, msg.extra);
+        assert_eq!(
+            WireMessage::decode(&decoded.encode().unwrap()).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn signature_binds_author_and_content() {
+        let alice = rootcell::Identity::generate().unwrap();
+        let alice_peer = crate::identity::peer_id(&alice.public_key()).unwrap();
+        let msg = WireMessage::text(alice_peer.to_string(), "hi")
+            .sign(&alice)
+            .unwrap();
+        let decoded = WireMessage::decode(&msg.encode().unwrap()).unwrap();
+        assert_eq!(decoded.verify().unwrap(), alice_peer);
+
+        // 新版本添加的字段同样受签名保护
+        let mut newer = WireMessage::text(alice_peer.to_string(), "hi");
+        newer.extra.insert(
+            "reactions".to_string(),
+            ciborium::Value::Text("👍".to_string()),
+        );
+        let newer = newer.sign(&alice).unwrap();
+        assert!(
+            WireMessage::decode(&newer.encode().unwrap())
+                .unwrap()
+                .verify()
+                .is_ok()
+        );
+        let mut stripped = newer.clone();
+        stripped.extra.clear();
+        assert!(stripped.verify().is_err());
+
+        let mut tampered = msg.clone();
+        tampered.body = b"bye".to_vec();
+        assert!(tampered.verify().is_err());
+
+        // 冒充他人作者或不签名都无法通过验证
+        let mallory = rootcell::Identity::generate().unwrap();
+        let forged = WireMessage::text(alice_peer.to_string(), "hi")
+            .sign(&mallory)
+            .unwrap();
+        assert!(matches!(forged.verify(), Err(WireError::BadSignature(_))));
+        assert!(
+            WireMessage::text(alice_peer.to_string(), "hi")
+                .verify()
+                .is_err()
+        );
+    }
+}
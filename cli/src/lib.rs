@@ -1,7 +1,9 @@
 // 定义应用状态（Model）
 #![doc = include_str!("../../README.md")]
-use chat_core::ChatCore;
+use chat_core::PeerId;
+use chat_core::{ChatCore, ChatHandle, EventReceiver};
 use ratatui::widgets::ListState;
+use std::collections::HashMap;
 pub mod notui;
 pub mod tui;
 
@@ -17,13 +19,34 @@ pub struct App {
     messages: Vec<String>, // 所有消息
     message_list_state: ListState,
 
-    //contacts:HashMap<id,Socket>;
+    // --- 侧边栏：已加入的房间与私聊联系人 ---
+    rooms: Vec<String>,
+    contacts: Vec<PeerId>,
+    current: Conversation,
+    unread: HashMap<Conversation, u32>, // 非当前会话的未读数
     contact_list_state: ListState,
+    // --- /verify 打开的安全码视图 ---
+    verification: Option<chat_core::Verification>,
     // --- 输入框组件 ---
     input: String, // 当前输入的文本
 
     should_quit: bool,
-    core: ChatCore,
+    handle: ChatHandle,
+    events: Option<EventReceiver>,
+}
+/// 侧边栏中的会话：房间或与某个节点的私聊
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Conversation {
+    Room(String),
+    Direct(PeerId),
+}
+impl std::fmt::Display for Conversation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conversation::Room(room) => write!(f, "{room}"),
+            Conversation::Direct(peer) => write!(f, "@{peer}"),
+        }
+    }
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
 // 定义焦点枚举
@@ -34,30 +57,166 @@ enum Focus {
 }
 
 impl App {
-    pub async fn try_init() -> anyhow::Result<App> {
+    pub async fn try_init(cfg: &chat_core::CoreConfig) -> anyhow::Result<App> {
         let mut list_state = ListState::default();
         list_state.select(Some(0)); // 默认选中第一条消息
 
-        let cfg = chat_core::CoreConfig::new("~/.chat_history.db");
-        let mut core = chat_core::ChatCore::try_init(&cfg)?;
-        core.swarm
-            .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-        core.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-        core.swarm.listen_on("/ip6/::/udp/0/quic-v1".parse()?)?;
-        core.swarm.listen_on("/ip6/::/tcp/0".parse()?)?;
+        let (handle, events) = ChatCore::spawn(cfg).await?;
+        handle.listen("/ip4/0.0.0.0/udp/0/quic-v1".parse()?).await?;
+        handle.listen("/ip4/0.0.0.0/tcp/0".parse()?).await?;
+        handle.listen("/ip6/::/udp/0/quic-v1".parse()?).await?;
+        handle.listen("/ip6/::/tcp/0".parse()?).await?;
+
+        let rooms = handle.rooms().await?;
+        let current = Conversation::Room(
+            rooms
+                .first()
+                .cloned()
+                .unwrap_or_else(|| chat_core::DEFAULT_TOPIC.to_string()),
+        );
+        let contacts = handle
+            .storage()
+            .direct_peers()
+            .await?
+            .iter()
+            .filter_map(|peer| peer.parse().ok())
+            .collect();
+
+        let mut messages = vec![
+            "欢迎使用 chat cli".to_string(),
+            "按 Ctrl+Tab 切换焦点，↑↓ 选择消息".to_string(),
+            "按 Esc或Ctrl+C 退出应用，在输入框中Ctrl+Enter 发送".to_string(),
+            "输入 /join <房间> 加入房间，/leave 离开当前房间".to_string(),
+            "输入 /invite <联系人> 邀请或接纳当前房间的成员，/remove <联系人> 移出".to_string(),
+            "输入 /verify [联系人] 与对方核对安全码".to_string(),
+            "在会话列表中选择联系人开始私聊".to_string(),
+        ];
+        messages.extend(load_history(&handle, &current).await?);
 
         Ok(App {
             current_focus: Focus::Input,
-            messages: vec![
-                "欢迎使用 chat cli".to_string(),
-                "按 Ctrl+Tab 切换焦点，↑↓ 选择消息".to_string(),
-                "按 Esc或Ctrl+C 退出应用，在输入框中Ctrl+Enter 发送".to_string(),
-            ],
+            messages,
             message_list_state: list_state,
+            rooms,
+            contacts,
+            current,
+            unread: HashMap::new(),
             contact_list_state: list_state,
+            verification: None,
             input: String::new(),
             should_quit: false,
-            core,
+            handle,
+            events: Some(events),
         })
     }
 }
+impl App {
+    /// 切换到会话并载入其历史消息
+    async fn switch_conversation(&mut self, conversation: Conversation) -> anyhow::Result<()> {
+        self.messages = load_history(&self.handle, &conversation).await?;
+        self.messages.insert(0, format!("—— {conversation} ——"));
+        self.unread.remove(&conversation);
+        self.current = conversation;
+        self.message_list_state
+            .select(Some(self.messages.len().saturating_sub(1)));
+        Ok(())
+    }
+
+    /// 从核心重新读取已加入的房间
+    async fn refresh_rooms(&mut self) -> anyhow::Result<()> {
+        self.rooms = self.handle.rooms().await?;
+        Ok(())
+    }
+
+    /// 记录新的私聊联系人，已存在时忽略
+    fn add_contact(&mut self, peer: PeerId) {
+        if peer != self.handle.local_peer_id() && !self.contacts.contains(&peer) {
+            self.contacts.push(peer);
+        }
+    }
+
+    /// `/verify`、`/invite` 等命令的参数：完整 PeerId 或唯一匹配的结尾部分，省略时为当前私聊对象
+    fn resolve_contact(&self, arg: &str) -> anyhow::Result<PeerId> {
+        let arg = arg.trim();
+        if arg.is_empty() {
+            return match &self.current {
+                Conversation::Direct(peer) => Ok(*peer),
+                Conversation::Room(_) => anyhow::bail!("请指定联系人或先打开私聊"),
+            };
+        }
+        if let Ok(peer) = arg.parse() {
+            return Ok(peer);
+        }
+        let mut matches = self
+            .contacts
+            .iter()
+            .filter(|peer| peer.to_string().ends_with(arg));
+        match (matches.next(), matches.next()) {
+            (Some(peer), None) => Ok(*peer),
+            (Some(_), Some(_)) => anyhow::bail!("{arg} 匹配多个联系人"),
+            (None, _) => anyhow::bail!("未找到联系人 {arg}"),
+        }
+    }
+
+    /// 侧边栏条目：先房间后联系人
+    fn conversations(&self) -> Vec<Conversation> {
+        self.rooms
+            .iter()
+            .cloned()
+            .map(Conversation::Room)
+            .chain(self.contacts.iter().copied().map(Conversation::Direct))
+            .collect()
+    }
+}
+/// 用户主目录下的数据文件，取不到主目录时使用当前目录
+fn data_file(name: &str) -> std::path::PathBuf {
+    std::env::var_os("HOME")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_default()
+        .join(name)
+}
+/// cli 使用的核心配置
+pub fn core_config() -> chat_core::CoreConfig {
+    let db = data_file(".chat_history.db");
+    chat_core::CoreConfig::new(db.to_string_lossy()).with_identity_path(data_file(".chat_identity"))
+}
+/// 口令保护的主密钥文件
+pub fn vault_file() -> std::path::PathBuf {
+    chat_core::vault_path(&data_file(".chat_identity"))
+}
+/// 从终端读取保险库口令，新建保险库时要求输入两次
+pub fn prompt_passphrase(create: bool) -> anyhow::Result<String> {
+    let passphrase = rpassword::prompt_password("保险库口令: ")?;
+    if create {
+        if passphrase.is_empty() {
+            anyhow::bail!("口令不能为空");
+        }
+        if rpassword::prompt_password("再次输入口令: ")? != passphrase {
+            anyhow::bail!("两次输入的口令不一致");
+        }
+    }
+    Ok(passphrase)
+}
+/// 载入会话最近的聊天记录
+async fn load_history(
+    handle: &ChatHandle,
+    conversation: &Conversation,
+) -> anyhow::Result<Vec<String>> {
+    const HISTORY_PAGE: u32 = 50;
+    let storage = handle.storage();
+    let conversation_id = match conversation {
+        Conversation::Room(room) => storage.topic_conversation(room).await?,
+        Conversation::Direct(peer) => storage.direct_conversation(&peer.to_string()).await?,
+    };
+    let total = storage.message_count(conversation_id).await?;
+    let history = storage
+        .messages_in_conversation(conversation_id, total.saturating_sub(HISTORY_PAGE)..total)
+        .await?;
+    Ok(history
+        .iter()
+        .map(|m| {
+            let from = if m.outgoing { "我" } else { m.author.as_str() };
+            format!("[历史] {from}: {}", String::from_utf8_lossy(&m.body))
+        })
+        .collect())
+}
@@ -12,6 +12,22 @@ pub struct Cli {
     ///是否使用终端ui界面
     #[arg(long)]
     no_tui: bool,
+    ///生成新的节点身份（旧 PeerId 作废）后再启动
+    #[arg(long)]
+    rotate_identity: bool,
+    ///用口令保护的文件代替系统密钥环保存主密钥（无图形界面的服务器）；
+    ///已存在保险库文件时自动使用
+    #[arg(long)]
+    vault: bool,
+    ///自愿充当信箱，为不在线的节点代存私聊
+    #[arg(long)]
+    mailbox: bool,
+    ///DHT 引导节点，可重复，如 /ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...
+    #[arg(long = "bootstrap", value_name = "MULTIADDR")]
+    bootstrap: Vec<chat_core::Multiaddr>,
+    ///不用 mDNS 发现局域网内的节点，只经 DHT 发现
+    #[arg(long)]
+    no_mdns: bool,
 }
 
 #[tokio::main]
@@ -23,7 +39,25 @@ async fn main() -> anyhow::Result<()> {
         args.use_json, args.no_tui
     );
     println!("Hello world!\n ");
-    let mut app: App = App::try_init().await.unwrap();
+    let mut cfg = chat_cli::core_config();
+    let vault_exists = chat_cli::vault_file().exists();
+    if args.vault || vault_exists {
+        cfg = cfg.with_vault_passphrase(chat_cli::prompt_passphrase(!vault_exists)?);
+    }
+    if args.mailbox {
+        cfg = cfg.with_mailbox(chat_core::mailbox::MailboxConfig::default());
+    }
+    for addr in args.bootstrap {
+        cfg = cfg.with_bootstrap_node(addr);
+    }
+    if args.no_mdns {
+        cfg = cfg.with_mdns(false);
+    }
+    if args.rotate_identity {
+        let peer_id = chat_core::rotate_identity(&cfg)?;
+        println!("新的节点身份: {peer_id}\n");
+    }
+    let mut app: App = App::try_init(&cfg).await.unwrap();
 
     if std::io::stdout().is_terminal() {
         //此处是面向终端用户的输出界面，除此以外是对shell调用，可精简交互
@@ -1,24 +1,127 @@
 use crossterm::event::{Event, KeyCode, KeyEventKind};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use futures::StreamExt;
-use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::layout::{Constraint, Direction, Flex, Layout, Rect};
 use ratatui::prelude::CrosstermBackend;
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::Text;
-use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Wrap};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap};
 use ratatui::{Frame, Terminal};
 use std::io::stdout;
 use std::time::Duration;
 use tokio::time::interval;
 
-use crate::{App, Focus};
-fn getcontacts(contacts: &mut Vec<ListItem>) {
-    let list = vec!["a".to_string(), "b".to_string()];
-    *contacts = list
+use crate::{App, Conversation, Focus};
+use chat_core::MessageEvent;
+/// 侧边栏条目：已加入的房间和私聊联系人，附带未读数
+fn sidebar_items(app: &App) -> Vec<ListItem<'static>> {
+    app.conversations()
         .iter()
-        .map(|m| ListItem::new(Text::from(m.clone())))
-        .collect::<Vec<ListItem>>()
-        .clone();
+        .map(|conversation| {
+            let marker = if *conversation == app.current {
+                "# "
+            } else {
+                "  "
+            };
+            let text = match app.unread.get(conversation) {
+                Some(n) => format!("{marker}{conversation} ({n})"),
+                None => format!("{marker}{conversation}"),
+            };
+            ListItem::new(Text::from(text))
+        })
+        .collect()
+}
+/// 将核心事件渲染为消息列表中的一行
+fn event_line(event: &MessageEvent) -> String {
+    match event {
+        MessageEvent::MessageReceived {
+            author, payload, ..
+        } => {
+            format!("{author}: {}", payload.text_body())
+        }
+        MessageEvent::DirectMessageReceived {
+            author, payload, ..
+        } => {
+            format!("[私聊] {author}: {}", payload.text_body())
+        }
+        MessageEvent::DirectDelivered { peer, .. } => format!("[私聊] 已送达 {peer}"),
+        MessageEvent::DirectStored { peer, mailbox, .. } => {
+            format!("[私聊] {peer} 不在线，已由 {mailbox} 代存")
+        }
+        MessageEvent::DirectFailed { peer, error, .. } => {
+            format!("[错误] 发送给 {peer} 的私聊失败: {error}")
+        }
+        MessageEvent::PeerDiscovered { peer, .. } => format!("[网络] 发现节点 {peer}"),
+        MessageEvent::PeerExpired { peer, .. } => format!("[网络] 节点已离开 {peer}"),
+        MessageEvent::HistorySynced { room, peer, count } => {
+            format!("[房间] 从 {peer} 同步了 {room} 的 {count} 条消息")
+        }
+        MessageEvent::RoomJoined(room) => format!("[房间] 已加入 {room}"),
+        MessageEvent::RoomLeft(room) => format!("[房间] 已离开 {room}"),
+        MessageEvent::RoomInvited { room, from } => {
+            format!("[房间] {from} 邀请你加入 {room}，加入后用 /invite {from} 接纳")
+        }
+        MessageEvent::ListeningOn(addr) => format!("[网络] 正在监听 {addr}"),
+        MessageEvent::ConnectionEstablished(peer) => format!("[网络] 已连接 {peer}"),
+        MessageEvent::ConnectionClosed { peer, .. } => format!("[网络] 已断开 {peer}"),
+        MessageEvent::KeyRevoked { peer, reason, .. } => {
+            format!("[安全] {peer} 的密钥已撤销: {reason}")
+        }
+        MessageEvent::RevokedMessageDropped { peer, .. } => {
+            format!("[安全] 已丢弃已撤销节点 {peer} 的消息")
+        }
+        MessageEvent::DeviceAdded { user, device, name } => {
+            format!("[设备] {name} ({device}) 已加入账户 {user}")
+        }
+        MessageEvent::IdentityKeyChanged { peer, was_verified } => {
+            let note = if *was_verified {
+                "，原有核验已失效"
+            } else {
+                ""
+            };
+            format!("[安全] {peer} 的身份密钥已变化{note}，请用 /verify 重新核对安全码")
+        }
+        MessageEvent::Error(e) => format!("[错误] {e}"),
+    }
+}
+/// `/verify` 视图：安全码、emoji 与核验状态，浮在消息区上方
+fn render_verification(frame: &mut Frame, area: Rect, verification: &chat_core::Verification) {
+    let number = verification.safety_number.to_string();
+    let (first, second) = number.split_at(number.len() / 2);
+    let status = if verification.verified {
+        "已核验"
+    } else {
+        "未核验"
+    };
+    let lines = vec![
+        format!("联系人: {}", verification.peer),
+        String::new(),
+        "与对方比对以下安全码，一致即说明没有中间人：".to_string(),
+        format!("  {}", first.trim()),
+        format!("  {}", second.trim()),
+        String::new(),
+        format!("  {}", verification.safety_number.emoji().join("  ")),
+        format!("  {}", verification.safety_number.words().join(" ")),
+        String::new(),
+        format!("状态: {status}"),
+        "y 标记为已核验，n 取消核验，其他键关闭".to_string(),
+    ];
+    let [area] = Layout::vertical([Constraint::Length(13)])
+        .flex(Flex::Center)
+        .areas(area);
+    let [area] = Layout::horizontal([Constraint::Percentage(80)])
+        .flex(Flex::Center)
+        .areas(area);
+    let view = Paragraph::new(lines.join("\n"))
+        .block(
+            Block::default()
+                .title(" 安全码 ")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Yellow)),
+        )
+        .wrap(Wrap { trim: false });
+    frame.render_widget(Clear, area);
+    frame.render_widget(view, area);
 }
 fn tui_render(frame: &mut Frame, app: &App) {
     // 创建布局
@@ -75,13 +178,12 @@ fn tui_render(frame: &mut Frame, app: &App) {
         messages_area,
         &mut app.message_list_state.clone(),
     );
-    let mut contacts: Vec<ListItem> = vec![];
-    getcontacts(&mut contacts);
+    let contacts = sidebar_items(app);
 
     let contact_list = List::new(contacts)
         .block(
             Block::default()
-                .title(" 联系人列表 ")
+                .title(" 会话列表 ")
                 .borders(Borders::ALL)
                 .border_style(match app.current_focus {
                     Focus::SidebarArea => Style::default().fg(Color::Yellow),
@@ -128,46 +230,161 @@ fn tui_render(frame: &mut Frame, app: &App) {
     };
     let status_bar = Paragraph::new(status).block(Block::default().borders(Borders::TOP));
     frame.render_widget(status_bar, messages_area);
+
+    if let Some(verification) = &app.verification {
+        render_verification(frame, messages_area, verification);
+    }
 }
-fn handle_event(app: &mut App, event: Event) -> std::io::Result<()> {
+async fn handle_event(app: &mut App, event: Event) -> std::io::Result<()> {
+    if let (Some(verification), Event::Key(key)) = (&app.verification, &event) {
+        if key.kind == KeyEventKind::Press {
+            handle_verification_view(app, verification.peer, key.code).await;
+        }
+        return Ok(());
+    }
     match event {
         //状态机
         Event::Key(key) if key.kind == KeyEventKind::Press => match app.current_focus {
             Focus::Messages => handle_messages_focus(app, key.code),
-            Focus::Input => handle_input_focus(app, key.code),
-            Focus::SidebarArea => handle_sidebar_area_focus(app, key.code),
+            Focus::Input => handle_input_focus(app, key.code).await,
+            Focus::SidebarArea => handle_sidebar_area_focus(app, key.code).await,
         },
         _ => {}
     }
     Ok(())
 }
-fn handle_sidebar_area_focus(_app: &mut App, _key_code: KeyCode) {
-    /*let list_len = app.conta.len();
+async fn handle_sidebar_area_focus(app: &mut App, key_code: KeyCode) {
+    let conversations = app.conversations();
+    let list_len = conversations.len();
     match key_code {
-        KeyCode::Up => {
-            if list_len > 0 {
-                let i = app.message_list_state.selected().unwrap_or(0);
-                app.message_list_state.select(Some(i.saturating_sub(1)));
-            }
+        KeyCode::Up if list_len > 0 => {
+            let i = app.contact_list_state.selected().unwrap_or(0);
+            app.contact_list_state.select(Some(i.saturating_sub(1)));
         }
-        KeyCode::Down => {
-            if list_len > 0 {
-                let i = app.message_list_state.selected().unwrap_or(0);
-                app.message_list_state
-                    .select(Some((i + 1).min(list_len - 1)));
-            }
+        KeyCode::Down if list_len > 0 => {
+            let i = app.contact_list_state.selected().unwrap_or(0);
+            app.contact_list_state
+                .select(Some((i + 1).min(list_len - 1)));
         }
 
         KeyCode::Enter => {
-            // 回复选中的消息
-            if let Some(i) = app.message_list_state.selected() {
-                if let Some(msg) = app.messages.get(i) {
-                    app.input = format!("回复「{}」: ", msg);
-                    app.current_focus = Focus::Input;
+            // 打开选中的房间或私聊
+            if let Some(conversation) = app
+                .contact_list_state
+                .selected()
+                .and_then(|i| conversations.get(i))
+                .cloned()
+            {
+                if let Err(e) = app.switch_conversation(conversation).await {
+                    app.messages.push(format!("[错误] {e}"));
                 }
+                app.current_focus = Focus::Input;
+            }
+        }
+        _ => {}
+    }
+}
+/// 安全码视图中的按键：y/n 设置核验状态，其他键关闭视图
+async fn handle_verification_view(app: &mut App, peer: chat_core::PeerId, key_code: KeyCode) {
+    let verified = match key_code {
+        KeyCode::Char('y') => true,
+        KeyCode::Char('n') => false,
+        _ => {
+            app.verification = None;
+            return;
+        }
+    };
+    match app.handle.set_verified(peer, verified).await {
+        Ok(verification) => app.verification = Some(verification),
+        Err(e) => {
+            app.verification = None;
+            app.messages.push(format!("[错误] {e}"));
+        }
+    }
+}
+/// 处理输入框中的 `/` 命令，返回是否已处理
+async fn handle_command(app: &mut App, line: &str) -> bool {
+    let result = if let Some(room) = line.strip_prefix("/join ") {
+        let room = room.trim().to_string();
+        match app.handle.join_room(room.clone()).await {
+            Ok(_) => app.switch_conversation(Conversation::Room(room)).await,
+            Err(e) => Err(e),
+        }
+    } else if line == "/leave" {
+        let Conversation::Room(room) = app.current.clone() else {
+            app.messages.push("[错误] 私聊会话无需离开".to_string());
+            return true;
+        };
+        match app.handle.leave_room(room).await {
+            Ok(_) => {
+                app.refresh_rooms().await.ok();
+                let next = app
+                    .rooms
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| chat_core::DEFAULT_TOPIC.to_string());
+                app.switch_conversation(Conversation::Room(next)).await
             }
+            Err(e) => Err(e),
         }
-        _ => {}*/
+    } else if let Some(arg) = line.strip_prefix("/invite ") {
+        let Conversation::Room(room) = app.current.clone() else {
+            app.messages.push("[错误] 请先打开房间".to_string());
+            return true;
+        };
+        match app.resolve_contact(arg) {
+            Ok(peer) => app.handle.add_member(room.clone(), peer).await.map(|_| {
+                app.messages
+                    .push(format!("[房间] 已邀请 {peer} 加入 {room}"));
+            }),
+            Err(e) => Err(e),
+        }
+    } else if let Some(arg) = line.strip_prefix("/remove ") {
+        let Conversation::Room(room) = app.current.clone() else {
+            app.messages.push("[错误] 请先打开房间".to_string());
+            return true;
+        };
+        match app.resolve_contact(arg) {
+            Ok(peer) => app
+                .handle
+                .remove_member(room.clone(), peer)
+                .await
+                .map(|removed| {
+                    if removed {
+                        app.messages.push(format!("[房间] 已将 {peer} 移出 {room}"));
+                    }
+                }),
+            Err(e) => Err(e),
+        }
+    } else if line == "/verify" || line.starts_with("/verify ") {
+        match app.resolve_contact(&line["/verify".len()..]) {
+            Ok(peer) => app
+                .handle
+                .verification(peer)
+                .await
+                .map(|verification| app.verification = Some(verification)),
+            Err(e) => Err(e),
+        }
+    } else if line == "/pair" {
+        // 在新设备上输入 `/pair <口令>` 加入本账户
+        app.handle.start_pairing().await.map(|code| {
+            app.messages
+                .push(format!("[设备] 配对口令（5 分钟内有效）: {code}"));
+        })
+    } else if let Some(code) = line.strip_prefix("/pair ") {
+        app.handle.pair_with(code.trim()).await.map(|certificate| {
+            app.messages.push(format!(
+                "[设备] 已加入账户，证书有效至 {}",
+                certificate.expires_at
+            ));
+        })
+    } else {
+        return false;
+    };
+    if let Err(e) = result {
+        app.messages.push(format!("[错误] {e}"));
+    }
+    true
 }
 fn handle_messages_focus(app: &mut App, key_code: KeyCode) {
     let list_len = app.messages.len();
@@ -199,14 +416,26 @@ fn handle_messages_focus(app: &mut App, key_code: KeyCode) {
     }
 }
 
-fn handle_input_focus(app: &mut App, key_code: KeyCode) {
+async fn handle_input_focus(app: &mut App, key_code: KeyCode) {
     match key_code {
         KeyCode::Enter => {
             // 发送消息
-            if !app.input.trim().is_empty() {
+            let line = app.input.trim().to_string();
+            if line.starts_with('/') && handle_command(app, &line).await {
+                app.input.clear();
+            } else if !line.is_empty() {
                 app.messages.push(app.input.clone());
-                app.core.sendmessage(app.input.clone());
-                //send
+                let result = match &app.current {
+                    Conversation::Room(room) => {
+                        app.handle.send(room.clone(), app.input.clone()).await
+                    }
+                    Conversation::Direct(peer) => {
+                        app.handle.send_direct(*peer, app.input.clone()).await
+                    }
+                };
+                if let Err(e) = result {
+                    app.messages.push(format!("[错误] 发送失败: {e}"));
+                }
                 app.input.clear();
                 // 自动滚动到最新消息
                 app.message_list_state.select(Some(app.messages.len() - 1));
@@ -240,23 +469,37 @@ pub async fn tui_run(app: &mut App) -> anyhow::Result<()> {
     let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
     terminal.clear()?;
 
-    let mut rx = app
-        .core
-        .rx_message
-        .take()
-        .ok_or("消息通道问题")
-        .expect("消息通道问题");
+    let mut rx = app.events.take().ok_or(anyhow::anyhow!("消息通道问题"))?;
     let mut tick = interval(Duration::from_millis(16));
 
     loop {
         tokio::select! {
 
-            event = app.core.swarm.select_next_some() => chat_core::swarm_event(event,&mut  app.core),
-
             Some(msg)=rx.recv()=>{
-                let text = msg.data.as_str();
-                 app.messages.push(format!("\n[网络]  {}",
-                 text));
+                 match &msg {
+                     MessageEvent::MessageReceived { topic, .. }
+                         if app.current != Conversation::Room(topic.clone()) =>
+                     {
+                         *app.unread.entry(Conversation::Room(topic.clone())).or_default() += 1;
+                     }
+                     MessageEvent::DirectMessageReceived { from, .. }
+                         if app.current != Conversation::Direct(*from) =>
+                     {
+                         app.add_contact(*from);
+                         *app.unread.entry(Conversation::Direct(*from)).or_default() += 1;
+                     }
+                     MessageEvent::DirectDelivered { .. }
+                     | MessageEvent::HistorySynced { count: 0, .. } => {}
+                     MessageEvent::PeerDiscovered { peer, .. } => {
+                         app.add_contact(*peer);
+                         app.messages.push(event_line(&msg));
+                     }
+                     MessageEvent::RoomJoined(_) | MessageEvent::RoomLeft(_) => {
+                         app.refresh_rooms().await?;
+                         app.messages.push(event_line(&msg));
+                     }
+                     _ => app.messages.push(event_line(&msg)),
+                 }
                  // 自动滚动到最新消息
                 app.message_list_state.select(Some(app.messages.len() - 1));
                  terminal.draw(|frame| tui_render(frame, &app))?;
@@ -266,13 +509,13 @@ pub async fn tui_run(app: &mut App) -> anyhow::Result<()> {
                 if let Event::Key(key)=event
                 &&key.kind == KeyEventKind::Press{
                 match  key.code {
-                    KeyCode::Esc=> break,
+                    KeyCode::Esc if app.verification.is_none() => break,
                     KeyCode::Tab =>app.current_focus= app.current_focus.next_focus(),
                     _=>{
 
                     },
                 }}
-                handle_event(app, event)?;
+                handle_event(app, event).await?;
             }
 
 
@@ -288,6 +531,7 @@ pub async fn tui_run(app: &mut App) -> anyhow::Result<()> {
         }
         terminal.draw(|frame| tui_render(frame, &app))?;
     }
+    app.handle.shutdown().await;
     disable_raw_mode()?;
     terminal.clear()?;
     terminal.show_cursor()?;
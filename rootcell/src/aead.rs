@@ -0,0 +1,75 @@
+//! ChaCha20-Poly1305 认证加密
+//!
+//! 密文格式：`nonce(12) || ciphertext || tag(16)`，nonce 每次随机生成。
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use zeroize::Zeroizing;
+
+use crate::{SecretKey, TrustError};
+
+fn aead_key(key: &SecretKey) -> Result<LessSafeKey, TrustError> {
+    UnboundKey::new(&CHACHA20_POLY1305, key.expose_secret())
+        .map(LessSafeKey::new)
+        .map_err(|_| TrustError::CryptoFailure)
+}
+
+/// 加密并认证 `plaintext`，`aad` 参与认证但不加密
+pub(crate) fn seal(key: &SecretKey, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, TrustError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|_| TrustError::CryptoFailure)?;
+
+    let mut in_out = plaintext.to_vec();
+    aead_key(key)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut in_out,
+        )
+        .map_err(|_| TrustError::CryptoFailure)?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&in_out);
+    Ok(sealed)
+}
+
+/// 解密并校验；密钥错误或数据被篡改时返回 `AccessDenied`
+pub(crate) fn open(
+    key: &SecretKey,
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+    let (nonce, ciphertext) = sealed
+        .split_at_checked(NONCE_LEN)
+        .ok_or(TrustError::CryptoFailure)?;
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| TrustError::CryptoFailure)?;
+
+    let mut in_out = Zeroizing::new(ciphertext.to_vec());
+    let len = aead_key(key)?
+        .open_in_place(nonce, Aad::from(aad), &mut in_out)
+        .map_err(|_| TrustError::AccessDenied)?
+        .len();
+    in_out.truncate(len);
+    Ok(in_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_and_tamper() -> Result<(), TrustError> {
+        let key = SecretKey::generate()?;
+        let sealed = seal(&key, b"aad", b"hello")?;
+        assert_eq!(open(&key, b"aad", &sealed)?.as_slice(), b"hello");
+
+        assert!(matches!(
+            open(&key, b"other", &sealed),
+            Err(TrustError::AccessDenied)
+        ));
+        let wrong = SecretKey::generate()?;
+        assert!(matches!(
+            open(&wrong, b"aad", &sealed),
+            Err(TrustError::AccessDenied)
+        ));
+        Ok(())
+    }
+}
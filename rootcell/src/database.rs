@@ -0,0 +1,134 @@
+//! 聊天记录的静态加密密钥
+//!
+//! 数据库密钥由主密钥按代数派生，轮换时代数 +1，旧代数的密文需重新加密。
+//! 数据库中只保存代数与校验值，校验值不匹配说明主密钥不对，此时返回
+//! [`TrustError::WrongKey`] 而不是逐行解密失败。
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use std::fmt;
+
+use crate::{SecretKey, TrustError, aead, encoding::Reader};
+
+/// 密文格式版本
+const SEALED_VERSION: u8 = 1;
+const DATABASE_CONTEXT: &[u8] = b"rootcell/database";
+const CHECK_CONTEXT: &[u8] = b"rootcell/database/check";
+
+/// 某一代的数据库密钥
+pub struct DatabaseKey {
+    key: SecretKey,
+    generation: u64,
+}
+
+impl DatabaseKey {
+    pub(crate) fn derive(master: &SecretKey, generation: u64) -> Result<Self, TrustError> {
+        Ok(Self {
+            key: master.derive(DATABASE_CONTEXT, generation)?,
+            generation,
+        })
+    }
+
+    /// 密钥代数
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// 可公开保存的校验值，用于在读取数据前确认密钥正确
+    pub fn check_value(&self) -> Result<[u8; 32], TrustError> {
+        Ok(*self.key.derive(CHECK_CONTEXT, 0)?.expose_secret())
+    }
+
+    /// 与保存的校验值比对，不一致时返回 `WrongKey`
+    pub fn verify(&self, check_value: &[u8]) -> Result<(), TrustError> {
+        if bool::from(self.check_value()?.as_slice().ct_eq(check_value)) {
+            Ok(())
+        } else {
+            Err(TrustError::WrongKey(format!(
+                "database key generation {} does not match this database",
+                self.generation
+            )))
+        }
+    }
+
+    /// 加密一个字段，`aad` 绑定所在行（如消息 id），防止密文被挪到其他行
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, TrustError> {
+        let mut sealed = vec![SEALED_VERSION];
+        sealed.extend_from_slice(&self.generation.to_be_bytes());
+        sealed.extend(aead::seal(&self.key, aad, plaintext)?);
+        Ok(sealed)
+    }
+
+    /// 解密字段；由其他代数加密时返回 `WrongKey`，被篡改时返回 `AccessDenied`
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+        let body = match sealed.split_first() {
+            Some((&SEALED_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported database field version {version}"
+                )));
+            }
+            None => return Err(TrustError::CryptoFailure),
+        };
+        let (generation, ciphertext) = body
+            .split_first_chunk::<8>()
+            .ok_or(TrustError::CryptoFailure)?;
+        let generation = u64::from_be_bytes(*generation);
+        if generation != self.generation {
+            return Err(TrustError::WrongKey(format!(
+                "field sealed with database key generation {generation}, have {}",
+                self.generation
+            )));
+        }
+        aead::open(&self.key, aad, ciphertext)
+    }
+}
+
+/// 读取密文使用的密钥代数，不解密
+pub fn sealed_generation(sealed: &[u8]) -> Option<u64> {
+    match sealed.split_first()? {
+        (&SEALED_VERSION, body) => Reader::new(body).array().map(u64::from_be_bytes),
+        _ => None,
+    }
+}
+
+impl fmt::Debug for DatabaseKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseKey")
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generations_and_wrong_master_key() -> Result<(), TrustError> {
+        let master = SecretKey::generate()?;
+        let first = DatabaseKey::derive(&master, 0)?;
+        let sealed = first.seal(b"m1", b"hello")?;
+        assert_eq!(sealed_generation(&sealed), Some(0));
+        assert_eq!(first.open(b"m1", &sealed)?.as_slice(), b"hello");
+        assert!(matches!(
+            first.open(b"m2", &sealed),
+            Err(TrustError::AccessDenied)
+        ));
+
+        let second = DatabaseKey::derive(&master, 1)?;
+        assert!(matches!(
+            second.open(b"m1", &sealed),
+            Err(TrustError::WrongKey(_))
+        ));
+
+        let check = first.check_value()?;
+        first.verify(&check)?;
+        let stranger = DatabaseKey::derive(&SecretKey::generate()?, 0)?;
+        assert!(matches!(
+            stranger.verify(&check),
+            Err(TrustError::WrongKey(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! 多设备账户：用户身份密钥签发的设备证书与配对口令
+//!
+//! 用户身份是独立于设备的 ed25519 密钥，只保存在签发证书的设备上；每台设备仍用
+//! 自己的身份密钥通信，持有一张由用户密钥签名、带有效期的 [`DeviceCertificate`]。
+//! 新设备加入账户时，双方在带外交换一次性配对口令，新设备用 [`pairing_proof`]
+//! 证明自己知道口令，签发方校验后为其签发证书。
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{Identity, TrustError, identity::verify};
+
+/// 设备证书的签名上下文
+const SIGNATURE_CONTEXT: &[u8] = b"rootcell/device-certificate/v1";
+/// 配对证明的 HMAC 上下文
+const PAIRING_CONTEXT: &[u8] = b"rootcell/pairing/v1";
+/// 配对口令的随机字节数
+pub const PAIRING_SECRET_LEN: usize = 5;
+
+/// 用户身份密钥对设备密钥的证书
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCertificate {
+    /// 用户身份公钥（ed25519）
+    pub user_key: [u8; 32],
+    /// 设备身份公钥（ed25519），即设备 PeerId 中内嵌的公钥
+    pub device_key: [u8; 32],
+    /// 设备名称，仅供显示
+    pub device_name: String,
+    /// 签发时间（unix 毫秒）
+    pub issued_at: u64,
+    /// 过期时间（unix 毫秒）
+    pub expires_at: u64,
+    /// 用户密钥对以上字段的签名
+    pub signature: Vec<u8>,
+}
+
+impl DeviceCertificate {
+    /// 用用户身份为设备签发证书，有效期 `[issued_at, expires_at)`
+    pub fn issue(
+        user: &Identity,
+        device_key: [u8; 32],
+        device_name: impl Into<String>,
+        issued_at: u64,
+        expires_at: u64,
+    ) -> Self {
+        let mut certificate = Self {
+            user_key: user.public_key(),
+            device_key,
+            device_name: device_name.into(),
+            issued_at,
+            expires_at,
+            signature: Vec::new(),
+        };
+        certificate.signature = user.sign(&certificate.signed_message()).to_vec();
+        certificate
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        [
+            SIGNATURE_CONTEXT,
+            &self.user_key,
+            &self.device_key,
+            &self.issued_at.to_be_bytes(),
+            &self.expires_at.to_be_bytes(),
+            self.device_name.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// 校验签名与有效期；签名无效返回 `CryptoFailure`，不在有效期内返回 `Expired`
+    pub fn verify(&self, now: u64) -> Result<(), TrustError> {
+        let signature: &[u8; 64] = self
+            .signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| TrustError::CryptoFailure)?;
+        if !verify(&self.user_key, &self.signed_message(), signature) {
+            return Err(TrustError::CryptoFailure);
+        }
+        if now < self.issued_at || now >= self.expires_at {
+            return Err(TrustError::Expired(format!(
+                "device certificate valid from {} until {}",
+                self.issued_at, self.expires_at
+            )));
+        }
+        Ok(())
+    }
+
+    /// 剩余有效期不足总有效期的三分之一时应续签
+    pub fn needs_renewal(&self, now: u64) -> bool {
+        let lifetime = self.expires_at.saturating_sub(self.issued_at);
+        self.expires_at.saturating_sub(now) < lifetime / 3
+    }
+}
+
+/// 生成一次性配对口令的随机部分
+pub fn pairing_secret() -> Result<[u8; PAIRING_SECRET_LEN], TrustError> {
+    let mut secret = [0u8; PAIRING_SECRET_LEN];
+    getrandom::fill(&mut secret).map_err(|_| TrustError::CryptoFailure)?;
+    Ok(secret)
+}
+
+/// 新设备对配对口令的证明，绑定双方设备公钥，防止被转用到其他配对
+pub fn pairing_proof(secret: &[u8], device_key: &[u8; 32], issuer_key: &[u8; 32]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let tag = hmac::sign(&key, &[PAIRING_CONTEXT, device_key, issuer_key].concat());
+    let mut proof = [0u8; 32];
+    proof.copy_from_slice(tag.as_ref());
+    proof
+}
+
+/// 常量时间校验配对证明，不匹配时返回 `AccessDenied`
+pub fn verify_pairing_proof(
+    secret: &[u8],
+    device_key: &[u8; 32],
+    issuer_key: &[u8; 32],
+    proof: &[u8],
+) -> Result<(), TrustError> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(
+        &key,
+        &[PAIRING_CONTEXT, device_key, issuer_key].concat(),
+        proof,
+    )
+    .map_err(|_| TrustError::AccessDenied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_signature_and_expiry() -> Result<(), TrustError> {
+        let user = Identity::generate()?;
+        let device = Identity::generate()?;
+        let certificate = DeviceCertificate::issue(&user, device.public_key(), "laptop", 100, 400);
+        certificate.verify(100)?;
+        assert!(!certificate.needs_renewal(250));
+        assert!(certificate.needs_renewal(350));
+        assert!(matches!(
+            certificate.verify(400),
+            Err(TrustError::Expired(_))
+        ));
+        assert!(matches!(
+            certificate.verify(99),
+            Err(TrustError::Expired(_))
+        ));
+
+        let mut extended = certificate.clone();
+        extended.expires_at = 10_000;
+        assert!(matches!(
+            extended.verify(200),
+            Err(TrustError::CryptoFailure)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn pairing_proof_binds_secret_and_devices() -> Result<(), TrustError> {
+        let secret = pairing_secret()?;
+        let (device, issuer) = ([1u8; 32], [2u8; 32]);
+        let proof = pairing_proof(&secret, &device, &issuer);
+        verify_pairing_proof(&secret, &device, &issuer, &proof)?;
+        assert!(verify_pairing_proof(&secret, &issuer, &device, &proof).is_err());
+        assert!(verify_pairing_proof(&pairing_secret()?, &device, &issuer, &proof).is_err());
+        Ok(())
+    }
+}
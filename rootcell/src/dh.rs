@@ -0,0 +1,55 @@
+//! X25519 密钥对，用于棘轮与预密钥
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use std::fmt;
+
+use crate::TrustError;
+
+/// X25519 密钥对，私钥在 Drop 时清零
+pub struct DhKeyPair {
+    secret: StaticSecret,
+    public: [u8; 32],
+}
+
+impl DhKeyPair {
+    /// 生成新密钥对（CSPRNG）
+    pub fn generate() -> Result<Self, TrustError> {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        getrandom::fill(bytes.as_mut()).map_err(|_| TrustError::CryptoFailure)?;
+        Ok(Self::from_secret_bytes(*bytes))
+    }
+
+    pub(crate) fn from_secret_bytes(bytes: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub(crate) fn secret_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.secret.to_bytes())
+    }
+
+    /// 公钥
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// 与对端公钥协商共享秘密；对端为低阶点时返回 `CryptoFailure`
+    pub(crate) fn agree(&self, their_public: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>, TrustError> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*their_public));
+        if !shared.was_contributory() {
+            return Err(TrustError::CryptoFailure);
+        }
+        Ok(Zeroizing::new(shared.to_bytes()))
+    }
+}
+
+impl fmt::Debug for DhKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DhKeyPair")
+            .field("public", &self.public)
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
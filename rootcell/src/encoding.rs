@@ -0,0 +1,46 @@
+//! 密钥状态的定长二进制编码
+//!
+//! 只用于加密后落盘的内部状态；含密钥的缓冲区由调用方放在 `Zeroizing` 中。
+
+/// 写入可选的 32 字节字段：标记字节 + 内容
+pub(crate) fn write_optional(out: &mut Vec<u8>, value: Option<&[u8; 32]>) {
+    match value {
+        Some(bytes) => {
+            out.push(1);
+            out.extend_from_slice(bytes);
+        }
+        None => out.push(0),
+    }
+}
+
+/// 定长字段读取器
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    /// 是否已读完
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (head, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    pub(crate) fn optional(&mut self) -> Option<Option<[u8; 32]>> {
+        match self.array::<1>()? {
+            [0] => Some(None),
+            [1] => self.array().map(Some),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! 群组发送者密钥（[`KeyType::Group`](crate::KeyType::Group)）
+//!
+//! 每个成员为每个房间持有自己的发送链：链密钥经 [`SecretKey::derive`] 逐条派生
+//! 消息密钥，当前链状态通过加密私聊分发给其他成员。有成员离开时发送方换用新的
+//! 随机链（代数 +1）并只分发给留下的成员，离开者无法解密之后的消息。
+use zeroize::Zeroizing;
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{SecretKey, TrustError, aead, encoding::Reader};
+
+/// 单次最多跳过的消息数
+pub const MAX_SKIP: u32 = 1000;
+/// 暂存的跳过消息密钥上限
+pub const MAX_SKIPPED_KEYS: usize = 200;
+
+/// 群消息格式版本
+const MESSAGE_VERSION: u8 = 1;
+/// 分发格式版本
+const DISTRIBUTION_VERSION: u8 = 1;
+const CHAIN_CONTEXT: &[u8] = b"rootcell/group/chain";
+const MESSAGE_CONTEXT: &[u8] = b"rootcell/group/message";
+/// 消息头长度：代数 + 序号
+const HEADER_LEN: usize = 8;
+
+fn chain_step(chain: &SecretKey) -> Result<(SecretKey, SecretKey), TrustError> {
+    Ok((
+        chain.derive(CHAIN_CONTEXT, 0)?,
+        chain.derive(MESSAGE_CONTEXT, 0)?,
+    ))
+}
+
+fn header(key_id: u32, iteration: u32) -> [u8; HEADER_LEN] {
+    let mut out = [0u8; HEADER_LEN];
+    out[..4].copy_from_slice(&key_id.to_be_bytes());
+    out[4..].copy_from_slice(&iteration.to_be_bytes());
+    out
+}
+
+/// 读取群消息使用的发送者密钥代数，不解密
+pub fn message_key_id(message: &[u8]) -> Option<u32> {
+    match message.split_first()? {
+        (&MESSAGE_VERSION, rest) => Reader::new(rest).u32(),
+        _ => None,
+    }
+}
+
+/// 本地成员在某个房间的发送链
+pub struct GroupSender {
+    key_id: u32,
+    chain: SecretKey,
+    iteration: u32,
+}
+
+impl GroupSender {
+    /// 生成新的发送链，代数随机
+    pub fn generate() -> Result<Self, TrustError> {
+        let mut key_id = [0u8; 4];
+        getrandom::fill(&mut key_id).map_err(|_| TrustError::CryptoFailure)?;
+        Ok(Self {
+            key_id: u32::from_be_bytes(key_id),
+            chain: SecretKey::generate()?,
+            iteration: 0,
+        })
+    }
+
+    /// 成员变化后换用新的随机链，代数 +1
+    pub fn rotate(&self) -> Result<Self, TrustError> {
+        Ok(Self {
+            key_id: self.key_id.wrapping_add(1),
+            chain: SecretKey::generate()?,
+            iteration: 0,
+        })
+    }
+
+    /// 当前代数
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// 导出当前链状态，只能经加密通道发给其他成员
+    pub fn distribution(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(vec![DISTRIBUTION_VERSION]);
+        out.extend_from_slice(&header(self.key_id, self.iteration));
+        out.extend_from_slice(self.chain.expose_secret());
+        out
+    }
+
+    /// 加密一条群消息，`ad` 通常为房间名
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, TrustError> {
+        let (next, message_key) = chain_step(&self.chain)?;
+        let iteration = self
+            .iteration
+            .checked_add(1)
+            .ok_or(TrustError::CryptoFailure)?;
+        let header = header(self.key_id, self.iteration);
+
+        let mut message = vec![MESSAGE_VERSION];
+        message.extend_from_slice(&header);
+        message.extend(aead::seal(
+            &message_key,
+            &[ad, header.as_slice()].concat(),
+            plaintext,
+        )?);
+        self.chain = next;
+        self.iteration = iteration;
+        Ok(message)
+    }
+}
+
+impl fmt::Debug for GroupSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupSender")
+            .field("key_id", &self.key_id)
+            .field("iteration", &self.iteration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// 其他成员在某个房间的接收链
+pub struct GroupReceiver {
+    key_id: u32,
+    chain: SecretKey,
+    iteration: u32,
+    skipped: BTreeMap<u32, SecretKey>,
+}
+
+impl GroupReceiver {
+    /// 由 [`GroupSender::distribution`] 的输出建立
+    pub fn from_distribution(distribution: &[u8]) -> Result<Self, TrustError> {
+        let malformed = || TrustError::Storage("malformed sender key distribution".to_string());
+        let body = match distribution.split_first() {
+            Some((&DISTRIBUTION_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported sender key version {version}"
+                )));
+            }
+            None => return Err(malformed()),
+        };
+        let mut reader = Reader::new(body);
+        let key_id = reader.u32().ok_or_else(malformed)?;
+        let iteration = reader.u32().ok_or_else(malformed)?;
+        let chain = SecretKey::from_bytes(reader.array().ok_or_else(malformed)?);
+        if !reader.is_empty() {
+            return Err(malformed());
+        }
+        Ok(Self {
+            key_id,
+            chain,
+            iteration,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    /// 发送方代数
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// 解密一条群消息，支持乱序；代数不符或被篡改时返回 `AccessDenied`，失败时状态不变
+    pub fn decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+        let (header_bytes, body) = match message.split_first() {
+            Some((&MESSAGE_VERSION, rest)) => rest
+                .split_first_chunk::<HEADER_LEN>()
+                .ok_or(TrustError::CryptoFailure)?,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported group message version {version}"
+                )));
+            }
+            None => return Err(TrustError::CryptoFailure),
+        };
+        let mut reader = Reader::new(header_bytes);
+        let key_id = reader.u32().ok_or(TrustError::CryptoFailure)?;
+        let iteration = reader.u32().ok_or(TrustError::CryptoFailure)?;
+        if key_id != self.key_id {
+            return Err(TrustError::AccessDenied);
+        }
+        let aad = [ad, header_bytes.as_slice()].concat();
+
+        if iteration < self.iteration {
+            let key = self
+                .skipped
+                .get(&iteration)
+                .ok_or(TrustError::CryptoFailure)?;
+            let plaintext = aead::open(key, &aad, body)?;
+            let _ = self.skipped.remove(&iteration);
+            return Ok(plaintext);
+        }
+        if iteration.saturating_sub(self.iteration) > MAX_SKIP {
+            return Err(TrustError::CryptoFailure);
+        }
+
+        let mut skipped = Vec::new();
+        let (mut next, mut message_key) = chain_step(&self.chain)?;
+        for n in self.iteration..iteration {
+            let (following, following_key) = chain_step(&next)?;
+            skipped.push((n, std::mem::replace(&mut message_key, following_key)));
+            next = following;
+        }
+        let plaintext = aead::open(&message_key, &aad, body)?;
+
+        self.skipped.extend(skipped);
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            let _ = self.skipped.pop_first();
+        }
+        self.chain = next;
+        self.iteration = iteration.checked_add(1).ok_or(TrustError::CryptoFailure)?;
+        Ok(plaintext)
+    }
+}
+
+impl fmt::Debug for GroupReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupReceiver")
+            .field("key_id", &self.key_id)
+            .field("iteration", &self.iteration)
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_decrypt_in_any_order() -> Result<(), TrustError> {
+        let mut sender = GroupSender::generate()?;
+        let mut receiver = GroupReceiver::from_distribution(&sender.distribution())?;
+        let messages: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| sender.encrypt(&[i], b"room"))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(message_key_id(&messages[0]), Some(sender.key_id()));
+        for i in [2u8, 0, 3, 1] {
+            assert_eq!(
+                receiver
+                    .decrypt(&messages[usize::from(i)], b"room")?
+                    .as_slice(),
+                [i]
+            );
+        }
+        assert!(receiver.decrypt(&messages[1], b"room").is_err());
+        assert!(receiver.decrypt(&messages[1], b"other room").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn late_joiner_only_sees_later_messages() -> Result<(), TrustError> {
+        let mut sender = GroupSender::generate()?;
+        let before = sender.encrypt(b"before", b"room")?;
+        let mut receiver = GroupReceiver::from_distribution(&sender.distribution())?;
+        let after = sender.encrypt(b"after", b"room")?;
+        assert!(receiver.decrypt(&before, b"room").is_err());
+        assert_eq!(receiver.decrypt(&after, b"room")?.as_slice(), b"after");
+        Ok(())
+    }
+
+    #[test]
+    fn rotation_locks_out_old_members() -> Result<(), TrustError> {
+        let sender = GroupSender::generate()?;
+        let mut departed = GroupReceiver::from_distribution(&sender.distribution())?;
+        let mut sender = sender.rotate()?;
+        let mut remaining = GroupReceiver::from_distribution(&sender.distribution())?;
+        let message = sender.encrypt(b"after rotation", b"room")?;
+        assert!(matches!(
+            departed.decrypt(&message, b"room"),
+            Err(TrustError::AccessDenied)
+        ));
+        assert_eq!(
+            remaining.decrypt(&message, b"room")?.as_slice(),
+            b"after rotation"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! 受保护的共享密钥
+//!
+//! [`SecretKey`] 不实现 `Clone`，需要在多处持有同一密钥时放进 [`SecretBox`]：密钥
+//! 只在堆上保存一份，独占一个 4 KiB 对齐的内存块，并尽量用 `mlock` 锁定在内存中，
+//! 避免被换出到交换分区。克隆 `SecretBox` 只增加引用计数，最后一个引用释放时先
+//! 清零密钥再解除锁定。
+use std::{fmt, sync::Arc};
+
+use crate::{SecretKey, TrustError};
+
+/// 按页对齐的密钥存储，避免与其他数据共用被锁定的页
+///
+/// 页面大于 4 KiB 的平台上仍可能与其他分配共页，解除锁定时会一并解锁
+#[repr(C, align(4096))]
+struct Page {
+    key: SecretKey,
+}
+
+/// 锁定的密钥页，释放顺序见 `Drop`
+struct Guarded {
+    page: Box<Page>,
+    lock: Option<region::LockGuard>,
+}
+
+impl Guarded {
+    /// 分配并锁定全零的内存块
+    ///
+    /// 锁定失败（如超出 `RLIMIT_MEMLOCK`）时仍可使用，见 [`SecretBox::is_locked`]
+    fn alloc() -> Self {
+        let page = Box::new(Page {
+            key: SecretKey::from_bytes([0u8; 32]),
+        });
+        let lock = region::lock(std::ptr::from_ref(&*page), size_of::<Page>()).ok();
+        Self { page, lock }
+    }
+}
+
+/// 先在锁定状态下清零密钥，再解除锁定，最后才释放内存块
+impl Drop for Guarded {
+    fn drop(&mut self) {
+        self.page.key.burn();
+        drop(self.lock.take());
+    }
+}
+
+/// 堆上、内存锁定、引用计数共享的密钥
+///
+/// 密钥总是在锁定后的内存块中生成、派生或复制，不经过栈上的临时副本
+#[derive(Clone)]
+pub struct SecretBox {
+    inner: Arc<Guarded>,
+}
+
+impl SecretBox {
+    /// 在锁定后的内存块中由 `fill` 原位写入密钥
+    fn build(
+        fill: impl FnOnce(&mut [u8; 32]) -> Result<(), TrustError>,
+    ) -> Result<Self, TrustError> {
+        let mut guarded = Guarded::alloc();
+        fill(&mut guarded.page.key.bytes)?;
+        Ok(Self {
+            inner: Arc::new(guarded),
+        })
+    }
+
+    /// 生成新密钥并直接放入受保护内存
+    pub fn generate() -> Result<Self, TrustError> {
+        Self::build(|bytes| getrandom::fill(bytes).map_err(|_| TrustError::CryptoFailure))
+    }
+
+    /// 把密钥复制进受保护内存，原密钥由调用方释放时清零
+    pub fn copy_from(key: &SecretKey) -> Self {
+        Self::copy_from_bytes(key.expose_secret())
+    }
+
+    /// 把原始密钥字节复制进受保护内存，调用方负责清零输入
+    pub fn copy_from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut guarded = Guarded::alloc();
+        guarded.page.key.bytes.copy_from_slice(bytes);
+        Self {
+            inner: Arc::new(guarded),
+        }
+    }
+
+    /// 派生子密钥（HKDF-SHA256），结果直接写入新的受保护内存
+    pub fn derive(&self, context: &[u8], subkey_id: u64) -> Result<Self, TrustError> {
+        let parent = self.expose();
+        Self::build(|bytes| parent.derive_into(context, subkey_id, bytes))
+    }
+
+    /// 借用密钥
+    pub fn expose(&self) -> &SecretKey {
+        &self.inner.page.key
+    }
+
+    /// 密钥所在内存是否已锁定
+    pub fn is_locked(&self) -> bool {
+        self.inner.lock.is_some()
+    }
+}
+
+/// 禁止 Debug 泄露内容
+impl fmt::Debug for SecretBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretBox")
+            .field("key", &"[REDACTED]")
+            .field("locked", &self.is_locked())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_box_holds_one_key() -> Result<(), TrustError> {
+        let secret = SecretBox::generate()?;
+        let shared = secret.clone();
+        assert!(std::ptr::eq(secret.expose(), shared.expose()));
+        assert!(secret.expose().ct_eq(shared.expose()));
+        assert_eq!(std::ptr::from_ref(secret.expose()).align_offset(4096), 0);
+        drop(secret);
+        assert!(shared.expose().is_valid());
+        let bytes = format!("{:?}", shared.expose().expose_secret());
+        assert!(!format!("{shared:?}").contains(&bytes));
+        Ok(())
+    }
+
+    #[test]
+    fn derives_inside_the_box() -> Result<(), TrustError> {
+        let master = SecretBox::generate()?;
+        let derived = master.derive(b"test", 7)?;
+        assert!(derived.expose().ct_eq(&master.expose().derive(b"test", 7)?));
+        let copy = SecretBox::copy_from(derived.expose());
+        assert!(copy.expose().ct_eq(derived.expose()));
+        assert!(!std::ptr::eq(copy.expose(), derived.expose()));
+        Ok(())
+    }
+}
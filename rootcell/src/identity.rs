@@ -0,0 +1,140 @@
+//! 长期身份密钥（ed25519）
+//!
+//! 身份私钥只以加密形式落盘，包装密钥由信任根的主密钥派生。
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
+
+use std::fmt;
+
+use crate::{SecretKey, TrustError, aead, dh::DhKeyPair};
+
+/// 加密文件格式版本
+const SEALED_VERSION: u8 = 1;
+/// 绑定到密文的关联数据，防止与其他用途的密文混用
+const SEALED_AAD: &[u8] = b"rootcell/identity/v1";
+
+/// ed25519 身份密钥对
+///
+/// 私钥在 Drop 时清零，调试输出只显示公钥
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// 生成新身份（CSPRNG）
+    pub fn generate() -> Result<Self, TrustError> {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        getrandom::fill(seed.as_mut()).map_err(|_| TrustError::CryptoFailure)?;
+        Ok(Self {
+            signing: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// 公钥
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    /// 导出私钥种子，供网络层构造同一身份
+    ///
+    /// 返回值离开作用域即清零，调用方不得另行保存
+    pub fn secret_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.signing.to_bytes())
+    }
+
+    /// 签名
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing.sign(message).to_bytes()
+    }
+
+    /// 同一私钥对应的 X25519 私钥，用于密钥协商
+    pub(crate) fn x25519_secret(&self) -> StaticSecret {
+        StaticSecret::from(self.signing.to_scalar_bytes())
+    }
+
+    /// 同一私钥对应的 X25519 密钥对
+    pub(crate) fn dh_key_pair(&self) -> DhKeyPair {
+        DhKeyPair::from_secret_bytes(self.signing.to_scalar_bytes())
+    }
+
+    /// 用包装密钥加密身份私钥
+    pub fn seal(&self, key: &SecretKey) -> Result<Vec<u8>, TrustError> {
+        let mut sealed = vec![SEALED_VERSION];
+        sealed.extend(aead::seal(key, SEALED_AAD, self.secret_bytes().as_ref())?);
+        Ok(sealed)
+    }
+
+    /// 解密 [`Identity::seal`] 的输出；包装密钥错误时返回 `AccessDenied`
+    pub fn open(sealed: &[u8], key: &SecretKey) -> Result<Self, TrustError> {
+        let (version, body) = sealed.split_first().ok_or(TrustError::CryptoFailure)?;
+        if *version != SEALED_VERSION {
+            return Err(TrustError::Storage(format!(
+                "unsupported identity file version {version}"
+            )));
+        }
+        let seed = aead::open(key, SEALED_AAD, body)?;
+        let seed: &[u8; 32] = seed
+            .as_slice()
+            .try_into()
+            .map_err(|_| TrustError::CryptoFailure)?;
+        Ok(Self {
+            signing: SigningKey::from_bytes(seed),
+        })
+    }
+}
+
+/// 校验 ed25519 签名
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(public_key)
+        .map(|key| {
+            key.verify(message, &Signature::from_bytes(signature))
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// 将 ed25519 公钥映射为 X25519 公钥（与 libsodium 的转换一致）
+pub fn x25519_public_key(public_key: &[u8; 32]) -> Result<[u8; 32], TrustError> {
+    VerifyingKey::from_bytes(public_key)
+        .map(|key| key.to_montgomery().to_bytes())
+        .map_err(|_| TrustError::CryptoFailure)
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &self.public_key())
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip() -> Result<(), TrustError> {
+        let key = SecretKey::generate()?;
+        let identity = Identity::generate()?;
+        let opened = Identity::open(&identity.seal(&key)?, &key)?;
+        assert_eq!(opened.public_key(), identity.public_key());
+
+        let wrong = SecretKey::generate()?;
+        assert!(matches!(
+            Identity::open(&identity.seal(&key)?, &wrong),
+            Err(TrustError::AccessDenied)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn sign_and_verify() -> Result<(), TrustError> {
+        let identity = Identity::generate()?;
+        let signature = identity.sign(b"hello");
+        assert!(verify(&identity.public_key(), b"hello", &signature));
+        assert!(!verify(&identity.public_key(), b"hellO", &signature));
+        Ok(())
+    }
+}
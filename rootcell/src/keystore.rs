@@ -0,0 +1,295 @@
+//! 密钥持久化后端
+//!
+//! [`SecurityCore`](crate::SecurityCore) 只通过 [`KeyStore`] 读写主密钥，具体存在
+//! 系统密钥环（[`KeyringStore`]）、口令加密的文件（[`FileKeyStore`]）还是内存
+//! （[`MemoryKeyStore`]，测试用）由调用方决定。
+use ring::pbkdf2;
+use zeroize::Zeroizing;
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{SecretKey, TrustError, aead, encoding::Reader};
+
+/// 密钥条目存储
+///
+/// 条目名只允许 ASCII 字母、数字、`-`、`_` 与 `.`
+pub trait KeyStore: fmt::Debug + Send + Sync {
+    /// 读取条目，不存在时返回 None
+    fn load(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, TrustError>;
+    /// 写入条目，已存在时覆盖
+    fn store(&self, name: &str, secret: &[u8]) -> Result<(), TrustError>;
+    /// 删除条目，不存在时不报错
+    fn delete(&self, name: &str) -> Result<(), TrustError>;
+}
+
+pub(crate) fn check_name(name: &str) -> Result<(), TrustError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(TrustError::Storage(format!(
+            "invalid key entry name {name:?}"
+        )))
+    }
+}
+
+/// 系统密钥环：Linux 上为 Secret Service，并以内核 keyutils 作会话缓存
+#[cfg(not(target_os = "android"))]
+#[derive(Debug)]
+pub struct KeyringStore {
+    service: String,
+}
+
+#[cfg(not(target_os = "android"))]
+impl KeyringStore {
+    /// 使用指定服务名下的条目
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+        }
+    }
+
+    fn entry(&self, name: &str) -> Result<keyring::Entry, TrustError> {
+        check_name(name)?;
+        keyring::Entry::new(&self.service, name).map_err(keyring_error)
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl KeyStore for KeyringStore {
+    fn load(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, TrustError> {
+        match self.entry(name)?.get_secret() {
+            Ok(secret) => Ok(Some(Zeroizing::new(secret))),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+
+    fn store(&self, name: &str, secret: &[u8]) -> Result<(), TrustError> {
+        self.entry(name)?.set_secret(secret).map_err(keyring_error)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), TrustError> {
+        match self.entry(name)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+fn keyring_error(e: keyring::Error) -> TrustError {
+    match e {
+        keyring::Error::NoStorageAccess(_) => TrustError::AccessDenied,
+        keyring::Error::PlatformFailure(e) => {
+            TrustError::Storage(format!("keyring unavailable: {e}"))
+        }
+        other => TrustError::Storage(other.to_string()),
+    }
+}
+
+/// 口令加密的文件存储，用于没有密钥环的环境
+///
+/// 目录下 `params` 保存 KDF 参数与盐，每个条目一个 `<name>.key` 文件；
+/// 口令错误时读取返回 `AccessDenied`
+pub struct FileKeyStore {
+    dir: PathBuf,
+    key: SecretKey,
+}
+
+/// 参数文件格式版本
+const PARAMS_VERSION: u8 = 1;
+const PARAMS_FILE: &str = "params";
+/// PBKDF2-HMAC-SHA256 迭代次数
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const ENTRY_AAD: &[u8] = b"rootcell/keystore/";
+
+impl FileKeyStore {
+    /// 打开目录，首次使用时生成盐并写入参数文件
+    pub fn open(dir: impl Into<PathBuf>, passphrase: &[u8]) -> Result<Self, TrustError> {
+        Self::open_with_iterations(dir.into(), passphrase, PBKDF2_ITERATIONS)
+    }
+
+    /// `iterations` 只在新建时生效，已有目录沿用参数文件中的值
+    pub(crate) fn open_with_iterations(
+        dir: PathBuf,
+        passphrase: &[u8],
+        iterations: u32,
+    ) -> Result<Self, TrustError> {
+        let params_path = dir.join(PARAMS_FILE);
+        let (iterations, salt) = match fs::read(&params_path) {
+            Ok(params) => parse_params(&params)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = [0u8; SALT_LEN];
+                getrandom::fill(&mut salt).map_err(|_| TrustError::CryptoFailure)?;
+                let mut params = vec![PARAMS_VERSION];
+                params.extend_from_slice(&iterations.to_be_bytes());
+                params.extend_from_slice(&salt);
+                crate::write_private_file(&params_path, &params)?;
+                (iterations, salt)
+            }
+            Err(e) => return Err(TrustError::Storage(e.to_string())),
+        };
+        let iterations = NonZeroU32::new(iterations)
+            .ok_or_else(|| TrustError::Storage("zero KDF iterations".to_string()))?;
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            passphrase,
+            bytes.as_mut(),
+        );
+        Ok(Self {
+            dir,
+            key: SecretKey::from_bytes(*bytes),
+        })
+    }
+
+    fn entry_path(&self, name: &str) -> Result<PathBuf, TrustError> {
+        check_name(name)?;
+        Ok(self.dir.join(format!("{name}.key")))
+    }
+
+    /// 存储目录
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+fn parse_params(params: &[u8]) -> Result<(u32, [u8; SALT_LEN]), TrustError> {
+    let malformed = || TrustError::Storage("malformed key store parameters".to_string());
+    match params.split_first() {
+        Some((&PARAMS_VERSION, body)) => {
+            let mut reader = Reader::new(body);
+            let iterations = reader.u32().ok_or_else(malformed)?;
+            let salt = reader.array().ok_or_else(malformed)?;
+            if !reader.is_empty() {
+                return Err(malformed());
+            }
+            Ok((iterations, salt))
+        }
+        Some((version, _)) => Err(TrustError::Storage(format!(
+            "unsupported key store version {version}"
+        ))),
+        None => Err(malformed()),
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, TrustError> {
+        match fs::read(self.entry_path(name)?) {
+            Ok(sealed) => {
+                let aad = [ENTRY_AAD, name.as_bytes()].concat();
+                aead::open(&self.key, &aad, &sealed).map(Some)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+
+    fn store(&self, name: &str, secret: &[u8]) -> Result<(), TrustError> {
+        let path = self.entry_path(name)?;
+        let aad = [ENTRY_AAD, name.as_bytes()].concat();
+        crate::write_private_file(&path, &aead::seal(&self.key, &aad, secret)?)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), TrustError> {
+        match fs::remove_file(self.entry_path(name)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for FileKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileKeyStore")
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+type Entries = HashMap<String, Zeroizing<Vec<u8>>>;
+
+/// 进程内存储，进程退出即丢失（测试用）
+#[derive(Debug, Default)]
+pub struct MemoryKeyStore {
+    entries: Mutex<Entries>,
+}
+
+impl MemoryKeyStore {
+    /// 空存储
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> Result<std::sync::MutexGuard<'_, Entries>, TrustError> {
+        self.entries
+            .lock()
+            .map_err(|_| TrustError::Storage("memory key store poisoned".to_string()))
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn load(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, TrustError> {
+        check_name(name)?;
+        Ok(self.entries()?.get(name).cloned())
+    }
+
+    fn store(&self, name: &str, secret: &[u8]) -> Result<(), TrustError> {
+        check_name(name)?;
+        let _ = self
+            .entries()?
+            .insert(name.to_string(), Zeroizing::new(secret.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), TrustError> {
+        let _ = self.entries()?.remove(name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_needs_the_passphrase() -> Result<(), TrustError> {
+        let dir = tempfile::tempdir().map_err(|e| TrustError::Storage(e.to_string()))?;
+        let store = FileKeyStore::open_with_iterations(dir.path().to_path_buf(), b"pass", 10)?;
+        assert!(store.load("master-key")?.is_none());
+        store.store("master-key", b"secret")?;
+        assert_eq!(
+            store.load("master-key")?.as_deref().map(Vec::as_slice),
+            Some(b"secret".as_slice())
+        );
+        assert!(store.store("../escape", b"x").is_err());
+
+        let reopened = FileKeyStore::open(dir.path(), b"pass")?;
+        assert!(reopened.load("master-key")?.is_some());
+        let wrong = FileKeyStore::open(dir.path(), b"wrong")?;
+        assert!(matches!(
+            wrong.load("master-key"),
+            Err(TrustError::AccessDenied)
+        ));
+
+        store.delete("master-key")?;
+        store.delete("master-key")?;
+        assert!(store.load("master-key")?.is_none());
+        Ok(())
+    }
+}
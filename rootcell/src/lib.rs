@@ -37,12 +37,46 @@
 //! - 最小依赖树
 //! - 常量时间操作
 //!
-use std::fmt;
+use std::{fmt, fs, io::Write, path::Path};
 use subtle::ConstantTimeEq;
 use zeroize::{Zeroize, ZeroizeOnDrop};
+mod aead;
 mod cilent;
+pub mod database;
+pub mod device;
+pub mod dh;
+mod encoding;
+pub mod group;
+pub mod guarded;
+pub mod identity;
+pub mod keystore;
 mod platform;
+pub mod prekey;
+pub mod ratchet;
+pub mod revocation;
+pub mod safety;
 mod server;
+pub mod session;
+#[cfg(all(feature = "tpm", not(any(target_os = "ios", target_os = "android"))))]
+pub mod tpm;
+pub mod vault;
+pub use database::DatabaseKey;
+pub use device::DeviceCertificate;
+pub use dh::DhKeyPair;
+pub use group::{GroupReceiver, GroupSender};
+pub use guarded::SecretBox;
+pub use identity::Identity;
+#[cfg(not(target_os = "android"))]
+pub use keystore::KeyringStore;
+pub use keystore::{FileKeyStore, KeyStore, MemoryKeyStore};
+pub use prekey::{PreKeyBundle, PreKeyStore, PublishedBundle};
+pub use ratchet::Ratchet;
+pub use revocation::{Revocation, RevocationKind, RevocationList};
+pub use safety::SafetyNumber;
+pub use session::Session;
+#[cfg(all(feature = "tpm", not(any(target_os = "ios", target_os = "android"))))]
+pub use tpm::TpmKeyStore;
+pub use vault::{Kdf, Vault};
 ///! 信任根错误类型
 #[derive(thiserror::Error, Debug)]
 pub enum TrustError {
@@ -55,35 +89,166 @@ pub enum TrustError {
     ///密钥已撤销:{0}
     #[error("KeyRevoked:{0}")]
     KeyRevoked(String),
+    ///证书等有时效的凭据不在有效期内:{0}
+    #[error("Expired:{0}")]
+    Expired(String),
     ///加密操作失败
     #[error("CryptoFailure")]
     CryptoFailure,
+    ///密钥与数据不匹配，如换了主密钥后打开旧数据库:{0}
+    #[error("WrongKey:{0}")]
+    WrongKey(String),
     ///存储错误: {0}
     #[error(" Storage error:{0}")]
     Storage(String),
 }
-///!安全核心模块
-struct SecurityCore {
-    //密钥
-    key: SecretKey,
-    //消息缓存
-    message_cache: Vec<u8>,
+/// 安全核心：持有主密钥，其余密钥均由它派生或包装
+///
+/// 主密钥保存在 [`KeyStore`] 中（默认为系统密钥环），首次启动时生成
+#[derive(Debug)]
+pub struct SecurityCore {
+    //密钥，放在锁定内存中
+    key: SecretBox,
 }
+/// 密钥环中主密钥条目的服务名
+const KEYRING_SERVICE: &str = "mychat";
+/// 主密钥的条目名
+const MASTER_KEY_ENTRY: &str = "master-key";
 impl SecurityCore {
-    fn try_init() -> Result<Self, TrustError> {
-        let key;
-        let newkey = SecretKey::generate();
-        if let Err(e) = newkey {
-            return Err(e);
-        } else {
-            key = newkey.unwrap();
+    /// 从系统密钥环载入主密钥，不存在时生成并保存
+    ///
+    /// 平台没有可用的密钥环时返回 `HardwareUnavailable`
+    pub fn try_init() -> Result<Self, TrustError> {
+        Self::from_store(platform::default_store(KEYRING_SERVICE)?.as_ref())
+    }
+
+    /// 从指定存储载入主密钥，不存在时生成并保存
+    pub fn from_store(store: &dyn KeyStore) -> Result<Self, TrustError> {
+        match store.load(MASTER_KEY_ENTRY)? {
+            Some(secret) => {
+                let bytes: &[u8; 32] = secret.as_slice().try_into().map_err(|_| {
+                    TrustError::Storage("malformed master key in key store".to_string())
+                })?;
+                Ok(Self {
+                    key: SecretBox::copy_from_bytes(bytes),
+                })
+            }
+            None => {
+                let key = SecretBox::generate()?;
+                store.store(MASTER_KEY_ENTRY, key.expose().expose_secret())?;
+                Ok(Self { key })
+            }
         }
+    }
+
+    /// 从口令保护的保险库文件载入主密钥，文件不存在时生成新主密钥并保存
+    ///
+    /// 口令错误时返回 `AccessDenied`
+    pub fn from_vault(path: &Path, passphrase: &[u8]) -> Result<Self, TrustError> {
+        let vault = match Vault::load(path)? {
+            Some(vault) => vault,
+            None => {
+                let vault = Vault::create(passphrase)?;
+                vault.save(path)?;
+                vault
+            }
+        };
+        Ok(Self::with_master_key(vault.unlock(passphrase)?))
+    }
+
+    /// 使用调用方提供的主密钥（测试或外部密钥来源）
+    pub fn with_master_key(key: SecretKey) -> Self {
+        Self {
+            key: SecretBox::copy_from(&key),
+        }
+    }
+
+    /// 读取加密保存的身份，文件不存在时生成新身份
+    pub fn load_or_create_identity(&self, path: &Path) -> Result<Identity, TrustError> {
+        match self.load_identity(path)? {
+            Some(identity) => Ok(identity),
+            None => self.rotate_identity(path),
+        }
+    }
+
+    /// 读取加密保存的身份，文件不存在时返回 None
+    pub fn load_identity(&self, path: &Path) -> Result<Option<Identity>, TrustError> {
+        match fs::read(path) {
+            Ok(sealed) => Identity::open(&sealed, &self.identity_wrapping_key()?).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+
+    /// 生成新身份并覆盖保存，旧身份随之作废
+    pub fn rotate_identity(&self, path: &Path) -> Result<Identity, TrustError> {
+        let identity = Identity::generate()?;
+        write_private_file(path, &identity.seal(&self.identity_wrapping_key()?)?)?;
+        Ok(identity)
+    }
+
+    /// 读取加密保存的预密钥，文件不存在时生成 `count` 个一次性预密钥并保存
+    pub fn load_or_create_prekeys(
+        &self,
+        path: &Path,
+        count: u32,
+    ) -> Result<PreKeyStore, TrustError> {
+        match fs::read(path) {
+            Ok(sealed) => PreKeyStore::open(&sealed, &self.prekey_wrapping_key()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let store = PreKeyStore::generate(count)?;
+                self.save_prekeys(path, &store)?;
+                Ok(store)
+            }
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+
+    /// 保存预密钥，发布或消耗一次性预密钥后调用
+    pub fn save_prekeys(&self, path: &Path, store: &PreKeyStore) -> Result<(), TrustError> {
+        write_private_file(path, &store.seal(&self.prekey_wrapping_key()?)?)
+    }
+
+    /// 第 `generation` 代聊天记录加密密钥，轮换时由调用方递增代数
+    pub fn database_key(&self, generation: u64) -> Result<DatabaseKey, TrustError> {
+        DatabaseKey::derive(self.key.expose(), generation)
+    }
+
+    /// 加密持久化棘轮会话状态的密钥，见 [`Ratchet::seal_state`]
+    pub fn session_state_key(&self) -> Result<SecretBox, TrustError> {
+        self.key.derive(b"rootcell/session-state", 0)
+    }
+
+    fn prekey_wrapping_key(&self) -> Result<SecretKey, TrustError> {
+        self.key.expose().derive(b"rootcell/prekey-file", 0)
+    }
+
+    fn identity_wrapping_key(&self) -> Result<SecretKey, TrustError> {
+        self.key.expose().derive(b"rootcell/identity-file", 0)
+    }
+}
 
-        Ok(Self {
-            key: key,
-            message_cache: Vec::new(),
-        })
+/// 原子写入仅所有者可读写的文件
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), TrustError> {
+    let storage_error = |e: std::io::Error| TrustError::Storage(e.to_string());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(storage_error)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+
+    let mut options = fs::OpenOptions::new();
+    let _ = options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        let _ = options.mode(0o600);
     }
+    let mut file = options.open(&tmp).map_err(storage_error)?;
+    file.write_all(contents).map_err(storage_error)?;
+    file.sync_all().map_err(storage_error)?;
+    fs::rename(&tmp, path).map_err(storage_error)
 }
 ///密钥类型
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
@@ -102,7 +267,7 @@ pub enum KeyType {
 /// 32字节对称密钥（AES-256/ChaCha20-Poly1305）
 ///
 /// 安全特性：
-/// - 禁止 Clone/Copy（防止意外内存复制）
+/// - 不实现 Clone/Copy（防止意外内存复制），需要共享时放入 [`SecretBox`]
 /// - Drop 时自动清零（ZeroizeOnDrop）
 /// - 调试输出掩盖（防止日志泄露）
 /// - 常量时间比较（防时序攻击）
@@ -116,7 +281,7 @@ pub struct SecretKey {
     version: u64,
 }
 
-/// 密钥使用一次的包装器（类似 move 语义）
+/// 只能使用一次的密钥：取出内部密钥会消费包装器，编译期即可阻止重复使用
 #[derive(Debug)]
 pub struct OneTimeKey(SecretKey);
 impl SecretKey {
@@ -145,24 +310,33 @@ impl SecretKey {
 
     /// 派生子密钥（HKDF-SHA256）
     pub fn derive(&self, context: &[u8], subkey_id: u64) -> Result<Self, TrustError> {
+        let mut key = Self {
+            bytes: [0u8; 32],
+            version: 1,
+        };
+        self.derive_into(context, subkey_id, &mut key.bytes)?;
+        Ok(key)
+    }
+
+    /// 派生子密钥并直接写入 `okm`，供 [`SecretBox`] 在受保护内存中派生
+    pub(crate) fn derive_into(
+        &self,
+        context: &[u8],
+        subkey_id: u64,
+        okm: &mut [u8; 32],
+    ) -> Result<(), TrustError> {
         use ring::hkdf::{HKDF_SHA256, Salt};
 
         let salt = Salt::new(HKDF_SHA256, &self.bytes);
         let prk = salt.extract(&[]);
 
         let info = [context, &subkey_id.to_be_bytes()].concat();
-        let mut okm = [0u8; 32];
 
         // ring 0.17：expand -> Okm -> fill
         prk.expand(&[&info], HKDF_SHA256)
             .map_err(|_| TrustError::CryptoFailure)?
-            .fill(&mut okm)
-            .map_err(|_| TrustError::CryptoFailure)?;
-
-        Ok(Self {
-            bytes: okm,
-            version: 1,
-        })
+            .fill(okm)
+            .map_err(|_| TrustError::CryptoFailure)
     }
 
     /// 常量时间相等性比较
@@ -182,15 +356,6 @@ impl SecretKey {
     }
 }
 
-/// 禁止 Clone（防止隐式内存复制）
-impl Clone for SecretKey {
-    fn clone(&self) -> Self {
-        panic!(
-            "SecretKey cannot be cloned. Use key derivation or Arc<Mutex<SecretKey>> if sharing is absolutely necessary"
-        );
-    }
-}
-
 /// 禁止 Debug 泄露内容
 impl fmt::Debug for SecretKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -233,14 +398,84 @@ impl<'de> serde::Deserialize<'de> for SecretKey {
 }
 
 impl OneTimeKey {
-    /// 消费密钥，获取内部值（只能调用一次）
-    pub fn into_inner(mut self) -> SecretKey {
-        std::mem::replace(&mut self.0, SecretKey::generate().expect("CSPRNG failed"))
+    /// 包装已有密钥
+    pub fn new(key: SecretKey) -> Self {
+        Self(key)
+    }
+
+    /// 生成新的一次性密钥
+    pub fn generate() -> Result<Self, TrustError> {
+        SecretKey::generate().map(Self)
+    }
+
+    /// 消费包装器取出密钥；密钥已销毁（见 [`SecretKey::burn`]）时返回 `CryptoFailure`
+    ///
+    /// 未取出的密钥随包装器释放时由 [`SecretKey`] 自身清零
+    pub fn into_inner(self) -> Result<SecretKey, TrustError> {
+        let key = self.0;
+        if key.is_valid() {
+            Ok(key)
+        } else {
+            Err(TrustError::CryptoFailure)
+        }
     }
 }
 
-impl Drop for OneTimeKey {
-    fn drop(&mut self) {
-        self.0.burn();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_persists_until_rotated() -> Result<(), TrustError> {
+        let dir = tempfile::tempdir().map_err(|e| TrustError::Storage(e.to_string()))?;
+        let path = dir.path().join("identity.key");
+        let core = SecurityCore::with_master_key(SecretKey::generate()?);
+
+        let first = core.load_or_create_identity(&path)?;
+        let again = core.load_or_create_identity(&path)?;
+        assert_eq!(first.public_key(), again.public_key());
+
+        let rotated = core.rotate_identity(&path)?;
+        assert_ne!(rotated.public_key(), first.public_key());
+        assert_eq!(
+            core.load_or_create_identity(&path)?.public_key(),
+            rotated.public_key()
+        );
+
+        let other = SecurityCore::with_master_key(SecretKey::generate()?);
+        assert!(matches!(
+            other.load_or_create_identity(&path),
+            Err(TrustError::AccessDenied)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn one_time_key_is_consumed_once() -> Result<(), TrustError> {
+        let key = OneTimeKey::generate()?;
+        assert!(key.into_inner()?.is_valid());
+
+        let mut burned = SecretKey::generate()?;
+        burned.burn();
+        assert!(matches!(
+            OneTimeKey::new(burned).into_inner(),
+            Err(TrustError::CryptoFailure)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn master_key_persists_in_key_store() -> Result<(), TrustError> {
+        let store = MemoryKeyStore::new();
+        let first = SecurityCore::from_store(&store)?;
+        let again = SecurityCore::from_store(&store)?;
+        assert!(first.key.expose().ct_eq(again.key.expose()));
+
+        store.store(MASTER_KEY_ENTRY, b"short")?;
+        assert!(matches!(
+            SecurityCore::from_store(&store),
+            Err(TrustError::Storage(_))
+        ));
+        Ok(())
     }
 }
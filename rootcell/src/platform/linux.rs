@@ -0,0 +1,20 @@
+//! Linux：主密钥由 Secret Service（经 D-Bus）持久保存，内核 keyutils 作会话缓存
+use std::path::Path;
+
+use crate::{TrustError, keystore::KeyringStore};
+
+/// 是否存在 D-Bus 会话总线；无图形会话的服务器上通常没有
+fn session_bus_available() -> bool {
+    std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some()
+        || std::env::var_os("XDG_RUNTIME_DIR")
+            .is_some_and(|dir| Path::new(&dir).join("bus").exists())
+}
+
+/// 没有会话总线时 Secret Service 不可用，返回 `HardwareUnavailable`，
+/// 调用方应改用口令保护的文件存储
+pub(crate) fn keyring_store(service: &str) -> Result<KeyringStore, TrustError> {
+    if !session_bus_available() {
+        return Err(TrustError::HardwareUnavailable);
+    }
+    Ok(KeyringStore::new(service))
+}
@@ -1,3 +1,6 @@
+//! 平台相关的密钥存储选择
+use crate::{KeyStore, TrustError};
+
 #[cfg(target_os = "android")]
 pub mod android;
 #[cfg(target_os = "ios")]
@@ -8,3 +11,21 @@ mod linux;
 mod macos;
 #[cfg(target_os = "windows")]
 mod windows;
+
+/// 当前平台默认的主密钥存储
+#[cfg(target_os = "linux")]
+pub(crate) fn default_store(service: &str) -> Result<Box<dyn KeyStore>, TrustError> {
+    Ok(Box::new(linux::keyring_store(service)?))
+}
+
+/// 当前平台默认的主密钥存储
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn default_store(service: &str) -> Result<Box<dyn KeyStore>, TrustError> {
+    Ok(Box::new(crate::KeyringStore::new(service)))
+}
+
+/// Android 的硬件密钥库尚未接入
+#[cfg(target_os = "android")]
+pub(crate) fn default_store(_service: &str) -> Result<Box<dyn KeyStore>, TrustError> {
+    Err(TrustError::HardwareUnavailable)
+}
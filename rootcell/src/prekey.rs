@@ -0,0 +1,434 @@
+//! X3DH 风格的异步密钥协商与预密钥包
+//!
+//! 接收方预先发布预密钥包：身份公钥、经身份密钥签名的预密钥和一批一次性预密钥。
+//! 发起方取得其中一个一次性预密钥即可离线建立会话，得到的共享秘密直接用于
+//! 初始化 [`Ratchet`]。每个一次性预密钥只发布一次、只能被接受一次。
+use ring::hkdf::{HKDF_SHA256, Salt};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    Identity, Ratchet, SecretKey, TrustError, aead,
+    dh::DhKeyPair,
+    encoding::Reader,
+    identity::{verify, x25519_public_key},
+};
+
+/// 签名预密钥的签名上下文
+const SIGNATURE_CONTEXT: &[u8] = b"rootcell/signed-prekey/v1";
+/// X3DH 输出的 HKDF info
+const X3DH_INFO: &[u8] = b"rootcell/x3dh/v1";
+/// 加密状态格式版本
+const STORE_VERSION: u8 = 1;
+const STORE_AAD: &[u8] = b"rootcell/prekey-store/v1";
+
+/// 一次性预密钥公钥
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OneTimePreKey {
+    /// 编号，接受会话时据此找到私钥
+    pub id: u32,
+    /// X25519 公钥
+    pub key: [u8; 32],
+}
+
+/// 发布给其他节点缓存的预密钥包，可包含多个一次性预密钥
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedBundle {
+    /// ed25519 身份公钥
+    pub identity_key: [u8; 32],
+    /// 签名预密钥编号
+    pub signed_prekey_id: u32,
+    /// 签名预密钥（X25519）
+    pub signed_prekey: [u8; 32],
+    /// 身份密钥对签名预密钥的签名
+    pub signature: Vec<u8>,
+    /// 尚未分发的一次性预密钥
+    pub one_time_prekeys: Vec<OneTimePreKey>,
+}
+
+/// 分发给单个发起方的预密钥包，至多含一个一次性预密钥
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreKeyBundle {
+    /// ed25519 身份公钥
+    pub identity_key: [u8; 32],
+    /// 签名预密钥编号
+    pub signed_prekey_id: u32,
+    /// 签名预密钥（X25519）
+    pub signed_prekey: [u8; 32],
+    /// 身份密钥对签名预密钥的签名
+    pub signature: Vec<u8>,
+    /// 一次性预密钥，缓存耗尽时为 None（前向安全性较弱）
+    pub one_time_prekey: Option<OneTimePreKey>,
+}
+
+/// 发起方随第一条消息发送的协商信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct X3dhHeader {
+    /// 发起方 ed25519 身份公钥
+    pub identity_key: [u8; 32],
+    /// 发起方临时公钥（X25519）
+    pub ephemeral_key: [u8; 32],
+    /// 使用的签名预密钥编号
+    pub signed_prekey_id: u32,
+    /// 使用的一次性预密钥编号
+    pub one_time_prekey_id: Option<u32>,
+}
+
+fn signed_message(id: u32, key: &[u8; 32]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, &id.to_be_bytes(), key].concat()
+}
+
+fn verify_signed_prekey(
+    identity_key: &[u8; 32],
+    id: u32,
+    key: &[u8; 32],
+    signature: &[u8],
+) -> Result<(), TrustError> {
+    let signature: &[u8; 64] = signature
+        .try_into()
+        .map_err(|_| TrustError::CryptoFailure)?;
+    if verify(identity_key, &signed_message(id, key), signature) {
+        Ok(())
+    } else {
+        Err(TrustError::CryptoFailure)
+    }
+}
+
+impl PublishedBundle {
+    /// 校验签名预密钥的签名
+    pub fn verify(&self) -> Result<(), TrustError> {
+        verify_signed_prekey(
+            &self.identity_key,
+            self.signed_prekey_id,
+            &self.signed_prekey,
+            &self.signature,
+        )
+    }
+
+    /// 取出一个一次性预密钥组成分发用的包，取出的预密钥不会再次分发
+    pub fn take_bundle(&mut self) -> PreKeyBundle {
+        PreKeyBundle {
+            identity_key: self.identity_key,
+            signed_prekey_id: self.signed_prekey_id,
+            signed_prekey: self.signed_prekey,
+            signature: self.signature.clone(),
+            one_time_prekey: self.one_time_prekeys.pop(),
+        }
+    }
+}
+
+impl PreKeyBundle {
+    /// 校验签名预密钥的签名
+    pub fn verify(&self) -> Result<(), TrustError> {
+        verify_signed_prekey(
+            &self.identity_key,
+            self.signed_prekey_id,
+            &self.signed_prekey,
+            &self.signature,
+        )
+    }
+}
+
+/// 本地预密钥私钥及一次性预密钥的发布、消耗记录
+pub struct PreKeyStore {
+    signed_prekey_id: u32,
+    signed_prekey: DhKeyPair,
+    one_time: BTreeMap<u32, DhKeyPair>,
+    ///已发布过的一次性预密钥，不再重复发布
+    published: BTreeSet<u32>,
+    next_id: u32,
+}
+
+impl PreKeyStore {
+    /// 生成签名预密钥和 `count` 个一次性预密钥
+    pub fn generate(count: u32) -> Result<Self, TrustError> {
+        let mut store = Self {
+            signed_prekey_id: 1,
+            signed_prekey: DhKeyPair::generate()?,
+            one_time: BTreeMap::new(),
+            published: BTreeSet::new(),
+            next_id: 1,
+        };
+        store.replenish(count)?;
+        Ok(store)
+    }
+
+    /// 追加 `count` 个一次性预密钥
+    pub fn replenish(&mut self, count: u32) -> Result<(), TrustError> {
+        for _ in 0..count {
+            let id = self.next_id;
+            self.next_id = id.checked_add(1).ok_or(TrustError::CryptoFailure)?;
+            let _ = self.one_time.insert(id, DhKeyPair::generate()?);
+        }
+        Ok(())
+    }
+
+    /// 尚未发布的一次性预密钥数量
+    pub fn unpublished(&self) -> usize {
+        self.one_time
+            .keys()
+            .filter(|id| !self.published.contains(id))
+            .count()
+    }
+
+    /// 生成发布用的预密钥包，附带至多 `count` 个从未发布过的一次性预密钥
+    ///
+    /// 返回的一次性预密钥随即标记为已发布，调用方应随后持久化本存储
+    pub fn publish(&mut self, identity: &Identity, count: usize) -> PublishedBundle {
+        let fresh: Vec<u32> = self
+            .one_time
+            .keys()
+            .copied()
+            .filter(|id| !self.published.contains(id))
+            .take(count)
+            .collect();
+        let one_time_prekeys = fresh
+            .iter()
+            .filter_map(|id| {
+                let key = self.one_time.get(id)?.public_key();
+                Some(OneTimePreKey { id: *id, key })
+            })
+            .collect();
+        self.published.extend(fresh);
+        PublishedBundle {
+            identity_key: identity.public_key(),
+            signed_prekey_id: self.signed_prekey_id,
+            signed_prekey: self.signed_prekey.public_key(),
+            signature: identity
+                .sign(&signed_message(
+                    self.signed_prekey_id,
+                    &self.signed_prekey.public_key(),
+                ))
+                .to_vec(),
+            one_time_prekeys,
+        }
+    }
+
+    /// 作为接收方接受发起方的协商，消耗对应的一次性预密钥
+    ///
+    /// 一次性预密钥已被使用时返回 `KeyRevoked`
+    pub fn accept(
+        &mut self,
+        identity: &Identity,
+        header: &X3dhHeader,
+    ) -> Result<Ratchet, TrustError> {
+        if header.signed_prekey_id != self.signed_prekey_id {
+            return Err(TrustError::KeyRevoked(format!(
+                "signed pre-key {} is no longer available",
+                header.signed_prekey_id
+            )));
+        }
+        let one_time = match header.one_time_prekey_id {
+            Some(id) => Some(self.one_time.get(&id).ok_or_else(|| {
+                TrustError::KeyRevoked(format!("one-time pre-key {id} was already used"))
+            })?),
+            None => None,
+        };
+        let remote_identity = x25519_public_key(&header.identity_key)?;
+        let mut ikm = Zeroizing::new(vec![0xFF; 32]);
+        ikm.extend_from_slice(self.signed_prekey.agree(&remote_identity)?.as_ref());
+        ikm.extend_from_slice(
+            identity
+                .dh_key_pair()
+                .agree(&header.ephemeral_key)?
+                .as_ref(),
+        );
+        ikm.extend_from_slice(self.signed_prekey.agree(&header.ephemeral_key)?.as_ref());
+        if let Some(key) = one_time {
+            ikm.extend_from_slice(key.agree(&header.ephemeral_key)?.as_ref());
+        }
+        let shared = kdf_x3dh(&ikm)?;
+
+        if let Some(id) = header.one_time_prekey_id {
+            let _ = self.one_time.remove(&id);
+            let _ = self.published.remove(&id);
+        }
+        let signed_prekey = DhKeyPair::from_secret_bytes(*self.signed_prekey.secret_bytes());
+        Ok(Ratchet::respond(shared, signed_prekey))
+    }
+
+    /// 用存储密钥加密导出
+    pub fn seal(&self, key: &SecretKey) -> Result<Vec<u8>, TrustError> {
+        let mut out = Zeroizing::new(Vec::new());
+        out.extend_from_slice(&self.signed_prekey_id.to_be_bytes());
+        out.extend_from_slice(self.signed_prekey.secret_bytes().as_ref());
+        out.extend_from_slice(&self.next_id.to_be_bytes());
+        let count = u32::try_from(self.one_time.len()).map_err(|_| TrustError::CryptoFailure)?;
+        out.extend_from_slice(&count.to_be_bytes());
+        for (id, pair) in &self.one_time {
+            out.extend_from_slice(&id.to_be_bytes());
+            out.push(u8::from(self.published.contains(id)));
+            out.extend_from_slice(pair.secret_bytes().as_ref());
+        }
+
+        let mut sealed = vec![STORE_VERSION];
+        sealed.extend(aead::seal(key, STORE_AAD, &out)?);
+        Ok(sealed)
+    }
+
+    /// 解密 [`PreKeyStore::seal`] 的输出；密钥错误时返回 `AccessDenied`
+    pub fn open(sealed: &[u8], key: &SecretKey) -> Result<Self, TrustError> {
+        let body = match sealed.split_first() {
+            Some((&STORE_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported pre-key store version {version}"
+                )));
+            }
+            None => return Err(TrustError::CryptoFailure),
+        };
+        let plain = aead::open(key, STORE_AAD, body)?;
+        let mut reader = Reader::new(&plain);
+        let malformed = || TrustError::Storage("malformed pre-key store".to_string());
+
+        let mut store = Self {
+            signed_prekey_id: reader.u32().ok_or_else(malformed)?,
+            signed_prekey: DhKeyPair::from_secret_bytes(reader.array().ok_or_else(malformed)?),
+            one_time: BTreeMap::new(),
+            published: BTreeSet::new(),
+            next_id: reader.u32().ok_or_else(malformed)?,
+        };
+        for _ in 0..reader.u32().ok_or_else(malformed)? {
+            let id = reader.u32().ok_or_else(malformed)?;
+            let [published] = reader.array().ok_or_else(malformed)?;
+            let pair = DhKeyPair::from_secret_bytes(reader.array().ok_or_else(malformed)?);
+            let _ = store.one_time.insert(id, pair);
+            if published != 0 {
+                let _ = store.published.insert(id);
+            }
+        }
+        if !reader.is_empty() {
+            return Err(malformed());
+        }
+        Ok(store)
+    }
+}
+
+impl fmt::Debug for PreKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreKeyStore")
+            .field("signed_prekey_id", &self.signed_prekey_id)
+            .field("one_time", &self.one_time.len())
+            .field("published", &self.published.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// 作为发起方用对方的预密钥包建立会话
+///
+/// 返回需随第一条消息发送的协商信息，以及已可加密的棘轮
+pub fn initiate(
+    identity: &Identity,
+    bundle: &PreKeyBundle,
+) -> Result<(X3dhHeader, Ratchet), TrustError> {
+    bundle.verify()?;
+    let ephemeral = DhKeyPair::generate()?;
+    let remote_identity = x25519_public_key(&bundle.identity_key)?;
+    let mut ikm = Zeroizing::new(vec![0xFF; 32]);
+    ikm.extend_from_slice(
+        identity
+            .dh_key_pair()
+            .agree(&bundle.signed_prekey)?
+            .as_ref(),
+    );
+    ikm.extend_from_slice(ephemeral.agree(&remote_identity)?.as_ref());
+    ikm.extend_from_slice(ephemeral.agree(&bundle.signed_prekey)?.as_ref());
+    if let Some(one_time) = &bundle.one_time_prekey {
+        ikm.extend_from_slice(ephemeral.agree(&one_time.key)?.as_ref());
+    }
+    let shared = kdf_x3dh(&ikm)?;
+
+    let header = X3dhHeader {
+        identity_key: identity.public_key(),
+        ephemeral_key: ephemeral.public_key(),
+        signed_prekey_id: bundle.signed_prekey_id,
+        one_time_prekey_id: bundle.one_time_prekey.map(|k| k.id),
+    };
+    Ok((header, Ratchet::initiate(shared, &bundle.signed_prekey)?))
+}
+
+/// 棘轮消息的关联数据：发起方与接收方的身份公钥
+pub fn associated_data(initiator: &[u8; 32], responder: &[u8; 32]) -> Vec<u8> {
+    [initiator.as_slice(), responder.as_slice()].concat()
+}
+
+fn kdf_x3dh(ikm: &[u8]) -> Result<SecretKey, TrustError> {
+    let mut okm = [0u8; 32];
+    Salt::new(HKDF_SHA256, &[0u8; 32])
+        .extract(ikm)
+        .expand(&[X3DH_INFO], HKDF_SHA256)
+        .map_err(|_| TrustError::CryptoFailure)?
+        .fill(&mut okm)
+        .map_err(|_| TrustError::CryptoFailure)?;
+    Ok(SecretKey::from_bytes(okm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_session_setup() -> Result<(), TrustError> {
+        let alice = Identity::generate()?;
+        let bob = Identity::generate()?;
+        let mut bob_store = PreKeyStore::generate(4)?;
+        let mut published = bob_store.publish(&bob, 2);
+        published.verify()?;
+        assert_eq!(published.one_time_prekeys.len(), 2);
+        assert_eq!(bob_store.unpublished(), 2);
+
+        let bundle = published.take_bundle();
+        let ad = associated_data(&alice.public_key(), &bob.public_key());
+        let (header, mut alice_ratchet) = initiate(&alice, &bundle)?;
+        let first = alice_ratchet.encrypt(b"hello offline bob", &ad)?;
+
+        let mut bob_ratchet = bob_store.accept(&bob, &header)?;
+        assert_eq!(
+            bob_ratchet.decrypt(&first, &ad)?.as_slice(),
+            b"hello offline bob"
+        );
+        let reply = bob_ratchet.encrypt(b"hi alice", &ad)?;
+        assert_eq!(alice_ratchet.decrypt(&reply, &ad)?.as_slice(), b"hi alice");
+
+        // 一次性预密钥只能用一次
+        assert!(matches!(
+            bob_store.accept(&bob, &header),
+            Err(TrustError::KeyRevoked(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_forged_signed_prekey() -> Result<(), TrustError> {
+        let bob = Identity::generate()?;
+        let mallory = Identity::generate()?;
+        let mut bundle = PreKeyStore::generate(1)?.publish(&bob, 1).take_bundle();
+        bundle.signed_prekey = PreKeyStore::generate(0)?.publish(&mallory, 0).signed_prekey;
+        assert!(bundle.verify().is_err());
+        assert!(initiate(&mallory, &bundle).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn published_keys_are_not_reissued() -> Result<(), TrustError> {
+        let bob = Identity::generate()?;
+        let mut store = PreKeyStore::generate(3)?;
+        let first = store.publish(&bob, 2);
+        let key = SecretKey::generate()?;
+        let mut store = PreKeyStore::open(&store.seal(&key)?, &key)?;
+        let second = store.publish(&bob, 2);
+        assert_eq!(second.one_time_prekeys.len(), 1);
+        assert!(
+            second
+                .one_time_prekeys
+                .iter()
+                .all(|k| !first.one_time_prekeys.contains(k))
+        );
+        Ok(())
+    }
+}
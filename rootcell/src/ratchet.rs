@@ -0,0 +1,543 @@
+//! Signal 风格的双棘轮（Double Ratchet）
+//!
+//! - DH 棘轮：每收到对端的新棘轮公钥就做一次 X25519，经 HKDF 更新根密钥
+//! - 对称棘轮：链密钥通过 [`SecretKey::derive`] 逐条派生消息密钥
+//! - 乱序与丢包：跳过的消息密钥暂存，数量有上限，超出时丢弃最旧的
+//!
+//! 解密失败不会改变会话状态；状态可用 [`Ratchet::seal_state`] 加密后持久化。
+use ring::hkdf::{HKDF_SHA256, Salt};
+use zeroize::Zeroizing;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use crate::{
+    SecretKey, TrustError, aead,
+    dh::DhKeyPair,
+    encoding::{Reader, write_optional},
+};
+
+/// 单条链上一次最多跳过的消息数
+pub const MAX_SKIP: u32 = 1000;
+/// 暂存的跳过消息密钥总数上限
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// 消息格式版本
+const MESSAGE_VERSION: u8 = 1;
+/// 加密状态格式版本
+const STATE_VERSION: u8 = 1;
+const STATE_AAD: &[u8] = b"rootcell/ratchet-state/v1";
+const ROOT_INFO: &[u8] = b"rootcell/ratchet/root";
+const CHAIN_INFO: &[u8] = b"rootcell/ratchet/chain";
+const CHAIN_CONTEXT: &[u8] = b"rootcell/ratchet/next-chain";
+const MESSAGE_CONTEXT: &[u8] = b"rootcell/ratchet/message";
+/// 消息头长度：棘轮公钥 + 上条链长度 + 序号
+const HEADER_LEN: usize = 40;
+
+/// 跳过的消息密钥索引：(对端棘轮公钥, 序号)
+type SkippedIndex = ([u8; 32], u32);
+
+/// 消息头，明文传输并参与认证
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    dh: [u8; 32],
+    ///发送方上一条发送链的长度
+    pn: u32,
+    n: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..32].copy_from_slice(&self.dh);
+        out[32..36].copy_from_slice(&self.pn.to_be_bytes());
+        out[36..].copy_from_slice(&self.n.to_be_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        Some(Self {
+            dh: reader.array()?,
+            pn: reader.u32()?,
+            n: reader.u32()?,
+        })
+    }
+}
+
+/// 一次 DH 棘轮的结果，解密成功后才写回状态
+struct DhStep {
+    root: SecretKey,
+    sending: DhKeyPair,
+    sending_chain: SecretKey,
+    remote: [u8; 32],
+}
+
+/// 解密前计算出的新状态
+struct Staged {
+    skipped: Vec<(SkippedIndex, SecretKey)>,
+    step: Option<DhStep>,
+    receiving_chain: SecretKey,
+    message_key: SecretKey,
+    received: u32,
+}
+
+/// 双棘轮会话状态
+pub struct Ratchet {
+    root: SecretKey,
+    sending: DhKeyPair,
+    remote: Option<[u8; 32]>,
+    sending_chain: Option<SecretKey>,
+    receiving_chain: Option<SecretKey>,
+    ///当前发送链已发送条数
+    sent: u32,
+    ///当前接收链已接收条数
+    received: u32,
+    ///上一条发送链的长度
+    previous_sent: u32,
+    skipped: HashMap<SkippedIndex, SecretKey>,
+    ///跳过密钥的插入顺序，用于淘汰最旧的
+    skipped_order: VecDeque<SkippedIndex>,
+}
+
+impl Ratchet {
+    /// 发起方：已知共享秘密和对端的初始棘轮公钥（如签名预密钥）
+    pub fn initiate(shared: SecretKey, remote: &[u8; 32]) -> Result<Self, TrustError> {
+        let sending = DhKeyPair::generate()?;
+        let (root, sending_chain) = kdf_root(&shared, &*sending.agree(remote)?)?;
+        Ok(Self {
+            root,
+            sending,
+            remote: Some(*remote),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        })
+    }
+
+    /// 响应方：持有发起方使用的初始棘轮密钥对；收到第一条消息前不能发送
+    pub fn respond(shared: SecretKey, local: DhKeyPair) -> Self {
+        Self {
+            root: shared,
+            sending: local,
+            remote: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        }
+    }
+
+    /// 加密一条消息，`ad` 为双方约定的关联数据（如双方身份公钥）
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, TrustError> {
+        let chain = self
+            .sending_chain
+            .as_ref()
+            .ok_or(TrustError::CryptoFailure)?;
+        let (next_chain, message_key) = chain_step(chain)?;
+        let header = Header {
+            dh: self.sending.public_key(),
+            pn: self.previous_sent,
+            n: self.sent,
+        }
+        .encode();
+        let sent = self.sent.checked_add(1).ok_or(TrustError::CryptoFailure)?;
+
+        let mut message = vec![MESSAGE_VERSION];
+        message.extend_from_slice(&header);
+        message.extend(aead::seal(
+            &message_key,
+            &[ad, header.as_slice()].concat(),
+            plaintext,
+        )?);
+        self.sending_chain = Some(next_chain);
+        self.sent = sent;
+        Ok(message)
+    }
+
+    /// 解密一条消息，支持乱序和丢包；失败时状态不变
+    pub fn decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+        let (header_bytes, body) = match message.split_first() {
+            Some((&MESSAGE_VERSION, rest)) => rest
+                .split_first_chunk::<HEADER_LEN>()
+                .ok_or(TrustError::CryptoFailure)?,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported ratchet message version {version}"
+                )));
+            }
+            None => return Err(TrustError::CryptoFailure),
+        };
+        let header = Header::decode(header_bytes).ok_or(TrustError::CryptoFailure)?;
+        let aad = [ad, header_bytes.as_slice()].concat();
+
+        let index = (header.dh, header.n);
+        if let Some(key) = self.skipped.get(&index) {
+            let plaintext = aead::open(key, &aad, body)?;
+            let _ = self.skipped.remove(&index);
+            self.skipped_order.retain(|i| *i != index);
+            return Ok(plaintext);
+        }
+
+        let staged = self.stage(&header)?;
+        let plaintext = aead::open(&staged.message_key, &aad, body)?;
+        self.commit(staged);
+        Ok(plaintext)
+    }
+
+    /// 计算解密 `header` 所需的新状态，不修改自身
+    fn stage(&self, header: &Header) -> Result<Staged, TrustError> {
+        let mut skipped = Vec::new();
+        if self.remote == Some(header.dh) {
+            let chain = self
+                .receiving_chain
+                .as_ref()
+                .ok_or(TrustError::CryptoFailure)?;
+            // 早于当前位置且不在暂存中：重放或已淘汰
+            if header.n < self.received {
+                return Err(TrustError::CryptoFailure);
+            }
+            let (receiving_chain, message_key) =
+                chain_until(chain, header.dh, self.received, header.n, &mut skipped)?;
+            return Ok(Staged {
+                skipped,
+                step: None,
+                receiving_chain,
+                message_key,
+                received: next_index(header.n)?,
+            });
+        }
+
+        // 对端换了棘轮公钥：先收下旧接收链上剩余的消息密钥
+        if let (Some(remote), Some(chain)) = (self.remote, &self.receiving_chain)
+            && header.pn > self.received
+        {
+            let last = header.pn.saturating_sub(1);
+            let (_, message_key) = chain_until(chain, remote, self.received, last, &mut skipped)?;
+            skipped.push(((remote, last), message_key));
+        }
+        let (root, receiving) = kdf_root(&self.root, &*self.sending.agree(&header.dh)?)?;
+        let sending = DhKeyPair::generate()?;
+        let (root, sending_chain) = kdf_root(&root, &*sending.agree(&header.dh)?)?;
+        let (receiving_chain, message_key) =
+            chain_until(&receiving, header.dh, 0, header.n, &mut skipped)?;
+        Ok(Staged {
+            skipped,
+            step: Some(DhStep {
+                root,
+                sending,
+                sending_chain,
+                remote: header.dh,
+            }),
+            receiving_chain,
+            message_key,
+            received: next_index(header.n)?,
+        })
+    }
+
+    fn commit(&mut self, staged: Staged) {
+        for (index, key) in staged.skipped {
+            self.remember_skipped(index, key);
+        }
+        if let Some(step) = staged.step {
+            self.root = step.root;
+            self.sending = step.sending;
+            self.sending_chain = Some(step.sending_chain);
+            self.remote = Some(step.remote);
+            self.previous_sent = self.sent;
+            self.sent = 0;
+        }
+        self.receiving_chain = Some(staged.receiving_chain);
+        self.received = staged.received;
+    }
+
+    fn remember_skipped(&mut self, index: SkippedIndex, key: SecretKey) {
+        if self.skipped.insert(index, key).is_none() {
+            self.skipped_order.push_back(index);
+        }
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            match self.skipped_order.pop_front() {
+                Some(oldest) => {
+                    let _ = self.skipped.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// 暂存的跳过消息密钥数量
+    pub fn skipped_keys(&self) -> usize {
+        self.skipped.len()
+    }
+
+    /// 用存储密钥加密导出会话状态
+    pub fn seal_state(&self, key: &SecretKey) -> Result<Vec<u8>, TrustError> {
+        let mut out = Zeroizing::new(Vec::new());
+        out.extend_from_slice(self.root.expose_secret());
+        out.extend_from_slice(self.sending.secret_bytes().as_ref());
+        write_optional(&mut out, self.remote.as_ref());
+        write_optional(
+            &mut out,
+            self.sending_chain.as_ref().map(SecretKey::expose_secret),
+        );
+        write_optional(
+            &mut out,
+            self.receiving_chain.as_ref().map(SecretKey::expose_secret),
+        );
+        for n in [self.sent, self.received, self.previous_sent] {
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        let count =
+            u32::try_from(self.skipped_order.len()).map_err(|_| TrustError::CryptoFailure)?;
+        out.extend_from_slice(&count.to_be_bytes());
+        for index in &self.skipped_order {
+            let key = self.skipped.get(index).ok_or(TrustError::CryptoFailure)?;
+            out.extend_from_slice(&index.0);
+            out.extend_from_slice(&index.1.to_be_bytes());
+            out.extend_from_slice(key.expose_secret());
+        }
+
+        let mut sealed = vec![STATE_VERSION];
+        sealed.extend(aead::seal(key, STATE_AAD, &out)?);
+        Ok(sealed)
+    }
+
+    /// 解密 [`Ratchet::seal_state`] 的输出；密钥错误时返回 `AccessDenied`
+    pub fn open_state(sealed: &[u8], key: &SecretKey) -> Result<Self, TrustError> {
+        let body = match sealed.split_first() {
+            Some((&STATE_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported ratchet state version {version}"
+                )));
+            }
+            None => return Err(TrustError::CryptoFailure),
+        };
+        let plain = aead::open(key, STATE_AAD, body)?;
+        let mut reader = Reader::new(&plain);
+        let malformed = || TrustError::Storage("malformed ratchet state".to_string());
+
+        let root = SecretKey::from_bytes(reader.array().ok_or_else(malformed)?);
+        let sending = DhKeyPair::from_secret_bytes(reader.array().ok_or_else(malformed)?);
+        let remote = reader.optional().ok_or_else(malformed)?;
+        let sending_chain = reader
+            .optional()
+            .ok_or_else(malformed)?
+            .map(SecretKey::from_bytes);
+        let receiving_chain = reader
+            .optional()
+            .ok_or_else(malformed)?
+            .map(SecretKey::from_bytes);
+        let sent = reader.u32().ok_or_else(malformed)?;
+        let received = reader.u32().ok_or_else(malformed)?;
+        let previous_sent = reader.u32().ok_or_else(malformed)?;
+
+        let mut ratchet = Self {
+            root,
+            sending,
+            remote,
+            sending_chain,
+            receiving_chain,
+            sent,
+            received,
+            previous_sent,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        };
+        for _ in 0..reader.u32().ok_or_else(malformed)? {
+            let dh = reader.array().ok_or_else(malformed)?;
+            let n = reader.u32().ok_or_else(malformed)?;
+            let key = SecretKey::from_bytes(reader.array().ok_or_else(malformed)?);
+            ratchet.remember_skipped((dh, n), key);
+        }
+        if !reader.is_empty() {
+            return Err(malformed());
+        }
+        Ok(ratchet)
+    }
+}
+
+impl fmt::Debug for Ratchet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ratchet")
+            .field("sending", &self.sending.public_key())
+            .field("remote", &self.remote)
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// KDF_RK：以根密钥为盐、DH 输出为输入做 HKDF，得到新根密钥和链密钥
+fn kdf_root(root: &SecretKey, dh_out: &[u8; 32]) -> Result<(SecretKey, SecretKey), TrustError> {
+    let prk = Salt::new(HKDF_SHA256, root.expose_secret()).extract(dh_out);
+    let expand = |info: &[u8]| {
+        let mut okm = [0u8; 32];
+        prk.expand(&[info], HKDF_SHA256)
+            .map_err(|_| TrustError::CryptoFailure)?
+            .fill(&mut okm)
+            .map_err(|_| TrustError::CryptoFailure)?;
+        Ok::<_, TrustError>(SecretKey::from_bytes(okm))
+    };
+    Ok((expand(ROOT_INFO)?, expand(CHAIN_INFO)?))
+}
+
+/// KDF_CK：返回 (下一个链密钥, 消息密钥)
+fn chain_step(chain: &SecretKey) -> Result<(SecretKey, SecretKey), TrustError> {
+    Ok((
+        chain.derive(CHAIN_CONTEXT, 0)?,
+        chain.derive(MESSAGE_CONTEXT, 0)?,
+    ))
+}
+
+/// 从位于 `from` 的链密钥推进到 `target`，中间的消息密钥放入 `skipped`
+///
+/// 返回 (`target` 之后的链密钥, `target` 的消息密钥)
+fn chain_until(
+    chain: &SecretKey,
+    remote: [u8; 32],
+    from: u32,
+    target: u32,
+    skipped: &mut Vec<(SkippedIndex, SecretKey)>,
+) -> Result<(SecretKey, SecretKey), TrustError> {
+    if target.checked_sub(from).is_none_or(|gap| gap > MAX_SKIP) {
+        return Err(TrustError::CryptoFailure);
+    }
+    let (mut next, mut message_key) = chain_step(chain)?;
+    for n in from..target {
+        let (following, following_key) = chain_step(&next)?;
+        skipped.push((
+            (remote, n),
+            std::mem::replace(&mut message_key, following_key),
+        ));
+        next = following;
+    }
+    Ok((next, message_key))
+}
+
+fn next_index(n: u32) -> Result<u32, TrustError> {
+    n.checked_add(1).ok_or(TrustError::CryptoFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"alice|bob";
+
+    fn pair() -> Result<(Ratchet, Ratchet), TrustError> {
+        let shared = SecretKey::generate()?;
+        let bob_key = DhKeyPair::generate()?;
+        let alice = Ratchet::initiate(shared.derive(b"test", 0)?, &bob_key.public_key())?;
+        let bob = Ratchet::respond(shared.derive(b"test", 0)?, bob_key);
+        Ok((alice, bob))
+    }
+
+    fn open(ratchet: &mut Ratchet, message: &[u8]) -> Result<Vec<u8>, TrustError> {
+        Ok(ratchet.decrypt(message, AD)?.to_vec())
+    }
+
+    #[test]
+    fn ping_pong() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        assert!(bob.encrypt(b"too early", AD).is_err());
+        for round in 0..3u8 {
+            let a = alice.encrypt(&[round], AD)?;
+            assert_eq!(open(&mut bob, &a)?, [round]);
+            let b = bob.encrypt(&[round, round], AD)?;
+            assert_eq!(open(&mut alice, &b)?, [round, round]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reordering_and_loss_within_chain() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        let sent: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| alice.encrypt(&[i], AD))
+            .collect::<Result<_, _>>()?;
+        // 2 丢失，其余乱序到达
+        for i in [3u8, 0, 4, 1] {
+            assert_eq!(open(&mut bob, &sent[usize::from(i)])?, [i]);
+        }
+        assert_eq!(bob.skipped_keys(), 1);
+        // 重放被拒绝
+        assert!(open(&mut bob, &sent[3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn late_message_from_previous_chain() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        let a0 = alice.encrypt(b"a0", AD)?;
+        let a1 = alice.encrypt(b"a1", AD)?;
+        assert_eq!(open(&mut bob, &a0)?, b"a0");
+
+        let b0 = bob.encrypt(b"b0", AD)?;
+        assert_eq!(open(&mut alice, &b0)?, b"b0");
+        let a2 = alice.encrypt(b"a2", AD)?;
+        // a1 延迟到新链之后才到
+        assert_eq!(open(&mut bob, &a2)?, b"a2");
+        assert_eq!(open(&mut bob, &a1)?, b"a1");
+        assert_eq!(bob.skipped_keys(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn tampering_leaves_state_intact() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        let message = alice.encrypt(b"hello", AD)?;
+        let mut tampered = message.clone();
+        if let Some(last) = tampered.last_mut() {
+            *last ^= 1;
+        }
+        assert!(matches!(
+            bob.decrypt(&tampered, AD),
+            Err(TrustError::AccessDenied)
+        ));
+        assert!(bob.decrypt(&message, b"other ad").is_err());
+        assert_eq!(open(&mut bob, &message)?, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn skipping_is_bounded() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        for _ in 0..=MAX_SKIP {
+            let _ = alice.encrypt(b"lost", AD)?;
+        }
+        let too_far = alice.encrypt(b"too far", AD)?;
+        assert!(bob.decrypt(&too_far, AD).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn state_survives_serialization() -> Result<(), TrustError> {
+        let (mut alice, mut bob) = pair()?;
+        let storage_key = SecretKey::generate()?;
+        let a0 = alice.encrypt(b"a0", AD)?;
+        let a1 = alice.encrypt(b"a1", AD)?;
+        assert_eq!(open(&mut bob, &a1)?, b"a1");
+
+        let sealed = bob.seal_state(&storage_key)?;
+        assert!(matches!(
+            Ratchet::open_state(&sealed, &SecretKey::generate()?),
+            Err(TrustError::AccessDenied)
+        ));
+        let mut bob = Ratchet::open_state(&sealed, &storage_key)?;
+        assert_eq!(open(&mut bob, &a0)?, b"a0");
+        let reply = bob.encrypt(b"b0", AD)?;
+        assert_eq!(open(&mut alice, &reply)?, b"b0");
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+//! 密钥撤销声明
+//!
+//! 身份撤销由被撤销的身份自己签名（私钥泄露或停用后的最后一次签名）；设备撤销由
+//! 设备所属的身份签名。声明自带签名，任何节点都可以转发，收到后先校验再加入
+//! [`RevocationList`]。撤销不可恢复，同一密钥只记录最早的一份声明。
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    hash::Hash,
+};
+
+use crate::{Identity, TrustError, identity::verify};
+
+/// 撤销声明的签名上下文
+const SIGNATURE_CONTEXT: &[u8] = b"rootcell/revocation/v1";
+
+/// 被撤销的密钥类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RevocationKind {
+    /// 长期身份密钥，签发者即被撤销的身份
+    Identity,
+    /// 某个身份名下的设备密钥
+    Device,
+}
+
+impl RevocationKind {
+    fn code(self) -> u8 {
+        match self {
+            RevocationKind::Identity => 1,
+            RevocationKind::Device => 2,
+        }
+    }
+}
+
+/// 经签名的撤销声明
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocation {
+    /// 撤销类型
+    pub kind: RevocationKind,
+    /// 签发者的 ed25519 身份公钥
+    pub issuer: [u8; 32],
+    /// 被撤销的 ed25519 公钥；身份撤销时与签发者相同
+    pub revoked_key: [u8; 32],
+    /// 签发时间（unix 毫秒）
+    pub issued_at: u64,
+    /// 撤销原因，仅供显示
+    pub reason: String,
+    /// 签发者对以上字段的签名
+    pub signature: Vec<u8>,
+}
+
+impl Revocation {
+    /// 撤销自己的身份
+    pub fn revoke_identity(identity: &Identity, issued_at: u64, reason: impl Into<String>) -> Self {
+        let public_key = identity.public_key();
+        Self::sign(
+            identity,
+            RevocationKind::Identity,
+            public_key,
+            issued_at,
+            reason.into(),
+        )
+    }
+
+    /// 以身份名义撤销其名下的设备密钥
+    pub fn revoke_device(
+        identity: &Identity,
+        device_key: [u8; 32],
+        issued_at: u64,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self::sign(
+            identity,
+            RevocationKind::Device,
+            device_key,
+            issued_at,
+            reason.into(),
+        )
+    }
+
+    fn sign(
+        identity: &Identity,
+        kind: RevocationKind,
+        revoked_key: [u8; 32],
+        issued_at: u64,
+        reason: String,
+    ) -> Self {
+        let mut revocation = Self {
+            kind,
+            issuer: identity.public_key(),
+            revoked_key,
+            issued_at,
+            reason,
+            signature: Vec::new(),
+        };
+        revocation.signature = identity.sign(&revocation.signed_message()).to_vec();
+        revocation
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        [
+            SIGNATURE_CONTEXT,
+            &[self.kind.code()],
+            &self.issuer,
+            &self.revoked_key,
+            &self.issued_at.to_be_bytes(),
+            self.reason.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// 校验签名；身份撤销必须由被撤销的身份本身签发
+    pub fn verify(&self) -> Result<(), TrustError> {
+        if self.kind == RevocationKind::Identity && self.issuer != self.revoked_key {
+            return Err(TrustError::CryptoFailure);
+        }
+        let signature: &[u8; 64] = self
+            .signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| TrustError::CryptoFailure)?;
+        if verify(&self.issuer, &self.signed_message(), signature) {
+            Ok(())
+        } else {
+            Err(TrustError::CryptoFailure)
+        }
+    }
+
+    fn revoked_error(&self) -> TrustError {
+        let what = match self.kind {
+            RevocationKind::Identity => "identity",
+            RevocationKind::Device => "device",
+        };
+        TrustError::KeyRevoked(format!("{what} key revoked: {}", self.reason))
+    }
+}
+
+/// 已校验的撤销声明集合
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    ///按被撤销的身份公钥索引
+    identities: HashMap<[u8; 32], Revocation>,
+    ///按（所属身份，设备公钥）索引
+    devices: HashMap<([u8; 32], [u8; 32]), Revocation>,
+}
+
+impl RevocationList {
+    /// 空列表
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 校验并加入声明，返回是否是新撤销的密钥；签名无效时返回 `CryptoFailure`
+    pub fn insert(&mut self, revocation: Revocation) -> Result<bool, TrustError> {
+        revocation.verify()?;
+        Ok(match revocation.kind {
+            RevocationKind::Identity => {
+                insert_new(&mut self.identities, revocation.revoked_key, revocation)
+            }
+            RevocationKind::Device => insert_new(
+                &mut self.devices,
+                (revocation.issuer, revocation.revoked_key),
+                revocation,
+            ),
+        })
+    }
+
+    /// 身份已撤销时返回 `KeyRevoked`
+    pub fn check_identity(&self, identity_key: &[u8; 32]) -> Result<(), TrustError> {
+        match self.identities.get(identity_key) {
+            Some(revocation) => Err(revocation.revoked_error()),
+            None => Ok(()),
+        }
+    }
+
+    /// 设备本身或其所属身份已撤销时返回 `KeyRevoked`
+    pub fn check_device(&self, owner: &[u8; 32], device_key: &[u8; 32]) -> Result<(), TrustError> {
+        self.check_identity(owner)?;
+        match self.devices.get(&(*owner, *device_key)) {
+            Some(revocation) => Err(revocation.revoked_error()),
+            None => Ok(()),
+        }
+    }
+
+    /// 全部声明，用于转发给其他节点
+    pub fn iter(&self) -> impl Iterator<Item = &Revocation> {
+        self.identities.values().chain(self.devices.values())
+    }
+
+    /// 声明数
+    pub fn len(&self) -> usize {
+        self.identities.len().saturating_add(self.devices.len())
+    }
+
+    /// 是否为空
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.devices.is_empty()
+    }
+}
+
+/// 只保留最早收到的声明
+fn insert_new<K: Eq + Hash>(
+    map: &mut HashMap<K, Revocation>,
+    key: K,
+    revocation: Revocation,
+) -> bool {
+    match map.entry(key) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            let _ = entry.insert(revocation);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revocations_are_verified_and_enforced() -> Result<(), TrustError> {
+        let alice = Identity::generate()?;
+        let device = Identity::generate()?;
+        let mut list = RevocationList::new();
+
+        let mut forged = Revocation::revoke_identity(&alice, 1, "lost laptop");
+        forged.reason = "something else".to_string();
+        assert!(matches!(
+            list.insert(forged),
+            Err(TrustError::CryptoFailure)
+        ));
+        // 只能撤销自己的身份
+        let mallory = Identity::generate()?;
+        let mut claimed = Revocation::revoke_identity(&mallory, 1, "");
+        claimed.revoked_key = alice.public_key();
+        assert!(list.insert(claimed).is_err());
+        list.check_identity(&alice.public_key())?;
+
+        let revoke_device = Revocation::revoke_device(&alice, device.public_key(), 2, "stolen");
+        assert!(list.insert(revoke_device.clone())?);
+        assert!(!list.insert(revoke_device)?);
+        assert!(matches!(
+            list.check_device(&alice.public_key(), &device.public_key()),
+            Err(TrustError::KeyRevoked(_))
+        ));
+        // 设备撤销不影响身份本身，也不能波及他人名下的同一公钥
+        list.check_identity(&alice.public_key())?;
+        list.check_device(&mallory.public_key(), &device.public_key())?;
+
+        assert!(list.insert(Revocation::revoke_identity(&alice, 3, "compromised"))?);
+        assert!(matches!(
+            list.check_identity(&alice.public_key()),
+            Err(TrustError::KeyRevoked(_))
+        ));
+        assert_eq!(list.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! 安全码：两个身份公钥的确定性指纹，双方带外比对以发现中间人
+//!
+//! 数字形式沿用 Signal 的做法：每个公钥迭代 SHA-512 得到 30 位数字，按大小排序后
+//! 拼成 60 位，通信双方看到的完全相同。短形式从两个公钥的 SHA-256 中取 7 个
+//! 6 比特索引，对应 64 个 emoji 及其名称，适合当面或通话时念出来比对。
+use ring::digest;
+
+use std::fmt;
+
+/// 指纹格式版本，参与哈希
+const FINGERPRINT_VERSION: [u8; 2] = [0, 0];
+/// 每个公钥的哈希迭代次数
+const ITERATIONS: u32 = 5200;
+/// 每个公钥贡献的 5 位数字组数
+const CHUNKS: usize = 6;
+/// 短形式的 emoji 个数
+const EMOJI_COUNT: usize = 7;
+/// 短形式的 emoji 短语上下文
+const EMOJI_CONTEXT: &[u8] = b"rootcell/safety-emoji/v1";
+
+/// 短形式使用的 emoji 与名称
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "狗"),
+    ("🐱", "猫"),
+    ("🦁", "狮子"),
+    ("🐎", "马"),
+    ("🦄", "独角兽"),
+    ("🐷", "猪"),
+    ("🐘", "大象"),
+    ("🐰", "兔子"),
+    ("🐼", "熊猫"),
+    ("🐓", "公鸡"),
+    ("🐧", "企鹅"),
+    ("🐢", "乌龟"),
+    ("🐟", "鱼"),
+    ("🐙", "章鱼"),
+    ("🦋", "蝴蝶"),
+    ("🌷", "花"),
+    ("🌳", "树"),
+    ("🌵", "仙人掌"),
+    ("🍄", "蘑菇"),
+    ("🌏", "地球"),
+    ("🌙", "月亮"),
+    ("☁️", "云"),
+    ("🔥", "火"),
+    ("🍌", "香蕉"),
+    ("🍎", "苹果"),
+    ("🍓", "草莓"),
+    ("🌽", "玉米"),
+    ("🍕", "披萨"),
+    ("🎂", "蛋糕"),
+    ("❤️", "心"),
+    ("😀", "笑脸"),
+    ("🤖", "机器人"),
+    ("🎩", "帽子"),
+    ("👓", "眼镜"),
+    ("🔧", "扳手"),
+    ("🎅", "圣诞老人"),
+    ("👍", "点赞"),
+    ("☂️", "雨伞"),
+    ("⌛", "沙漏"),
+    ("⏰", "闹钟"),
+    ("🎁", "礼物"),
+    ("💡", "灯泡"),
+    ("📕", "书"),
+    ("✏️", "铅笔"),
+    ("📎", "回形针"),
+    ("✂️", "剪刀"),
+    ("🔒", "锁"),
+    ("🔑", "钥匙"),
+    ("🔨", "锤子"),
+    ("☎️", "电话"),
+    ("🏁", "旗子"),
+    ("🚂", "火车"),
+    ("🚲", "自行车"),
+    ("✈️", "飞机"),
+    ("🚀", "火箭"),
+    ("🏆", "奖杯"),
+    ("⚽", "足球"),
+    ("🎸", "吉他"),
+    ("🎺", "小号"),
+    ("🔔", "铃铛"),
+    ("⚓", "锚"),
+    ("🎧", "耳机"),
+    ("📁", "文件夹"),
+    ("📌", "图钉"),
+];
+
+/// 一对身份公钥的安全码，与参数顺序无关
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    ///60 位数字
+    digits: String,
+    ///EMOJI 表中的索引
+    emoji: [usize; EMOJI_COUNT],
+}
+
+impl SafetyNumber {
+    /// 计算本方与对方身份公钥（ed25519）的安全码
+    pub fn new(local_key: &[u8; 32], remote_key: &[u8; 32]) -> Self {
+        let (first, second) = if local_key <= remote_key {
+            (local_key, remote_key)
+        } else {
+            (remote_key, local_key)
+        };
+        let digits = [fingerprint_digits(first), fingerprint_digits(second)].concat();
+
+        let hash = digest::digest(
+            &digest::SHA256,
+            &[EMOJI_CONTEXT, first.as_slice(), second.as_slice()].concat(),
+        );
+        let mut emoji = [0usize; EMOJI_COUNT];
+        for (index, byte) in emoji.iter_mut().zip(hash.as_ref()) {
+            *index = usize::from(byte & 0x3f);
+        }
+        Self { digits, emoji }
+    }
+
+    /// 60 位数字，不含分隔
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// 短形式的 emoji
+    pub fn emoji(&self) -> Vec<&'static str> {
+        self.emoji_table().map(|(emoji, _)| emoji).collect()
+    }
+
+    /// 短形式的 emoji 名称，便于口头比对
+    pub fn words(&self) -> Vec<&'static str> {
+        self.emoji_table().map(|(_, word)| word).collect()
+    }
+
+    fn emoji_table(&self) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+        self.emoji
+            .iter()
+            .filter_map(|index| EMOJI.get(*index).copied())
+    }
+
+    /// 比对用户输入或扫描得到的数字，忽略空白
+    pub fn matches(&self, input: &str) -> bool {
+        let input: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+        input == self.digits
+    }
+}
+
+impl fmt::Display for SafetyNumber {
+    /// 每 5 位一组，以空格分隔
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, group) in self.digits.as_bytes().chunks(5).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(std::str::from_utf8(group).map_err(|_| fmt::Error)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// 单个公钥的 30 位数字指纹
+fn fingerprint_digits(key: &[u8; 32]) -> String {
+    let mut hash = digest::digest(
+        &digest::SHA512,
+        &[FINGERPRINT_VERSION.as_slice(), key, key].concat(),
+    );
+    for _ in 1..ITERATIONS {
+        hash = digest::digest(&digest::SHA512, &[hash.as_ref(), key].concat());
+    }
+    hash.as_ref()
+        .chunks_exact(5)
+        .take(CHUNKS)
+        .map(|chunk| {
+            let mut bytes = [0u8; 8];
+            for (dst, src) in bytes.iter_mut().skip(3).zip(chunk) {
+                *dst = *src;
+            }
+            let value = u64::from_be_bytes(bytes).checked_rem(100_000).unwrap_or(0);
+            format!("{value:05}")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safety_number_is_symmetric_and_key_bound() {
+        let (alice, bob, mallory) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let number = SafetyNumber::new(&alice, &bob);
+        assert_eq!(number, SafetyNumber::new(&bob, &alice));
+        assert_eq!(number.digits().len(), 60);
+        assert!(number.digits().chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number.to_string().split(' ').count(), 12);
+        assert!(number.matches(&number.to_string()));
+        assert_eq!(number.emoji().len(), 7);
+        assert_eq!(number.words().len(), 7);
+
+        let intercepted = SafetyNumber::new(&alice, &mallory);
+        assert_ne!(number.digits(), intercepted.digits());
+        assert!(!intercepted.matches(number.digits()));
+        // 本方的 30 位在两个安全码中保持不变
+        assert!(intercepted.digits().starts_with(&number.digits()[..30]));
+    }
+}
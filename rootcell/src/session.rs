@@ -0,0 +1,120 @@
+//! 两个身份之间的端到端加密会话
+//!
+//! 双方的 ed25519 身份密钥映射为 X25519 后做密钥协商，共享秘密经 HKDF
+//! 派生出两个方向各自的 [`SecretKey`]，消息用 ChaCha20-Poly1305 加密。
+//! 对方只需公钥即可建立会话，无需额外握手。
+use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
+
+use std::fmt;
+
+use crate::{Identity, SecretKey, TrustError, aead, identity::x25519_public_key};
+
+/// 密文格式版本
+const SEALED_VERSION: u8 = 1;
+/// 派生方向密钥的上下文
+const SESSION_CONTEXT: &[u8] = b"rootcell/session/v1";
+
+/// 与某个对端的加密会话
+///
+/// 发送与接收使用不同密钥，对端密文无法被原样回送给对端解开
+pub struct Session {
+    peer_public_key: [u8; 32],
+    send: SecretKey,
+    recv: SecretKey,
+}
+
+impl Session {
+    /// 用本地身份与对端 ed25519 公钥建立会话
+    pub fn establish(identity: &Identity, peer_public_key: &[u8; 32]) -> Result<Self, TrustError> {
+        let peer = PublicKey::from(x25519_public_key(peer_public_key)?);
+        let shared = identity.x25519_secret().diffie_hellman(&peer);
+        // 拒绝低阶点，否则共享秘密可被预测
+        if !shared.was_contributory() {
+            return Err(TrustError::CryptoFailure);
+        }
+        let root = SecretKey::from_bytes(shared.to_bytes());
+        let local = identity.public_key();
+        let direction = |from: &[u8; 32], to: &[u8; 32]| {
+            root.derive(
+                &[SESSION_CONTEXT, from.as_slice(), to.as_slice()].concat(),
+                0,
+            )
+        };
+        Ok(Self {
+            peer_public_key: *peer_public_key,
+            send: direction(&local, peer_public_key)?,
+            recv: direction(peer_public_key, &local)?,
+        })
+    }
+
+    /// 对端 ed25519 公钥
+    pub fn peer_public_key(&self) -> [u8; 32] {
+        self.peer_public_key
+    }
+
+    /// 加密发给对端的消息
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, TrustError> {
+        let mut sealed = vec![SEALED_VERSION];
+        sealed.extend(aead::seal(&self.send, SESSION_CONTEXT, plaintext)?);
+        Ok(sealed)
+    }
+
+    /// 解密对端发来的消息；密钥不符或被篡改时返回 `AccessDenied`
+    pub fn open(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+        match sealed.split_first() {
+            Some((&SEALED_VERSION, body)) => aead::open(&self.recv, SESSION_CONTEXT, body),
+            Some((version, _)) => Err(TrustError::Storage(format!(
+                "unsupported session message version {version}"
+            ))),
+            None => Err(TrustError::CryptoFailure),
+        }
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("peer_public_key", &self.peer_public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_agree() -> Result<(), TrustError> {
+        let alice = Identity::generate()?;
+        let bob = Identity::generate()?;
+        let to_bob = Session::establish(&alice, &bob.public_key())?;
+        let to_alice = Session::establish(&bob, &alice.public_key())?;
+
+        let sealed = to_bob.seal(b"hi bob")?;
+        assert_eq!(to_alice.open(&sealed)?.as_slice(), b"hi bob");
+        let reply = to_alice.seal(b"hi alice")?;
+        assert_eq!(to_bob.open(&reply)?.as_slice(), b"hi alice");
+
+        // 自己发出的密文不能被自己的接收方向解开
+        assert!(matches!(
+            to_bob.open(&sealed),
+            Err(TrustError::AccessDenied)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn third_party_cannot_open() -> Result<(), TrustError> {
+        let alice = Identity::generate()?;
+        let bob = Identity::generate()?;
+        let eve = Identity::generate()?;
+        let sealed = Session::establish(&alice, &bob.public_key())?.seal(b"secret")?;
+        let eve_session = Session::establish(&eve, &alice.public_key())?;
+        assert!(matches!(
+            eve_session.open(&sealed),
+            Err(TrustError::AccessDenied)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! TPM 2.0 密封的密钥存储（`tpm` feature）
+//!
+//! 每个条目作为 keyed-hash 对象密封在存储层级的主密钥下，密封后的 public/private
+//! 部分写入 `<dir>/<name>.tpm`，换一台机器（另一个 TPM）无法解封。可选绑定 PCR，
+//! 启动链变化后解封失败并返回 `AccessDenied`；没有 TPM 时返回 `HardwareUnavailable`。
+//!
+//! TCTI 取自环境变量 `TCTI`（用 swtpm 测试时设为 `swtpm:port=2321`），未设置时使用
+//! 默认设备。
+use tss_esapi::{
+    Context, TctiNameConf,
+    attributes::{ObjectAttributesBuilder, SessionAttributesBuilder},
+    constants::SessionType,
+    handles::{KeyHandle, SessionHandle},
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        key_bits::RsaKeyBits,
+        resource_handles::Hierarchy,
+        session_handles::{AuthSession, PolicySession},
+    },
+    structures::{
+        Digest, KeyedHashScheme, PcrSelectionList, PcrSelectionListBuilder, PcrSlot, Private,
+        Public, PublicBuilder, PublicKeyedHashParameters, RsaExponent, SensitiveData,
+        SymmetricDefinition, SymmetricDefinitionObject,
+    },
+    traits::{Marshall, UnMarshall},
+    utils::create_restricted_decryption_rsa_public,
+};
+use zeroize::Zeroizing;
+
+use std::{fmt, fs, path::PathBuf};
+
+use crate::{
+    TrustError,
+    encoding::Reader,
+    keystore::{KeyStore, check_name},
+};
+
+/// 密封文件格式版本
+const BLOB_VERSION: u8 = 1;
+/// PCR 位图覆盖的寄存器数
+const PCR_COUNT: u32 = 24;
+
+/// 密钥密封在 TPM 中的存储
+pub struct TpmKeyStore {
+    dir: PathBuf,
+    tcti: TctiNameConf,
+    /// 解封时要求与密封时一致的 PCR（SHA-256 bank），为空表示不绑定
+    pcrs: Vec<PcrSlot>,
+}
+
+impl TpmKeyStore {
+    /// 不绑定 PCR；没有可用 TPM 时返回 `HardwareUnavailable`
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, TrustError> {
+        Self::with_pcrs(dir, Vec::new())
+    }
+
+    /// 新写入的条目绑定到指定 PCR 的当前值；已有条目按写入时的绑定解封
+    pub fn with_pcrs(dir: impl Into<PathBuf>, pcrs: Vec<PcrSlot>) -> Result<Self, TrustError> {
+        let tcti = TctiNameConf::from_environment_variable()
+            .unwrap_or_else(|_| TctiNameConf::Device(Default::default()));
+        let store = Self {
+            dir: dir.into(),
+            tcti,
+            pcrs,
+        };
+        // 打开一次上下文，确认 TPM 存在
+        drop(store.context()?);
+        Ok(store)
+    }
+
+    fn context(&self) -> Result<Context, TrustError> {
+        Context::new(self.tcti.clone()).map_err(|_| TrustError::HardwareUnavailable)
+    }
+
+    fn entry_path(&self, name: &str) -> Result<PathBuf, TrustError> {
+        check_name(name)?;
+        Ok(self.dir.join(format!("{name}.tpm")))
+    }
+
+    fn seal(&self, secret: &[u8]) -> Result<Vec<u8>, TrustError> {
+        let mut ctx = self.context()?;
+        let primary = primary_key(&mut ctx)?;
+        let result = seal_under(&mut ctx, primary, &self.pcrs, secret);
+        let _ = ctx.flush_context(primary.into());
+        let (public, private) = result?;
+
+        let public = public.marshall().map_err(tpm_error)?;
+        let mut blob = vec![BLOB_VERSION];
+        blob.extend_from_slice(&pcr_mask(&self.pcrs).to_be_bytes());
+        blob.extend_from_slice(
+            &u32::try_from(public.len())
+                .map_err(|_| TrustError::CryptoFailure)?
+                .to_be_bytes(),
+        );
+        blob.extend_from_slice(&public);
+        blob.extend_from_slice(private.value());
+        Ok(blob)
+    }
+
+    fn unseal(&self, blob: &[u8]) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+        let malformed = || TrustError::Storage("malformed sealed key file".to_string());
+        let body = match blob.split_first() {
+            Some((&BLOB_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TrustError::Storage(format!(
+                    "unsupported sealed key version {version}"
+                )));
+            }
+            None => return Err(malformed()),
+        };
+        let mut reader = Reader::new(body);
+        let pcrs = pcr_slots(reader.u32().ok_or_else(malformed)?)?;
+        let public_len =
+            usize::try_from(reader.u32().ok_or_else(malformed)?).map_err(|_| malformed())?;
+        let rest = body.get(8..).ok_or_else(malformed)?;
+        let (public, private) = rest.split_at_checked(public_len).ok_or_else(malformed)?;
+        let public = Public::unmarshall(public).map_err(|_| malformed())?;
+        let private = Private::try_from(private.to_vec()).map_err(|_| malformed())?;
+
+        let mut ctx = self.context()?;
+        let primary = primary_key(&mut ctx)?;
+        let result = unseal_under(&mut ctx, primary, public, private, &pcrs);
+        let _ = ctx.flush_context(primary.into());
+        result
+    }
+}
+
+impl KeyStore for TpmKeyStore {
+    fn load(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, TrustError> {
+        match fs::read(self.entry_path(name)?) {
+            Ok(blob) => self.unseal(&blob).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+
+    fn store(&self, name: &str, secret: &[u8]) -> Result<(), TrustError> {
+        let path = self.entry_path(name)?;
+        crate::write_private_file(&path, &self.seal(secret)?)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), TrustError> {
+        match fs::remove_file(self.entry_path(name)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for TpmKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TpmKeyStore")
+            .field("dir", &self.dir)
+            .field("pcrs", &self.pcrs)
+            .finish_non_exhaustive()
+    }
+}
+
+fn tpm_error(e: tss_esapi::Error) -> TrustError {
+    TrustError::Storage(format!("tpm: {e}"))
+}
+
+fn pcr_mask(pcrs: &[PcrSlot]) -> u32 {
+    pcrs.iter().fold(0, |mask, slot| mask | u32::from(*slot))
+}
+
+fn pcr_slots(mask: u32) -> Result<Vec<PcrSlot>, TrustError> {
+    (0..PCR_COUNT)
+        .filter_map(|i| 1u32.checked_shl(i).filter(|bit| mask & bit != 0))
+        .map(|bit| {
+            PcrSlot::try_from(bit)
+                .map_err(|_| TrustError::Storage("invalid PCR in sealed key file".to_string()))
+        })
+        .collect()
+}
+
+fn pcr_selection(pcrs: &[PcrSlot]) -> Result<PcrSelectionList, TrustError> {
+    PcrSelectionListBuilder::new()
+        .with_selection(HashingAlgorithm::Sha256, pcrs)
+        .build()
+        .map_err(tpm_error)
+}
+
+/// 存储层级下的 RSA 主密钥，模板固定，因此每次都能重新派生出同一把
+fn primary_key(ctx: &mut Context) -> Result<KeyHandle, TrustError> {
+    let public = create_restricted_decryption_rsa_public(
+        SymmetricDefinitionObject::AES_128_CFB,
+        RsaKeyBits::Rsa2048,
+        RsaExponent::default(),
+    )
+    .map_err(tpm_error)?;
+    ctx.execute_with_nullauth_session(|ctx| {
+        ctx.create_primary(Hierarchy::Owner, public, None, None, None, None)
+    })
+    .map(|created| created.key_handle)
+    .map_err(tpm_error)
+}
+
+/// 启动带 PCR 策略的会话；`Trial` 会话只用于计算策略摘要
+fn pcr_policy(
+    ctx: &mut Context,
+    pcrs: &[PcrSlot],
+    session_type: SessionType,
+) -> Result<AuthSession, TrustError> {
+    let session = ctx
+        .start_auth_session(
+            None,
+            None,
+            None,
+            session_type,
+            SymmetricDefinition::AES_128_CFB,
+            HashingAlgorithm::Sha256,
+        )
+        .map_err(tpm_error)?
+        .ok_or_else(|| TrustError::Storage("tpm returned no session".to_string()))?;
+    let (attributes, mask) = SessionAttributesBuilder::new()
+        .with_decrypt(true)
+        .with_encrypt(true)
+        .build();
+    ctx.tr_sess_set_attributes(session, attributes, mask)
+        .map_err(tpm_error)?;
+    let policy = PolicySession::try_from(session).map_err(tpm_error)?;
+    ctx.policy_pcr(policy, Digest::default(), pcr_selection(pcrs)?)
+        .map_err(tpm_error)?;
+    Ok(session)
+}
+
+fn seal_under(
+    ctx: &mut Context,
+    primary: KeyHandle,
+    pcrs: &[PcrSlot],
+    secret: &[u8],
+) -> Result<(Public, Private), TrustError> {
+    let policy = if pcrs.is_empty() {
+        Digest::default()
+    } else {
+        let trial = pcr_policy(ctx, pcrs, SessionType::Trial)?;
+        let digest = PolicySession::try_from(trial)
+            .and_then(|policy| ctx.policy_get_digest(policy))
+            .map_err(tpm_error);
+        let _ = ctx.flush_context(SessionHandle::from(trial).into());
+        digest?
+    };
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_no_da(true)
+        // 绑定 PCR 时只能通过策略会话解封
+        .with_user_with_auth(pcrs.is_empty())
+        .build()
+        .map_err(tpm_error)?;
+    let public = PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::KeyedHash)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(attributes)
+        .with_auth_policy(policy)
+        .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+        .with_keyed_hash_unique_identifier(Digest::default())
+        .build()
+        .map_err(tpm_error)?;
+    let sensitive = SensitiveData::try_from(secret.to_vec()).map_err(tpm_error)?;
+    let created = ctx
+        .execute_with_nullauth_session(|ctx| {
+            ctx.create(primary, public, None, Some(sensitive), None, None)
+        })
+        .map_err(tpm_error)?;
+    Ok((created.out_public, created.out_private))
+}
+
+fn unseal_under(
+    ctx: &mut Context,
+    primary: KeyHandle,
+    public: Public,
+    private: Private,
+    pcrs: &[PcrSlot],
+) -> Result<Zeroizing<Vec<u8>>, TrustError> {
+    let object = ctx
+        .execute_with_nullauth_session(|ctx| ctx.load(primary, private, public))
+        .map_err(tpm_error)?;
+    let unsealed = if pcrs.is_empty() {
+        ctx.execute_with_nullauth_session(|ctx| ctx.unseal(object.into()))
+    } else {
+        match pcr_policy(ctx, pcrs, SessionType::Policy) {
+            Ok(session) => {
+                let unsealed =
+                    ctx.execute_with_session(Some(session), |ctx| ctx.unseal(object.into()));
+                let _ = ctx.flush_context(SessionHandle::from(session).into());
+                unsealed
+            }
+            Err(e) => {
+                let _ = ctx.flush_context(object.into());
+                return Err(e);
+            }
+        }
+    };
+    let _ = ctx.flush_context(object.into());
+    // PCR 与密封时不一致或对象被替换时 TPM 拒绝解封
+    unsealed
+        .map(|data| Zeroizing::new(data.value().to_vec()))
+        .map_err(|_| TrustError::AccessDenied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tss_esapi::{handles::PcrHandle, structures::DigestValues};
+
+    /// 用 swtpm 运行：
+    /// `swtpm socket --tpm2 --server type=tcp,port=2321 --ctrl type=tcp,port=2322 --tpmstate dir=/tmp/swtpm --flags startup-clear`
+    /// 然后 `TCTI=swtpm:port=2321 cargo test -p rootcell --features tpm -- --ignored`
+    #[test]
+    #[ignore = "requires a TPM or the swtpm simulator"]
+    fn seal_unseal_and_pcr_binding() -> Result<(), TrustError> {
+        let dir = tempfile::tempdir().map_err(|e| TrustError::Storage(e.to_string()))?;
+        let store = TpmKeyStore::open(dir.path())?;
+        store.store("master-key", &[7u8; 32])?;
+        assert_eq!(
+            store.load("master-key")?.as_deref().map(Vec::as_slice),
+            Some([7u8; 32].as_slice())
+        );
+
+        // PCR16 是可由软件扩展的调试寄存器
+        let bound = TpmKeyStore::with_pcrs(dir.path(), vec![PcrSlot::Slot16])?;
+        bound.store("bound", b"pcr bound")?;
+        assert!(bound.load("bound")?.is_some());
+
+        let mut values = DigestValues::new();
+        values.set(
+            HashingAlgorithm::Sha256,
+            Digest::try_from(vec![1u8; 32]).map_err(tpm_error)?,
+        );
+        let mut ctx = bound.context()?;
+        ctx.execute_with_nullauth_session(|ctx| ctx.pcr_extend(PcrHandle::Pcr16, values))
+            .map_err(tpm_error)?;
+        assert!(matches!(bound.load("bound"), Err(TrustError::AccessDenied)));
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! 口令保护的主密钥文件，用于没有系统密钥环的服务器
+//!
+//! 文件格式：`magic(4) || version(1) || kdf(1) || 参数(3×u32) || salt(16) || 包装后的主密钥`。
+//! 头部整体作为 AEAD 关联数据，篡改 KDF 参数同样导致解锁失败。
+//! 口令错误与数据损坏都返回 `AccessDenied`，失败路径总是先完整执行 KDF，
+//! 不因失败原因不同而提前返回。
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::pbkdf2;
+use zeroize::Zeroizing;
+
+use std::{fmt, fs, num::NonZeroU32, path::Path};
+
+use crate::{SecretKey, TrustError, aead, encoding::Reader};
+
+const MAGIC: &[u8; 4] = b"RCVT";
+/// 文件格式版本
+const VAULT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+/// 头部长度：magic + 版本 + KDF + 3 个参数 + 盐
+const HEADER_LEN: usize = 4 + 1 + 1 + 12 + SALT_LEN;
+const KDF_ARGON2ID: u8 = 1;
+const KDF_PBKDF2: u8 = 2;
+
+/// 口令派生算法与参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// Argon2id，内存单位 KiB
+    Argon2id {
+        /// 内存开销（KiB）
+        memory_kib: u32,
+        /// 迭代次数
+        iterations: u32,
+        /// 并行度
+        parallelism: u32,
+    },
+    /// PBKDF2-HMAC-SHA256，用于不便使用 Argon2 的环境
+    Pbkdf2 {
+        /// 迭代次数
+        iterations: u32,
+    },
+}
+
+impl Default for Kdf {
+    /// OWASP 推荐的 Argon2id 最低参数：19 MiB、2 次迭代、单线程
+    fn default() -> Self {
+        Kdf::Argon2id {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Kdf {
+    fn encode(self) -> (u8, [u32; 3]) {
+        match self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => (KDF_ARGON2ID, [memory_kib, iterations, parallelism]),
+            Kdf::Pbkdf2 { iterations } => (KDF_PBKDF2, [iterations, 0, 0]),
+        }
+    }
+
+    fn decode(id: u8, params: [u32; 3]) -> Result<Self, TrustError> {
+        match (id, params) {
+            (KDF_ARGON2ID, [memory_kib, iterations, parallelism]) => Ok(Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            }),
+            (KDF_PBKDF2, [iterations, 0, 0]) => Ok(Kdf::Pbkdf2 { iterations }),
+            _ => Err(TrustError::Storage(format!("unsupported vault KDF {id}"))),
+        }
+    }
+
+    /// 由口令派生包装密钥
+    fn derive(self, passphrase: &[u8], salt: &[u8]) -> Result<SecretKey, TrustError> {
+        let mut out = Zeroizing::new([0u8; 32]);
+        match self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, Some(32))
+                    .map_err(|e| TrustError::Storage(format!("invalid Argon2 parameters: {e}")))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, out.as_mut())
+                    .map_err(|_| TrustError::CryptoFailure)?;
+            }
+            Kdf::Pbkdf2 { iterations } => {
+                let iterations = NonZeroU32::new(iterations)
+                    .ok_or_else(|| TrustError::Storage("zero KDF iterations".to_string()))?;
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations,
+                    salt,
+                    passphrase,
+                    out.as_mut(),
+                );
+            }
+        }
+        Ok(SecretKey::from_bytes(*out))
+    }
+}
+
+/// 口令包装的主密钥
+pub struct Vault {
+    kdf: Kdf,
+    salt: [u8; SALT_LEN],
+    wrapped: Vec<u8>,
+}
+
+impl Vault {
+    /// 生成新的主密钥并用口令包装（默认 Argon2id）
+    pub fn create(passphrase: &[u8]) -> Result<Self, TrustError> {
+        Self::create_with(Kdf::default(), passphrase)
+    }
+
+    /// 使用指定的 KDF 参数
+    pub fn create_with(kdf: Kdf, passphrase: &[u8]) -> Result<Self, TrustError> {
+        Self::wrap(kdf, passphrase, &SecretKey::generate()?)
+    }
+
+    fn wrap(kdf: Kdf, passphrase: &[u8], master: &SecretKey) -> Result<Self, TrustError> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::fill(&mut salt).map_err(|_| TrustError::CryptoFailure)?;
+        let mut vault = Self {
+            kdf,
+            salt,
+            wrapped: Vec::new(),
+        };
+        let key = kdf.derive(passphrase, &salt)?;
+        vault.wrapped = aead::seal(&key, &vault.header(), master.expose_secret())?;
+        Ok(vault)
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let (id, params) = self.kdf.encode();
+        let mut header = [0u8; HEADER_LEN];
+        let mut fields = MAGIC.to_vec();
+        fields.push(VAULT_VERSION);
+        fields.push(id);
+        for param in params {
+            fields.extend_from_slice(&param.to_be_bytes());
+        }
+        fields.extend_from_slice(&self.salt);
+        header.copy_from_slice(&fields);
+        header
+    }
+
+    /// 用口令解出主密钥；口令错误或文件被篡改时返回 `AccessDenied`
+    pub fn unlock(&self, passphrase: &[u8]) -> Result<SecretKey, TrustError> {
+        let key = self.kdf.derive(passphrase, &self.salt)?;
+        let master = aead::open(&key, &self.header(), &self.wrapped)?;
+        let bytes: [u8; 32] = master
+            .as_slice()
+            .try_into()
+            .map_err(|_| TrustError::AccessDenied)?;
+        Ok(SecretKey::from_bytes(bytes))
+    }
+
+    /// 更换口令，主密钥不变；同时换用新盐
+    pub fn change_passphrase(&mut self, old: &[u8], new: &[u8]) -> Result<(), TrustError> {
+        let master = self.unlock(old)?;
+        *self = Self::wrap(self.kdf, new, &master)?;
+        Ok(())
+    }
+
+    /// KDF 参数
+    pub fn kdf(&self) -> Kdf {
+        self.kdf
+    }
+
+    /// 序列化为文件内容
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.header().to_vec();
+        out.extend_from_slice(&self.wrapped);
+        out
+    }
+
+    /// 解析文件内容，不校验口令
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrustError> {
+        let malformed = || TrustError::Storage("malformed vault file".to_string());
+        let (header, wrapped) = bytes.split_at_checked(HEADER_LEN).ok_or_else(malformed)?;
+        let mut reader = Reader::new(header);
+        if reader.array::<4>().as_ref() != Some(MAGIC) {
+            return Err(malformed());
+        }
+        let [version, id] = reader.array().ok_or_else(malformed)?;
+        if version != VAULT_VERSION {
+            return Err(TrustError::Storage(format!(
+                "unsupported vault version {version}"
+            )));
+        }
+        let params = [
+            reader.u32().ok_or_else(malformed)?,
+            reader.u32().ok_or_else(malformed)?,
+            reader.u32().ok_or_else(malformed)?,
+        ];
+        Ok(Self {
+            kdf: Kdf::decode(id, params)?,
+            salt: reader.array().ok_or_else(malformed)?,
+            wrapped: wrapped.to_vec(),
+        })
+    }
+
+    /// 读取保险库文件，不存在时返回 None
+    pub fn load(path: &Path) -> Result<Option<Self>, TrustError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TrustError::Storage(e.to_string())),
+        }
+    }
+
+    /// 原子写入保险库文件，仅所有者可读写
+    pub fn save(&self, path: &Path) -> Result<(), TrustError> {
+        crate::write_private_file(path, &self.to_bytes())
+    }
+}
+
+impl fmt::Debug for Vault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vault")
+            .field("kdf", &self.kdf)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST_ARGON2: Kdf = Kdf::Argon2id {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn unlock_and_change_passphrase() -> Result<(), TrustError> {
+        for kdf in [FAST_ARGON2, Kdf::Pbkdf2 { iterations: 10 }] {
+            let mut vault = Vault::create_with(kdf, b"correct horse")?;
+            let master = vault.unlock(b"correct horse")?;
+            assert!(matches!(
+                vault.unlock(b"wrong"),
+                Err(TrustError::AccessDenied)
+            ));
+
+            let reloaded = Vault::from_bytes(&vault.to_bytes())?;
+            assert_eq!(reloaded.kdf(), kdf);
+            assert!(reloaded.unlock(b"correct horse")?.ct_eq(&master));
+
+            assert!(vault.change_passphrase(b"wrong", b"new").is_err());
+            vault.change_passphrase(b"correct horse", b"battery staple")?;
+            assert!(vault.unlock(b"battery staple")?.ct_eq(&master));
+            assert!(vault.unlock(b"correct horse").is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_parameters_are_rejected() -> Result<(), TrustError> {
+        let vault = Vault::create_with(FAST_ARGON2, b"pass")?;
+        let mut bytes = vault.to_bytes();
+        // 把内存参数从 64 KiB 改为 65 KiB
+        bytes[9] = 65;
+        assert!(matches!(
+            Vault::from_bytes(&bytes)?.unlock(b"pass"),
+            Err(TrustError::AccessDenied)
+        ));
+        assert!(Vault::from_bytes(&bytes[..10]).is_err());
+        Ok(())
+    }
+}
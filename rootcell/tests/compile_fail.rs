@@ -0,0 +1,6 @@
+//! 编译期保证：密钥不能被复制，一次性密钥不能重复取出
+#[test]
+fn key_misuse_does_not_compile() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/compile_fail/*.rs");
+}
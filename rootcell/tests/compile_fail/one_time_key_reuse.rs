@@ -0,0 +1,9 @@
+use rootcell::{OneTimeKey, TrustError};
+
+fn main() -> Result<(), TrustError> {
+    let key = OneTimeKey::generate()?;
+    let first = key.into_inner()?;
+    let second = key.into_inner()?;
+    assert!(first.ct_eq(&second));
+    Ok(())
+}
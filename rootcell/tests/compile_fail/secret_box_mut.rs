@@ -0,0 +1,7 @@
+use rootcell::{SecretBox, TrustError};
+
+fn main() -> Result<(), TrustError> {
+    let shared = SecretBox::generate()?;
+    shared.expose().burn();
+    Ok(())
+}
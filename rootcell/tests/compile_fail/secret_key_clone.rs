@@ -0,0 +1,8 @@
+use rootcell::{SecretKey, TrustError};
+
+fn main() -> Result<(), TrustError> {
+    let key = SecretKey::generate()?;
+    let copy = key.clone();
+    assert!(key.ct_eq(&copy));
+    Ok(())
+}
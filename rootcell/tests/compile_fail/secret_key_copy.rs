@@ -0,0 +1,10 @@
+use rootcell::{SecretKey, TrustError};
+
+fn keep(_key: SecretKey) {}
+
+fn main() -> Result<(), TrustError> {
+    let key = SecretKey::generate()?;
+    keep(key);
+    assert!(key.is_valid());
+    Ok(())
+}
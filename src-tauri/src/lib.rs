@@ -1,21 +1,373 @@
+use chat_core::{ChatCore, ChatHandle, EventReceiver, MessageEvent};
+use serde::Serialize;
+use tauri::{Emitter, Manager};
+
+/// 推送给前端的事件，字段均为可直接渲染的字符串
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum UiEvent {
+    PeerDiscovered {
+        peer: String,
+        addr: String,
+    },
+    PeerExpired {
+        peer: String,
+        addr: String,
+    },
+    MessageReceived {
+        from: String,
+        topic: String,
+        id: String,
+        author: String,
+        user: Option<String>,
+        sent_at: i64,
+        text: String,
+    },
+    DirectMessageReceived {
+        from: String,
+        author: String,
+        user: Option<String>,
+        id: String,
+        sent_at: i64,
+        text: String,
+    },
+    DirectDelivered {
+        peer: String,
+        id: String,
+    },
+    DirectStored {
+        peer: String,
+        id: String,
+        mailbox: String,
+    },
+    DirectFailed {
+        peer: String,
+        id: String,
+        error: String,
+    },
+    HistorySynced {
+        room: String,
+        peer: String,
+        count: usize,
+    },
+    RoomJoined {
+        room: String,
+    },
+    RoomLeft {
+        room: String,
+    },
+    RoomInvited {
+        room: String,
+        from: String,
+    },
+    ListeningOn {
+        addr: String,
+    },
+    ConnectionEstablished {
+        peer: String,
+    },
+    ConnectionClosed {
+        peer: String,
+        cause: Option<String>,
+    },
+    KeyRevoked {
+        peer: String,
+        kind: String,
+        reason: String,
+    },
+    RevokedMessageDropped {
+        peer: String,
+        reason: String,
+    },
+    DeviceAdded {
+        user: String,
+        device: String,
+        name: String,
+    },
+    IdentityKeyChanged {
+        peer: String,
+        was_verified: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+impl From<MessageEvent> for UiEvent {
+    fn from(event: MessageEvent) -> Self {
+        match event {
+            MessageEvent::PeerDiscovered { peer, addr } => UiEvent::PeerDiscovered {
+                peer: peer.to_string(),
+                addr: addr.to_string(),
+            },
+            MessageEvent::PeerExpired { peer, addr } => UiEvent::PeerExpired {
+                peer: peer.to_string(),
+                addr: addr.to_string(),
+            },
+            MessageEvent::MessageReceived {
+                from,
+                author,
+                topic,
+                payload,
+                ..
+            } => UiEvent::MessageReceived {
+                from: from.to_string(),
+                topic,
+                text: payload.text_body(),
+                id: payload.id,
+                author: author.device.to_string(),
+                user: author.user.map(|user| user.to_string()),
+                sent_at: payload.sent_at,
+            },
+            MessageEvent::DirectMessageReceived {
+                from,
+                author,
+                payload,
+            } => UiEvent::DirectMessageReceived {
+                from: from.to_string(),
+                author: author.device.to_string(),
+                user: author.user.map(|user| user.to_string()),
+                text: payload.text_body(),
+                id: payload.id,
+                sent_at: payload.sent_at,
+            },
+            MessageEvent::DirectDelivered { peer, id } => UiEvent::DirectDelivered {
+                peer: peer.to_string(),
+                id,
+            },
+            MessageEvent::DirectStored { peer, id, mailbox } => UiEvent::DirectStored {
+                peer: peer.to_string(),
+                id,
+                mailbox: mailbox.to_string(),
+            },
+            MessageEvent::DirectFailed { peer, id, error } => UiEvent::DirectFailed {
+                peer: peer.to_string(),
+                id,
+                error,
+            },
+            MessageEvent::HistorySynced { room, peer, count } => UiEvent::HistorySynced {
+                room,
+                peer: peer.to_string(),
+                count,
+            },
+            MessageEvent::RoomJoined(room) => UiEvent::RoomJoined { room },
+            MessageEvent::RoomLeft(room) => UiEvent::RoomLeft { room },
+            MessageEvent::RoomInvited { room, from } => UiEvent::RoomInvited {
+                room,
+                from: from.to_string(),
+            },
+            MessageEvent::ListeningOn(addr) => UiEvent::ListeningOn {
+                addr: addr.to_string(),
+            },
+            MessageEvent::ConnectionEstablished(peer) => UiEvent::ConnectionEstablished {
+                peer: peer.to_string(),
+            },
+            MessageEvent::ConnectionClosed { peer, cause } => UiEvent::ConnectionClosed {
+                peer: peer.to_string(),
+                cause,
+            },
+            MessageEvent::KeyRevoked { peer, kind, reason } => UiEvent::KeyRevoked {
+                peer: peer.to_string(),
+                kind: format!("{kind:?}").to_lowercase(),
+                reason,
+            },
+            MessageEvent::RevokedMessageDropped { peer, reason } => {
+                UiEvent::RevokedMessageDropped {
+                    peer: peer.to_string(),
+                    reason,
+                }
+            }
+            MessageEvent::DeviceAdded { user, device, name } => UiEvent::DeviceAdded {
+                user: user.to_string(),
+                device: device.to_string(),
+                name,
+            },
+            MessageEvent::IdentityKeyChanged { peer, was_verified } => {
+                UiEvent::IdentityKeyChanged {
+                    peer: peer.to_string(),
+                    was_verified,
+                }
+            }
+            MessageEvent::Error(message) => UiEvent::Error { message },
+        }
+    }
+}
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
-fn send(message: &str) -> bool {
-    //chat_core::ChatCore::sendmessage(message.to_string());
-    true
+async fn send(
+    room: Option<String>,
+    message: String,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<bool, String> {
+    let room = room.unwrap_or_else(|| chat_core::DEFAULT_TOPIC.to_string());
+    chat.send(room, message).await.map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// 发送私聊，返回消息 id，送达结果见 `directDelivered` / `directFailed` 事件
+#[tauri::command]
+async fn send_direct(
+    peer: String,
+    message: String,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<String, String> {
+    let peer = peer.parse().map_err(|e| format!("invalid peer id: {e}"))?;
+    let sent = chat
+        .send_direct(peer, message)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(sent.id)
+}
+
+#[tauri::command]
+async fn join_room(room: String, chat: tauri::State<'_, ChatHandle>) -> Result<bool, String> {
+    chat.join_room(room).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn leave_room(room: String, chat: tauri::State<'_, ChatHandle>) -> Result<bool, String> {
+    chat.leave_room(room).await.map_err(|e| e.to_string())
+}
+
+/// 邀请或接纳房间成员
+#[tauri::command]
+async fn add_member(
+    room: String,
+    peer: String,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<bool, String> {
+    let peer = peer.parse().map_err(|e| format!("invalid peer id: {e}"))?;
+    chat.add_member(room, peer).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_member(
+    room: String,
+    peer: String,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<bool, String> {
+    let peer = peer.parse().map_err(|e| format!("invalid peer id: {e}"))?;
+    chat.remove_member(room, peer)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rooms(chat: tauri::State<'_, ChatHandle>) -> Result<Vec<String>, String> {
+    chat.rooms().await.map_err(|e| e.to_string())
+}
+/// 与联系人的安全码，供前端展示与比对
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SafetyNumberView {
+    peer: String,
+    safety_number: String,
+    emoji: Vec<&'static str>,
+    words: Vec<&'static str>,
+    verified: bool,
+}
+
+impl From<chat_core::Verification> for SafetyNumberView {
+    fn from(verification: chat_core::Verification) -> Self {
+        SafetyNumberView {
+            peer: verification.peer.to_string(),
+            safety_number: verification.safety_number.to_string(),
+            emoji: verification.safety_number.emoji(),
+            words: verification.safety_number.words(),
+            verified: verification.verified,
+        }
+    }
+}
+
+#[tauri::command]
+async fn safety_number(
+    peer: String,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<SafetyNumberView, String> {
+    let peer = peer.parse().map_err(|e| format!("invalid peer id: {e}"))?;
+    let verification = chat.verification(peer).await.map_err(|e| e.to_string())?;
+    Ok(verification.into())
+}
+
+/// 比对安全码后标记（或取消）联系人为已核验
+#[tauri::command]
+async fn set_verified(
+    peer: String,
+    verified: bool,
+    chat: tauri::State<'_, ChatHandle>,
+) -> Result<SafetyNumberView, String> {
+    let peer = peer.parse().map_err(|e| format!("invalid peer id: {e}"))?;
+    let verification = chat
+        .set_verified(peer, verified)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(verification.into())
+}
+
+/// 生成配对口令，在新设备上输入后即加入本账户
+#[tauri::command]
+async fn start_pairing(chat: tauri::State<'_, ChatHandle>) -> Result<String, String> {
+    chat.start_pairing().await.map_err(|e| e.to_string())
+}
+
+/// 输入另一台设备显示的配对口令加入其账户，返回证书过期时间（unix 毫秒）
+#[tauri::command]
+async fn pair_with(code: String, chat: tauri::State<'_, ChatHandle>) -> Result<u64, String> {
+    let certificate = chat.pair_with(code).await.map_err(|e| e.to_string())?;
+    Ok(certificate.expires_at)
 }
 /*app_data_dir()		数据库、配置
 app_local_data_dir()	缓存、日志
 app_config_dir()		用户配置
 temp_dir() */
+/// 启动聊天核心，返回句柄与事件接收端
+async fn start_core(app: &tauri::AppHandle) -> anyhow::Result<(ChatHandle, EventReceiver)> {
+    let data_dir = app.path().app_data_dir()?;
+    std::fs::create_dir_all(&data_dir)?;
+    let db = data_dir.join("chat_history.db");
+    let cfg = chat_core::CoreConfig::new(db.to_string_lossy())
+        .with_identity_path(data_dir.join("identity.key"));
+    let (handle, events) = ChatCore::spawn(&cfg).await?;
+    handle.listen("/ip4/0.0.0.0/udp/0/quic-v1".parse()?).await?;
+    handle.listen("/ip4/0.0.0.0/tcp/0".parse()?).await?;
+    Ok((handle, events))
+}
+/// 把核心事件转发到前端 `chat-event`
+async fn forward_events(app: tauri::AppHandle, mut events: EventReceiver) {
+    while let Some(event) = events.recv().await {
+        if let Err(e) = app.emit("chat-event", UiEvent::from(event)) {
+            eprintln!("failed to emit chat event: {e}");
+        }
+    }
+}
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let core =
-        //chat_core::ChatCore::try_init(&chat_core::CoreConfig::new("sqlite:///path/to/database.db"));
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        //.manage()
-        .invoke_handler(tauri::generate_handler![send])
+        .setup(|app| {
+            let handle = app.handle().clone();
+            let (chat, events) = tauri::async_runtime::block_on(start_core(&handle))?;
+            app.manage(chat);
+            tauri::async_runtime::spawn(forward_events(handle, events));
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            send,
+            send_direct,
+            join_room,
+            leave_room,
+            add_member,
+            remove_member,
+            rooms,
+            start_pairing,
+            pair_with,
+            safety_number,
+            set_verified
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }